@@ -0,0 +1,205 @@
+//! Snapshot and restore of the ALAN database via SQLite's online backup API,
+//! and merging of JSON exports (`zsh_alan_export`) from other machines.
+
+use rusqlite::{Connection, DatabaseName};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::Path;
+
+use super::stats::AlanExport;
+
+/// Write a consistent snapshot of the ALAN database at `db_path` to `dest_path`.
+/// Safe to run while the server is live — the backup API copies a
+/// transactionally consistent view.
+pub fn backup(db_path: &str, dest_path: &str) -> Result<(), String> {
+    let conn = super::open_db(db_path)?;
+    if let Some(parent) = Path::new(dest_path).parent() {
+        if !parent.as_os_str().is_empty() {
+            std::fs::create_dir_all(parent).map_err(|e| format!("mkdir: {}", e))?;
+        }
+    }
+    conn.backup(DatabaseName::Main, dest_path, None)
+        .map_err(|e| format!("backup: {}", e))
+}
+
+/// Replace the ALAN database at `db_path` with the snapshot at `src_path`.
+/// The schema is re-applied afterwards so snapshots from older versions
+/// gain any tables added since.
+pub fn restore(db_path: &str, src_path: &str) -> Result<(), String> {
+    if !Path::new(src_path).is_file() {
+        return Err(format!("restore: no such file: {}", src_path));
+    }
+    let mut conn = super::open_db(db_path)?;
+    conn.restore(DatabaseName::Main, src_path, None::<fn(rusqlite::backup::Progress)>)
+        .map_err(|e| format!("restore: {}", e))?;
+    super::init_schema(&conn)
+}
+
+/// Count observations in a database — used to report what a backup/restore moved.
+pub fn observation_count(conn: &Connection) -> i64 {
+    conn.query_row("SELECT COUNT(*) FROM observations", [], |row| row.get(0))
+        .unwrap_or(0)
+}
+
+/// Row counts from [`merge_export`].
+#[derive(Debug, Default, Serialize)]
+pub struct ImportSummary {
+    pub observations_added: usize,
+    /// Observations folded into a local one for the same command and outcome.
+    pub observations_merged: usize,
+    /// Observations imported before (same id), left alone.
+    pub observations_skipped: usize,
+    pub streaks_added: usize,
+    /// Streaks for a `command_hash` already present, combined with the local one.
+    pub streaks_merged: usize,
+    pub ssh_observations_added: usize,
+}
+
+/// Merge an export into the database in one transaction; any failing row
+/// rolls the whole import back.
+///
+/// An observation whose `command_hash` already has one with the same outcome
+/// (exit code and timeout) is folded into the latest such row: weights and
+/// repeat counts are summed. Others are inserted. Imported ids are
+/// remembered, so importing the same file again changes nothing. SSH
+/// observations are keyed by id and follow their observation. Streaks are
+/// keyed by `command_hash`: longest streaks take the maximum, and the current
+/// streak and last result come from whichever side was updated last.
+pub fn merge_export(conn: &mut Connection, export: &AlanExport) -> Result<ImportSummary, String> {
+    let tx = conn.transaction().map_err(|e| format!("import: {}", e))?;
+    let mut summary = ImportSummary::default();
+    // Imported observation id -> the local row that holds it
+    let mut local_ids: HashMap<String, String> = HashMap::new();
+
+    for o in &export.observations {
+        let seen: Option<String> = tx
+            .query_row(
+                "SELECT merged_into FROM imported_observations WHERE id = ?1
+                 UNION ALL SELECT id FROM observations WHERE id = ?1 LIMIT 1",
+                [&o.id],
+                |row| row.get(0),
+            )
+            .ok();
+        if let Some(local) = seen {
+            local_ids.insert(o.id.clone(), local);
+            summary.observations_skipped += 1;
+            continue;
+        }
+        let duplicate: Option<String> = tx
+            .query_row(
+                "SELECT id FROM observations
+                 WHERE command_hash = ?1 AND exit_code IS ?2 AND timed_out = ?3
+                 ORDER BY created_at DESC LIMIT 1",
+                rusqlite::params![o.command_hash, o.exit_code, o.timed_out as i64],
+                |row| row.get(0),
+            )
+            .ok();
+        if let Some(local) = duplicate {
+            tx.execute(
+                "UPDATE observations
+                 SET weight = weight + ?2, repeat_count = COALESCE(repeat_count, 1) + ?3,
+                     pinned = MAX(pinned, ?4)
+                 WHERE id = ?1",
+                rusqlite::params![local, o.weight, o.repeat_count, o.pinned as i64],
+            )
+            .map_err(|e| format!("import observation {}: {}", o.id, e))?;
+            tx.execute(
+                "INSERT INTO imported_observations (id, merged_into) VALUES (?1, ?2)",
+                rusqlite::params![o.id, local],
+            )
+            .map_err(|e| format!("import observation {}: {}", o.id, e))?;
+            local_ids.insert(o.id.clone(), local);
+            summary.observations_merged += 1;
+            continue;
+        }
+        tx.execute(
+            "INSERT INTO observations
+             (id, command_hash, command_template, command_preview, exit_code, duration_ms,
+              timed_out, output_snippet, error_snippet, weight, created_at, last_accessed,
+              prev_hash, cwd, repeat_count, pinned)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16)",
+            rusqlite::params![
+                o.id,
+                o.command_hash,
+                o.command_template,
+                o.command_preview,
+                o.exit_code,
+                o.duration_ms,
+                o.timed_out as i64,
+                o.output_snippet,
+                o.error_snippet,
+                o.weight,
+                o.created_at,
+                o.last_accessed,
+                o.prev_hash,
+                o.cwd,
+                o.repeat_count,
+                o.pinned as i64,
+            ],
+        )
+        .map_err(|e| format!("import observation {}: {}", o.id, e))?;
+        summary.observations_added += 1;
+    }
+
+    for s in &export.streaks {
+        let exists: bool = tx
+            .query_row("SELECT 1 FROM streaks WHERE command_hash = ?1", [&s.command_hash], |_| Ok(true))
+            .unwrap_or(false);
+        tx.execute(
+            "INSERT INTO streaks
+             (command_hash, current_streak, longest_success_streak, longest_fail_streak,
+              last_result, last_updated)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+             ON CONFLICT(command_hash) DO UPDATE SET
+                longest_success_streak = MAX(longest_success_streak, excluded.longest_success_streak),
+                longest_fail_streak = MAX(longest_fail_streak, excluded.longest_fail_streak),
+                current_streak = CASE WHEN COALESCE(excluded.last_updated, 0) > COALESCE(last_updated, 0)
+                    THEN excluded.current_streak ELSE current_streak END,
+                last_result = CASE WHEN COALESCE(excluded.last_updated, 0) > COALESCE(last_updated, 0)
+                    THEN excluded.last_result ELSE last_result END,
+                last_updated = MAX(COALESCE(last_updated, 0), COALESCE(excluded.last_updated, 0))",
+            rusqlite::params![
+                s.command_hash,
+                s.current_streak,
+                s.longest_success_streak,
+                s.longest_fail_streak,
+                s.last_result,
+                s.last_updated,
+            ],
+        )
+        .map_err(|e| format!("import streak {}: {}", s.command_hash, e))?;
+        if exists {
+            summary.streaks_merged += 1;
+        } else {
+            summary.streaks_added += 1;
+        }
+    }
+
+    for s in &export.ssh_observations {
+        let inserted = tx
+            .execute(
+                "INSERT OR IGNORE INTO ssh_observations
+                 (id, observation_id, host, remote_command, remote_command_template, exit_code,
+                  exit_type, duration_ms, timed_out, weight, created_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+                rusqlite::params![
+                    s.id,
+                    s.observation_id.as_ref().map(|id| local_ids.get(id).unwrap_or(id)),
+                    s.host,
+                    s.remote_command,
+                    s.remote_command_template,
+                    s.exit_code,
+                    s.exit_type,
+                    s.duration_ms,
+                    s.timed_out as i64,
+                    s.weight,
+                    s.created_at,
+                ],
+            )
+            .map_err(|e| format!("import ssh observation {}: {}", s.id, e))?;
+        summary.ssh_observations_added += inserted;
+    }
+
+    tx.commit().map_err(|e| format!("import commit: {}", e))?;
+    Ok(summary)
+}
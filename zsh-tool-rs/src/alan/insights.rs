@@ -4,6 +4,7 @@
 //! Post-insights: exit code awareness, pipe masking, silent detection.
 
 use rusqlite::Connection;
+use serde::Serialize;
 use std::collections::HashMap;
 
 use super::hash;
@@ -28,6 +29,57 @@ fn universal_exit_codes() -> HashMap<i32, &'static str> {
     ])
 }
 
+/// Universal exit codes retrying cannot fix: the command is not executable
+/// or does not exist.
+const PERMANENT_EXIT_CODES: [i32; 2] = [126, 127];
+
+/// How a failed run responds to being retried.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FailureClass {
+    /// Worth retrying: a timeout or an exit in the transient set.
+    Transient,
+    /// Retrying the same command cannot help (126, 127).
+    Permanent,
+    /// Any other failure; not retried.
+    Other,
+}
+
+/// Classify a failed run for retrying. Timeouts are transient when
+/// `retry_timeouts` is set; exits in `transient_codes` (e.g. SSH's 255) are
+/// transient unless they are permanent universal codes.
+pub fn classify_failure(exit_code: i32, timed_out: bool, transient_codes: &[i32], retry_timeouts: bool) -> FailureClass {
+    if timed_out {
+        return if retry_timeouts { FailureClass::Transient } else { FailureClass::Other };
+    }
+    if PERMANENT_EXIT_CODES.contains(&exit_code) {
+        FailureClass::Permanent
+    } else if transient_codes.contains(&exit_code) {
+        FailureClass::Transient
+    } else {
+        FailureClass::Other
+    }
+}
+
+/// `exit N`, with the universal meaning when there is one:
+/// `command not found (exit 127)`.
+pub fn describe_exit(exit_code: i32) -> String {
+    match universal_exit_codes().get(&exit_code) {
+        Some(meaning) => format!("{} (exit {})", meaning, exit_code),
+        None => format!("exit {}", exit_code),
+    }
+}
+
+/// Runs of the exact command within the recent window, as counted for the
+/// retry insight.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct RetryInfo {
+    /// Earlier runs in the window; the upcoming run is retry #`retry_count + 1`.
+    pub retry_count: i64,
+    pub recent_successes: i64,
+    pub recent_failures: i64,
+    pub window_minutes: u64,
+}
+
 /// Generate pre-execution insights for a command.
 /// Returns Vec of (level, message) tuples. Level is "info" or "warning".
 pub fn get_pre_insights(
@@ -37,9 +89,63 @@ pub fn get_pre_insights(
     streak_threshold: i64,
     recent_window_minutes: u64,
 ) -> Vec<(String, String)> {
+    get_pre_insights_with_retry(conn, command, session_id, streak_threshold, recent_window_minutes).0
+}
+
+/// Like [`get_pre_insights`], also returning the retry counts behind the
+/// retry message so callers can act on them without parsing prose.
+pub fn get_pre_insights_with_retry(
+    conn: &Connection,
+    command: &str,
+    session_id: &str,
+    streak_threshold: i64,
+    recent_window_minutes: u64,
+) -> (Vec<(String, String)>, RetryInfo) {
+    pre_insights(
+        conn,
+        command,
+        &hash::hash_command(command),
+        &hash::template_command(command),
+        session_id,
+        streak_threshold,
+        recent_window_minutes,
+    )
+}
+
+/// Like [`get_pre_insights`] for a command known only by its stored hash,
+/// template and (possibly truncated) preview, so a cut-down preview still
+/// finds the full command's history.
+pub fn get_pre_insights_for_hash(
+    conn: &Connection,
+    preview: &str,
+    command_hash: &str,
+    command_template: &str,
+    session_id: &str,
+    streak_threshold: i64,
+    recent_window_minutes: u64,
+) -> Vec<(String, String)> {
+    pre_insights(
+        conn,
+        preview,
+        command_hash,
+        command_template,
+        session_id,
+        streak_threshold,
+        recent_window_minutes,
+    )
+    .0
+}
+
+fn pre_insights(
+    conn: &Connection,
+    command: &str,
+    command_hash: &str,
+    command_template: &str,
+    session_id: &str,
+    streak_threshold: i64,
+    recent_window_minutes: u64,
+) -> (Vec<(String, String)>, RetryInfo) {
     let mut insights = Vec::new();
-    let command_hash = hash::hash_command(command);
-    let command_template = hash::template_command(command);
     let now = std::time::SystemTime::now()
         .duration_since(std::time::UNIX_EPOCH)
         .unwrap_or_default()
@@ -48,9 +154,15 @@ pub fn get_pre_insights(
 
     // --- Recent activity (retry detection) ---
     let (is_retry, retry_count, recent_successes, recent_failures) =
-        get_recent_exact(conn, &command_hash, window_start);
+        get_recent_exact(conn, command_hash, window_start);
+    let retry_info = RetryInfo {
+        retry_count,
+        recent_successes,
+        recent_failures,
+        window_minutes: recent_window_minutes,
+    };
 
-    let similar = get_recent_similar(conn, &command_template, &command_hash, window_start);
+    let similar = get_recent_similar(conn, command_template, command_hash, window_start);
 
     // Retry detection
     if is_retry && retry_count >= 1 {
@@ -97,7 +209,7 @@ pub fn get_pre_insights(
     }
 
     // --- Streak info ---
-    if let Some((current, _longest_success, _longest_fail)) = get_streak(conn, &command_hash) {
+    if let Some((current, _longest_success, _longest_fail)) = get_streak(conn, command_hash) {
         if current >= streak_threshold {
             insights.push((
                 "info".into(),
@@ -112,7 +224,7 @@ pub fn get_pre_insights(
     }
 
     // --- Pattern history ---
-    if let Some(stats) = get_pattern_stats(conn, &command_hash) {
+    if let Some(stats) = get_pattern_stats(conn, command_hash) {
         if stats.timeout_rate > 0.5 {
             insights.push((
                 "warning".into(),
@@ -144,7 +256,7 @@ pub fn get_pre_insights(
     insights.extend(ssh_insights);
 
     // --- manopt presentation on repeated failures ---
-    let fail_count = get_template_fail_count(conn, session_id, &command_template);
+    let fail_count = get_template_fail_count(conn, session_id, command_template);
     // fail_present - 1 because we haven't recorded the current execution yet
     if fail_count >= 2 {
         let base_cmd = extract_base_command(command);
@@ -158,14 +270,99 @@ pub fn get_pre_insights(
         }
     }
 
+    (insights, retry_info)
+}
+
+/// Remediation guidance for the patterns that tripped the circuit breaker.
+/// Each hash resolves to its last recorded preview; hashes without history
+/// are skipped.
+pub fn get_timeout_remediation(conn: &Connection, hashes: &[String]) -> Vec<(String, String)> {
+    let mut insights = Vec::new();
+    for command_hash in hashes {
+        let preview = match get_preview(conn, command_hash) {
+            Some(p) => p,
+            None => continue,
+        };
+        let stats = get_pattern_stats(conn, command_hash);
+        let completed_avg_ms = get_completed_avg_ms(conn, command_hash);
+
+        let history = match &stats {
+            Some(s) => format!(
+                " ({:.0}% timeouts over {} runs)",
+                s.timeout_rate * 100.0,
+                s.observations
+            ),
+            None => String::new(),
+        };
+        let advice = match completed_avg_ms {
+            Some(ms) => format!(
+                "Completes in ~{:.0}s when it succeeds; raise its timeout or run it with a short yield_after and zsh_poll.",
+                ms / 1000.0
+            ),
+            None => "Never completed in history; check for interactive prompts (pass --yes/-y, or use pty + zsh_send) or an unreachable host.".to_string(),
+        };
+        insights.push((
+            "warning".into(),
+            format!("Timed out: '{}'{}. {}", preview, history, advice),
+        ));
+    }
+    if !insights.is_empty() {
+        insights.push((
+            "info".into(),
+            "Fix the cause, then zsh_neverhang_reset to close the circuit early.".into(),
+        ));
+    }
     insights
 }
 
+fn get_preview(conn: &Connection, command_hash: &str) -> Option<String> {
+    ["SELECT command_preview FROM observations
+      WHERE command_hash = ? AND command_preview IS NOT NULL
+      ORDER BY created_at DESC LIMIT 1",
+     "SELECT command_preview FROM recent_commands
+      WHERE command_hash = ? AND command_preview IS NOT NULL
+      ORDER BY timestamp DESC LIMIT 1"]
+    .iter()
+    .find_map(|sql| {
+        conn.query_row(sql, rusqlite::params![command_hash], |row| row.get(0))
+            .ok()
+    })
+}
+
+fn get_completed_avg_ms(conn: &Connection, command_hash: &str) -> Option<f64> {
+    conn.query_row(
+        "SELECT AVG(duration_ms) FROM observations WHERE command_hash = ? AND timed_out = 0",
+        rusqlite::params![command_hash],
+        |row| row.get::<_, Option<f64>>(0),
+    )
+    .ok()
+    .flatten()
+}
+
 /// Generate post-execution insights based on exit codes and output.
 pub fn get_post_insights(
     command: &str,
     pipestatus: &[i32],
     output: &str,
+) -> Vec<(String, String)> {
+    get_post_insights_with_silent(command, pipestatus, output, DEFAULT_SILENT_COMMANDS)
+}
+
+/// Base commands that print nothing when they succeed, so "No output
+/// produced" would only be noise. Overridable with `silent_commands`.
+pub const DEFAULT_SILENT_COMMANDS: &[&str] = &[
+    "alias", "cd", "chgrp", "chmod", "chown", "cp", "export", "kill", "ln", "mkdir", "mv",
+    "rm", "rmdir", "setopt", "sleep", "source", "touch", "true", "unalias", "unset",
+    "unsetopt", "wait",
+];
+
+/// Like [`get_post_insights`], skipping the empty-output insight when every
+/// command in `command` has its base command in `silent_commands`.
+pub fn get_post_insights_with_silent(
+    command: &str,
+    pipestatus: &[i32],
+    output: &str,
+    silent_commands: &[impl AsRef<str>],
 ) -> Vec<(String, String)> {
     let mut insights = Vec::new();
 
@@ -176,7 +373,7 @@ pub fn get_post_insights(
     let overall_exit = pipestatus[pipestatus.len() - 1];
 
     // Silent command detection
-    if overall_exit == 0 && output.trim().is_empty() {
+    if overall_exit == 0 && output.trim().is_empty() && !expected_silent(command, silent_commands) {
         insights.push(("info".into(), "No output produced.".into()));
     }
 
@@ -215,6 +412,76 @@ pub fn get_post_insights(
     insights
 }
 
+/// Running this many times longer than the pattern's average is flagged.
+pub const SLOW_RUN_FACTOR: f64 = 3.0;
+
+/// Share of the timeout after which a running command is flagged.
+pub const TIMEOUT_WARN_FRACTION: f64 = 0.8;
+
+/// Output lines that signal trouble even before the command exits.
+const ERROR_SIGNATURES: &[(&str, &str)] = &[
+    ("Traceback (most recent call last)", "a Python traceback"),
+    ("panicked at", "a Rust panic"),
+    ("Segmentation fault", "a segfault"),
+    ("No space left on device", "a full disk"),
+    ("Out of memory", "an out-of-memory error"),
+    ("FATAL", "a fatal error"),
+];
+
+/// Insights for a command that is still running, from how long it has run
+/// against its usual duration and timeout, and from output seen since the
+/// last check. Each comes with a key naming the condition so callers can
+/// report it once.
+pub fn get_runtime_insights(
+    elapsed_secs: f64,
+    usual_secs: Option<f64>,
+    timeout_secs: u64,
+    new_output: &str,
+) -> Vec<(String, (String, String))> {
+    let mut insights = Vec::new();
+
+    if let Some(usual) = usual_secs.filter(|u| *u > 0.0) {
+        if elapsed_secs >= usual * SLOW_RUN_FACTOR {
+            insights.push((
+                "slow".to_string(),
+                (
+                    "warning".to_string(),
+                    format!(
+                        "Running {:.0}s, {:.1}x its usual {:.1}s. May be stuck.",
+                        elapsed_secs,
+                        elapsed_secs / usual,
+                        usual
+                    ),
+                ),
+            ));
+        }
+    }
+
+    if timeout_secs > 0 && elapsed_secs >= timeout_secs as f64 * TIMEOUT_WARN_FRACTION {
+        insights.push((
+            "timeout".to_string(),
+            (
+                "warning".to_string(),
+                format!("Approaching timeout: {:.0}s of {}s used.", elapsed_secs, timeout_secs),
+            ),
+        ));
+    }
+
+    for line in new_output.lines() {
+        for (signature, what) in ERROR_SIGNATURES {
+            if line.contains(signature) {
+                let line: String = line.trim().chars().take(120).collect();
+                insights.push((
+                    format!("output:{}", signature),
+                    ("warning".to_string(), format!("Output shows {}: {}", what, line)),
+                ));
+            }
+        }
+    }
+
+    insights
+}
+
 // --- Internal helpers ---
 
 fn get_recent_exact(
@@ -294,7 +561,7 @@ struct PatternStats {
 fn get_pattern_stats(conn: &Connection, command_hash: &str) -> Option<PatternStats> {
     conn.query_row(
         "SELECT
-            COUNT(*) as total,
+            SUM(COALESCE(repeat_count, 1)) as total,
             SUM(weight) as weighted_total,
             SUM(CASE WHEN timed_out = 1 THEN weight ELSE 0 END) as timeout_weight,
             SUM(CASE WHEN exit_code = 0 THEN weight ELSE 0 END) as success_weight,
@@ -303,7 +570,7 @@ fn get_pattern_stats(conn: &Connection, command_hash: &str) -> Option<PatternSta
          WHERE command_hash = ?",
         rusqlite::params![command_hash],
         |row| {
-            let total: i64 = row.get(0)?;
+            let total: i64 = row.get::<_, Option<i64>>(0)?.unwrap_or(0);
             if total == 0 {
                 return Ok(None);
             }
@@ -360,6 +627,21 @@ fn get_cached_manopt(conn: &Connection, base_cmd: &str) -> Option<String> {
     .ok()
 }
 
+/// Whether every command in a `;`, `&&`, `||` or `|` chain is one that
+/// normally prints nothing.
+fn expected_silent(command: &str, silent_commands: &[impl AsRef<str>]) -> bool {
+    let mut segments = command
+        .split([';', '&', '|', '\n'])
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .peekable();
+    segments.peek().is_some()
+        && segments.all(|segment| {
+            let base = extract_base_command(segment);
+            silent_commands.iter().any(|c| c.as_ref() == base)
+        })
+}
+
 /// Extract base command name from a command string.
 /// "git status" -> "git", "/usr/bin/grep foo" -> "grep"
 pub fn extract_base_command(command: &str) -> String {
@@ -0,0 +1,87 @@
+//! Health checks for the ALAN database (`zsh_alan_check`, `zsh-tool alan-check`).
+
+use rusqlite::{Connection, OpenFlags};
+use serde::Serialize;
+
+/// Result of [`check`].
+#[derive(Debug, Serialize)]
+pub struct CheckReport {
+    /// Whether `PRAGMA integrity_check` passed.
+    pub ok: bool,
+    /// `PRAGMA integrity_check` output: `["ok"]` or the problems found.
+    pub integrity: Vec<String>,
+    /// Row count of every table, by name.
+    pub tables: Vec<TableRows>,
+    /// `ssh_observations` rows whose observation no longer exists. Harmless;
+    /// the next prune removes them.
+    pub orphaned_ssh_observations: i64,
+}
+
+/// One entry of [`CheckReport::tables`].
+#[derive(Debug, Serialize)]
+pub struct TableRows {
+    pub table: String,
+    pub rows: i64,
+}
+
+/// [`check`] the database at `db_path`, opened read-only and as found: no
+/// schema setup or migrations touch a file that may be damaged. A missing
+/// file is an error rather than a new empty database.
+pub fn check_path(db_path: &str) -> Result<CheckReport, String> {
+    if !std::path::Path::new(db_path).is_file() {
+        return Err(format!("no ALAN database at {}", db_path));
+    }
+    let conn = Connection::open_with_flags(db_path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .map_err(|e| format!("open {}: {}", db_path, e))?;
+    check(&conn)
+}
+
+/// Run `PRAGMA integrity_check`, count the rows of every table and look for
+/// orphaned `ssh_observations`. When the integrity check fails, tables that
+/// can't be read are left out instead of failing the whole report.
+pub fn check(conn: &Connection) -> Result<CheckReport, String> {
+    let integrity = conn
+        .prepare("PRAGMA integrity_check")
+        .and_then(|mut stmt| {
+            stmt.query_map([], |row| row.get::<_, String>(0))?
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .map_err(|e| format!("integrity_check: {}", e))?;
+    let ok = integrity == ["ok"];
+
+    let names = conn
+        .prepare("SELECT name FROM sqlite_master WHERE type = 'table' AND name NOT LIKE 'sqlite_%' ORDER BY name")
+        .and_then(|mut stmt| {
+            stmt.query_map([], |row| row.get::<_, String>(0))?
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .or_else(|e| if ok { Err(format!("list tables: {}", e)) } else { Ok(Vec::new()) })?;
+    let has_ssh = names.iter().any(|t| t == "ssh_observations") && names.iter().any(|t| t == "observations");
+    let mut tables = Vec::new();
+    for table in names {
+        match conn.query_row(&format!("SELECT COUNT(*) FROM \"{}\"", table.replace('"', "\"\"")), [], |row| row.get(0)) {
+            Ok(rows) => tables.push(TableRows { table, rows }),
+            Err(e) if ok => return Err(format!("count {}: {}", table, e)),
+            Err(_) => {}
+        }
+    }
+
+    let orphaned_ssh_observations = if has_ssh {
+        conn.query_row(
+            "SELECT COUNT(*) FROM ssh_observations
+             WHERE observation_id NOT IN (SELECT id FROM observations)",
+            [],
+            |row| row.get(0),
+        )
+        .or_else(|e| if ok { Err(format!("count orphaned ssh_observations: {}", e)) } else { Ok(0) })?
+    } else {
+        0
+    };
+
+    Ok(CheckReport {
+        ok,
+        integrity,
+        tables,
+        orphaned_ssh_observations,
+    })
+}
@@ -3,12 +3,22 @@
 
 use regex::Regex;
 use rusqlite::Connection;
-use std::process::Command;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
 
 /// Run `man <command>` and parse options into a formatted table.
 /// Returns the table string, or None if no man page or no options found.
 pub fn parse_manopt(base_command: &str, max_width: usize) -> Option<String> {
-    let man_text = get_man_text(base_command)?;
+    parse_manopt_within(base_command, max_width, None)
+}
+
+/// Like [`parse_manopt`], giving up (and killing `man`) after `timeout`.
+pub fn parse_manopt_within(
+    base_command: &str,
+    max_width: usize,
+    timeout: Option<Duration>,
+) -> Option<String> {
+    let man_text = get_man_text(base_command, timeout)?;
     let section = extract_options_section(&man_text);
 
     let lines = if section.is_empty() {
@@ -28,7 +38,16 @@ pub fn parse_manopt(base_command: &str, max_width: usize) -> Option<String> {
 
 /// Run manopt, cache the result, return the table text.
 pub fn run_and_cache(conn: &Connection, base_command: &str) -> Option<String> {
-    let text = parse_manopt(base_command, 120)?;
+    run_and_cache_within(conn, base_command, None)
+}
+
+/// Like [`run_and_cache`], bounding the man parse by `timeout`.
+pub fn run_and_cache_within(
+    conn: &Connection,
+    base_command: &str,
+    timeout: Option<Duration>,
+) -> Option<String> {
+    let text = parse_manopt_within(base_command, 120, timeout)?;
     let now_iso = chrono::Utc::now().to_rfc3339();
 
     let _ = conn.execute(
@@ -52,8 +71,45 @@ pub fn get_cached(conn: &Connection, base_command: &str) -> Option<String> {
 
 // --- Internals ---
 
-fn get_man_text(command: &str) -> Option<String> {
-    let man_output = Command::new("man").arg(command).output().ok()?;
+fn get_man_text(command: &str, timeout: Option<Duration>) -> Option<String> {
+    let man_output = match timeout {
+        None => Command::new("man").arg(command).output().ok()?,
+        Some(limit) => {
+            let mut child = Command::new("man")
+                .arg(command)
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+                .ok()?;
+            let deadline = Instant::now() + limit;
+            // Drain stdout on a thread so a large page can't fill the pipe and stall `man`
+            let mut stdout = child.stdout.take()?;
+            let reader = std::thread::spawn(move || {
+                use std::io::Read;
+                let mut buf = Vec::new();
+                let _ = stdout.read_to_end(&mut buf);
+                buf
+            });
+            loop {
+                match child.try_wait().ok()? {
+                    Some(_) => break,
+                    None if Instant::now() >= deadline => {
+                        let _ = child.kill();
+                        let _ = child.wait();
+                        return None;
+                    }
+                    None => std::thread::sleep(Duration::from_millis(20)),
+                }
+            }
+            let status = child.wait().ok()?;
+            std::process::Output {
+                status,
+                stdout: reader.join().ok()?,
+                stderr: Vec::new(),
+            }
+        }
+    };
 
     if !man_output.status.success() {
         return None;
@@ -62,8 +118,8 @@ fn get_man_text(command: &str) -> Option<String> {
     // Strip backspace-based formatting using `col -b`
     let col_output = Command::new("col")
         .arg("-b")
-        .stdin(std::process::Stdio::piped())
-        .stdout(std::process::Stdio::piped())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
         .spawn()
         .ok()
         .and_then(|mut child| {
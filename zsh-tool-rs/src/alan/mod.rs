@@ -1,11 +1,15 @@
 use rusqlite::Connection;
 use std::path::Path;
 
+pub mod backup;
 pub mod hash;
 pub mod insights;
+pub mod maintenance;
 pub mod manopt;
+pub mod network;
 pub mod pipeline;
 pub mod prune;
+pub mod sequence;
 pub mod ssh;
 pub mod stats;
 pub mod streak;
@@ -37,7 +41,10 @@ pub fn init_schema(conn: &Connection) -> Result<(), String> {
             error_snippet TEXT,
             weight REAL DEFAULT 1.0,
             created_at TEXT NOT NULL,
-            last_accessed TEXT
+            last_accessed TEXT,
+            prev_hash TEXT,
+            cwd TEXT,
+            repeat_count INTEGER DEFAULT 1
         );
 
         CREATE INDEX IF NOT EXISTS idx_command_hash ON observations(command_hash);
@@ -108,13 +115,121 @@ pub fn init_schema(conn: &Connection) -> Result<(), String> {
         );
         ",
     )
-    .map_err(|e| format!("schema: {}", e))
+    .map_err(|e| format!("schema: {}", e))?;
+    migrate(conn)
 }
 
+/// Schema version this binary writes; stored as `schema_version` in `meta`.
+pub const CURRENT_SCHEMA_VERSION: i64 = 5;
+
+/// Ordered migrations: entry `n` takes the schema from version `n` to `n + 1`.
+/// Append new steps (usually `ALTER TABLE ... ADD COLUMN`) and bump
+/// `CURRENT_SCHEMA_VERSION`; never edit a step that has shipped.
+const MIGRATIONS: &[&str] = &[
+    // 1: baseline, the tables created by init_schema plus the legacy columns
+    "",
+    // 2: pinned observations are exempt from decay and pruning (zsh_alan_pin)
+    "ALTER TABLE observations ADD COLUMN pinned INTEGER NOT NULL DEFAULT 0;",
+    // 3: JSON-RPC id of the tools/call behind each run, for tracing
+    "ALTER TABLE recent_commands ADD COLUMN request_id TEXT;",
+    // 4: ids of imported observations, so importing a file twice is a no-op
+    "CREATE TABLE IF NOT EXISTS imported_observations (
+        id TEXT PRIMARY KEY,
+        merged_into TEXT NOT NULL
+    );",
+    // 5: stderr of each failed run, so session views quote this session's error
+    "ALTER TABLE recent_commands ADD COLUMN error_snippet TEXT;",
+];
+
+/// On-disk schema version; databases from before versioning read as 0.
+pub fn schema_version(conn: &Connection) -> Result<i64, String> {
+    let value: Option<String> = conn
+        .query_row("SELECT value FROM meta WHERE key = 'schema_version'", [], |r| r.get(0))
+        .map(Some)
+        .or_else(|e| match e {
+            rusqlite::Error::QueryReturnedNoRows => Ok(None),
+            e => Err(e),
+        })
+        .map_err(|e| format!("schema: {}", e))?;
+    match value {
+        Some(v) => v.parse().map_err(|_| format!("schema: bad schema_version {:?}", v)),
+        None => Ok(0),
+    }
+}
+
+/// Apply each migration the on-disk version is behind, one transaction per
+/// step so a failure leaves the database at the last completed version.
+/// A database written by a newer binary is left as is.
+fn apply_migrations(conn: &Connection) -> Result<(), String> {
+    let mut version = schema_version(conn)?;
+    while version < CURRENT_SCHEMA_VERSION {
+        let step = MIGRATIONS
+            .get(version as usize)
+            .ok_or_else(|| format!("schema: no migration from version {}", version))?;
+        let tx = conn
+            .unchecked_transaction()
+            .map_err(|e| format!("schema: {}", e))?;
+        tx.execute_batch(step)
+            .map_err(|e| format!("schema: migration to version {}: {}", version + 1, e))?;
+        tx.execute(
+            "INSERT OR REPLACE INTO meta (key, value) VALUES ('schema_version', ?1)",
+            [(version + 1).to_string()],
+        )
+        .map_err(|e| format!("schema: {}", e))?;
+        tx.commit().map_err(|e| format!("schema: {}", e))?;
+        version += 1;
+    }
+    Ok(())
+}
+
+/// Bring databases created by older versions up to the current schema.
+fn migrate(conn: &Connection) -> Result<(), String> {
+    // Columns added before schema versioning existed
+    for (column, decl) in [
+        ("prev_hash", "TEXT"),
+        ("cwd", "TEXT"),
+        ("repeat_count", "INTEGER DEFAULT 1"),
+    ] {
+        let present = conn
+            .prepare("SELECT 1 FROM pragma_table_info('observations') WHERE name = ?1")
+            .and_then(|mut stmt| stmt.exists([column]))
+            .map_err(|e| format!("schema: {}", e))?;
+        if !present {
+            conn.execute(&format!("ALTER TABLE observations ADD COLUMN {} {}", column, decl), [])
+                .map_err(|e| format!("schema: {}", e))?;
+        }
+    }
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_prev_hash ON observations(prev_hash)",
+        [],
+    )
+    .map_err(|e| format!("schema: {}", e))?;
+    apply_migrations(conn)
+}
+
+/// Longest prefix of `text` that fits in the 200-byte preview column
+/// without splitting a UTF-8 character.
+pub(crate) fn preview(text: &str) -> &str {
+    truncate_bytes(text, 200)
+}
+
+/// Longest prefix of at most `max` bytes ending on a char boundary.
+fn truncate_bytes(text: &str, max: usize) -> &str {
+    let mut end = text.len().min(max);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+/// Snippet columns (`output_snippet`, `error_snippet`) hold at most this many bytes.
+pub const SNIPPET_BYTES: usize = 500;
+
 /// Record a command execution in the ALAN database.
 ///
-/// This is the core write path — observations, recent_commands, streaks,
-/// and pipeline segments. SSH recording and manopt stay in Python.
+/// This is the core write path — observations (with the session's previous
+/// command as `prev_hash`), recent_commands, streaks, and pipeline segments.
+/// SSH recording and manopt stay in Python.
 /// TODO(phase3): port SSH recording and manopt triggering to Rust.
 #[allow(clippy::too_many_arguments)]
 pub fn record(
@@ -126,6 +241,71 @@ pub fn record(
     timed_out: bool,
     stdout_snippet: &str,
     pipestatus: &[i32],
+) -> Result<(), String> {
+    record_with_stderr(
+        conn, session_id, command, exit_code, duration_ms, timed_out, stdout_snippet, "", pipestatus,
+    )
+}
+
+/// Like [`record`], also keeping separately captured stderr. It lands in
+/// `error_snippet` only when the command failed.
+#[allow(clippy::too_many_arguments)]
+pub fn record_with_stderr(
+    conn: &Connection,
+    session_id: &str,
+    command: &str,
+    exit_code: i32,
+    duration_ms: u64,
+    timed_out: bool,
+    stdout_snippet: &str,
+    stderr_snippet: &str,
+    pipestatus: &[i32],
+) -> Result<(), String> {
+    record_with_options(
+        conn,
+        session_id,
+        command,
+        exit_code,
+        duration_ms,
+        timed_out,
+        stdout_snippet,
+        stderr_snippet,
+        pipestatus,
+        &RecordOptions::default(),
+    )
+}
+
+/// Recording knobs beyond the observation itself.
+#[derive(Debug, Default, Clone)]
+pub struct RecordOptions {
+    /// Working directory the command ran in, stored with the observation.
+    pub cwd: Option<String>,
+    /// Fold a repeat of the same command (same hash, exit code and cwd) into
+    /// the existing observation when it was last seen less than this many
+    /// seconds ago, bumping its `repeat_count` and weight. 0 disables.
+    pub dedup_window_secs: u64,
+    /// JSON-RPC id (as JSON text) of the MCP request that ran the command,
+    /// stored on its `recent_commands` rows.
+    pub request_id: Option<String>,
+}
+
+/// Like [`record_with_stderr`], with [`RecordOptions`].
+///
+/// Coalescing only applies to `observations`. `recent_commands` keeps every
+/// run: retry and streak detection count them, and it is already pruned to
+/// a short window.
+#[allow(clippy::too_many_arguments)]
+pub fn record_with_options(
+    conn: &Connection,
+    session_id: &str,
+    command: &str,
+    exit_code: i32,
+    duration_ms: u64,
+    timed_out: bool,
+    stdout_snippet: &str,
+    stderr_snippet: &str,
+    pipestatus: &[i32],
+    opts: &RecordOptions,
 ) -> Result<(), String> {
     let command_hash = hash::hash_command(command);
     let command_template = hash::template_command(command);
@@ -135,41 +315,67 @@ pub fn record(
         .unwrap_or_default()
         .as_secs_f64();
     let now_iso = chrono::Utc::now().to_rfc3339();
-    let observation_id = uuid::Uuid::new_v4().to_string();
+    let cwd = opts.cwd.as_deref();
 
-    let preview_len = command.len().min(200);
-    let command_preview = &command[..preview_len];
+    let command_preview = preview(command);
+    let error_snippet = if success == 0 && !stderr_snippet.trim().is_empty() {
+        Some(truncate_bytes(stderr_snippet, SNIPPET_BYTES))
+    } else {
+        None
+    };
+    // Must be read before this command lands in recent_commands
+    let prev_hash = sequence::previous_hash(conn, session_id);
 
     // Record in observations (long-term learning)
-    conn.execute(
-        "INSERT INTO observations
-         (id, command_hash, command_template, command_preview, exit_code,
-          duration_ms, timed_out, output_snippet, error_snippet, weight, created_at)
-         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, NULL, 1.0, ?9)",
-        rusqlite::params![
-            observation_id,
-            command_hash,
-            command_template,
-            command_preview,
-            exit_code,
-            duration_ms as i64,
-            if timed_out { 1 } else { 0 },
-            if stdout_snippet.is_empty() {
-                None
-            } else {
-                Some(&stdout_snippet[..stdout_snippet.len().min(500)])
-            },
-            now_iso,
-        ],
-    )
-    .map_err(|e| format!("insert observation: {}", e))?;
+    let coalesced = coalesce_observation(
+        conn,
+        &command_hash,
+        exit_code,
+        timed_out,
+        cwd,
+        opts.dedup_window_secs,
+        &now_iso,
+    )?;
+    let observation_id = match coalesced {
+        Some(id) => id,
+        None => {
+            let id = uuid::Uuid::new_v4().to_string();
+            conn.execute(
+                "INSERT INTO observations
+                 (id, command_hash, command_template, command_preview, exit_code,
+                  duration_ms, timed_out, output_snippet, error_snippet, weight, created_at, prev_hash, cwd, pinned)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, 1.0, ?10, ?11, ?12,
+                         EXISTS (SELECT 1 FROM observations WHERE command_template = ?3 AND pinned = 1))",
+                rusqlite::params![
+                    id,
+                    command_hash,
+                    command_template,
+                    command_preview,
+                    exit_code,
+                    duration_ms as i64,
+                    if timed_out { 1 } else { 0 },
+                    if stdout_snippet.is_empty() {
+                        None
+                    } else {
+                        Some(truncate_bytes(stdout_snippet, SNIPPET_BYTES))
+                    },
+                    error_snippet,
+                    now_iso,
+                    prev_hash,
+                    cwd,
+                ],
+            )
+            .map_err(|e| format!("insert observation: {}", e))?;
+            id
+        }
+    };
 
     // Record in recent_commands (hot cache)
     conn.execute(
         "INSERT INTO recent_commands
          (session_id, command_hash, command_template, command_preview,
-          timestamp, duration_ms, exit_code, timed_out, success)
-         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+          timestamp, duration_ms, exit_code, timed_out, success, request_id, error_snippet)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
         rusqlite::params![
             session_id,
             command_hash,
@@ -180,6 +386,8 @@ pub fn record(
             exit_code,
             if timed_out { 1 } else { 0 },
             success,
+            opts.request_id,
+            error_snippet,
         ],
     )
     .map_err(|e| format!("insert recent: {}", e))?;
@@ -202,38 +410,52 @@ pub fn record(
                 let seg_hash = hash::hash_command(seg);
                 let seg_template = hash::template_command(seg);
                 let seg_success: i32 = if seg_exit == 0 { 1 } else { 0 };
-                let seg_obs_id = uuid::Uuid::new_v4().to_string();
-                let seg_preview_len = seg.len().min(200);
+                let seg_preview = preview(seg);
 
-                conn.execute(
-                    "INSERT INTO observations
-                     (id, command_hash, command_template, command_preview, exit_code,
-                      duration_ms, timed_out, output_snippet, error_snippet, weight, created_at)
-                     VALUES (?1, ?2, ?3, ?4, ?5, 0, 0, NULL, NULL, 1.0, ?6)",
-                    rusqlite::params![
-                        seg_obs_id,
-                        seg_hash,
-                        seg_template,
-                        &seg[..seg_preview_len],
-                        seg_exit,
-                        now_iso,
-                    ],
-                )
-                .map_err(|e| format!("insert seg observation: {}", e))?;
+                let seg_coalesced = coalesce_observation(
+                    conn,
+                    &seg_hash,
+                    seg_exit,
+                    false,
+                    cwd,
+                    opts.dedup_window_secs,
+                    &now_iso,
+                )?;
+                if seg_coalesced.is_none() {
+                    let seg_obs_id = uuid::Uuid::new_v4().to_string();
+                    conn.execute(
+                        "INSERT INTO observations
+                         (id, command_hash, command_template, command_preview, exit_code,
+                          duration_ms, timed_out, output_snippet, error_snippet, weight, created_at, cwd, pinned)
+                         VALUES (?1, ?2, ?3, ?4, ?5, 0, 0, NULL, NULL, 1.0, ?6, ?7,
+                                 EXISTS (SELECT 1 FROM observations WHERE command_template = ?3 AND pinned = 1))",
+                        rusqlite::params![
+                            seg_obs_id,
+                            seg_hash,
+                            seg_template,
+                            seg_preview,
+                            seg_exit,
+                            now_iso,
+                            cwd,
+                        ],
+                    )
+                    .map_err(|e| format!("insert seg observation: {}", e))?;
+                }
 
                 conn.execute(
                     "INSERT INTO recent_commands
                      (session_id, command_hash, command_template, command_preview,
-                      timestamp, duration_ms, exit_code, timed_out, success)
-                     VALUES (?1, ?2, ?3, ?4, ?5, 0, ?6, 0, ?7)",
+                      timestamp, duration_ms, exit_code, timed_out, success, request_id)
+                     VALUES (?1, ?2, ?3, ?4, ?5, 0, ?6, 0, ?7, ?8)",
                     rusqlite::params![
                         session_id,
                         seg_hash,
                         seg_template,
-                        &seg[..seg_preview_len],
+                        seg_preview,
                         now,
                         seg_exit,
                         seg_success,
+                        opts.request_id,
                     ],
                 )
                 .map_err(|e| format!("insert seg recent: {}", e))?;
@@ -253,3 +475,162 @@ pub fn record(
 
     Ok(())
 }
+
+/// Fold a repeat into the newest matching observation seen within
+/// `window_secs`, returning its id. `last_accessed` tracks the latest repeat,
+/// so a steady poll loop keeps extending the same row.
+#[allow(clippy::too_many_arguments)]
+fn coalesce_observation(
+    conn: &Connection,
+    command_hash: &str,
+    exit_code: i32,
+    timed_out: bool,
+    cwd: Option<&str>,
+    window_secs: u64,
+    now_iso: &str,
+) -> Result<Option<String>, String> {
+    if window_secs == 0 {
+        return Ok(None);
+    }
+    let id: Option<String> = conn
+        .query_row(
+            "SELECT id FROM observations
+             WHERE command_hash = ?1 AND exit_code = ?2 AND timed_out = ?3 AND cwd IS ?4
+               AND (JULIANDAY(?5) - JULIANDAY(COALESCE(last_accessed, created_at))) * 86400 < ?6
+             ORDER BY COALESCE(last_accessed, created_at) DESC LIMIT 1",
+            rusqlite::params![
+                command_hash,
+                exit_code,
+                if timed_out { 1 } else { 0 },
+                cwd,
+                now_iso,
+                window_secs as f64,
+            ],
+            |row| row.get(0),
+        )
+        .ok();
+    if let Some(ref id) = id {
+        conn.execute(
+            "UPDATE observations
+             SET repeat_count = COALESCE(repeat_count, 1) + 1, weight = weight + 1.0, last_accessed = ?2
+             WHERE id = ?1",
+            rusqlite::params![id, now_iso],
+        )
+        .map_err(|e| format!("coalesce observation: {}", e))?;
+    }
+    Ok(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fresh_db() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        init_schema(&conn).unwrap();
+        conn
+    }
+
+    fn run(conn: &Connection, command: &str, exit_code: i32, cwd: &str, window: u64) {
+        let opts = RecordOptions {
+            cwd: Some(cwd.to_string()),
+            dedup_window_secs: window,
+            ..Default::default()
+        };
+        record_with_options(conn, "s", command, exit_code, 5, false, "", "", &[], &opts).unwrap();
+    }
+
+    fn rows(conn: &Connection) -> Vec<(i32, String, i64)> {
+        conn.prepare("SELECT exit_code, cwd, repeat_count FROM observations ORDER BY created_at, exit_code, cwd")
+            .unwrap()
+            .query_map([], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)))
+            .unwrap()
+            .map(Result::unwrap)
+            .collect()
+    }
+
+    #[test]
+    fn test_repeats_within_window_coalesce() {
+        let conn = fresh_db();
+        for _ in 0..20 {
+            run(&conn, "kubectl get pods", 0, "/srv", 60);
+        }
+        assert_eq!(rows(&conn), vec![(0, "/srv".to_string(), 20)]);
+        let weight: f64 = conn
+            .query_row("SELECT weight FROM observations", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(weight, 20.0);
+        // Every run still lands in the hot cache for retry detection
+        let recent: i64 = conn
+            .query_row("SELECT COUNT(*) FROM recent_commands", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(recent, 20);
+
+        // A different exit code or cwd is a different observation
+        run(&conn, "kubectl get pods", 1, "/srv", 60);
+        run(&conn, "kubectl get pods", 0, "/tmp", 60);
+        assert_eq!(rows(&conn).len(), 3);
+    }
+
+    #[test]
+    fn test_migrations_match_current_version() {
+        assert_eq!(MIGRATIONS.len() as i64, CURRENT_SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn test_schema_version_set_on_new_and_legacy_dbs() {
+        assert_eq!(schema_version(&fresh_db()).unwrap(), CURRENT_SCHEMA_VERSION);
+
+        // A pre-versioning database has a meta table but no schema_version row
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE meta (key TEXT PRIMARY KEY, value TEXT);
+             INSERT INTO meta (key, value) VALUES ('last_prune', '2024-01-01T00:00:00Z');",
+        )
+        .unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), 0);
+        init_schema(&conn).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), CURRENT_SCHEMA_VERSION);
+        // Re-running is a no-op
+        init_schema(&conn).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), CURRENT_SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn test_newer_schema_version_is_left_alone() {
+        let conn = fresh_db();
+        conn.execute("UPDATE meta SET value = '99' WHERE key = 'schema_version'", []).unwrap();
+        init_schema(&conn).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), 99);
+    }
+
+    #[test]
+    fn test_request_id_stored_on_recent_commands() {
+        let conn = fresh_db();
+        let opts = RecordOptions { request_id: Some("42".into()), ..Default::default() };
+        record_with_options(&conn, "s", "make | tee log", 0, 10, false, "", "", &[0, 0], &opts).unwrap();
+        record(&conn, "s", "ls", 0, 10, false, "", &[0]).unwrap();
+
+        let ids: Vec<(String, Option<String>)> = conn
+            .prepare("SELECT command_preview, request_id FROM recent_commands ORDER BY id")
+            .unwrap()
+            .query_map([], |r| Ok((r.get(0)?, r.get(1)?)))
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        // The whole pipeline and each of its segments carry the id
+        assert_eq!(ids.len(), 4, "{:?}", ids);
+        assert!(ids[..3].iter().all(|(_, id)| id.as_deref() == Some("42")), "{:?}", ids);
+        assert_eq!(ids[3], ("ls".to_string(), None));
+    }
+
+    #[test]
+    fn test_zero_window_records_every_run() {
+        let conn = fresh_db();
+        for _ in 0..3 {
+            run(&conn, "kubectl get pods", 0, "/srv", 0);
+        }
+        assert_eq!(rows(&conn).len(), 3);
+        assert!(rows(&conn).iter().all(|r| r.2 == 1));
+    }
+}
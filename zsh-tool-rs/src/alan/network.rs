@@ -0,0 +1,124 @@
+//! Network-command detection and an opt-in connectivity pre-check.
+//!
+//! Commands like `curl` or `git clone` hang until timeout when the machine is
+//! offline. A fast TCP connect to a known host catches that up front.
+
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use super::insights::extract_base_command;
+use super::pipeline::parse_pipeline;
+
+/// Commands that always hit the network.
+const NETWORK_COMMANDS: &[&str] = &[
+    "curl", "wget", "scp", "rsync", "ping", "dig", "nslookup", "apt", "apt-get",
+];
+
+/// (base, subcommand) pairs that hit the network.
+const NETWORK_SUBCOMMANDS: &[(&str, &str)] = &[
+    ("git", "clone"),
+    ("git", "fetch"),
+    ("git", "pull"),
+    ("git", "push"),
+    ("npm", "install"),
+    ("npm", "ci"),
+    ("yarn", "install"),
+    ("yarn", "add"),
+    ("pip", "install"),
+    ("pip3", "install"),
+    ("cargo", "install"),
+    ("cargo", "fetch"),
+    ("docker", "pull"),
+    ("docker", "push"),
+    ("brew", "install"),
+    ("brew", "update"),
+];
+
+/// Whether any segment of `command` (split on pipes, `&&`, `||`, `;`) is a
+/// known network command. Returns the matching base command.
+pub fn network_command(command: &str) -> Option<String> {
+    for pipe_seg in parse_pipeline(command) {
+        for seg in pipe_seg.split(['&', ';']).flat_map(|s| s.split("||")) {
+            let seg = seg.trim();
+            let base = extract_base_command(seg);
+            if base.is_empty() {
+                continue;
+            }
+            if NETWORK_COMMANDS.contains(&base.as_str()) {
+                return Some(base);
+            }
+            let sub = seg.split_whitespace().nth(1).unwrap_or("");
+            if NETWORK_SUBCOMMANDS.iter().any(|&(b, s)| b == base && s == sub) {
+                return Some(format!("{} {}", base, sub));
+            }
+        }
+    }
+    None
+}
+
+/// Try a TCP connect to `host` ("addr:port"). True if reachable within `timeout`.
+pub fn probe(host: &str, timeout: Duration) -> bool {
+    let addrs: Vec<SocketAddr> = match host.to_socket_addrs() {
+        Ok(a) => a.collect(),
+        Err(_) => return false,
+    };
+    addrs
+        .iter()
+        .any(|addr| TcpStream::connect_timeout(addr, timeout).is_ok())
+}
+
+/// Warn if `command` needs the network and `host` is unreachable.
+pub fn get_network_insights(command: &str, host: &str, timeout: Duration) -> Vec<(String, String)> {
+    let Some(cmd) = network_command(command) else {
+        return Vec::new();
+    };
+    if probe(host, timeout) {
+        return Vec::new();
+    }
+    vec![(
+        "warning".into(),
+        format!(
+            "Network appears offline ({} unreachable). '{}' will likely fail or hang.",
+            host, cmd
+        ),
+    )]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_network_command_detection() {
+        assert_eq!(network_command("curl -s https://example.com").as_deref(), Some("curl"));
+        assert_eq!(network_command("git clone git@host:repo").as_deref(), Some("git clone"));
+        assert_eq!(network_command("cd app && npm install").as_deref(), Some("npm install"));
+        assert_eq!(network_command("cat f | /usr/bin/wget -i -").as_deref(), Some("wget"));
+        assert!(network_command("git status").is_none());
+        assert!(network_command("ls -la").is_none());
+    }
+
+    #[test]
+    fn test_offline_warns() {
+        // Bind then drop to get a local port with nothing listening.
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let host = format!("127.0.0.1:{}", port);
+        let insights = get_network_insights("curl https://example.com", &host, Duration::from_millis(200));
+        assert_eq!(insights.len(), 1);
+        assert_eq!(insights[0].0, "warning");
+        assert!(insights[0].1.contains("offline"));
+    }
+
+    #[test]
+    fn test_online_no_warning() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let host = listener.local_addr().unwrap().to_string();
+        assert!(get_network_insights("curl https://example.com", &host, Duration::from_millis(200)).is_empty());
+    }
+
+    #[test]
+    fn test_non_network_command_skips_probe() {
+        assert!(get_network_insights("echo hi", "invalid-host-no-port", Duration::from_millis(10)).is_empty());
+    }
+}
@@ -2,7 +2,11 @@
 
 use rusqlite::Connection;
 
-/// Apply temporal decay to all observation weights.
+/// Pinned observations kept per template, newest first. Pinning exempts a
+/// template from decay, not from growing with every run.
+pub const PINNED_ROWS_PER_TEMPLATE: i64 = 100;
+
+/// Apply temporal decay to all unpinned observation weights.
 /// Uses exponential half-life decay: weight * 0.5^(age_hours / half_life_hours)
 pub fn apply_decay(conn: &Connection, half_life_hours: u64, prune_threshold: f64) {
     let _ = conn.execute(
@@ -10,7 +14,7 @@ pub fn apply_decay(conn: &Connection, half_life_hours: u64, prune_threshold: f64
          SET weight = weight * POWER(0.5,
              (JULIANDAY('now') - JULIANDAY(created_at)) * 24 / ?1
          )
-         WHERE weight > ?2",
+         WHERE weight > ?2 AND pinned = 0",
         rusqlite::params![half_life_hours as f64, prune_threshold],
     );
 
@@ -25,7 +29,9 @@ pub fn apply_decay(conn: &Connection, half_life_hours: u64, prune_threshold: f64
     );
 }
 
-/// Remove decayed entries and enforce max entry limit.
+/// Remove decayed entries and enforce max entry limit. Pinned observations
+/// are never decayed away; they count toward `max_entries` ahead of the
+/// rest, and each template keeps its newest [`PINNED_ROWS_PER_TEMPLATE`].
 pub fn prune(
     conn: &Connection,
     half_life_hours: u64,
@@ -36,19 +42,32 @@ pub fn prune(
 
     // Remove low-weight observations
     let _ = conn.execute(
-        "DELETE FROM observations WHERE weight < ?1",
+        "DELETE FROM observations WHERE weight < ?1 AND pinned = 0",
         rusqlite::params![prune_threshold],
     );
 
     // Enforce max entries
     let _ = conn.execute(
         "DELETE FROM observations
-         WHERE id NOT IN (
-             SELECT id FROM observations ORDER BY weight DESC LIMIT ?1
+         WHERE pinned = 0 AND id NOT IN (
+             SELECT id FROM observations ORDER BY pinned DESC, weight DESC LIMIT ?1
          )",
         rusqlite::params![max_entries as i64],
     );
 
+    // Cap pinned rows per template
+    let _ = conn.execute(
+        "DELETE FROM observations WHERE id IN (
+             SELECT id FROM (
+                 SELECT id, ROW_NUMBER() OVER (
+                     PARTITION BY command_template ORDER BY created_at DESC, rowid DESC
+                 ) AS newest
+                 FROM observations WHERE pinned = 1
+             ) WHERE newest > ?1
+         )",
+        rusqlite::params![PINNED_ROWS_PER_TEMPLATE],
+    );
+
     // Remove low-weight SSH observations
     let _ = conn.execute(
         "DELETE FROM ssh_observations WHERE weight < ?1",
@@ -70,6 +89,26 @@ pub fn prune(
     );
 }
 
+/// Pin or unpin every observation of `template`; returns the rows changed.
+/// Observations recorded later for a pinned template start out pinned.
+pub fn set_pinned(conn: &Connection, template: &str, pinned: bool) -> Result<usize, String> {
+    conn.execute(
+        "UPDATE observations SET pinned = ?2 WHERE command_template = ?1 AND pinned != ?2",
+        rusqlite::params![template, pinned as i64],
+    )
+    .map_err(|e| format!("pin: {}", e))
+}
+
+/// Templates with at least one pinned observation, alphabetically.
+pub fn pinned_templates(conn: &Connection) -> Result<Vec<String>, String> {
+    conn.prepare(
+        "SELECT DISTINCT command_template FROM observations
+         WHERE pinned = 1 AND command_template IS NOT NULL ORDER BY command_template",
+    )
+    .and_then(|mut stmt| stmt.query_map([], |row| row.get(0))?.collect())
+    .map_err(|e| format!("pin: {}", e))
+}
+
 /// Prune only if enough time has passed since last prune.
 pub fn maybe_prune(
     conn: &Connection,
@@ -204,6 +243,79 @@ mod tests {
         assert_eq!(ssh_count, 0);
     }
 
+    #[test]
+    fn test_pinned_pattern_survives_aggressive_pruning() {
+        let conn = fresh_db();
+        for (id, template, weight) in [
+            ("deploy1", "deploy-prod *", 0.001),
+            ("deploy2", "deploy-prod *", 1.0),
+            ("ls1", "ls *", 0.001),
+            ("ls2", "ls *", 1.0),
+            ("ls3", "ls *", 5.0),
+        ] {
+            conn.execute(
+                "INSERT INTO observations (id, command_hash, command_template, command_preview,
+                 exit_code, duration_ms, weight, created_at)
+                 VALUES (?1, ?1, ?2, ?2, 0, 100, ?3, '2020-01-01T00:00:00Z')",
+                rusqlite::params![id, template, weight],
+            )
+            .unwrap();
+        }
+        assert_eq!(set_pinned(&conn, "deploy-prod *", true).unwrap(), 2);
+        assert_eq!(pinned_templates(&conn).unwrap(), vec!["deploy-prod *".to_string()]);
+
+        // Below-threshold weights and a cap of one entry
+        prune(&conn, 1, 0.01, 1);
+
+        let survivors: Vec<(String, f64)> = conn
+            .prepare("SELECT id, weight FROM observations ORDER BY id")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(
+            survivors,
+            vec![("deploy1".to_string(), 0.001), ("deploy2".to_string(), 1.0)]
+        );
+
+        // Unpinned, they are pruned like anything else
+        set_pinned(&conn, "deploy-prod *", false).unwrap();
+        prune(&conn, 1, 0.01, 10000);
+        let count: i64 = conn
+            .query_row("SELECT COUNT(*) FROM observations", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn test_pinned_rows_capped_per_template() {
+        let conn = fresh_db();
+        let extra = 5;
+        for i in 0..PINNED_ROWS_PER_TEMPLATE + extra {
+            conn.execute(
+                "INSERT INTO observations (id, command_hash, command_template, command_preview,
+                 exit_code, duration_ms, weight, created_at, pinned)
+                 VALUES (?1, 'deploy', 'deploy-prod *', 'deploy-prod eu', 0, 100, 1.0,
+                         datetime('2024-01-01', ?2 || ' minutes'), 1)",
+                rusqlite::params![format!("d{}", i), i],
+            )
+            .unwrap();
+        }
+
+        prune(&conn, 24, 0.01, 10000);
+
+        let (count, oldest): (i64, String) = conn
+            .query_row(
+                "SELECT COUNT(*), (SELECT id FROM observations ORDER BY created_at LIMIT 1) FROM observations",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(count, PINNED_ROWS_PER_TEMPLATE);
+        assert_eq!(oldest, format!("d{}", extra));
+    }
+
     #[test]
     fn test_maybe_prune_skips_if_recent() {
         let conn = fresh_db();
@@ -0,0 +1,174 @@
+//! Command co-occurrence (zsh_alan_next).
+//!
+//! Each observation carries the hash of the command that ran just before it
+//! in the same session (`prev_hash`). Counting what followed a command gives
+//! a frequency-ranked guess at what comes next: `git add` → `git commit` →
+//! `git push`. Only top-level commands are linked; pipeline segments and the
+//! first command of a session have no predecessor.
+
+use rusqlite::Connection;
+use serde::Serialize;
+
+use super::hash;
+
+#[derive(Debug, Serialize)]
+pub struct NextCommand {
+    /// Most recent preview recorded for this pattern.
+    pub command: String,
+    pub template: Option<String>,
+    pub count: i64,
+    /// Share of all observed follow-ups of the queried command.
+    pub probability: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct NextPrediction {
+    pub command: String,
+    pub known: bool,
+    pub total_transitions: i64,
+    pub next: Vec<NextCommand>,
+}
+
+/// Hash of the latest top-level command in the session's hot cache. Pipeline
+/// segments share their parent's timestamp but are inserted after it, so the
+/// lowest id at the latest timestamp is the parent.
+pub fn previous_hash(conn: &Connection, session_id: &str) -> Option<String> {
+    conn.query_row(
+        "SELECT command_hash FROM recent_commands
+         WHERE session_id = ?1
+           AND timestamp = (SELECT MAX(timestamp) FROM recent_commands WHERE session_id = ?1)
+         ORDER BY id ASC LIMIT 1",
+        rusqlite::params![session_id],
+        |row| row.get(0),
+    )
+    .ok()
+}
+
+/// Commands that most often followed `command`, most frequent first.
+/// Immediate repeats (retries) are left out; they say nothing about the
+/// next step.
+pub fn predict_next(conn: &Connection, command: &str, limit: i64) -> NextPrediction {
+    let command_hash = hash::hash_command(command);
+
+    let total: i64 = conn
+        .query_row(
+            "SELECT COUNT(*) FROM observations WHERE prev_hash = ?1 AND command_hash != ?1",
+            rusqlite::params![command_hash],
+            |row| row.get(0),
+        )
+        .unwrap_or(0);
+
+    let next = if total > 0 {
+        follow_ups(conn, &command_hash, total, limit)
+    } else {
+        Vec::new()
+    };
+
+    NextPrediction {
+        command: command.to_string(),
+        known: total > 0,
+        total_transitions: total,
+        next,
+    }
+}
+
+fn follow_ups(conn: &Connection, command_hash: &str, total: i64, limit: i64) -> Vec<NextCommand> {
+    // Bare columns next to MAX() come from the row holding the maximum
+    let mut stmt = match conn.prepare(
+        "SELECT command_preview, command_template, COUNT(*) as cnt, MAX(created_at) as latest
+         FROM observations
+         WHERE prev_hash = ?1 AND command_hash != ?1
+         GROUP BY command_hash
+         ORDER BY cnt DESC, latest DESC LIMIT ?2",
+    ) {
+        Ok(s) => s,
+        Err(_) => return Vec::new(),
+    };
+    let rows = stmt.query_map(rusqlite::params![command_hash, limit], |row| {
+        let count: i64 = row.get(2)?;
+        Ok(NextCommand {
+            command: row.get::<_, Option<String>>(0)?.unwrap_or_default(),
+            template: row.get(1)?,
+            count,
+            probability: count as f64 / total as f64,
+        })
+    });
+    match rows {
+        Ok(rows) => rows.flatten().collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alan;
+
+    fn fresh_db() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        alan::init_schema(&conn).unwrap();
+        conn
+    }
+
+    fn run(conn: &Connection, session: &str, command: &str) {
+        alan::record(conn, session, command, 0, 10, false, "", &[]).unwrap();
+    }
+
+    #[test]
+    fn test_predicts_most_frequent_follow_up() {
+        let conn = fresh_db();
+        for session in ["s1", "s2"] {
+            run(&conn, session, "git add .");
+            run(&conn, session, "git commit -m \"wip\"");
+            run(&conn, session, "git push");
+        }
+        run(&conn, "s3", "git add .");
+        run(&conn, "s3", "git add .");
+        run(&conn, "s3", "git status");
+
+        let prediction = predict_next(&conn, "git add .", 5);
+        assert!(prediction.known);
+        // Two commits and one status; the retry of `git add .` is not counted
+        assert_eq!(prediction.total_transitions, 3);
+        assert_eq!(prediction.next[0].command, "git commit -m \"wip\"");
+        assert_eq!(prediction.next[0].count, 2);
+        assert!((prediction.next[0].probability - 2.0 / 3.0).abs() < 1e-9);
+        assert_eq!(prediction.next[1].command, "git status");
+
+        let after_commit = predict_next(&conn, "git commit -m \"other\"", 5);
+        assert_eq!(after_commit.next[0].command, "git push");
+
+        // First command of a session has no predecessor
+        let unknown = predict_next(&conn, "git push", 5);
+        assert!(!unknown.known);
+        assert!(unknown.next.is_empty());
+    }
+
+    #[test]
+    fn test_migrates_observations_without_prev_hash() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE observations (
+                id TEXT PRIMARY KEY,
+                command_hash TEXT NOT NULL,
+                command_template TEXT,
+                command_preview TEXT,
+                exit_code INTEGER,
+                duration_ms INTEGER,
+                timed_out INTEGER DEFAULT 0,
+                output_snippet TEXT,
+                error_snippet TEXT,
+                weight REAL DEFAULT 1.0,
+                created_at TEXT NOT NULL,
+                last_accessed TEXT
+            );",
+        )
+        .unwrap();
+        alan::init_schema(&conn).unwrap();
+        // Idempotent on an already migrated database
+        alan::init_schema(&conn).unwrap();
+        run(&conn, "m1", "make");
+        run(&conn, "m1", "make test");
+        assert_eq!(predict_next(&conn, "make", 5).next[0].command, "make test");
+    }
+}
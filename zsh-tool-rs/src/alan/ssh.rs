@@ -109,7 +109,7 @@ pub fn record_ssh(
     let remote_preview = ssh_info
         .remote_command
         .as_deref()
-        .map(|c| &c[..c.len().min(200)]);
+        .map(super::preview);
 
     let ssh_id = uuid::Uuid::new_v4().to_string();
     let now_iso = chrono::Utc::now().to_rfc3339();
@@ -137,6 +137,30 @@ pub fn record_ssh(
     Ok(())
 }
 
+/// How many of a host's latest completed SSH runs [`host_slowest_ms`] looks at.
+const SLOWEST_RECENT_RUNS: i64 = 20;
+
+/// Longest duration among the latest completed (not timed out) SSH commands
+/// to `host`, once there are at least `min_observations` of them. Timeouts
+/// only say the limit was hit, and old outliers age out.
+pub fn host_slowest_ms(conn: &Connection, host: &str, min_observations: i64) -> Option<u64> {
+    let (count, slowest) = conn
+        .query_row(
+            "SELECT COUNT(*), MAX(duration_ms) FROM (
+                SELECT duration_ms FROM ssh_observations
+                WHERE host = ?1 AND timed_out = 0
+                ORDER BY created_at DESC, rowid DESC LIMIT ?2
+             )",
+            rusqlite::params![host, SLOWEST_RECENT_RUNS],
+            |row| Ok((row.get::<_, i64>(0)?, row.get::<_, Option<i64>>(1)?)),
+        )
+        .ok()?;
+    if count < min_observations {
+        return None;
+    }
+    slowest.map(|ms| ms.max(0) as u64)
+}
+
 /// Generate SSH-specific insights for a command.
 pub fn get_ssh_insights(conn: &Connection, command: &str) -> Vec<(String, String)> {
     let ssh_info = match parse_ssh_command(command) {
@@ -1,7 +1,7 @@
-//! ALAN statistics for MCP tool responses (zsh_alan_stats, zsh_alan_query).
+//! ALAN statistics for MCP tool responses (zsh_alan_stats, zsh_alan_query) and raw table exports (zsh_alan_export).
 
 use rusqlite::Connection;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
 use super::hash;
@@ -43,7 +43,7 @@ pub fn get_stats(conn: &Connection, session_id: &str) -> AlanStats {
     let (total_obs, unique, total_weight, oldest, newest) = conn
         .query_row(
             "SELECT
-                COUNT(*) as total_observations,
+                SUM(COALESCE(repeat_count, 1)) as total_observations,
                 COUNT(DISTINCT command_hash) as unique_patterns,
                 SUM(weight) as total_weight,
                 MIN(created_at) as oldest,
@@ -52,7 +52,7 @@ pub fn get_stats(conn: &Connection, session_id: &str) -> AlanStats {
             [],
             |row| {
                 Ok((
-                    row.get::<_, i64>(0)?,
+                    row.get::<_, Option<i64>>(0)?.unwrap_or(0),
                     row.get::<_, i64>(1)?,
                     row.get::<_, Option<f64>>(2)?.unwrap_or(0.0),
                     row.get::<_, Option<String>>(3)?,
@@ -159,6 +159,173 @@ pub fn get_hot_patterns(conn: &Connection, session_id: &str, limit: i64) -> Vec<
     .unwrap_or_default()
 }
 
+/// A command that failed in the current session (zsh_session_summary).
+#[derive(Debug, Serialize)]
+pub struct SessionFailure {
+    /// Stored preview of the command; see `truncated`.
+    pub command: String,
+    /// True when `command` is a cut-down preview of a longer command.
+    pub truncated: bool,
+    #[serde(skip)]
+    pub command_hash: String,
+    #[serde(skip)]
+    pub command_template: String,
+    pub failures: i64,
+    /// Exit code, timeout flag and stderr of the latest failed run.
+    pub last_exit_code: Option<i64>,
+    pub timed_out: bool,
+    pub last_error: Option<String>,
+}
+
+/// Commands that failed in this session, most recently failed first.
+pub fn get_session_failures(conn: &Connection, session_id: &str, limit: i64) -> Vec<SessionFailure> {
+    let mut stmt = match conn.prepare(
+        "SELECT l.command_preview, l.command_hash, l.command_template, g.failures,
+                l.exit_code, l.timed_out, l.error_snippet
+         FROM (SELECT command_hash, COUNT(*) as failures, MAX(id) as last_id
+               FROM recent_commands WHERE session_id = ? AND success = 0
+               GROUP BY command_hash) g
+         JOIN recent_commands l ON l.id = g.last_id
+         ORDER BY l.timestamp DESC LIMIT ?",
+    ) {
+        Ok(s) => s,
+        Err(_) => return Vec::new(),
+    };
+
+    stmt.query_map(rusqlite::params![session_id, limit], |row| {
+        let command = row.get::<_, Option<String>>(0)?.unwrap_or_default();
+        let command_hash: String = row.get(1)?;
+        Ok(SessionFailure {
+            truncated: hash::hash_command(&command) != command_hash,
+            command,
+            command_hash,
+            command_template: row.get::<_, Option<String>>(2)?.unwrap_or_default(),
+            failures: row.get(3)?,
+            last_exit_code: row.get(4)?,
+            timed_out: row.get::<_, Option<i64>>(5)?.unwrap_or(0) != 0,
+            last_error: row.get(6)?,
+        })
+    })
+    .map(|iter| iter.filter_map(|r| r.ok()).collect())
+    .unwrap_or_default()
+}
+
+/// One command template with its long-term observation stats (zsh_alan_templates).
+#[derive(Debug, Serialize)]
+pub struct TemplateStats {
+    pub template: String,
+    pub observations: i64,
+    pub success_rate: f64,
+    pub avg_duration_ms: Option<f64>,
+}
+
+/// A page of known templates, most frequent first.
+#[derive(Debug, Serialize)]
+pub struct TemplateList {
+    pub total: i64,
+    pub limit: i64,
+    pub offset: i64,
+    pub templates: Vec<TemplateStats>,
+}
+
+/// List distinct command templates with observation counts and success rates.
+/// Coalesced observations count once per run (`repeat_count`).
+pub fn list_templates(conn: &Connection, limit: i64, offset: i64) -> TemplateList {
+    let total: i64 = conn
+        .query_row(
+            "SELECT COUNT(DISTINCT command_template) FROM observations
+             WHERE command_template IS NOT NULL",
+            [],
+            |row| row.get(0),
+        )
+        .unwrap_or(0);
+
+    let templates = conn
+        .prepare(
+            "SELECT
+                command_template,
+                SUM(COALESCE(repeat_count, 1)) as count,
+                SUM(CASE WHEN exit_code = 0 THEN COALESCE(repeat_count, 1) ELSE 0 END) as successes,
+                AVG(duration_ms) as avg_duration
+             FROM observations WHERE command_template IS NOT NULL
+             GROUP BY command_template
+             ORDER BY count DESC, command_template ASC
+             LIMIT ? OFFSET ?",
+        )
+        .and_then(|mut stmt| {
+            stmt.query_map(rusqlite::params![limit, offset], |row| {
+                let count: i64 = row.get(1)?;
+                let successes: i64 = row.get::<_, Option<i64>>(2)?.unwrap_or(0);
+                Ok(TemplateStats {
+                    template: row.get(0)?,
+                    observations: count,
+                    success_rate: if count > 0 {
+                        successes as f64 / count as f64
+                    } else {
+                        0.0
+                    },
+                    avg_duration_ms: row.get(3)?,
+                })
+            })
+            .map(|iter| iter.filter_map(|r| r.ok()).collect())
+        })
+        .unwrap_or_default();
+
+    TemplateList {
+        total,
+        limit,
+        offset,
+        templates,
+    }
+}
+
+/// One command template's timeout record (zsh_alan_timeouts).
+#[derive(Debug, Serialize)]
+pub struct TimeoutStats {
+    pub template: String,
+    pub observations: i64,
+    pub timeouts: i64,
+    pub timeout_rate: f64,
+    pub avg_duration_ms: Option<f64>,
+    /// Longest recorded run, timed out or not.
+    pub max_duration_ms: Option<i64>,
+}
+
+/// Templates that have timed out at least once, highest timeout rate first
+/// (ties broken by timeout count), for tuning per-command timeouts.
+/// Coalesced observations count once per run (`repeat_count`).
+pub fn timeout_ranking(conn: &Connection, limit: i64) -> Vec<TimeoutStats> {
+    conn.prepare(
+        "SELECT
+            command_template,
+            SUM(COALESCE(repeat_count, 1)) as count,
+            SUM(CASE WHEN timed_out = 1 THEN COALESCE(repeat_count, 1) ELSE 0 END) as timeouts,
+            AVG(duration_ms) as avg_duration,
+            MAX(duration_ms) as max_duration
+         FROM observations WHERE command_template IS NOT NULL
+         GROUP BY command_template
+         HAVING timeouts > 0
+         ORDER BY CAST(timeouts AS REAL) / count DESC, timeouts DESC, command_template ASC
+         LIMIT ?",
+    )
+    .and_then(|mut stmt| {
+        stmt.query_map([limit], |row| {
+            let count: i64 = row.get(1)?;
+            let timeouts: i64 = row.get(2)?;
+            Ok(TimeoutStats {
+                template: row.get(0)?,
+                observations: count,
+                timeouts,
+                timeout_rate: timeouts as f64 / count as f64,
+                avg_duration_ms: row.get(3)?,
+                max_duration_ms: row.get(4)?,
+            })
+        })
+        .map(|iter| iter.filter_map(|r| r.ok()).collect())
+    })
+    .unwrap_or_default()
+}
+
 /// Pattern stats for zsh_alan_query tool.
 #[derive(Debug, Serialize)]
 pub struct PatternQueryResult {
@@ -173,6 +340,9 @@ pub struct PatternQueryResult {
     pub avg_duration_ms: Option<f64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub streak: Option<HashMap<String, serde_json::Value>>,
+    /// Most recent recorded stderr from a failed run of this pattern.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
 }
 
 /// Query pattern stats for a command (zsh_alan_query tool).
@@ -181,7 +351,7 @@ pub fn query_pattern(conn: &Connection, command: &str) -> PatternQueryResult {
 
     let row = conn.query_row(
         "SELECT
-            COUNT(*) as total,
+            SUM(COALESCE(repeat_count, 1)) as total,
             SUM(weight) as weighted_total,
             SUM(CASE WHEN timed_out = 1 THEN weight ELSE 0 END) as timeout_weight,
             SUM(CASE WHEN exit_code = 0 THEN weight ELSE 0 END) as success_weight,
@@ -190,7 +360,7 @@ pub fn query_pattern(conn: &Connection, command: &str) -> PatternQueryResult {
         rusqlite::params![command_hash],
         |row| {
             Ok((
-                row.get::<_, i64>(0)?,
+                row.get::<_, Option<i64>>(0)?.unwrap_or(0),
                 row.get::<_, Option<f64>>(1)?.unwrap_or(0.0),
                 row.get::<_, Option<f64>>(2)?.unwrap_or(0.0),
                 row.get::<_, Option<f64>>(3)?.unwrap_or(0.0),
@@ -238,6 +408,16 @@ pub fn query_pattern(conn: &Connection, command: &str) -> PatternQueryResult {
                 )
                 .ok();
 
+            let last_error = conn
+                .query_row(
+                    "SELECT error_snippet FROM observations
+                     WHERE command_hash = ? AND error_snippet IS NOT NULL
+                     ORDER BY created_at DESC LIMIT 1",
+                    rusqlite::params![command_hash],
+                    |row| row.get::<_, String>(0),
+                )
+                .ok();
+
             PatternQueryResult {
                 known: true,
                 observations: Some(total),
@@ -245,6 +425,7 @@ pub fn query_pattern(conn: &Connection, command: &str) -> PatternQueryResult {
                 timeout_rate: Some(timeout_weight / denom),
                 avg_duration_ms: avg_dur,
                 streak,
+                last_error,
             }
         }
         _ => PatternQueryResult {
@@ -254,6 +435,248 @@ pub fn query_pattern(conn: &Connection, command: &str) -> PatternQueryResult {
             timeout_rate: None,
             avg_duration_ms: None,
             streak: None,
+            last_error: None,
         },
     }
 }
+
+/// Predicted duration for a command (zsh_estimate tool).
+#[derive(Debug, Serialize)]
+pub struct DurationEstimate {
+    pub known: bool,
+    /// "high" / "medium" / "low" by observation count, "unknown" for new patterns.
+    pub confidence: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub observations: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub avg_duration_ms: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub p90_duration_ms: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout_rate: Option<f64>,
+}
+
+/// Estimate how long a command will take from its pattern history,
+/// without running it.
+pub fn estimate_duration(conn: &Connection, command: &str) -> DurationEstimate {
+    let pattern = query_pattern(conn, command);
+    let observations = match pattern.observations {
+        Some(n) if pattern.known => n,
+        _ => {
+            return DurationEstimate {
+                known: false,
+                confidence: "unknown".to_string(),
+                observations: None,
+                avg_duration_ms: None,
+                p90_duration_ms: None,
+                timeout_rate: None,
+            }
+        }
+    };
+
+    let durations: Vec<i64> = conn
+        .prepare(
+            "SELECT duration_ms FROM observations
+             WHERE command_hash = ? AND duration_ms IS NOT NULL
+             ORDER BY duration_ms",
+        )
+        .and_then(|mut stmt| {
+            stmt.query_map(rusqlite::params![hash::hash_command(command)], |row| row.get(0))?
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let confidence = match observations {
+        n if n >= 10 => "high",
+        n if n >= 3 => "medium",
+        _ => "low",
+    };
+
+    DurationEstimate {
+        known: true,
+        confidence: confidence.to_string(),
+        observations: Some(observations),
+        avg_duration_ms: pattern.avg_duration_ms,
+        p90_duration_ms: percentile(&durations, 0.9),
+        timeout_rate: pattern.timeout_rate,
+    }
+}
+
+/// Nearest-rank percentile of an ascending slice.
+fn percentile(sorted: &[i64], p: f64) -> Option<i64> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = (p * sorted.len() as f64).ceil() as usize;
+    Some(sorted[rank.clamp(1, sorted.len()) - 1])
+}
+
+/// One `observations` row as stored (zsh_alan_export, zsh_alan_import).
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ObservationRow {
+    pub id: String,
+    pub command_hash: String,
+    pub command_template: Option<String>,
+    pub command_preview: Option<String>,
+    pub exit_code: Option<i64>,
+    pub duration_ms: Option<i64>,
+    pub timed_out: bool,
+    pub output_snippet: Option<String>,
+    pub error_snippet: Option<String>,
+    pub weight: f64,
+    pub created_at: String,
+    pub last_accessed: Option<String>,
+    pub prev_hash: Option<String>,
+    pub cwd: Option<String>,
+    pub repeat_count: i64,
+    /// Exempt from decay and pruning (zsh_alan_pin); absent in older exports.
+    #[serde(default)]
+    pub pinned: bool,
+}
+
+/// One `streaks` row; `last_updated` is epoch seconds.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StreakRow {
+    pub command_hash: String,
+    pub current_streak: i64,
+    pub longest_success_streak: i64,
+    pub longest_fail_streak: i64,
+    pub last_result: Option<i64>,
+    pub last_updated: Option<f64>,
+}
+
+/// One `ssh_observations` row.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SshObservationRow {
+    pub id: String,
+    pub observation_id: Option<String>,
+    pub host: String,
+    pub remote_command: Option<String>,
+    pub remote_command_template: Option<String>,
+    pub exit_code: Option<i64>,
+    pub exit_type: Option<String>,
+    pub duration_ms: Option<i64>,
+    pub timed_out: bool,
+    pub weight: f64,
+    pub created_at: String,
+}
+
+/// The learning tables as plain rows, oldest first. Unknown top-level keys
+/// are rejected on import: they mean an export from an incompatible version.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AlanExport {
+    #[serde(default)]
+    pub exported_at: String,
+    #[serde(default)]
+    pub since: Option<String>,
+    #[serde(default)]
+    pub observations: Vec<ObservationRow>,
+    #[serde(default)]
+    pub streaks: Vec<StreakRow>,
+    #[serde(default)]
+    pub ssh_observations: Vec<SshObservationRow>,
+}
+
+/// Dump `observations`, `streaks` and `ssh_observations`, keeping only rows
+/// created or updated at or after `since` (RFC 3339) when given.
+pub fn export(conn: &Connection, since: Option<&str>) -> Result<AlanExport, String> {
+    let since_utc = since
+        .map(|s| {
+            chrono::DateTime::parse_from_rfc3339(s)
+                .map(|dt| dt.with_timezone(&chrono::Utc))
+                .map_err(|e| format!("invalid since timestamp {:?}: {}", s, e))
+        })
+        .transpose()?;
+    // created_at is written by `to_rfc3339` in UTC, so it compares as text
+    let since_text = since_utc.map(|dt| dt.to_rfc3339()).unwrap_or_default();
+    let since_epoch = since_utc.map(|dt| dt.timestamp_millis() as f64 / 1000.0).unwrap_or(0.0);
+
+    let observations = conn
+        .prepare(
+            "SELECT id, command_hash, command_template, command_preview, exit_code,
+                    duration_ms, timed_out, output_snippet, error_snippet, weight,
+                    created_at, last_accessed, prev_hash, cwd, repeat_count, pinned
+             FROM observations WHERE created_at >= ?1 ORDER BY created_at, id",
+        )
+        .and_then(|mut stmt| {
+            stmt.query_map([&since_text], |row| {
+                Ok(ObservationRow {
+                    id: row.get(0)?,
+                    command_hash: row.get(1)?,
+                    command_template: row.get(2)?,
+                    command_preview: row.get(3)?,
+                    exit_code: row.get(4)?,
+                    duration_ms: row.get(5)?,
+                    timed_out: row.get::<_, Option<i64>>(6)?.unwrap_or(0) != 0,
+                    output_snippet: row.get(7)?,
+                    error_snippet: row.get(8)?,
+                    weight: row.get::<_, Option<f64>>(9)?.unwrap_or(1.0),
+                    created_at: row.get(10)?,
+                    last_accessed: row.get(11)?,
+                    prev_hash: row.get(12)?,
+                    cwd: row.get(13)?,
+                    repeat_count: row.get::<_, Option<i64>>(14)?.unwrap_or(1),
+                    pinned: row.get::<_, i64>(15)? != 0,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()
+        })
+        .map_err(|e| format!("export observations: {}", e))?;
+
+    let streaks = conn
+        .prepare(
+            "SELECT command_hash, current_streak, longest_success_streak,
+                    longest_fail_streak, last_result, last_updated
+             FROM streaks WHERE COALESCE(last_updated, 0) >= ?1
+             ORDER BY last_updated, command_hash",
+        )
+        .and_then(|mut stmt| {
+            stmt.query_map([since_epoch], |row| {
+                Ok(StreakRow {
+                    command_hash: row.get(0)?,
+                    current_streak: row.get::<_, Option<i64>>(1)?.unwrap_or(0),
+                    longest_success_streak: row.get::<_, Option<i64>>(2)?.unwrap_or(0),
+                    longest_fail_streak: row.get::<_, Option<i64>>(3)?.unwrap_or(0),
+                    last_result: row.get(4)?,
+                    last_updated: row.get(5)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()
+        })
+        .map_err(|e| format!("export streaks: {}", e))?;
+
+    let ssh_observations = conn
+        .prepare(
+            "SELECT id, observation_id, host, remote_command, remote_command_template,
+                    exit_code, exit_type, duration_ms, timed_out, weight, created_at
+             FROM ssh_observations WHERE created_at >= ?1 ORDER BY created_at, id",
+        )
+        .and_then(|mut stmt| {
+            stmt.query_map([&since_text], |row| {
+                Ok(SshObservationRow {
+                    id: row.get(0)?,
+                    observation_id: row.get(1)?,
+                    host: row.get(2)?,
+                    remote_command: row.get(3)?,
+                    remote_command_template: row.get(4)?,
+                    exit_code: row.get(5)?,
+                    exit_type: row.get(6)?,
+                    duration_ms: row.get(7)?,
+                    timed_out: row.get::<_, Option<i64>>(8)?.unwrap_or(0) != 0,
+                    weight: row.get::<_, Option<f64>>(9)?.unwrap_or(1.0),
+                    created_at: row.get(10)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()
+        })
+        .map_err(|e| format!("export ssh_observations: {}", e))?;
+
+    Ok(AlanExport {
+        exported_at: chrono::Utc::now().to_rfc3339(),
+        since: since.map(str::to_string),
+        observations,
+        streaks,
+        ssh_observations,
+    })
+}
@@ -2,10 +2,19 @@
 //!
 //! Prevents runaway commands by implementing a circuit breaker pattern.
 //! State machine: Closed -> Open -> HalfOpen -> Closed
+//!
+//! Each command pattern (command hash) has its own circuit, opened by
+//! `failure_threshold` failures of that pattern, so one hanging command does
+//! not block the rest. A global circuit opened by `global_failure_threshold`
+//! failures across all patterns is the backstop.
 
 use serde::Serialize;
+use std::collections::HashMap;
 use std::time::{SystemTime, UNIX_EPOCH};
 
+/// Default failures across all patterns that open the global circuit.
+pub const DEFAULT_GLOBAL_FAILURE_THRESHOLD: usize = 10;
+
 #[derive(Debug, Clone, PartialEq, Serialize)]
 #[serde(rename_all = "snake_case")]
 pub enum CircuitState {
@@ -24,14 +33,40 @@ impl std::fmt::Display for CircuitState {
     }
 }
 
+/// Circuit of one command pattern that has been opened.
+#[derive(Debug, Clone)]
+pub struct PatternCircuit {
+    pub state: CircuitState,
+    pub opened_at: f64,
+    /// Successes recorded since the pattern went HalfOpen.
+    pub half_open_successes: usize,
+}
+
 pub struct CircuitBreaker {
+    /// Global circuit state.
     pub state: CircuitState,
-    pub failures: Vec<(f64, String)>, // (timestamp, command_hash)
+    pub failures: Vec<(f64, String)>, // (timestamp, command_hash): timeouts and tripping exits
     pub last_failure: Option<f64>,
     pub opened_at: Option<f64>,
+    /// The global circuit was last opened by `trip`, not by failures.
+    pub tripped_manually: bool,
+    /// Whether the first blocked call since opening has been reported.
+    pub notice_shown: bool,
+    /// Failures of one pattern that open its circuit.
     pub failure_threshold: usize,
+    /// Failures across all patterns that open the global circuit. 0 disables it.
+    pub global_failure_threshold: usize,
     pub recovery_timeout: u64,
     pub sample_window: u64,
+    /// Exit codes `record_failure` counts like a timeout (e.g. 137 for SIGKILL).
+    pub trip_exit_codes: Vec<i32>,
+    /// Consecutive successes needed in HalfOpen before the circuit closes.
+    pub half_open_required_successes: usize,
+    /// Successes recorded since the global circuit went HalfOpen.
+    pub half_open_successes: usize,
+    /// Patterns whose circuit is open or half-open, by command hash. Closed
+    /// patterns are removed.
+    pub patterns: HashMap<String, PatternCircuit>,
 }
 
 impl CircuitBreaker {
@@ -41,9 +76,16 @@ impl CircuitBreaker {
             failures: Vec::new(),
             last_failure: None,
             opened_at: None,
+            tripped_manually: false,
+            notice_shown: false,
             failure_threshold,
+            global_failure_threshold: DEFAULT_GLOBAL_FAILURE_THRESHOLD,
             recovery_timeout,
             sample_window,
+            trip_exit_codes: Vec::new(),
+            half_open_required_successes: 1,
+            half_open_successes: 0,
+            patterns: HashMap::new(),
         }
     }
 
@@ -54,7 +96,8 @@ impl CircuitBreaker {
             .as_secs_f64()
     }
 
-    /// Record a timeout failure.
+    /// Record a timeout failure. A failure while HalfOpen reopens that
+    /// circuit at once.
     pub fn record_timeout(&mut self, command_hash: &str) {
         let now = Self::now();
         self.failures.push((now, command_hash.to_string()));
@@ -64,24 +107,134 @@ impl CircuitBreaker {
         let cutoff = now - self.sample_window as f64;
         self.failures.retain(|(t, _)| *t > cutoff);
 
-        // Check if we should open the circuit
-        if self.failures.len() >= self.failure_threshold {
+        // Open this pattern's circuit
+        let pattern_state = self.patterns.get(command_hash).map(|p| p.state.clone());
+        if pattern_state == Some(CircuitState::HalfOpen)
+            || self.pattern_failures(command_hash) >= self.failure_threshold
+        {
+            if pattern_state != Some(CircuitState::Open) {
+                self.notice_shown = false;
+            }
+            self.patterns.insert(
+                command_hash.to_string(),
+                PatternCircuit { state: CircuitState::Open, opened_at: now, half_open_successes: 0 },
+            );
+        }
+
+        // Open the global circuit
+        let global_tripped =
+            self.global_failure_threshold > 0 && self.failures.len() >= self.global_failure_threshold;
+        if self.state == CircuitState::HalfOpen || global_tripped {
+            if self.state != CircuitState::Open {
+                self.notice_shown = false;
+            }
             self.state = CircuitState::Open;
             self.opened_at = Some(now);
+            self.tripped_manually = false;
+        }
+    }
+
+    /// Recent failures of one pattern.
+    fn pattern_failures(&self, command_hash: &str) -> usize {
+        self.failures.iter().filter(|(_, h)| h == command_hash).count()
+    }
+
+    /// Record a command that finished with a non-zero exit. Codes in
+    /// `trip_exit_codes` count toward opening the circuit like a timeout;
+    /// any other exit means the command ran to completion and counts as a
+    /// success. Returns whether the exit counted as a failure.
+    pub fn record_failure(&mut self, command_hash: &str, exit_code: i32) -> bool {
+        if self.trip_exit_codes.contains(&exit_code) {
+            self.record_timeout(command_hash);
+            true
+        } else {
+            self.record_success(command_hash);
+            false
         }
     }
 
-    /// Record a successful execution.
-    pub fn record_success(&mut self) {
+    /// Record a successful execution. A HalfOpen circuit, the pattern's or
+    /// the global one, closes once `half_open_required_successes` probes in a
+    /// row have succeeded.
+    pub fn record_success(&mut self, command_hash: &str) {
+        let required = self.half_open_required_successes;
+        if let Some(pattern) = self.patterns.get_mut(command_hash) {
+            if pattern.state == CircuitState::HalfOpen {
+                pattern.half_open_successes += 1;
+                if pattern.half_open_successes >= required {
+                    self.patterns.remove(command_hash);
+                    self.failures.retain(|(_, h)| h != command_hash);
+                }
+            }
+        }
         if self.state == CircuitState::HalfOpen {
-            self.state = CircuitState::Closed;
-            self.failures.clear();
+            self.half_open_successes += 1;
+            if self.half_open_successes >= self.half_open_required_successes {
+                self.state = CircuitState::Closed;
+                self.failures.clear();
+                self.half_open_successes = 0;
+            }
         }
     }
 
-    /// Check if execution should be allowed.
+    /// HalfOpen progress, e.g. `2/3 probes passed`.
+    fn probe_progress(&self, successes: usize) -> String {
+        format!("{}/{} probes passed", successes, self.half_open_required_successes.max(1))
+    }
+
+    /// Check if a command with `command_hash` should be allowed: the global
+    /// circuit first, then the pattern's own.
     /// Returns (allowed, optional_message).
-    pub fn should_allow(&mut self) -> (bool, Option<String>) {
+    pub fn should_allow(&mut self, command_hash: &str) -> (bool, Option<String>) {
+        let (allowed, global_msg) = self.global_allow();
+        if !allowed {
+            return (false, global_msg);
+        }
+        let (allowed, pattern_msg) = self.pattern_allow(command_hash);
+        (allowed, pattern_msg.or(global_msg))
+    }
+
+    fn pattern_allow(&mut self, command_hash: &str) -> (bool, Option<String>) {
+        let failures = self.pattern_failures(command_hash);
+        let recovery_timeout = self.recovery_timeout as f64;
+        let Some(pattern) = self.patterns.get_mut(command_hash) else {
+            return (true, None);
+        };
+        match pattern.state {
+            CircuitState::Closed => (true, None),
+            CircuitState::Open => {
+                let elapsed = Self::now() - pattern.opened_at;
+                if elapsed > recovery_timeout {
+                    pattern.state = CircuitState::HalfOpen;
+                    pattern.half_open_successes = 0;
+                    return (
+                        true,
+                        Some("NEVERHANG: Circuit half-open for this command pattern, testing recovery".into()),
+                    );
+                }
+                (
+                    false,
+                    Some(format!(
+                        "NEVERHANG: Circuit OPEN for this command pattern due to {} recent timeouts or killed commands. Retry in {}s",
+                        failures,
+                        (recovery_timeout - elapsed) as i64
+                    )),
+                )
+            }
+            CircuitState::HalfOpen => {
+                let successes = pattern.half_open_successes;
+                (
+                    true,
+                    Some(format!(
+                        "NEVERHANG: Circuit half-open for this command pattern, monitoring ({})",
+                        self.probe_progress(successes)
+                    )),
+                )
+            }
+        }
+    }
+
+    fn global_allow(&mut self) -> (bool, Option<String>) {
         match self.state {
             CircuitState::Closed => (true, None),
             CircuitState::Open => {
@@ -89,19 +242,21 @@ impl CircuitBreaker {
                     let elapsed = Self::now() - opened_at;
                     if elapsed > self.recovery_timeout as f64 {
                         self.state = CircuitState::HalfOpen;
+                        self.half_open_successes = 0;
                         return (
                             true,
                             Some("NEVERHANG: Circuit half-open, testing recovery".into()),
                         );
                     }
                     let remaining = self.recovery_timeout as f64 - elapsed;
+                    let cause = if self.tripped_manually {
+                        ", opened manually via zsh_circuit_trip".to_string()
+                    } else {
+                        format!(" due to {} recent timeouts or killed commands", self.failures.len())
+                    };
                     (
                         false,
-                        Some(format!(
-                            "NEVERHANG: Circuit OPEN due to {} recent timeouts. Retry in {}s",
-                            self.failures.len(),
-                            remaining as i64
-                        )),
+                        Some(format!("NEVERHANG: Circuit OPEN{}. Retry in {}s", cause, remaining as i64)),
                     )
                 } else {
                     (false, Some("NEVERHANG: Circuit OPEN".into()))
@@ -109,7 +264,7 @@ impl CircuitBreaker {
             }
             CircuitState::HalfOpen => (
                 true,
-                Some("NEVERHANG: Circuit half-open, monitoring".into()),
+                Some(format!("NEVERHANG: Circuit half-open, monitoring ({})", self.probe_progress(self.half_open_successes))),
             ),
         }
     }
@@ -120,6 +275,38 @@ impl CircuitBreaker {
         self.failures.clear();
         self.last_failure = None;
         self.opened_at = None;
+        self.tripped_manually = false;
+        self.notice_shown = false;
+        self.half_open_successes = 0;
+        self.patterns.clear();
+    }
+
+    /// Force the global circuit open as if it had just tripped, so clients
+    /// can exercise the blocked path. Recovers like any other open circuit.
+    pub fn trip(&mut self) {
+        self.state = CircuitState::Open;
+        self.opened_at = Some(Self::now());
+        self.tripped_manually = true;
+        self.half_open_successes = 0;
+        self.notice_shown = false;
+    }
+
+    /// True for the first blocked call since the circuit opened, false after.
+    pub fn take_first_block(&mut self) -> bool {
+        let first = !self.notice_shown;
+        self.notice_shown = true;
+        first
+    }
+
+    /// Distinct command hashes behind the recent timeouts and tripping exits, oldest first.
+    pub fn failing_hashes(&self) -> Vec<String> {
+        let mut hashes: Vec<String> = Vec::new();
+        for (_, h) in &self.failures {
+            if !hashes.contains(h) {
+                hashes.push(h.clone());
+            }
+        }
+        hashes
     }
 
     /// Get circuit breaker status for reporting.
@@ -129,31 +316,77 @@ impl CircuitBreaker {
             (self.recovery_timeout as f64 - elapsed).max(0.0) as u64
         });
 
+        let mut patterns: Vec<PatternStatus> = self
+            .patterns
+            .iter()
+            .map(|(hash, p)| PatternStatus {
+                command_hash: hash.clone(),
+                state: p.state.to_string(),
+                recent_failures: self.pattern_failures(hash),
+                half_open_probes: (p.state == CircuitState::HalfOpen)
+                    .then(|| self.probe_progress(p.half_open_successes)),
+                time_until_retry: (p.state == CircuitState::Open).then(|| {
+                    (self.recovery_timeout as f64 - (Self::now() - p.opened_at)).max(0.0) as u64
+                }),
+            })
+            .collect();
+        patterns.sort_by(|a, b| a.command_hash.cmp(&b.command_hash));
+
         CircuitStatus {
             state: self.state.to_string(),
             recent_failures: self.failures.len(),
             failure_threshold: self.failure_threshold,
+            global_failure_threshold: self.global_failure_threshold,
             recovery_timeout: self.recovery_timeout,
+            sample_window: self.sample_window,
+            half_open_required_successes: self.half_open_required_successes,
+            half_open_probes: (self.state == CircuitState::HalfOpen)
+                .then(|| self.probe_progress(self.half_open_successes)),
             opened_at: self.opened_at,
             time_until_retry,
+            patterns,
         }
     }
 }
 
+/// Global circuit status, with every open or half-open pattern.
 #[derive(Debug, Serialize)]
 pub struct CircuitStatus {
     pub state: String,
     pub recent_failures: usize,
+    /// Failures of one pattern that open its circuit.
     pub failure_threshold: usize,
+    /// Failures across all patterns that open the global circuit.
+    pub global_failure_threshold: usize,
     pub recovery_timeout: u64,
+    /// Seconds a timeout or tripping exit counts toward `failure_threshold`.
+    pub sample_window: u64,
+    pub half_open_required_successes: usize,
+    /// HalfOpen progress such as `2/3 probes passed`; None in other states.
+    pub half_open_probes: Option<String>,
     pub opened_at: Option<f64>,
     pub time_until_retry: Option<u64>,
+    pub patterns: Vec<PatternStatus>,
+}
+
+/// One entry of [`CircuitStatus::patterns`].
+#[derive(Debug, Serialize)]
+pub struct PatternStatus {
+    pub command_hash: String,
+    pub state: String,
+    pub recent_failures: usize,
+    pub half_open_probes: Option<String>,
+    pub time_until_retry: Option<u64>,
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn pattern_state(cb: &CircuitBreaker, hash: &str) -> Option<CircuitState> {
+        cb.patterns.get(hash).map(|p| p.state.clone())
+    }
+
     #[test]
     fn test_initial_state_closed() {
         let cb = CircuitBreaker::new(3, 300, 3600);
@@ -163,7 +396,7 @@ mod tests {
     #[test]
     fn test_allows_when_closed() {
         let mut cb = CircuitBreaker::new(3, 300, 3600);
-        let (allowed, msg) = cb.should_allow();
+        let (allowed, msg) = cb.should_allow("hash");
         assert!(allowed);
         assert!(msg.is_none());
     }
@@ -172,42 +405,174 @@ mod tests {
     fn test_opens_after_threshold() {
         let mut cb = CircuitBreaker::new(3, 300, 3600);
         cb.record_timeout("hash1");
+        cb.record_timeout("hash1");
         cb.record_timeout("hash2");
-        assert_eq!(cb.state, CircuitState::Closed);
-        cb.record_timeout("hash3");
-        assert_eq!(cb.state, CircuitState::Open);
+        assert_eq!(pattern_state(&cb, "hash1"), None);
+        cb.record_timeout("hash1");
+        assert_eq!(pattern_state(&cb, "hash1"), Some(CircuitState::Open));
+        assert_eq!(pattern_state(&cb, "hash2"), None);
+        assert_eq!(cb.state, CircuitState::Closed, "global backstop not reached");
     }
 
     #[test]
-    fn test_blocks_when_open() {
+    fn test_blocks_only_the_failing_pattern() {
         let mut cb = CircuitBreaker::new(3, 300, 3600);
-        for i in 0..3 {
+        for _ in 0..3 {
+            cb.record_timeout("ssh-deadhost");
+        }
+        let (allowed, msg) = cb.should_allow("ssh-deadhost");
+        assert!(!allowed);
+        assert!(msg.unwrap().contains("Circuit OPEN for this command pattern"));
+        assert_eq!(cb.should_allow("git-status"), (true, None));
+    }
+
+    #[test]
+    fn test_global_backstop_blocks_every_pattern() {
+        let mut cb = CircuitBreaker::new(3, 300, 3600);
+        cb.global_failure_threshold = 4;
+        for i in 0..4 {
             cb.record_timeout(&format!("hash{}", i));
         }
-        let (allowed, msg) = cb.should_allow();
+        assert_eq!(cb.state, CircuitState::Open);
+        assert!(cb.patterns.is_empty());
+        let (allowed, msg) = cb.should_allow("anything");
         assert!(!allowed);
-        assert!(msg.unwrap().contains("NEVERHANG"));
+        assert!(msg.unwrap().contains("Circuit OPEN due to 4 recent"));
+
+        cb.reset();
+        cb.global_failure_threshold = 0;
+        for i in 0..20 {
+            cb.record_timeout(&format!("hash{}", i));
+        }
+        assert_eq!(cb.state, CircuitState::Closed, "0 disables the backstop");
     }
 
     #[test]
     fn test_success_closes_half_open() {
         let mut cb = CircuitBreaker::new(3, 300, 3600);
         cb.state = CircuitState::HalfOpen;
-        cb.record_success();
+        cb.record_success("hash");
         assert_eq!(cb.state, CircuitState::Closed);
         assert!(cb.failures.is_empty());
     }
 
     #[test]
-    fn test_reset() {
+    fn test_pattern_recovers_through_half_open() {
+        let mut cb = CircuitBreaker::new(2, 0, 3600);
+        cb.record_timeout("flaky");
+        cb.record_timeout("other");
+        cb.record_timeout("flaky");
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        let (allowed, msg) = cb.should_allow("flaky");
+        assert!(allowed);
+        assert!(msg.unwrap().contains("half-open for this command pattern"));
+        assert_eq!(pattern_state(&cb, "flaky"), Some(CircuitState::HalfOpen));
+
+        cb.record_success("other");
+        assert_eq!(pattern_state(&cb, "flaky"), Some(CircuitState::HalfOpen), "other patterns do not probe");
+        cb.record_success("flaky");
+        assert_eq!(pattern_state(&cb, "flaky"), None);
+        assert_eq!(cb.failing_hashes(), vec!["other"]);
+    }
+
+    #[test]
+    fn test_half_open_needs_consecutive_successes() {
         let mut cb = CircuitBreaker::new(3, 300, 3600);
+        cb.half_open_required_successes = 3;
+        cb.state = CircuitState::HalfOpen;
+        cb.record_success("hash");
+        cb.record_success("hash");
+        assert_eq!(cb.state, CircuitState::HalfOpen);
+        assert_eq!(cb.get_status().half_open_probes.as_deref(), Some("2/3 probes passed"));
+        let (allowed, msg) = cb.should_allow("hash");
+        assert!(allowed);
+        assert!(msg.unwrap().contains("(2/3 probes passed)"));
+        cb.record_success("hash");
+        assert_eq!(cb.state, CircuitState::Closed);
+        assert!(cb.get_status().half_open_probes.is_none());
+    }
+
+    #[test]
+    fn test_failure_in_half_open_reopens_immediately() {
+        let mut cb = CircuitBreaker::new(3, 0, 3600);
+        cb.global_failure_threshold = 3;
+        cb.half_open_required_successes = 2;
         for i in 0..3 {
             cb.record_timeout(&format!("hash{}", i));
         }
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        assert!(cb.should_allow("hash").0);
+        assert_eq!(cb.state, CircuitState::HalfOpen);
+        cb.record_success("hash");
+        cb.failures.clear();
+        cb.record_timeout("flaky");
+        assert_eq!(cb.state, CircuitState::Open, "one failure is enough below the threshold");
+
+        // The next probe round starts from zero
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        assert!(cb.should_allow("hash").0);
+        assert_eq!(cb.get_status().half_open_probes.as_deref(), Some("0/2 probes passed"));
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut cb = CircuitBreaker::new(3, 300, 3600);
+        cb.global_failure_threshold = 3;
+        for _ in 0..3 {
+            cb.record_timeout("hash");
+        }
         assert_eq!(cb.state, CircuitState::Open);
+        assert_eq!(pattern_state(&cb, "hash"), Some(CircuitState::Open));
         cb.reset();
         assert_eq!(cb.state, CircuitState::Closed);
         assert!(cb.failures.is_empty());
+        assert!(cb.patterns.is_empty());
+    }
+
+    #[test]
+    fn test_trip_opens_global_circuit() {
+        let mut cb = CircuitBreaker::new(3, 300, 3600);
+        cb.trip();
+        assert_eq!(cb.state, CircuitState::Open);
+        assert!(cb.opened_at.is_some());
+        let (allowed, msg) = cb.should_allow("anything");
+        assert!(!allowed);
+        let msg = msg.unwrap();
+        assert!(msg.contains("Circuit OPEN, opened manually via zsh_circuit_trip. Retry in"), "{}", msg);
+        assert!(!msg.contains("recent timeouts"), "{}", msg);
+        assert!(cb.get_status().time_until_retry.is_some_and(|t| t >= 299));
+
+        cb.reset();
+        assert!(cb.should_allow("anything").0);
+    }
+
+    #[test]
+    fn test_record_failure_counts_only_trip_exit_codes() {
+        let mut cb = CircuitBreaker::new(2, 300, 3600);
+        cb.trip_exit_codes = vec![124, 137, 143];
+        assert!(!cb.record_failure("build", 1));
+        assert!(!cb.record_failure("grep", 2));
+        assert!(cb.failures.is_empty());
+        assert!(cb.record_failure("server", 137));
+        assert_eq!(pattern_state(&cb, "server"), None);
+        assert!(cb.record_failure("server", 143));
+        assert_eq!(pattern_state(&cb, "server"), Some(CircuitState::Open));
+        assert_eq!(cb.failing_hashes(), vec!["server"]);
+    }
+
+    #[test]
+    fn test_record_failure_mixes_with_timeouts_and_closes_half_open() {
+        let mut cb = CircuitBreaker::new(2, 300, 3600);
+        assert!(!cb.record_failure("a", 137), "no trip codes configured");
+        cb.trip_exit_codes = vec![137];
+        cb.record_timeout("a");
+        cb.record_failure("a", 137);
+        assert_eq!(pattern_state(&cb, "a"), Some(CircuitState::Open));
+
+        cb.patterns.get_mut("a").unwrap().state = CircuitState::HalfOpen;
+        cb.record_failure("a", 1);
+        assert_eq!(pattern_state(&cb, "a"), None);
+        assert!(cb.failures.is_empty());
     }
 
     #[test]
@@ -217,4 +582,44 @@ mod tests {
         let json = serde_json::to_string(&status).unwrap();
         assert!(json.contains("\"state\":\"closed\""));
     }
+
+    #[test]
+    fn test_status_reports_configured_thresholds() {
+        let status = CircuitBreaker::new(5, 120, 900).get_status();
+        assert_eq!(status.failure_threshold, 5);
+        assert_eq!(status.global_failure_threshold, DEFAULT_GLOBAL_FAILURE_THRESHOLD);
+        assert_eq!(status.recovery_timeout, 120);
+        assert_eq!(status.sample_window, 900);
+    }
+
+    #[test]
+    fn test_status_reports_pattern_circuits() {
+        let mut cb = CircuitBreaker::new(2, 300, 3600);
+        cb.record_timeout("b");
+        cb.record_timeout("b");
+        cb.record_timeout("c");
+        let status = cb.get_status();
+        assert_eq!(status.state, "closed");
+        assert_eq!(status.recent_failures, 3);
+        assert_eq!(status.patterns.len(), 1);
+        let b = &status.patterns[0];
+        assert_eq!((b.command_hash.as_str(), b.state.as_str(), b.recent_failures), ("b", "open", 2));
+        assert!(b.time_until_retry.is_some_and(|t| t <= 300));
+    }
+
+    #[test]
+    fn test_first_block_reported_once_per_opening() {
+        let mut cb = CircuitBreaker::new(2, 300, 3600);
+        cb.record_timeout("a");
+        cb.record_timeout("a");
+        assert!(cb.take_first_block());
+        assert!(!cb.take_first_block());
+        cb.record_timeout("b");
+        assert!(!cb.take_first_block());
+        assert_eq!(cb.failing_hashes(), vec!["a", "b"]);
+        cb.reset();
+        cb.record_timeout("c");
+        cb.record_timeout("c");
+        assert!(cb.take_first_block());
+    }
 }
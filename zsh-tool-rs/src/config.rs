@@ -1,5 +1,101 @@
+use std::collections::HashMap;
 use std::path::Path;
 
+use crate::executor::ShellKind;
+
+/// How timestamps are rendered in tool results.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimestampFormat {
+    /// Seconds since the Unix epoch, as a float.
+    Epoch,
+    /// RFC 3339 / ISO 8601 string in UTC.
+    Iso8601,
+}
+
+impl TimestampFormat {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "epoch" => Some(Self::Epoch),
+            "iso8601" | "iso" | "rfc3339" => Some(Self::Iso8601),
+            _ => None,
+        }
+    }
+}
+
+/// Which end of the output survives the `max_output_lines` cap.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LineKeep {
+    Head,
+    Tail,
+}
+
+impl LineKeep {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "head" => Some(Self::Head),
+            "tail" => Some(Self::Tail),
+            _ => None,
+        }
+    }
+}
+
+/// Which part of the output survives the `truncate_output_at` byte limit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TruncateMode {
+    Head,
+    Tail,
+    /// Half the budget from each end around an elision marker.
+    Middle,
+}
+
+impl TruncateMode {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "head" => Some(Self::Head),
+            "tail" => Some(Self::Tail),
+            "middle" => Some(Self::Middle),
+            _ => None,
+        }
+    }
+}
+
+/// How much explanation a blocked `zsh` call carries.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BlockDetail {
+    /// `blocked_reason` and a one-line `detail` only.
+    Brief,
+    /// Also the ALAN insights and remediation hints for the command.
+    Full,
+}
+
+impl BlockDetail {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "brief" => Some(Self::Brief),
+            "full" => Some(Self::Full),
+            _ => None,
+        }
+    }
+}
+
+/// Where a config value came from. Later sources override earlier ones.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConfigSource {
+    Default,
+    Yaml,
+    Env,
+}
+
+impl ConfigSource {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Default => "default",
+            Self::Yaml => "yaml",
+            Self::Env => "env",
+        }
+    }
+}
+
 pub struct Config {
     // NEVERHANG
     pub neverhang_timeout_default: u64,
@@ -7,8 +103,53 @@ pub struct Config {
     pub neverhang_failure_threshold: usize,
     pub neverhang_recovery_timeout: u64,
     pub neverhang_sample_window: u64,
+    /// Exit codes that count toward opening the circuit like a timeout
+    /// (default: 124, 137, 143 — `timeout`, SIGKILL and SIGTERM kills).
+    /// Other non-zero exits do not.
+    pub circuit_trip_exit_codes: Vec<i32>,
+    /// Timeouts and tripping exits across all command patterns that open the
+    /// global circuit, blocking every command. 0 disables it.
+    pub circuit_global_failure_threshold: usize,
+    /// Exit codes the `zsh` tool's `retries` treats as transient (default:
+    /// 255, SSH connection failure). 126 and 127 are never retried.
+    pub retry_exit_codes: Vec<i32>,
+    /// Whether `retries` re-runs commands that timed out.
+    pub retry_timeouts: bool,
+    /// Consecutive successful probes needed to close a half-open circuit.
+    pub half_open_required_successes: usize,
+    /// On the first block after the circuit opens, explain which patterns
+    /// timed out and how to fix them.
+    pub circuit_cooldown_notice: bool,
+    /// Verbosity of blocked results (circuit open, rate limited).
+    pub blocked_detail: BlockDetail,
     // Yield
     pub yield_after_default: f64,
+    /// Multiplier applied to the yield sleep. 1.0 in production; tests set
+    /// `ZSH_TOOL_TEST_CLOCK` to compress (e.g. 0.1) or skip (0) the wait.
+    pub yield_time_scale: f64,
+    /// Default for the `zsh` tool's `yield_on_output`: end the yield wait early
+    /// once this many bytes of output have arrived and gone quiet. 0 disables.
+    pub yield_on_output_bytes: usize,
+    /// Shorten the yield for historically slow patterns, stretch it slightly
+    /// for patterns that usually finish just past it.
+    pub adaptive_yield: bool,
+    /// Where exec processes write their meta files, under a per-server
+    /// `zsh-tool-meta-<pid>` subdirectory.
+    pub meta_dir: String,
+    /// Executor binary `serve` spawns for each command. None uses the
+    /// server's own executable (see `serve::resolve_exec_path`).
+    pub exec_path: Option<String>,
+    /// Shell language commands run under: `zsh`, `bash` or `sh`. Left
+    /// unset, it follows `shell_path` (see [`Config::shell_kind`]).
+    pub shell: ShellKind,
+    /// Binary for `shell`. None searches `PATH` for the shell's name,
+    /// falling back to `/bin/<name>` (see `executor::default_shell`).
+    pub shell_path: Option<String>,
+    /// Directory for `<task_id>.log` spool files holding the full output of
+    /// tasks that outgrow `truncate_output_at`. None disables spooling.
+    pub spool_dir: Option<String>,
+    /// Keep spool files after a task finishes or is killed.
+    pub keep_logs: bool,
     // ALAN
     pub alan_db_path: String,
     pub alan_decay_half_life_hours: u64,
@@ -17,15 +158,99 @@ pub struct Config {
     pub alan_max_entries: usize,
     pub alan_recent_window_minutes: u64,
     pub alan_streak_threshold: i64,
+    /// Fold identical observations (hash, exit code, cwd) repeated within this
+    /// many seconds into one row. 0 records every run.
+    pub alan_dedup_window_secs: u64,
     // manopt
     pub alan_manopt_enabled: bool,
     pub alan_manopt_timeout: f64,
     pub alan_manopt_fail_trigger: i64,
     pub alan_manopt_fail_present: i64,
+    // Network pre-check (opt-in)
+    pub network_check_enabled: bool,
+    pub network_check_host: String,
+    pub network_check_timeout_ms: u64,
+    // Spawn rate limit (exec subprocesses per second, 0 = unlimited)
+    pub max_spawns_per_sec: f64,
+    /// Exit after this many seconds without a request while no task runs. 0 never exits.
+    pub session_idle_timeout_secs: u64,
+    /// Soft RSS threshold (MB) for running tasks: polls warn above it, nothing is killed. 0 disables.
+    pub memory_warn_mb: u64,
+    /// Most tasks kept in the registry; registering one past it evicts the
+    /// oldest finished tasks. Running tasks are never evicted. 0 disables.
+    pub max_tasks: usize,
     // Output
     pub truncate_output_at: usize,
-    // Pipestatus marker
-    pub pipestatus_marker: String,
+    pub truncate_mode: TruncateMode,
+    /// Line cap applied before the byte limit. 0 disables it.
+    pub max_output_lines: usize,
+    /// Finished runs of one pattern hitting the output cap before a hint to
+    /// filter or redirect its output appears. 0 disables the hint.
+    pub truncation_hint_after: usize,
+    pub output_lines_keep: LineKeep,
+    pub empty_output_text: String,
+    /// Line put before returned command output, e.g. `<<<OUTPUT`. Empty omits it.
+    pub output_marker_start: String,
+    /// Line put after returned command output. Empty omits it.
+    pub output_marker_end: String,
+    /// Expand tabs in returned output to `tab_width` columns. The task buffer keeps raw tabs.
+    pub expand_tabs: bool,
+    pub tab_width: usize,
+    pub timestamp_format: TimestampFormat,
+    /// Rewrite Windows-style paths in `zsh` commands to POSIX (see `winpath`).
+    pub normalize_windows_paths: bool,
+    /// Start with verbose protocol logging on (also toggled by `zsh_debug`).
+    pub protocol_debug: bool,
+    /// Write background task completions to stdout as `notifications/message`
+    /// when they happen, instead of prepending them to the next tool result.
+    pub push_notifications: bool,
+    /// umask for executed commands (octal in config, e.g. `022`). None inherits the server's.
+    pub umask: Option<u32>,
+    /// Per-tool description overrides for tools/list, keyed by tool name.
+    pub tool_descriptions: HashMap<String, String>,
+    /// Tools to expose. None exposes every tool.
+    pub enabled_tools: Option<Vec<String>>,
+    /// Glob patterns of commands `zsh` refuses to run (see `policy`).
+    pub deny_commands: Vec<String>,
+    /// Base commands that succeed silently; no "No output produced" insight.
+    pub silent_commands: Vec<String>,
+    /// Regexes for commands whose `timeout` is not capped at
+    /// `neverhang_timeout_max` (e.g. `^cargo build`). Every command in a
+    /// chain must match one.
+    pub timeout_exempt_patterns: Vec<String>,
+    /// Ceiling on the `timeout` of a `timeout_exempt_patterns` command.
+    pub timeout_exempt_max: u64,
+    /// If set, only commands matching one of these globs run.
+    pub allow_commands: Option<Vec<String>>,
+    /// Block destructive commands (see `policy::is_destructive`) whose working
+    /// directory is `$HOME` or `/` unless the call passes `confirm: true`.
+    pub home_root_guard: bool,
+    /// Re-run a failed non-PTY `zsh` command once in PTY mode when its output
+    /// shows a TTY error (see `executor::is_tty_error`).
+    pub pty_fallback: bool,
+    /// After a PTY command exits, how many ms of silence to wait for its
+    /// last output before closing the terminal.
+    pub pty_drain_ms: u64,
+    /// Default for the `zsh` tool's `render_terminal` argument: return PTY
+    /// output as the rendered screen instead of the raw escape stream.
+    pub render_terminal: bool,
+    /// Default for the `zsh` tool's `output_hash` argument: add the SHA-256 of
+    /// the full output so agents can tell whether it changed between runs.
+    pub output_hash: bool,
+    /// Default for the `zsh` tool's `timestamp_lines` argument: prefix each
+    /// output line with the ms since the command started when it arrived.
+    pub timestamp_lines: bool,
+    /// Per base command default for capturing stderr apart from stdout.
+    pub separate_stderr: HashMap<String, bool>,
+    /// Per SSH host default `timeout` in seconds, used when a `zsh` call to
+    /// that host passes none (still capped at `neverhang_timeout_max`).
+    pub ssh_timeouts: HashMap<String, u64>,
+    /// Per base command verbosity flag or `NAME=value` setting used by
+    /// `zsh_retry_verbose` (see `verbose`).
+    pub verbose_flags: HashMap<String, String>,
+    /// Source of each field set by the YAML file or an env var, keyed by
+    /// field name. Fields missing here hold their default.
+    pub sources: HashMap<String, ConfigSource>,
 }
 
 impl Default for Config {
@@ -36,7 +261,22 @@ impl Default for Config {
             neverhang_failure_threshold: 3,
             neverhang_recovery_timeout: 300,
             neverhang_sample_window: 3600,
+            circuit_trip_exit_codes: vec![124, 137, 143],
+            circuit_global_failure_threshold: crate::circuit::DEFAULT_GLOBAL_FAILURE_THRESHOLD,
+            half_open_required_successes: 1,
+            retry_exit_codes: vec![255],
+            retry_timeouts: true,
+            circuit_cooldown_notice: true,
             yield_after_default: 2.0,
+            yield_time_scale: 1.0,
+            yield_on_output_bytes: 0,
+            adaptive_yield: true,
+            meta_dir: std::env::temp_dir().to_string_lossy().into_owned(),
+            exec_path: None,
+            shell: ShellKind::Zsh,
+            shell_path: None,
+            spool_dir: None,
+            keep_logs: false,
             alan_db_path: expand_tilde("~/.claude/plugins/zsh-tool/data/alan.db"),
             alan_decay_half_life_hours: 24,
             alan_prune_threshold: 0.01,
@@ -44,12 +284,57 @@ impl Default for Config {
             alan_max_entries: 10000,
             alan_recent_window_minutes: 10,
             alan_streak_threshold: 3,
+            alan_dedup_window_secs: 0,
             alan_manopt_enabled: true,
             alan_manopt_timeout: 2.0,
             alan_manopt_fail_trigger: 2,
             alan_manopt_fail_present: 3,
+            network_check_enabled: false,
+            network_check_host: "1.1.1.1:53".to_string(),
+            network_check_timeout_ms: 500,
+            max_spawns_per_sec: 10.0,
+            session_idle_timeout_secs: 0,
+            memory_warn_mb: 0,
+            max_tasks: 500,
             truncate_output_at: 30000,
-            pipestatus_marker: "___ZSH_PIPESTATUS_MARKER_f9a8b7c6___".to_string(),
+            truncate_mode: TruncateMode::Head,
+            max_output_lines: 0,
+            truncation_hint_after: 3,
+            output_lines_keep: LineKeep::Head,
+            blocked_detail: BlockDetail::Full,
+            empty_output_text: "(no output)".to_string(),
+            output_marker_start: String::new(),
+            output_marker_end: String::new(),
+            expand_tabs: false,
+            tab_width: 8,
+            timestamp_format: TimestampFormat::Epoch,
+            normalize_windows_paths: false,
+            protocol_debug: false,
+            push_notifications: false,
+            umask: None,
+            tool_descriptions: HashMap::new(),
+            enabled_tools: None,
+            deny_commands: Vec::new(),
+            timeout_exempt_patterns: Vec::new(),
+            timeout_exempt_max: 14400,
+            home_root_guard: false,
+            pty_fallback: false,
+            pty_drain_ms: crate::executor::PTY_DRAIN_GRACE_MS,
+            render_terminal: false,
+            output_hash: false,
+            timestamp_lines: false,
+            silent_commands: crate::alan::insights::DEFAULT_SILENT_COMMANDS
+                .iter()
+                .map(|c| c.to_string())
+                .collect(),
+            allow_commands: None,
+            separate_stderr: HashMap::new(),
+            ssh_timeouts: HashMap::new(),
+            verbose_flags: crate::verbose::DEFAULT_VERBOSE_FLAGS
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            sources: HashMap::new(),
         }
     }
 }
@@ -59,18 +344,356 @@ impl Config {
     pub fn load_from(path: &Path) -> Self {
         let mut cfg = Self::default();
         if let Ok(content) = std::fs::read_to_string(path) {
-            for line in content.lines() {
-                let line = line.trim();
+            // Name of the mapping section whose indented entries follow, if any
+            let mut section: Option<String> = None;
+            for raw in content.lines() {
+                let line = raw.trim();
                 if line.is_empty() || line.starts_with('#') {
                     continue;
                 }
+                let indented = raw.starts_with(' ') || raw.starts_with('\t');
+                if !indented {
+                    section = None;
+                }
+                if let Some(item) = line.strip_prefix("- ") {
+                    let item = unquote(item.trim()).to_string();
+                    match section.as_deref() {
+                        Some("enabled_tools") if indented => {
+                            cfg.enabled_tools.get_or_insert_with(Vec::new).push(item)
+                        }
+                        Some("deny_commands") if indented => cfg.deny_commands.push(item),
+                        Some("timeout_exempt_patterns") if indented => {
+                            cfg.timeout_exempt_patterns.push(item)
+                        }
+                        Some("silent_commands") if indented => cfg.silent_commands.push(item),
+                        Some("circuit_trip_exit_codes") if indented => match item.parse() {
+                            Ok(code) => cfg.circuit_trip_exit_codes.push(code),
+                            Err(_) => continue,
+                        },
+                        Some("retry_exit_codes") if indented => match item.parse() {
+                            Ok(code) => cfg.retry_exit_codes.push(code),
+                            Err(_) => continue,
+                        },
+                        Some("allow_commands") if indented => {
+                            cfg.allow_commands.get_or_insert_with(Vec::new).push(item)
+                        }
+                        _ => continue,
+                    }
+                    // Block list sections are named after their field
+                    if let Some(field) = section.as_deref() {
+                        cfg.set_source(field, ConfigSource::Yaml);
+                    }
+                    continue;
+                }
                 if let Some((key, value)) = line.split_once(':') {
                     let key = key.trim();
                     let value = value.trim();
-                    if key == "yield_after" {
-                        if let Ok(v) = value.parse() {
-                            cfg.yield_after_default = v;
+                    if indented {
+                        match section.as_deref() {
+                            Some("tool_descriptions") => {
+                                cfg.tool_descriptions
+                                    .insert(unquote(key).to_string(), unquote(value).to_string());
+                            }
+                            Some("separate_stderr") => {
+                                cfg.separate_stderr
+                                    .insert(unquote(key).to_string(), parse_bool(value));
+                            }
+                            Some("ssh_timeouts") => match value.parse() {
+                                Ok(secs) => {
+                                    cfg.ssh_timeouts.insert(unquote(key).to_string(), secs);
+                                }
+                                Err(_) => continue,
+                            },
+                            Some("verbose_flags") => {
+                                cfg.verbose_flags
+                                    .insert(unquote(key).to_string(), unquote(value).to_string());
+                            }
+                            _ => continue,
+                        }
+                        if let Some(field) = section.as_deref() {
+                            cfg.set_source(field, ConfigSource::Yaml);
+                        }
+                        continue;
+                    }
+                    match key {
+                        "tool_descriptions" | "separate_stderr" | "ssh_timeouts" | "verbose_flags" => {
+                            section = Some(key.to_string());
+                        }
+                        "enabled_tools" => {
+                            if value.is_empty() {
+                                section = Some(key.to_string());
+                            } else {
+                                cfg.enabled_tools = Some(parse_list(value));
+                                cfg.set_source("enabled_tools", ConfigSource::Yaml);
+                            }
+                        }
+                        "deny_commands" => {
+                            if value.is_empty() {
+                                section = Some(key.to_string());
+                            } else {
+                                cfg.deny_commands = parse_list(value);
+                                cfg.set_source("deny_commands", ConfigSource::Yaml);
+                            }
+                        }
+                        "timeout_exempt_patterns" => {
+                            if value.is_empty() {
+                                section = Some(key.to_string());
+                            } else {
+                                cfg.timeout_exempt_patterns = parse_list(value);
+                                cfg.set_source("timeout_exempt_patterns", ConfigSource::Yaml);
+                            }
+                        }
+                        "silent_commands" => {
+                            if value.is_empty() {
+                                // A block list replaces the defaults
+                                cfg.silent_commands.clear();
+                                cfg.set_source("silent_commands", ConfigSource::Yaml);
+                                section = Some(key.to_string());
+                            } else {
+                                cfg.silent_commands = parse_list(value);
+                                cfg.set_source("silent_commands", ConfigSource::Yaml);
+                            }
+                        }
+                        "allow_commands" => {
+                            if value.is_empty() {
+                                section = Some(key.to_string());
+                            } else {
+                                cfg.allow_commands = Some(parse_list(value));
+                                cfg.set_source("allow_commands", ConfigSource::Yaml);
+                            }
+                        }
+                        "yield_after" => {
+                            if let Ok(v) = value.parse() {
+                                cfg.yield_after_default = v;
+                                cfg.set_source("yield_after_default", ConfigSource::Yaml);
+                            }
+                        }
+                        "circuit_trip_exit_codes" => {
+                            // A list replaces the defaults
+                            cfg.circuit_trip_exit_codes = parse_exit_codes(value);
+                            cfg.set_source("circuit_trip_exit_codes", ConfigSource::Yaml);
+                            if value.is_empty() {
+                                section = Some(key.to_string());
+                            }
                         }
+                        "retry_exit_codes" => {
+                            // A list replaces the defaults
+                            cfg.retry_exit_codes = parse_exit_codes(value);
+                            cfg.set_source("retry_exit_codes", ConfigSource::Yaml);
+                            if value.is_empty() {
+                                section = Some(key.to_string());
+                            }
+                        }
+                        "retry_timeouts" => {
+                            cfg.retry_timeouts = parse_bool(value);
+                            cfg.set_source("retry_timeouts", ConfigSource::Yaml);
+                        }
+                        "circuit_global_failure_threshold" => {
+                            if let Ok(v) = value.parse() {
+                                cfg.circuit_global_failure_threshold = v;
+                                cfg.set_source("circuit_global_failure_threshold", ConfigSource::Yaml);
+                            }
+                        }
+                        "half_open_required_successes" => {
+                            if let Ok(v) = value.parse() {
+                                cfg.half_open_required_successes = v;
+                                cfg.set_source("half_open_required_successes", ConfigSource::Yaml);
+                            }
+                        }
+                        "circuit_cooldown_notice" => {
+                            cfg.circuit_cooldown_notice = parse_bool(value);
+                            cfg.set_source("circuit_cooldown_notice", ConfigSource::Yaml);
+                        }
+                        "adaptive_yield" => {
+                            cfg.adaptive_yield = parse_bool(value);
+                            cfg.set_source("adaptive_yield", ConfigSource::Yaml);
+                        }
+                        "expand_tabs" => {
+                            cfg.expand_tabs = parse_bool(value);
+                            cfg.set_source("expand_tabs", ConfigSource::Yaml);
+                        }
+                        "normalize_windows_paths" => {
+                            cfg.normalize_windows_paths = parse_bool(value);
+                            cfg.set_source("normalize_windows_paths", ConfigSource::Yaml);
+                        }
+                        "home_root_guard" => {
+                            cfg.home_root_guard = parse_bool(value);
+                            cfg.set_source("home_root_guard", ConfigSource::Yaml);
+                        }
+                        "pty_fallback" => {
+                            cfg.pty_fallback = parse_bool(value);
+                            cfg.set_source("pty_fallback", ConfigSource::Yaml);
+                        }
+                        "pty_drain_ms" => {
+                            if let Ok(v) = value.parse() {
+                                cfg.pty_drain_ms = v;
+                                cfg.set_source("pty_drain_ms", ConfigSource::Yaml);
+                            }
+                        }
+                        "render_terminal" => {
+                            cfg.render_terminal = parse_bool(value);
+                            cfg.set_source("render_terminal", ConfigSource::Yaml);
+                        }
+                        "timestamp_lines" => {
+                            cfg.timestamp_lines = parse_bool(value);
+                            cfg.set_source("timestamp_lines", ConfigSource::Yaml);
+                        }
+                        "output_hash" => {
+                            cfg.output_hash = parse_bool(value);
+                            cfg.set_source("output_hash", ConfigSource::Yaml);
+                        }
+                        "push_notifications" => {
+                            cfg.push_notifications = parse_bool(value);
+                            cfg.set_source("push_notifications", ConfigSource::Yaml);
+                        }
+                        "tab_width" => {
+                            if let Ok(v) = value.parse() {
+                                cfg.tab_width = v;
+                                cfg.set_source("tab_width", ConfigSource::Yaml);
+                            }
+                        }
+                        "output_marker_start" => {
+                            cfg.output_marker_start = unquote(value).to_string();
+                            cfg.set_source("output_marker_start", ConfigSource::Yaml);
+                        }
+                        "output_marker_end" => {
+                            cfg.output_marker_end = unquote(value).to_string();
+                            cfg.set_source("output_marker_end", ConfigSource::Yaml);
+                        }
+                        "empty_output_text" => {
+                            cfg.empty_output_text = unquote(value).to_string();
+                            cfg.set_source("empty_output_text", ConfigSource::Yaml);
+                        }
+                        "meta_dir" => {
+                            cfg.meta_dir = expand_tilde(unquote(value));
+                            cfg.set_source("meta_dir", ConfigSource::Yaml);
+                        }
+                        "exec_path" => {
+                            let path = unquote(value);
+                            cfg.exec_path = (!path.is_empty()).then(|| expand_tilde(path));
+                            cfg.set_source("exec_path", ConfigSource::Yaml);
+                        }
+                        "shell" => {
+                            if let Some(kind) = ShellKind::parse(unquote(value)) {
+                                cfg.shell = kind;
+                                cfg.set_source("shell", ConfigSource::Yaml);
+                            }
+                        }
+                        // zsh_path is the name from before `shell` existed
+                        "shell_path" | "zsh_path" => {
+                            let path = unquote(value);
+                            cfg.shell_path = (!path.is_empty()).then(|| expand_tilde(path));
+                            cfg.set_source("shell_path", ConfigSource::Yaml);
+                        }
+                        "spool_dir" => {
+                            let dir = unquote(value);
+                            cfg.spool_dir = (!dir.is_empty()).then(|| expand_tilde(dir));
+                            cfg.set_source("spool_dir", ConfigSource::Yaml);
+                        }
+                        "keep_logs" => {
+                            cfg.keep_logs = parse_bool(value);
+                            cfg.set_source("keep_logs", ConfigSource::Yaml);
+                        }
+                        "network_check" => {
+                            cfg.network_check_enabled = parse_bool(value);
+                            cfg.set_source("network_check_enabled", ConfigSource::Yaml);
+                        }
+                        "network_check_host" => {
+                            cfg.network_check_host = unquote(value).to_string();
+                            cfg.set_source("network_check_host", ConfigSource::Yaml);
+                        }
+                        "network_check_timeout_ms" => {
+                            if let Ok(v) = value.parse() {
+                                cfg.network_check_timeout_ms = v;
+                                cfg.set_source("network_check_timeout_ms", ConfigSource::Yaml);
+                            }
+                        }
+                        "max_spawns_per_sec" => {
+                            if let Ok(v) = value.parse::<f64>() {
+                                if v >= 0.0 {
+                                    cfg.max_spawns_per_sec = v;
+                                    cfg.set_source("max_spawns_per_sec", ConfigSource::Yaml);
+                                }
+                            }
+                        }
+                        // alan_dedup_window is the old name
+                        "alan_dedup_window_secs" | "alan_dedup_window" => {
+                            if let Ok(v) = value.parse() {
+                                cfg.alan_dedup_window_secs = v;
+                                cfg.set_source("alan_dedup_window_secs", ConfigSource::Yaml);
+                            }
+                        }
+                        "session_idle_timeout" => {
+                            if let Ok(v) = value.parse() {
+                                cfg.session_idle_timeout_secs = v;
+                                cfg.set_source("session_idle_timeout_secs", ConfigSource::Yaml);
+                            }
+                        }
+                        "timeout_exempt_max" => {
+                            if let Ok(v) = value.parse() {
+                                cfg.timeout_exempt_max = v;
+                                cfg.set_source("timeout_exempt_max", ConfigSource::Yaml);
+                            }
+                        }
+                        "yield_on_output_bytes" => {
+                            if let Ok(v) = value.parse() {
+                                cfg.yield_on_output_bytes = v;
+                                cfg.set_source("yield_on_output_bytes", ConfigSource::Yaml);
+                            }
+                        }
+                        "max_tasks" => {
+                            if let Ok(v) = value.parse() {
+                                cfg.max_tasks = v;
+                                cfg.set_source("max_tasks", ConfigSource::Yaml);
+                            }
+                        }
+                        "memory_warn_mb" => {
+                            if let Ok(v) = value.parse() {
+                                cfg.memory_warn_mb = v;
+                                cfg.set_source("memory_warn_mb", ConfigSource::Yaml);
+                            }
+                        }
+                        "max_output_lines" => {
+                            if let Ok(v) = value.parse() {
+                                cfg.max_output_lines = v;
+                                cfg.set_source("max_output_lines", ConfigSource::Yaml);
+                            }
+                        }
+                        "truncation_hint_after" => {
+                            if let Ok(v) = value.parse() {
+                                cfg.truncation_hint_after = v;
+                                cfg.set_source("truncation_hint_after", ConfigSource::Yaml);
+                            }
+                        }
+                        "truncate_mode" => {
+                            if let Some(m) = TruncateMode::parse(unquote(value)) {
+                                cfg.truncate_mode = m;
+                                cfg.set_source("truncate_mode", ConfigSource::Yaml);
+                            }
+                        }
+                        "output_lines_keep" => {
+                            if let Some(k) = LineKeep::parse(unquote(value)) {
+                                cfg.output_lines_keep = k;
+                                cfg.set_source("output_lines_keep", ConfigSource::Yaml);
+                            }
+                        }
+                        "blocked_detail" => {
+                            if let Some(d) = BlockDetail::parse(unquote(value)) {
+                                cfg.blocked_detail = d;
+                                cfg.set_source("blocked_detail", ConfigSource::Yaml);
+                            }
+                        }
+                        "umask" => {
+                            cfg.umask = parse_umask(unquote(value));
+                            cfg.set_source("umask", ConfigSource::Yaml);
+                        }
+                        "timestamp_format" => {
+                            if let Some(f) = TimestampFormat::parse(unquote(value)) {
+                                cfg.timestamp_format = f;
+                                cfg.set_source("timestamp_format", ConfigSource::Yaml);
+                            }
+                        }
+                        _ => {}
                     }
                 }
             }
@@ -97,34 +720,400 @@ impl Config {
         }
     }
 
+    /// Where the value of `field` came from.
+    pub fn source(&self, field: &str) -> ConfigSource {
+        self.sources.get(field).copied().unwrap_or(ConfigSource::Default)
+    }
+
+    /// Shell language commands actually run under: `shell` when set
+    /// explicitly, otherwise guessed from `shell_path`'s basename, so a
+    /// bash binary gets bash's pipestatus syntax.
+    pub fn shell_kind(&self) -> ShellKind {
+        match &self.shell_path {
+            Some(path) if self.source("shell") == ConfigSource::Default => ShellKind::from_path(path),
+            _ => self.shell,
+        }
+    }
+
+    fn set_source(&mut self, field: &str, source: ConfigSource) {
+        self.sources.insert(field.to_string(), source);
+    }
+
+    /// Every field with its effective value, for `zsh_config`.
+    pub fn entries(&self) -> Vec<(&'static str, serde_json::Value)> {
+        use serde_json::json;
+        let lower = |v: &dyn std::fmt::Debug| json!(format!("{:?}", v).to_lowercase());
+        vec![
+            ("neverhang_timeout_default", json!(self.neverhang_timeout_default)),
+            ("neverhang_timeout_max", json!(self.neverhang_timeout_max)),
+            ("neverhang_failure_threshold", json!(self.neverhang_failure_threshold)),
+            ("neverhang_recovery_timeout", json!(self.neverhang_recovery_timeout)),
+            ("neverhang_sample_window", json!(self.neverhang_sample_window)),
+            ("circuit_trip_exit_codes", json!(self.circuit_trip_exit_codes)),
+            ("circuit_global_failure_threshold", json!(self.circuit_global_failure_threshold)),
+            ("half_open_required_successes", json!(self.half_open_required_successes)),
+            ("retry_exit_codes", json!(self.retry_exit_codes)),
+            ("retry_timeouts", json!(self.retry_timeouts)),
+            ("circuit_cooldown_notice", json!(self.circuit_cooldown_notice)),
+            ("blocked_detail", lower(&self.blocked_detail)),
+            ("yield_after_default", json!(self.yield_after_default)),
+            ("yield_time_scale", json!(self.yield_time_scale)),
+            ("yield_on_output_bytes", json!(self.yield_on_output_bytes)),
+            ("adaptive_yield", json!(self.adaptive_yield)),
+            ("meta_dir", json!(self.meta_dir)),
+            ("exec_path", json!(self.exec_path)),
+            ("shell", json!(self.shell_kind().name())),
+            ("shell_path", json!(self.shell_path)),
+            ("spool_dir", json!(self.spool_dir)),
+            ("keep_logs", json!(self.keep_logs)),
+            ("alan_db_path", json!(self.alan_db_path)),
+            ("alan_decay_half_life_hours", json!(self.alan_decay_half_life_hours)),
+            ("alan_prune_threshold", json!(self.alan_prune_threshold)),
+            ("alan_prune_interval_hours", json!(self.alan_prune_interval_hours)),
+            ("alan_max_entries", json!(self.alan_max_entries)),
+            ("alan_recent_window_minutes", json!(self.alan_recent_window_minutes)),
+            ("alan_streak_threshold", json!(self.alan_streak_threshold)),
+            ("alan_dedup_window_secs", json!(self.alan_dedup_window_secs)),
+            ("alan_manopt_enabled", json!(self.alan_manopt_enabled)),
+            ("alan_manopt_timeout", json!(self.alan_manopt_timeout)),
+            ("alan_manopt_fail_trigger", json!(self.alan_manopt_fail_trigger)),
+            ("alan_manopt_fail_present", json!(self.alan_manopt_fail_present)),
+            ("network_check_enabled", json!(self.network_check_enabled)),
+            ("network_check_host", json!(self.network_check_host)),
+            ("network_check_timeout_ms", json!(self.network_check_timeout_ms)),
+            ("max_spawns_per_sec", json!(self.max_spawns_per_sec)),
+            ("session_idle_timeout_secs", json!(self.session_idle_timeout_secs)),
+            ("memory_warn_mb", json!(self.memory_warn_mb)),
+            ("max_tasks", json!(self.max_tasks)),
+            ("truncate_output_at", json!(self.truncate_output_at)),
+            ("truncate_mode", lower(&self.truncate_mode)),
+            ("max_output_lines", json!(self.max_output_lines)),
+            ("truncation_hint_after", json!(self.truncation_hint_after)),
+            ("output_lines_keep", lower(&self.output_lines_keep)),
+            ("empty_output_text", json!(self.empty_output_text)),
+            ("output_marker_start", json!(self.output_marker_start)),
+            ("output_marker_end", json!(self.output_marker_end)),
+            ("expand_tabs", json!(self.expand_tabs)),
+            ("tab_width", json!(self.tab_width)),
+            ("timestamp_format", lower(&self.timestamp_format)),
+            ("normalize_windows_paths", json!(self.normalize_windows_paths)),
+            ("protocol_debug", json!(self.protocol_debug)),
+            ("push_notifications", json!(self.push_notifications)),
+            ("umask", json!(self.umask.map(|m| format!("{:03o}", m)))),
+            ("tool_descriptions", json!(self.tool_descriptions)),
+            ("enabled_tools", json!(self.enabled_tools)),
+            ("deny_commands", json!(self.deny_commands)),
+            ("timeout_exempt_patterns", json!(self.timeout_exempt_patterns)),
+            ("timeout_exempt_max", json!(self.timeout_exempt_max)),
+            ("silent_commands", json!(self.silent_commands)),
+            ("allow_commands", json!(self.allow_commands)),
+            ("home_root_guard", json!(self.home_root_guard)),
+            ("pty_fallback", json!(self.pty_fallback)),
+            ("pty_drain_ms", json!(self.pty_drain_ms)),
+            ("render_terminal", json!(self.render_terminal)),
+            ("output_hash", json!(self.output_hash)),
+            ("timestamp_lines", json!(self.timestamp_lines)),
+            ("separate_stderr", json!(self.separate_stderr)),
+            ("ssh_timeouts", json!(self.ssh_timeouts)),
+            ("verbose_flags", json!(self.verbose_flags)),
+        ]
+    }
+
+    /// Whether a tool is exposed under the `enabled_tools` allowlist.
+    pub fn tool_enabled(&self, name: &str) -> bool {
+        self.enabled_tools
+            .as_ref()
+            .is_none_or(|tools| tools.iter().any(|t| t == name))
+    }
+
     fn apply_env_overrides(&mut self) {
         if let Ok(v) = std::env::var("NEVERHANG_TIMEOUT_DEFAULT") {
             if let Ok(n) = v.parse() {
                 self.neverhang_timeout_default = n;
+                self.set_source("neverhang_timeout_default", ConfigSource::Env);
             }
         }
         if let Ok(v) = std::env::var("NEVERHANG_TIMEOUT_MAX") {
             if let Ok(n) = v.parse() {
                 self.neverhang_timeout_max = n;
+                self.set_source("neverhang_timeout_max", ConfigSource::Env);
+            }
+        }
+        if let Ok(v) = std::env::var("ZSH_TOOL_TEST_CLOCK") {
+            if let Ok(n) = v.parse::<f64>() {
+                if n >= 0.0 {
+                    self.yield_time_scale = n;
+                    self.set_source("yield_time_scale", ConfigSource::Env);
+                }
+            }
+        }
+        if let Ok(v) = std::env::var("ZSH_TOOL_MAX_SPAWNS_PER_SEC") {
+            if let Ok(n) = v.parse::<f64>() {
+                if n >= 0.0 {
+                    self.max_spawns_per_sec = n;
+                    self.set_source("max_spawns_per_sec", ConfigSource::Env);
+                }
+            }
+        }
+        if let Ok(v) = std::env::var("ZSH_TOOL_SESSION_IDLE_TIMEOUT") {
+            if let Ok(n) = v.parse() {
+                self.session_idle_timeout_secs = n;
+                self.set_source("session_idle_timeout_secs", ConfigSource::Env);
+            }
+        }
+        if let Ok(v) = std::env::var("ZSH_TOOL_YIELD_ON_OUTPUT_BYTES") {
+            if let Ok(n) = v.parse() {
+                self.yield_on_output_bytes = n;
+                self.set_source("yield_on_output_bytes", ConfigSource::Env);
+            }
+        }
+        if let Ok(v) = std::env::var("ZSH_TOOL_MAX_TASKS") {
+            if let Ok(n) = v.parse() {
+                self.max_tasks = n;
+                self.set_source("max_tasks", ConfigSource::Env);
+            }
+        }
+        if let Ok(v) = std::env::var("ZSH_TOOL_MEMORY_WARN_MB") {
+            if let Ok(n) = v.parse() {
+                self.memory_warn_mb = n;
+                self.set_source("memory_warn_mb", ConfigSource::Env);
+            }
+        }
+        if let Ok(v) = std::env::var("ZSH_TOOL_TRUNCATION_HINT_AFTER") {
+            if let Ok(n) = v.parse() {
+                self.truncation_hint_after = n;
+                self.set_source("truncation_hint_after", ConfigSource::Env);
+            }
+        }
+        if let Ok(v) = std::env::var("ZSH_TOOL_TIMESTAMP_FORMAT") {
+            if let Some(f) = TimestampFormat::parse(&v) {
+                self.timestamp_format = f;
+                self.set_source("timestamp_format", ConfigSource::Env);
+            }
+        }
+        if let Ok(v) = std::env::var("ZSH_TOOL_ENABLED_TOOLS") {
+            self.enabled_tools = Some(parse_list(&v));
+            self.set_source("enabled_tools", ConfigSource::Env);
+        }
+        if let Ok(v) = std::env::var("ZSH_TOOL_DENY_COMMANDS") {
+            self.deny_commands = parse_list(&v);
+            self.set_source("deny_commands", ConfigSource::Env);
+        }
+        if let Ok(v) = std::env::var("ZSH_TOOL_TIMEOUT_EXEMPT_PATTERNS") {
+            self.timeout_exempt_patterns = parse_list(&v);
+            self.set_source("timeout_exempt_patterns", ConfigSource::Env);
+        }
+        if let Ok(v) = std::env::var("ZSH_TOOL_TIMEOUT_EXEMPT_MAX") {
+            if let Ok(n) = v.parse() {
+                self.timeout_exempt_max = n;
+                self.set_source("timeout_exempt_max", ConfigSource::Env);
+            }
+        }
+        if let Ok(v) = std::env::var("ZSH_TOOL_CIRCUIT_TRIP_EXIT_CODES") {
+            self.circuit_trip_exit_codes = parse_exit_codes(&v);
+            self.set_source("circuit_trip_exit_codes", ConfigSource::Env);
+        }
+        if let Ok(v) = std::env::var("ZSH_TOOL_CIRCUIT_GLOBAL_FAILURE_THRESHOLD") {
+            if let Ok(n) = v.parse() {
+                self.circuit_global_failure_threshold = n;
+                self.set_source("circuit_global_failure_threshold", ConfigSource::Env);
+            }
+        }
+        if let Ok(v) = std::env::var("ZSH_TOOL_HALF_OPEN_REQUIRED_SUCCESSES") {
+            if let Ok(n) = v.parse() {
+                self.half_open_required_successes = n;
+                self.set_source("half_open_required_successes", ConfigSource::Env);
+            }
+        }
+        if let Ok(v) = std::env::var("ZSH_TOOL_OUTPUT_MARKER_START") {
+            self.output_marker_start = v;
+            self.set_source("output_marker_start", ConfigSource::Env);
+        }
+        if let Ok(v) = std::env::var("ZSH_TOOL_OUTPUT_MARKER_END") {
+            self.output_marker_end = v;
+            self.set_source("output_marker_end", ConfigSource::Env);
+        }
+        if let Ok(v) = std::env::var("ZSH_TOOL_RETRY_EXIT_CODES") {
+            self.retry_exit_codes = parse_exit_codes(&v);
+            self.set_source("retry_exit_codes", ConfigSource::Env);
+        }
+        if let Ok(v) = std::env::var("ZSH_TOOL_RETRY_TIMEOUTS") {
+            self.retry_timeouts = parse_bool(&v);
+            self.set_source("retry_timeouts", ConfigSource::Env);
+        }
+        if let Ok(v) = std::env::var("ZSH_TOOL_SILENT_COMMANDS") {
+            self.silent_commands = parse_list(&v);
+            self.set_source("silent_commands", ConfigSource::Env);
+        }
+        if let Ok(v) = std::env::var("ZSH_TOOL_HOME_ROOT_GUARD") {
+            self.home_root_guard = parse_bool(&v);
+            self.set_source("home_root_guard", ConfigSource::Env);
+        }
+        if let Ok(v) = std::env::var("ZSH_TOOL_PTY_FALLBACK") {
+            self.pty_fallback = parse_bool(&v);
+            self.set_source("pty_fallback", ConfigSource::Env);
+        }
+        if let Ok(v) = std::env::var("ZSH_TOOL_PTY_DRAIN_MS") {
+            if let Ok(ms) = v.parse() {
+                self.pty_drain_ms = ms;
+                self.set_source("pty_drain_ms", ConfigSource::Env);
             }
         }
+        if let Ok(v) = std::env::var("ZSH_TOOL_RENDER_TERMINAL") {
+            self.render_terminal = parse_bool(&v);
+            self.set_source("render_terminal", ConfigSource::Env);
+        }
+        if let Ok(v) = std::env::var("ZSH_TOOL_TIMESTAMP_LINES") {
+            self.timestamp_lines = parse_bool(&v);
+            self.set_source("timestamp_lines", ConfigSource::Env);
+        }
+        if let Ok(v) = std::env::var("ZSH_TOOL_OUTPUT_HASH") {
+            self.output_hash = parse_bool(&v);
+            self.set_source("output_hash", ConfigSource::Env);
+        }
+        if let Ok(v) = std::env::var("ZSH_TOOL_ALLOW_COMMANDS") {
+            self.allow_commands = Some(parse_list(&v));
+            self.set_source("allow_commands", ConfigSource::Env);
+        }
+        if let Ok(v) = std::env::var("ZSH_TOOL_EXPAND_TABS") {
+            self.expand_tabs = parse_bool(&v);
+            self.set_source("expand_tabs", ConfigSource::Env);
+        }
+        if let Ok(v) = std::env::var("ZSH_TOOL_TAB_WIDTH") {
+            if let Ok(n) = v.parse() {
+                self.tab_width = n;
+                self.set_source("tab_width", ConfigSource::Env);
+            }
+        }
+        if let Ok(v) = std::env::var("ZSH_TOOL_NORMALIZE_WINDOWS_PATHS") {
+            self.normalize_windows_paths = parse_bool(&v);
+            self.set_source("normalize_windows_paths", ConfigSource::Env);
+        }
+        if let Ok(v) = std::env::var("ZSH_TOOL_PROTOCOL_DEBUG") {
+            self.protocol_debug = parse_bool(&v);
+            self.set_source("protocol_debug", ConfigSource::Env);
+        }
+        if let Ok(v) = std::env::var("ZSH_TOOL_PUSH_NOTIFICATIONS") {
+            self.push_notifications = parse_bool(&v);
+            self.set_source("push_notifications", ConfigSource::Env);
+        }
+        if let Ok(v) = std::env::var("ZSH_TOOL_TRUNCATE_MODE") {
+            if let Some(m) = TruncateMode::parse(&v) {
+                self.truncate_mode = m;
+                self.set_source("truncate_mode", ConfigSource::Env);
+            }
+        }
+        if let Ok(v) = std::env::var("ZSH_TOOL_BLOCKED_DETAIL") {
+            if let Some(d) = BlockDetail::parse(&v) {
+                self.blocked_detail = d;
+                self.set_source("blocked_detail", ConfigSource::Env);
+            }
+        }
+        if let Ok(v) = std::env::var("ZSH_TOOL_UMASK") {
+            self.umask = parse_umask(&v);
+            self.set_source("umask", ConfigSource::Env);
+        }
+        if let Ok(v) = std::env::var("ZSH_TOOL_META_DIR") {
+            self.meta_dir = expand_tilde(&v);
+            self.set_source("meta_dir", ConfigSource::Env);
+        }
+        if let Ok(v) = std::env::var("ZSH_TOOL_EXEC_PATH") {
+            self.exec_path = (!v.is_empty()).then(|| expand_tilde(&v));
+            self.set_source("exec_path", ConfigSource::Env);
+        }
+        if let Ok(v) = std::env::var("ZSH_TOOL_SHELL") {
+            if let Some(kind) = ShellKind::parse(&v) {
+                self.shell = kind;
+                self.set_source("shell", ConfigSource::Env);
+            }
+        }
+        // ZSH_TOOL_ZSH_PATH is the old name, still honoured when the new one is unset
+        if let Ok(v) = std::env::var("ZSH_TOOL_SHELL_PATH").or_else(|_| std::env::var("ZSH_TOOL_ZSH_PATH")) {
+            self.shell_path = (!v.is_empty()).then(|| expand_tilde(&v));
+            self.set_source("shell_path", ConfigSource::Env);
+        }
+        if let Ok(v) = std::env::var("ZSH_TOOL_SPOOL_DIR") {
+            self.spool_dir = (!v.is_empty()).then(|| expand_tilde(&v));
+            self.set_source("spool_dir", ConfigSource::Env);
+        }
+        if let Ok(v) = std::env::var("ZSH_TOOL_KEEP_LOGS") {
+            self.keep_logs = parse_bool(&v);
+            self.set_source("keep_logs", ConfigSource::Env);
+        }
         if let Ok(v) = std::env::var("ALAN_DB_PATH") {
             self.alan_db_path = expand_tilde(&v);
+            self.set_source("alan_db_path", ConfigSource::Env);
+        }
+        // ALAN_DEDUP_WINDOW is the old name, still honoured when the new one is unset
+        if let Ok(v) = std::env::var("ALAN_DEDUP_WINDOW_SECS").or_else(|_| std::env::var("ALAN_DEDUP_WINDOW")) {
+            if let Ok(n) = v.parse() {
+                self.alan_dedup_window_secs = n;
+                self.set_source("alan_dedup_window_secs", ConfigSource::Env);
+            }
         }
         if let Ok(v) = std::env::var("ALAN_MANOPT_ENABLED") {
-            self.alan_manopt_enabled =
-                !["0", "false", "no", "off"].contains(&v.to_lowercase().as_str());
+            self.alan_manopt_enabled = parse_bool(&v);
+            self.set_source("alan_manopt_enabled", ConfigSource::Env);
+        }
+        if let Ok(v) = std::env::var("ZSH_TOOL_NETWORK_CHECK") {
+            self.network_check_enabled = parse_bool(&v);
+            self.set_source("network_check_enabled", ConfigSource::Env);
+        }
+        if let Ok(v) = std::env::var("ZSH_TOOL_NETWORK_CHECK_HOST") {
+            self.network_check_host = v;
+            self.set_source("network_check_host", ConfigSource::Env);
         }
         if let Ok(v) = std::env::var("ALAN_MANOPT_TIMEOUT") {
             if let Ok(n) = v.parse() {
                 self.alan_manopt_timeout = n;
+                self.set_source("alan_manopt_timeout", ConfigSource::Env);
             }
         }
     }
 }
 
+/// Parse a boolean flag; anything other than 0/false/no/off is true.
+fn parse_bool(value: &str) -> bool {
+    !["0", "false", "no", "off"].contains(&unquote(value).to_lowercase().as_str())
+}
+
+/// Parse an octal umask such as `022`, `0o077` or `0002`. Out-of-range values are rejected.
+fn parse_umask(value: &str) -> Option<u32> {
+    let digits = value.trim().trim_start_matches("0o");
+    u32::from_str_radix(digits, 8).ok().filter(|m| *m <= 0o777)
+}
+
+/// Parse a list of exit codes, skipping entries that are not integers.
+fn parse_exit_codes(value: &str) -> Vec<i32> {
+    parse_list(value).iter().filter_map(|c| c.parse().ok()).collect()
+}
+
+/// Parse an inline YAML list (`[a, b]`) or a bare comma-separated one.
+fn parse_list(value: &str) -> Vec<String> {
+    let value = value.trim();
+    let inner = value
+        .strip_prefix('[')
+        .and_then(|v| v.strip_suffix(']'))
+        .unwrap_or(value);
+    inner
+        .split(',')
+        .map(|item| unquote(item.trim()).to_string())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+/// Strip one layer of matching YAML quotes from a scalar value.
+fn unquote(value: &str) -> &str {
+    for q in ['"', '\''] {
+        if value.len() >= 2 && value.starts_with(q) && value.ends_with(q) {
+            return &value[1..value.len() - 1];
+        }
+    }
+    value
+}
+
 /// Expand ~ to home directory. Simple replacement, no shellexpand dep needed.
-fn expand_tilde(path: &str) -> String {
+pub(crate) fn expand_tilde(path: &str) -> String {
     if path.starts_with("~/") || path == "~" {
         if let Ok(home) = std::env::var("HOME") {
             return path.replacen('~', &home, 1);
@@ -144,6 +1133,29 @@ mod tests {
         assert!(expanded.ends_with("/foo/bar"));
     }
 
+    #[test]
+    fn test_unquote() {
+        assert_eq!(unquote("\"\""), "");
+        assert_eq!(unquote("'(silent)'"), "(silent)");
+        assert_eq!(unquote("plain"), "plain");
+    }
+
+    #[test]
+    fn test_parse_list() {
+        assert_eq!(parse_list("[zsh_alan_stats, 'zsh_alan_query']"), vec!["zsh_alan_stats", "zsh_alan_query"]);
+        assert_eq!(parse_list("zsh,zsh_poll"), vec!["zsh", "zsh_poll"]);
+        assert!(parse_list("[]").is_empty());
+    }
+
+    #[test]
+    fn test_parse_umask() {
+        assert_eq!(parse_umask("022"), Some(0o022));
+        assert_eq!(parse_umask("0o077"), Some(0o077));
+        assert_eq!(parse_umask("0002"), Some(0o002));
+        assert_eq!(parse_umask("088"), None);
+        assert_eq!(parse_umask("1777"), None);
+    }
+
     #[test]
     fn test_expand_tilde_no_tilde() {
         assert_eq!(expand_tilde("/absolute/path"), "/absolute/path");
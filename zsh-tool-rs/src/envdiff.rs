@@ -0,0 +1,118 @@
+//! Environment snapshots and diffs for `zsh_env_snapshot` / `zsh_env_diff`.
+//!
+//! The environment is read from the shell (`env -0`), not from this process,
+//! so startup files and exports the shell applies are included.
+
+use std::collections::BTreeMap;
+use std::process::Command;
+
+use serde::Serialize;
+
+pub type EnvMap = BTreeMap<String, String>;
+
+/// A variable whose value differs between baseline and current.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct ChangedVar {
+    pub name: String,
+    pub baseline: String,
+    pub current: String,
+}
+
+#[derive(Debug, Serialize, PartialEq, Default)]
+pub struct EnvDiff {
+    pub added: EnvMap,
+    pub removed: EnvMap,
+    pub changed: Vec<ChangedVar>,
+}
+
+impl EnvDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// Run `env -0` in `shell` and parse the result.
+pub fn capture(shell: &std::path::Path) -> Result<EnvMap, String> {
+    let output = Command::new(shell)
+        .args(["-c", "env -0"])
+        .output()
+        .map_err(|e| format!("spawn: {}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "env exited {}: {}",
+            output.status.code().unwrap_or(-1),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(parse_env(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Parse `env -0` output: NUL-separated `NAME=value` entries. Values may
+/// contain newlines and `=`; entries without `=` are skipped.
+pub fn parse_env(raw: &str) -> EnvMap {
+    raw.split('\0')
+        .filter_map(|entry| {
+            let (name, value) = entry.split_once('=')?;
+            if name.is_empty() {
+                return None;
+            }
+            Some((name.to_string(), value.to_string()))
+        })
+        .collect()
+}
+
+/// What changed going from `baseline` to `current`.
+pub fn diff(baseline: &EnvMap, current: &EnvMap) -> EnvDiff {
+    let mut result = EnvDiff::default();
+    for (name, value) in current {
+        match baseline.get(name) {
+            None => {
+                result.added.insert(name.clone(), value.clone());
+            }
+            Some(old) if old != value => result.changed.push(ChangedVar {
+                name: name.clone(),
+                baseline: old.clone(),
+                current: value.clone(),
+            }),
+            Some(_) => {}
+        }
+    }
+    for (name, value) in baseline {
+        if !current.contains_key(name) {
+            result.removed.insert(name.clone(), value.clone());
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_env_handles_multiline_and_equals() {
+        let env = parse_env("A=1\0B=x=y\0C=line1\nline2\0garbage\0=nope\0");
+        assert_eq!(env.len(), 3);
+        assert_eq!(env["A"], "1");
+        assert_eq!(env["B"], "x=y");
+        assert_eq!(env["C"], "line1\nline2");
+    }
+
+    #[test]
+    fn test_diff_reports_added_removed_changed() {
+        let baseline = parse_env("KEEP=1\0GONE=old\0PATH=/bin\0");
+        let current = parse_env("KEEP=1\0NEW=fresh\0PATH=/opt/bin:/bin\0");
+        let d = diff(&baseline, &current);
+        assert_eq!(d.added.get("NEW").map(String::as_str), Some("fresh"));
+        assert_eq!(d.removed.get("GONE").map(String::as_str), Some("old"));
+        assert_eq!(
+            d.changed,
+            vec![ChangedVar {
+                name: "PATH".into(),
+                baseline: "/bin".into(),
+                current: "/opt/bin:/bin".into(),
+            }]
+        );
+        assert!(diff(&baseline, &baseline).is_empty());
+    }
+}
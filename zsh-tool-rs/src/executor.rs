@@ -5,24 +5,310 @@ use std::process::{Command, Stdio};
 use std::thread;
 use std::time::Instant;
 
+use crate::alan::SNIPPET_BYTES;
 use crate::meta::ExecResult;
+use crate::reap;
+use crate::stream::SocketTee;
+
+/// Per-execution knobs beyond the command and timeout.
+#[derive(Debug, Default, Clone)]
+pub struct ExecOptions {
+    /// Scheduling niceness for the shell and its children. Clamped by `clamp_nice`.
+    pub nice: Option<i32>,
+    /// Unix socket path to tee live output to (see [`SocketTee`]).
+    pub stream_socket: Option<String>,
+    /// Capture stderr on its own instead of merging it into stdout.
+    /// Ignored in PTY mode, where the terminal merges the streams.
+    pub separate_stderr: bool,
+    /// File-creation mask for the shell, e.g. 0o022. None inherits ours.
+    pub umask: Option<u32>,
+    /// Tag for finding escaped descendants (see [`reap`]). Generated when None.
+    pub reap_token: Option<String>,
+    /// Signal sent to the process group on timeout before falling back to
+    /// SIGKILL after [`TIMEOUT_SIGNAL_GRACE_SECS`]. None kills immediately.
+    pub timeout_signal: Option<i32>,
+    /// Working directory for the shell. None inherits ours.
+    pub cwd: Option<String>,
+    /// FIFO to feed the command's stdin from instead of ours. Opening waits
+    /// for a writer; the command sees EOF once the last writer closes it.
+    /// Ignored in PTY mode.
+    pub stdin_fifo: Option<String>,
+    /// Extra environment for the shell. An empty value unsets the variable.
+    pub env: Vec<(String, String)>,
+    /// Shell binary to run the command under. None uses [`default_shell`].
+    pub shell: Option<String>,
+    /// How long the PTY reader keeps draining after the child exits, in ms
+    /// of silence. None uses [`PTY_DRAIN_GRACE_MS`]. Ignored in pipe mode.
+    pub pty_drain_ms: Option<u64>,
+    /// Language of `shell`. None guesses it from the binary's name, or
+    /// means zsh when no binary is given either.
+    pub shell_kind: Option<ShellKind>,
+}
+
+/// How long a command gets to exit after its `timeout_signal` before SIGKILL.
+pub const TIMEOUT_SIGNAL_GRACE_SECS: u64 = 5;
+
+/// Default quiet period after a PTY command exits before its output is
+/// considered complete (see [`ExecOptions::pty_drain_ms`]).
+pub const PTY_DRAIN_GRACE_MS: u64 = 200;
+
+/// The whole PTY drain after the child exits is capped at this many drain
+/// graces, so a background process that keeps writing can't hold exec open.
+const PTY_DRAIN_DEADLINE_FACTOR: u64 = 5;
+
+/// Lowercase fragments of errors from programs that need a terminal.
+const TTY_ERRORS: &[&str] = &[
+    "not a tty",
+    "inappropriate ioctl for device",
+    "stdin is not a terminal",
+    "must be run from a terminal",
+    "no tty present",
+];
+
+/// Whether `output` carries an error typical of running without a terminal.
+pub fn is_tty_error(output: &str) -> bool {
+    let lower = output.to_lowercase();
+    TTY_ERRORS.iter().any(|e| lower.contains(e))
+}
+
+/// Parse a signal given as a name (`TERM`, `SIGINT`, case-insensitive) or number.
+pub fn parse_signal(value: &str) -> Option<i32> {
+    let value = value.trim();
+    if let Ok(n) = value.parse::<i32>() {
+        return (1..=64).contains(&n).then_some(n);
+    }
+    let upper = value.to_uppercase();
+    let name = upper.strip_prefix("SIG").unwrap_or(&upper);
+    let sig = match name {
+        "HUP" => libc::SIGHUP,
+        "INT" => libc::SIGINT,
+        "QUIT" => libc::SIGQUIT,
+        "ABRT" => libc::SIGABRT,
+        "KILL" => libc::SIGKILL,
+        "USR1" => libc::SIGUSR1,
+        "USR2" => libc::SIGUSR2,
+        "ALRM" => libc::SIGALRM,
+        "TERM" => libc::SIGTERM,
+        _ => return None,
+    };
+    Some(sig)
+}
+
+/// Send `sig` to process group `pgid` and give it the grace period to exit.
+/// Returns true once `exited` reports the child gone.
+fn signal_and_wait(pgid: i32, sig: i32, mut exited: impl FnMut() -> bool) -> bool {
+    unsafe { libc::kill(-pgid, sig); }
+    let deadline = Instant::now() + std::time::Duration::from_secs(TIMEOUT_SIGNAL_GRACE_SECS);
+    while Instant::now() < deadline {
+        if exited() {
+            return true;
+        }
+        thread::sleep(std::time::Duration::from_millis(50));
+    }
+    false
+}
+
+/// Cap on separately captured stderr; the rest is read and discarded.
+const STDERR_CAPTURE_LIMIT: usize = 256 * 1024;
+
+/// Append as much of `chunk` as fits in `limit` bytes of `captured`.
+fn capture_prefix(captured: &mut Vec<u8>, chunk: &[u8], limit: usize) {
+    let room = limit.saturating_sub(captured.len());
+    captured.extend_from_slice(&chunk[..chunk.len().min(room)]);
+}
+
+/// Clamp a requested nice level to what this process may set:
+/// -20..=19 as root, 0..=19 otherwise (unprivileged users can only lower priority).
+pub fn clamp_nice(nice: i32) -> i32 {
+    let floor = if unsafe { libc::geteuid() } == 0 { -20 } else { 0 };
+    nice.clamp(floor, 19)
+}
+
+/// Bind the output tee if one was requested. A bind failure is logged and
+/// the command runs without streaming.
+fn open_tee(opts: &ExecOptions) -> Option<SocketTee> {
+    let path = opts.stream_socket.as_deref()?;
+    SocketTee::bind(path)
+        .map_err(|e| eprintln!("[zsh-tool] {}", e))
+        .ok()
+}
+
+/// Apply the configured umask. Runs in the child between fork and exec.
+fn apply_umask(umask: Option<u32>) {
+    if let Some(mask) = umask {
+        unsafe {
+            libc::umask((mask & 0o777) as libc::mode_t);
+        }
+    }
+}
+
+/// Apply niceness to the calling process. Runs in the child between fork and exec.
+fn apply_nice(nice: Option<i32>) {
+    if let Some(n) = nice {
+        unsafe {
+            libc::setpriority(libc::PRIO_PROCESS, 0, clamp_nice(n));
+        }
+    }
+}
+
+/// Shell language commands run under. Decides the default binary and the
+/// syntax of the pipestatus sideband.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum ShellKind {
+    #[default]
+    Zsh,
+    Bash,
+    /// Any POSIX sh. Has no per-segment exit codes, only `$?`.
+    Sh,
+}
+
+impl ShellKind {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "zsh" => Some(Self::Zsh),
+            "bash" => Some(Self::Bash),
+            "sh" | "posix" => Some(Self::Sh),
+            _ => None,
+        }
+    }
+
+    /// Guess from a binary's basename; anything unrecognised is treated as
+    /// POSIX sh, whose `$?` works everywhere.
+    pub fn from_path(path: &str) -> Self {
+        match path.rsplit('/').next().unwrap_or(path) {
+            "zsh" => Self::Zsh,
+            "bash" => Self::Bash,
+            _ => Self::Sh,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Zsh => "zsh",
+            Self::Bash => "bash",
+            Self::Sh => "sh",
+        }
+    }
+
+    /// Expansion listing the last pipeline's exit codes.
+    fn pipestatus_expansion(self) -> &'static str {
+        match self {
+            Self::Zsh => "${pipestatus[*]}",
+            Self::Bash => "${PIPESTATUS[*]}",
+            Self::Sh => "$?",
+        }
+    }
+}
+
+/// Locate `kind`'s binary on `PATH` the way `which` does, falling back to
+/// `/bin/<name>`.
+pub fn default_shell(kind: ShellKind) -> String {
+    use std::os::unix::fs::PermissionsExt;
+    std::env::var_os("PATH")
+        .and_then(|paths| {
+            std::env::split_paths(&paths).map(|dir| dir.join(kind.name())).find(|p| {
+                std::fs::metadata(p).is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+            })
+        })
+        .map(|p| p.to_string_lossy().into_owned())
+        .unwrap_or_else(|| format!("/bin/{}", kind.name()))
+}
+
+/// Binary and language for `opts`: a configured binary without a kind is
+/// identified by its basename.
+fn shell_for(opts: &ExecOptions) -> (String, ShellKind) {
+    match (&opts.shell, opts.shell_kind) {
+        (Some(path), Some(kind)) => (path.clone(), kind),
+        (Some(path), None) => (path.clone(), ShellKind::from_path(path)),
+        (None, kind) => {
+            let kind = kind.unwrap_or_default();
+            (default_shell(kind), kind)
+        }
+    }
+}
+
+/// Prefix of the EXIT trap's line on fd 3, so anything else the command
+/// writes there is ignored.
+pub const PIPESTATUS_MARKER: &str = "___ZSH_PIPESTATUS_MARKER_f9a8b7c6___";
 
 /// Wrap command to send pipestatus to fd 3.
-fn wrap_command(command: &str) -> String {
+fn wrap_command(shell: ShellKind, command: &str) -> String {
     // EXIT trap instead of appending after command.
     // Appending breaks heredocs: "EOF; echo..." is not a valid terminator.
     // The trap fires after shell exit regardless of command structure.
-    format!("trap 'echo \"${{pipestatus[*]}}\" >&3' EXIT\n{}", command)
+    format!(
+        "trap 'echo \"{} {}\" >&3' EXIT\n{}",
+        PIPESTATUS_MARKER,
+        shell.pipestatus_expansion(),
+        command
+    )
+}
+
+/// Parse the statuses after the last marker line on fd 3 ("MARKER 1 0 0")
+/// into Vec<i32>. No marker line means the trap never ran: empty statuses.
+/// Returns None if any token isn't an integer, or if there are more statuses
+/// than the command has pipe segments.
+fn parse_pipestatus(raw: &str, command: &str) -> Option<Vec<i32>> {
+    let Some(line) = raw.lines().rev().find_map(|l| l.strip_prefix(PIPESTATUS_MARKER)) else {
+        return Some(Vec::new());
+    };
+    let codes: Vec<i32> = line
+        .split_whitespace()
+        .map(|s| s.parse::<i32>().ok())
+        .collect::<Option<_>>()?;
+    let max_segments = crate::alan::pipeline::parse_pipeline(command).len().max(1);
+    if codes.len() > max_segments {
+        return None;
+    }
+    Some(codes)
+}
+
+/// Like [`resolve_pipestatus`], but a killed command reports no statuses:
+/// the EXIT trap never ran, so any per-segment codes would be invented.
+fn timeout_aware_pipestatus(meta_raw: &str, command: &str, exit_code: i32, timed_out: bool) -> Vec<i32> {
+    if timed_out {
+        Vec::new()
+    } else {
+        resolve_pipestatus(meta_raw, command, exit_code)
+    }
 }
 
-/// Parse pipestatus string "1 0 0" into Vec<i32>.
-fn parse_pipestatus(raw: &str) -> Vec<i32> {
-    raw.split_whitespace()
-        .filter_map(|s| s.parse::<i32>().ok())
-        .collect()
+/// Turn the raw fd 3 sideband into a final pipestatus, reconciled with the
+/// process exit code.
+fn resolve_pipestatus(meta_raw: &str, command: &str, exit_code: i32) -> Vec<i32> {
+    let mut pipestatus = match parse_pipestatus(meta_raw, command) {
+        Some(p) => p,
+        None => {
+            eprintln!(
+                "zsh-tool exec: implausible pipestatus on fd 3 ({:?}), using exit code {}",
+                meta_raw.trim(),
+                exit_code
+            );
+            Vec::new()
+        }
+    };
+    // If pipestatus is empty (e.g. the shell exited before the trap could run),
+    // synthesize it from the process exit code.
+    if pipestatus.is_empty() {
+        pipestatus.push(exit_code);
+    }
+    // The EXIT trap fires when the shell exits, but for "exit N" the trap
+    // captures $pipestatus of the last pipeline — which is 0 for the exit
+    // builtin itself. If the trap says 0 but the process actually exited
+    // non-zero, trust the process exit code (it's the authoritative value).
+    if let Some(last) = pipestatus.last_mut() {
+        if *last == 0 && exit_code != 0 {
+            *last = exit_code;
+        }
+    }
+    pipestatus
 }
 
-pub fn execute_pipe(command: &str, timeout_secs: u64) -> Result<ExecResult, String> {
+pub fn execute_pipe(
+    command: &str,
+    timeout_secs: u64,
+    opts: &ExecOptions,
+) -> Result<ExecResult, String> {
     let start = Instant::now();
 
     // Create metadata pipe (fd 3 sideband)
@@ -36,20 +322,44 @@ pub fn execute_pipe(command: &str, timeout_secs: u64) -> Result<ExecResult, Stri
         (fds[0], fds[1])
     };
 
-    let wrapped = wrap_command(command);
+    let (shell_path, kind) = shell_for(opts);
+    let wrapped = wrap_command(kind, command);
+    let nice = opts.nice;
+    let separate_stderr = opts.separate_stderr;
+    let umask = opts.umask;
+    let timeout_signal = opts.timeout_signal;
+    let token = opts.reap_token.clone().unwrap_or_else(reap::new_token);
+
+    let mut shell = Command::new(&shell_path);
+    if let Some(ref dir) = opts.cwd {
+        shell.current_dir(dir);
+    }
+    for (key, value) in &opts.env {
+        if value.is_empty() {
+            shell.env_remove(key);
+        } else {
+            shell.env(key, value);
+        }
+    }
 
     // Spawn zsh with fd 3 mapped to metadata pipe
     let mut child = unsafe {
-        Command::new("/bin/zsh")
+        shell
             .args(["-c", &wrapped])
+            .env(reap::TOKEN_VAR, &token)
             .stdout(Stdio::piped())
             .stdin(Stdio::piped())
-            .stderr(Stdio::null()) // We merge via dup2 in pre_exec
+            // Merged via dup2 in pre_exec unless captured separately
+            .stderr(if separate_stderr { Stdio::piped() } else { Stdio::null() })
             .pre_exec(move || {
                 // New process group so we can kill all children on timeout
                 libc::setpgid(0, 0);
+                apply_nice(nice);
+                apply_umask(umask);
                 // Merge stderr into stdout
-                libc::dup2(1, 2);
+                if !separate_stderr {
+                    libc::dup2(1, 2);
+                }
                 // Close read end first — it's not needed in child, and if
                 // it landed on fd 3 (likely, since 0-2 are taken), closing
                 // it after dup2 would destroy the fd we just set up.
@@ -73,9 +383,11 @@ pub fn execute_pipe(command: &str, timeout_secs: u64) -> Result<ExecResult, Stri
         .ok_or("no stdout")?;
 
     // Stream child stdout -> our stdout (in a thread to avoid blocking)
+    let mut tee = open_tee(opts);
     let stdout_handle = thread::spawn(move || {
         let mut reader = child_stdout;
         let mut stdout = io::stdout().lock();
+        let mut snippet = Vec::new();
         let mut buf = [0u8; 4096];
         loop {
             match reader.read(&mut buf) {
@@ -83,16 +395,44 @@ pub fn execute_pipe(command: &str, timeout_secs: u64) -> Result<ExecResult, Stri
                 Ok(n) => {
                     let _ = stdout.write_all(&buf[..n]);
                     let _ = stdout.flush();
+                    if let Some(ref mut t) = tee {
+                        t.write(&buf[..n]);
+                    }
+                    capture_prefix(&mut snippet, &buf[..n], SNIPPET_BYTES);
                 }
                 Err(_) => break,
             }
         }
+        String::from_utf8_lossy(&snippet).into_owned()
+    });
+
+    // Collect stderr on its own thread so neither pipe can fill and stall the child
+    let stderr_handle = child.stderr.take().map(|mut child_err| {
+        thread::spawn(move || {
+            let mut captured = Vec::new();
+            let mut buf = [0u8; 4096];
+            loop {
+                match child_err.read(&mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => capture_prefix(&mut captured, &buf[..n], STDERR_CAPTURE_LIMIT),
+                }
+            }
+            String::from_utf8_lossy(&captured).into_owned()
+        })
     });
 
-    // Forward our stdin -> child stdin (for interactive input)
+    // Forward our stdin (or the FIFO) -> child stdin (for interactive input)
     let child_stdin = child.stdin.take();
+    let stdin_fifo = opts.stdin_fifo.clone();
     let _stdin_handle = child_stdin.map(|mut child_in| {
         thread::spawn(move || {
+            if let Some(path) = stdin_fifo {
+                // Blocks until an external process opens the FIFO for writing
+                if let Ok(mut fifo) = std::fs::File::open(&path) {
+                    let _ = io::copy(&mut fifo, &mut child_in);
+                }
+                return;
+            }
             let stdin = io::stdin();
             let mut buf = [0u8; 4096];
             loop {
@@ -122,10 +462,16 @@ pub fn execute_pipe(command: &str, timeout_secs: u64) -> Result<ExecResult, Stri
             }
             Ok(None) => {
                 if start.elapsed().as_secs() >= timeout_secs {
-                    // Kill entire process group (child + its subprocesses)
+                    // Kill entire process group (child + its subprocesses),
+                    // then anything that escaped it via setsid. A custom
+                    // timeout signal gets a grace period first.
                     let pid = child.id() as i32;
+                    if let Some(sig) = timeout_signal {
+                        signal_and_wait(pid, sig, || matches!(child.try_wait(), Ok(Some(_))));
+                    }
                     unsafe { libc::kill(-pid, libc::SIGKILL); }
                     let _ = child.wait();
+                    reap::kill_tagged(&token);
                     timed_out = true;
                     exit_code = -1;
                     break;
@@ -139,7 +485,8 @@ pub fn execute_pipe(command: &str, timeout_secs: u64) -> Result<ExecResult, Stri
     }
 
     // Wait for stdout thread to finish draining
-    let _ = stdout_handle.join();
+    let output_snippet = stdout_handle.join().unwrap_or_default();
+    let stderr = stderr_handle.map(|h| h.join().unwrap_or_default());
 
     // Read metadata from fd 3 pipe
     let mut meta_raw = String::new();
@@ -149,22 +496,8 @@ pub fn execute_pipe(command: &str, timeout_secs: u64) -> Result<ExecResult, Stri
         // File dropped here, closes the fd
     }
 
-    let mut pipestatus = parse_pipestatus(&meta_raw);
-    // If pipestatus is empty (e.g. the shell exited before the trap could run),
-    // synthesize it from the process exit code.
-    if pipestatus.is_empty() {
-        pipestatus.push(exit_code);
-    }
-    // The EXIT trap fires when the shell exits, but for "exit N" the trap
-    // captures $pipestatus of the last pipeline — which is 0 for the exit
-    // builtin itself. If the trap says 0 but the process actually exited
-    // non-zero, trust the process exit code (it's the authoritative value).
-    if let Some(last) = pipestatus.last_mut() {
-        if *last == 0 && exit_code != 0 {
-            *last = exit_code;
-        }
-    }
-    let final_exit = *pipestatus.last().unwrap();
+    let pipestatus = timeout_aware_pipestatus(&meta_raw, command, exit_code, timed_out);
+    let final_exit = pipestatus.last().copied().unwrap_or(exit_code);
 
     let elapsed_ms = start.elapsed().as_millis() as u64;
 
@@ -173,10 +506,16 @@ pub fn execute_pipe(command: &str, timeout_secs: u64) -> Result<ExecResult, Stri
         exit_code: final_exit,
         elapsed_ms,
         timed_out,
+        stderr,
+        output_snippet,
     })
 }
 
-pub fn execute_pty(command: &str, timeout_secs: u64) -> Result<ExecResult, String> {
+pub fn execute_pty(
+    command: &str,
+    timeout_secs: u64,
+    opts: &ExecOptions,
+) -> Result<ExecResult, String> {
     use nix::pty::{openpty, OpenptyResult};
     use nix::sys::signal::{kill, Signal};
     use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
@@ -201,7 +540,25 @@ pub fn execute_pty(command: &str, timeout_secs: u64) -> Result<ExecResult, Strin
     let master_raw = master.into_raw_fd();
     let slave_raw = slave.into_raw_fd();
 
-    let wrapped = wrap_command(command);
+    let (shell_path, kind) = shell_for(opts);
+    let wrapped = wrap_command(kind, command);
+    let zsh = CString::new(shell_path.as_str()).map_err(|e| format!("shell path: {}", e))?;
+    let token = opts.reap_token.clone().unwrap_or_else(reap::new_token);
+    // Built before fork; the child only calls setenv
+    let token_var = CString::new(reap::TOKEN_VAR).unwrap();
+    let token_val = CString::new(token.as_str()).map_err(|e| format!("reap token: {}", e))?;
+    let cwd = opts
+        .cwd
+        .as_deref()
+        .map(CString::new)
+        .transpose()
+        .map_err(|e| format!("cwd: {}", e))?;
+    let env = opts
+        .env
+        .iter()
+        .map(|(k, v)| Ok((CString::new(k.as_str())?, CString::new(v.as_str())?)))
+        .collect::<Result<Vec<_>, std::ffi::NulError>>()
+        .map_err(|e| format!("env: {}", e))?;
 
     // Fork manually (can't use Command for PTY — need direct control)
     let fork_result = unsafe { fork() }
@@ -211,6 +568,26 @@ pub fn execute_pty(command: &str, timeout_secs: u64) -> Result<ExecResult, Strin
         ForkResult::Child => {
             // New session (becomes session leader, detaches controlling terminal)
             let _ = nix::unistd::setsid();
+            apply_nice(opts.nice);
+            apply_umask(opts.umask);
+            for (key, value) in &env {
+                unsafe {
+                    if value.as_bytes().is_empty() {
+                        libc::unsetenv(key.as_ptr());
+                    } else {
+                        libc::setenv(key.as_ptr(), value.as_ptr(), 1);
+                    }
+                }
+            }
+            unsafe { libc::setenv(token_var.as_ptr(), token_val.as_ptr(), 1); }
+            if let Some(ref dir) = cwd {
+                if unsafe { libc::chdir(dir.as_ptr()) } != 0 {
+                    unsafe { libc::_exit(127); }
+                }
+            }
+            // Rust ignores SIGPIPE and a raw fork+exec inherits that; restore
+            // the default so `yes | head` style pipelines terminate normally
+            unsafe { libc::signal(libc::SIGPIPE, libc::SIG_DFL); }
 
             // Set slave PTY as controlling terminal via TIOCSCTTY
             unsafe { libc::ioctl(slave_raw, libc::TIOCSCTTY, 0); }
@@ -239,7 +616,6 @@ pub fn execute_pty(command: &str, timeout_secs: u64) -> Result<ExecResult, Strin
             }
 
             // Exec zsh
-            let zsh = CString::new("/bin/zsh").unwrap();
             let c_flag = CString::new("-c").unwrap();
             let c_cmd = CString::new(wrapped.as_str()).unwrap();
             let _ = execvp(&zsh, &[&zsh, &c_flag, &c_cmd]);
@@ -253,19 +629,57 @@ pub fn execute_pty(command: &str, timeout_secs: u64) -> Result<ExecResult, Strin
                 libc::close(meta_write_raw);
             }
 
-            // Read from PTY master → our stdout (in a thread)
+            // Read from PTY master → our stdout (in a thread). The master
+            // reports EOF only once every holder of the slave is gone, which
+            // a background process can postpone indefinitely, so after the
+            // child exits the reader also stops once the PTY has been quiet
+            // for the drain grace, or at the latest once the drain deadline
+            // passes. Output still in flight is read first.
             let master_read_fd = master_raw;
+            let mut tee = open_tee(opts);
+            let child_exited = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+            let reader_child_exited = std::sync::Arc::clone(&child_exited);
+            let drain_ms = opts.pty_drain_ms.unwrap_or(PTY_DRAIN_GRACE_MS).min(i32::MAX as u64);
+            let drain_limit = std::time::Duration::from_millis(drain_ms.saturating_mul(PTY_DRAIN_DEADLINE_FACTOR));
             let stdout_handle = thread::spawn(move || {
                 let mut stdout = io::stdout().lock();
+                let mut snippet = Vec::new();
                 let mut buf = [0u8; 4096];
+                let mut drain_deadline: Option<Instant> = None;
                 loop {
+                    let exited = reader_child_exited.load(std::sync::atomic::Ordering::Acquire);
+                    let wait_ms = if exited {
+                        let deadline = *drain_deadline.get_or_insert_with(|| Instant::now() + drain_limit);
+                        let left = deadline.saturating_duration_since(Instant::now());
+                        if left.is_zero() { break; }
+                        (left.as_millis() as u64).clamp(1, drain_ms) as i32
+                    } else {
+                        50
+                    };
+                    let mut pfd = libc::pollfd { fd: master_read_fd, events: libc::POLLIN, revents: 0 };
+                    let ready = unsafe { libc::poll(&mut pfd, 1, wait_ms) };
+                    if ready < 0 {
+                        if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                            continue;
+                        }
+                        break;
+                    }
+                    if ready == 0 {
+                        if exited { break; }
+                        continue;
+                    }
                     let n = unsafe {
                         libc::read(master_read_fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len())
                     };
                     if n <= 0 { break; }
                     let _ = stdout.write_all(&buf[..n as usize]);
                     let _ = stdout.flush();
+                    if let Some(ref mut t) = tee {
+                        t.write(&buf[..n as usize]);
+                    }
+                    capture_prefix(&mut snippet, &buf[..n as usize], SNIPPET_BYTES);
                 }
+                String::from_utf8_lossy(&snippet).into_owned()
             });
 
             // Wait for child with timeout
@@ -285,9 +699,20 @@ pub fn execute_pty(command: &str, timeout_secs: u64) -> Result<ExecResult, Strin
                     }
                     Ok(WaitStatus::StillAlive) => {
                         if start.elapsed().as_secs() >= timeout_secs {
-                            // Kill entire session (child is session leader via setsid)
+                            // Kill the session leader, then every tagged
+                            // process, including ones in sessions of their own.
+                            // A custom timeout signal goes to the whole group first.
+                            if let Some(sig) = opts.timeout_signal {
+                                signal_and_wait(child.as_raw(), sig, || {
+                                    !matches!(
+                                        waitpid(child, Some(WaitPidFlag::WNOHANG)),
+                                        Ok(WaitStatus::StillAlive)
+                                    )
+                                });
+                            }
                             let _ = kill(child, Signal::SIGKILL);
                             let _ = waitpid(child, None);
+                            reap::kill_tagged(&token);
                             timed_out = true;
                             raw_exit_code = -1;
                             break;
@@ -302,9 +727,10 @@ pub fn execute_pty(command: &str, timeout_secs: u64) -> Result<ExecResult, Strin
                 }
             }
 
-            // Close master PTY to signal EOF to stdout reader thread
+            // Let the reader drain what the child wrote before exiting
+            child_exited.store(true, std::sync::atomic::Ordering::Release);
+            let output_snippet = stdout_handle.join().unwrap_or_default();
             unsafe { libc::close(master_raw); }
-            let _ = stdout_handle.join();
 
             // Read metadata from fd 3 pipe
             let mut meta_raw = String::new();
@@ -313,26 +739,156 @@ pub fn execute_pty(command: &str, timeout_secs: u64) -> Result<ExecResult, Strin
                 let _ = meta_file.read_to_string(&mut meta_raw);
             }
 
-            let mut pipestatus = parse_pipestatus(&meta_raw);
-            if pipestatus.is_empty() {
-                pipestatus.push(raw_exit_code);
-            }
-            // Same correction as execute_pipe: the EXIT trap sees pipestatus=[0]
-            // for "exit N" since the builtin exits with status 0. Trust the
-            // process exit code when there's a mismatch.
-            if let Some(last) = pipestatus.last_mut() {
-                if *last == 0 && raw_exit_code != 0 {
-                    *last = raw_exit_code;
-                }
-            }
-            let final_exit = *pipestatus.last().unwrap();
+            let pipestatus = timeout_aware_pipestatus(&meta_raw, command, raw_exit_code, timed_out);
+            let final_exit = pipestatus.last().copied().unwrap_or(raw_exit_code);
 
             Ok(ExecResult {
                 pipestatus,
                 exit_code: final_exit,
                 elapsed_ms: start.elapsed().as_millis() as u64,
                 timed_out,
+                stderr: None,
+                output_snippet,
             })
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run `command` wrapped for `shell` and return what the trap wrote to fd 3.
+    fn trapped_pipestatus(shell: &str, command: &str) -> String {
+        let script = format!("exec 3>&1\n{}", wrap_command(ShellKind::from_path(shell), command));
+        let output = Command::new(shell).args(["-c", &script]).output().unwrap();
+        String::from_utf8_lossy(&output.stdout).trim().to_string()
+    }
+
+    #[test]
+    fn test_wrap_command_selects_pipestatus_per_shell() {
+        assert!(wrap_command(ShellKind::Zsh, "ls").contains("${pipestatus[*]}"));
+        assert!(wrap_command(ShellKind::Bash, "ls").contains("${PIPESTATUS[*]}"));
+        assert!(wrap_command(ShellKind::Sh, "ls").contains(" $?\""));
+        assert!(wrap_command(ShellKind::Zsh, "ls").contains(&format!("\"{} ", PIPESTATUS_MARKER)));
+    }
+
+    #[test]
+    fn test_shell_kind_parse_and_from_path() {
+        assert_eq!(ShellKind::parse("BASH"), Some(ShellKind::Bash));
+        assert_eq!(ShellKind::parse("posix"), Some(ShellKind::Sh));
+        assert_eq!(ShellKind::parse("fish"), None);
+        assert_eq!(ShellKind::from_path("/usr/local/bin/bash"), ShellKind::Bash);
+        assert_eq!(ShellKind::from_path("/bin/zsh"), ShellKind::Zsh);
+        assert_eq!(ShellKind::from_path("dash"), ShellKind::Sh);
+        assert!(default_shell(ShellKind::Sh).ends_with("/sh"));
+    }
+
+    #[test]
+    fn test_false_true_pipestatus_per_shell() {
+        // POSIX sh only knows the last segment's status
+        for (shell, expected) in [("/bin/zsh", "1 0"), ("/bin/bash", "1 0"), ("/bin/sh", "0")] {
+            if !std::path::Path::new(shell).exists() {
+                continue;
+            }
+            assert_eq!(trapped_pipestatus(shell, "false | true"), marked(expected).trim(), "shell {}", shell);
+        }
+        assert_eq!(trapped_pipestatus("/bin/sh", "true | false"), marked("1").trim());
+    }
+
+    #[test]
+    fn test_execute_pipe_runs_under_configured_shell() {
+        let opts = ExecOptions { shell: Some("/bin/bash".into()), ..Default::default() };
+        let r = execute_pipe("echo $BASH_VERSION | grep -c . ; false | true", 5, &opts).unwrap();
+        assert_eq!(r.exit_code, 0);
+        assert!(r.output_snippet.starts_with('1'), "{:?}", r.output_snippet);
+        assert_eq!(r.pipestatus, vec![1, 0]);
+    }
+
+    #[test]
+    fn test_bash_backend_captures_pipestatus() {
+        let bash = ExecOptions { shell_kind: Some(ShellKind::Bash), ..Default::default() };
+        let r = execute_pipe("(exit 3) | false | true", 5, &bash).unwrap();
+        assert_eq!((r.exit_code, r.pipestatus), (0, vec![3, 1, 0]));
+        let r = execute_pipe("true | (exit 4)", 5, &bash).unwrap();
+        assert_eq!((r.exit_code, r.pipestatus), (4, vec![0, 4]));
+
+        // POSIX sh falls back to the exit code alone
+        let sh = ExecOptions { shell_kind: Some(ShellKind::Sh), ..Default::default() };
+        let r = execute_pipe("(exit 3) | false | true", 5, &sh).unwrap();
+        assert_eq!((r.exit_code, r.pipestatus), (0, vec![0]));
+    }
+
+    #[test]
+    fn test_parse_signal() {
+        assert_eq!(parse_signal("TERM"), Some(libc::SIGTERM));
+        assert_eq!(parse_signal("sigint"), Some(libc::SIGINT));
+        assert_eq!(parse_signal("3"), Some(libc::SIGQUIT));
+        assert_eq!(parse_signal("SIGBOGUS"), None);
+        assert_eq!(parse_signal("0"), None);
+    }
+
+    /// fd 3 contents as the EXIT trap writes them.
+    fn marked(statuses: &str) -> String {
+        format!("{} {}\n", PIPESTATUS_MARKER, statuses)
+    }
+
+    #[test]
+    fn test_resolve_pipestatus_normal() {
+        assert_eq!(resolve_pipestatus(&marked("1 0"), "false | true", 0), vec![1, 0]);
+        assert_eq!(resolve_pipestatus(&marked("0"), "exit 3", 3), vec![3]);
+        assert_eq!(resolve_pipestatus("", "true", 0), vec![0]);
+    }
+
+    #[test]
+    fn test_timed_out_pipeline_has_no_statuses() {
+        assert!(timeout_aware_pipestatus("", "sleep 60 | cat", -1, true).is_empty());
+        assert_eq!(timeout_aware_pipestatus(&marked("1 0"), "false | true", 0, false), vec![1, 0]);
+    }
+
+    #[test]
+    fn test_resolve_pipestatus_ignores_command_fd3_writes() {
+        let raw = format!("garbage\n{}", marked("0"));
+        assert_eq!(resolve_pipestatus(&raw, "echo garbage >&3", 0), vec![0]);
+        let raw = format!("1 2 3 4\n{}", marked("1"));
+        assert_eq!(resolve_pipestatus(&raw, "echo 1 2 3 4 >&3; false", 1), vec![1]);
+        // Numbers alone, with no trap line, are not statuses
+        assert_eq!(resolve_pipestatus("7\n", "echo 7 >&3; exit 2", 2), vec![2]);
+        // A malformed trap line still falls back to the exit code
+        assert_eq!(resolve_pipestatus(&marked("x"), "true", 0), vec![0]);
+    }
+
+    #[test]
+    fn test_command_writing_numbers_to_fd3_keeps_pipestatus() {
+        // bash stands in for zsh; the trap line is the same apart from the expansion
+        for command in ["echo hi >&3; true", "echo 7 >&3; true"] {
+            let raw = trapped_pipestatus("/bin/bash", command);
+            assert_eq!(resolve_pipestatus(&raw, command, 0), vec![0], "fd 3 was {:?}", raw);
+        }
+    }
+
+    #[test]
+    fn test_capture_prefix_stops_at_limit() {
+        let mut captured = Vec::new();
+        capture_prefix(&mut captured, b"hello ", 8);
+        capture_prefix(&mut captured, b"world", 8);
+        capture_prefix(&mut captured, b"!", 8);
+        assert_eq!(captured, b"hello wo");
+    }
+
+    #[test]
+    fn test_is_tty_error() {
+        assert!(is_tty_error("the input device is not a TTY"));
+        assert!(is_tty_error("stty: 'standard input': Inappropriate ioctl for device"));
+        assert!(is_tty_error("sudo: no tty present and no askpass program specified"));
+        assert!(!is_tty_error("error: could not compile"));
+    }
+
+    #[test]
+    fn test_clamp_nice() {
+        assert_eq!(clamp_nice(25), 19);
+        assert_eq!(clamp_nice(10), 10);
+        let floor = if unsafe { libc::geteuid() } == 0 { -20 } else { 0 };
+        assert_eq!(clamp_nice(-50), floor);
+    }
+}
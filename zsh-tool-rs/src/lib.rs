@@ -1,6 +1,13 @@
 pub mod alan;
 pub mod circuit;
 pub mod config;
+pub mod envdiff;
 pub mod executor;
 pub mod meta;
+pub mod policy;
+pub mod ratelimit;
+pub mod reap;
 pub mod serve;
+pub mod stream;
+pub mod verbose;
+pub mod winpath;
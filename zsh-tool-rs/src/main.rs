@@ -2,6 +2,7 @@ use std::env;
 use std::process;
 
 use zsh_tool_exec::alan;
+use zsh_tool_exec::config::Config;
 use zsh_tool_exec::executor;
 use zsh_tool_exec::meta;
 use zsh_tool_exec::serve;
@@ -9,7 +10,12 @@ use zsh_tool_exec::serve;
 fn print_usage() {
     eprintln!("Usage:");
     eprintln!("  zsh-tool serve                          — MCP server over stdio");
-    eprintln!("  zsh-tool exec --meta <path> [--timeout <secs>] [--pty] [--db <path> --session-id <id>] -- <command>");
+    eprintln!("  zsh-tool serve --tcp <addr>             — MCP server on a TCP listener, one thread per connection");
+    eprintln!("  zsh-tool health                         — liveness check (exit 0 healthy, 1 unhealthy)");
+    eprintln!("  zsh-tool alan-backup <path>             — snapshot the ALAN database to <path>");
+    eprintln!("  zsh-tool alan-restore <path>            — replace the ALAN database with snapshot <path>");
+    eprintln!("  zsh-tool alan-check                     — integrity check and row counts of the ALAN database (exit 1 if corrupt)");
+    eprintln!("  zsh-tool exec --meta <path> [--timeout <secs>] [--pty [--pty-drain-ms <ms>]] [--shell <path>] [--shell-kind zsh|bash|sh] [--nice <n>] [--stream-socket <path>] [--separate-stderr] [--umask <octal>] [--reap-token <token>] [--timeout-signal <sig>] [--cwd <dir>] [--stdin-fifo <path>] [--env KEY=VALUE]... [--delay-ms <ms>] [--db <path> --session-id <id> [--dedup-window <secs>] [--request-id <id>]] -- <command>");
     process::exit(2);
 }
 
@@ -20,6 +26,11 @@ struct ExecArgs {
     command: String,
     db_path: Option<String>,
     session_id: Option<String>,
+    dedup_window_secs: u64,
+    request_id: Option<String>,
+    /// Pause before running the command (a retry's backoff), in ms.
+    delay_ms: u64,
+    opts: executor::ExecOptions,
 }
 
 fn parse_exec_args(args: &[String]) -> ExecArgs {
@@ -29,6 +40,10 @@ fn parse_exec_args(args: &[String]) -> ExecArgs {
     let mut command = String::new();
     let mut db_path: Option<String> = None;
     let mut session_id: Option<String> = None;
+    let mut dedup_window_secs: u64 = 0;
+    let mut request_id: Option<String> = None;
+    let mut delay_ms: u64 = 0;
+    let mut opts = executor::ExecOptions::default();
     let mut i = 0;
     let mut after_dashdash = false;
 
@@ -63,6 +78,65 @@ fn parse_exec_args(args: &[String]) -> ExecArgs {
                     unreachable!()
                 }));
             }
+            "--dedup-window" => {
+                i += 1;
+                dedup_window_secs = args.get(i).and_then(|s| s.parse().ok()).unwrap_or(0);
+            }
+            "--request-id" => {
+                i += 1;
+                request_id = args.get(i).cloned();
+            }
+            "--nice" => {
+                i += 1;
+                opts.nice = args.get(i).and_then(|s| s.parse().ok());
+            }
+            "--stream-socket" => {
+                i += 1;
+                opts.stream_socket = args.get(i).cloned();
+            }
+            "--separate-stderr" => opts.separate_stderr = true,
+            "--umask" => {
+                i += 1;
+                opts.umask = args.get(i).and_then(|s| u32::from_str_radix(s, 8).ok());
+            }
+            "--reap-token" => {
+                i += 1;
+                opts.reap_token = args.get(i).cloned();
+            }
+            "--timeout-signal" => {
+                i += 1;
+                opts.timeout_signal = args.get(i).and_then(|s| executor::parse_signal(s));
+            }
+            "--cwd" => {
+                i += 1;
+                opts.cwd = args.get(i).cloned();
+            }
+            "--stdin-fifo" => {
+                i += 1;
+                opts.stdin_fifo = args.get(i).cloned();
+            }
+            "--env" => {
+                i += 1;
+                if let Some((key, value)) = args.get(i).and_then(|s| s.split_once('=')) {
+                    opts.env.push((key.to_string(), value.to_string()));
+                }
+            }
+            "--shell" => {
+                i += 1;
+                opts.shell = args.get(i).cloned();
+            }
+            "--shell-kind" => {
+                i += 1;
+                opts.shell_kind = args.get(i).and_then(|s| executor::ShellKind::parse(s));
+            }
+            "--pty-drain-ms" => {
+                i += 1;
+                opts.pty_drain_ms = args.get(i).and_then(|s| s.parse().ok());
+            }
+            "--delay-ms" => {
+                i += 1;
+                delay_ms = args.get(i).and_then(|s| s.parse().ok()).unwrap_or(0);
+            }
             "--pty" => pty = true,
             "--" => after_dashdash = true,
             _ => {
@@ -84,14 +158,21 @@ fn parse_exec_args(args: &[String]) -> ExecArgs {
         command,
         db_path,
         session_id,
+        dedup_window_secs,
+        request_id,
+        delay_ms,
+        opts,
     }
 }
 
 fn run_exec(args: ExecArgs) {
+    if args.delay_ms > 0 {
+        std::thread::sleep(std::time::Duration::from_millis(args.delay_ms));
+    }
     let result = if args.pty {
-        executor::execute_pty(&args.command, args.timeout_secs)
+        executor::execute_pty(&args.command, args.timeout_secs, &args.opts)
     } else {
-        executor::execute_pipe(&args.command, args.timeout_secs)
+        executor::execute_pipe(&args.command, args.timeout_secs, &args.opts)
     };
 
     match result {
@@ -104,17 +185,28 @@ fn run_exec(args: ExecArgs) {
             if let (Some(ref db_path), Some(ref session_id)) =
                 (&args.db_path, &args.session_id)
             {
+                let record_opts = alan::RecordOptions {
+                    cwd: args.opts.cwd.clone().or_else(|| {
+                        env::current_dir()
+                            .ok()
+                            .map(|p| p.to_string_lossy().into_owned())
+                    }),
+                    dedup_window_secs: args.dedup_window_secs,
+                    request_id: args.request_id.clone(),
+                };
                 match alan::open_db(db_path) {
                     Ok(conn) => {
-                        if let Err(e) = alan::record(
+                        if let Err(e) = alan::record_with_options(
                             &conn,
                             session_id,
                             &args.command,
                             exec_result.exit_code,
                             exec_result.elapsed_ms,
                             exec_result.timed_out,
-                            "",
+                            &exec_result.output_snippet,
+                            exec_result.stderr.as_deref().unwrap_or(""),
                             &exec_result.pipestatus,
+                            &record_opts,
                         ) {
                             eprintln!("zsh-tool exec: alan record failed: {}", e);
                         }
@@ -133,6 +225,8 @@ fn run_exec(args: ExecArgs) {
                 exit_code: 127,
                 elapsed_ms: 0,
                 timed_out: false,
+                stderr: None,
+                output_snippet: String::new(),
             };
             let _ = meta::write_meta(&args.meta_path, &err_result);
             eprintln!("zsh-tool exec: {}", e);
@@ -141,6 +235,76 @@ fn run_exec(args: ExecArgs) {
     }
 }
 
+/// Cheap liveness check for supervisors: config loads, ALAN DB opens,
+/// and the shell can run a trivial command. Prints one status line.
+fn run_health() {
+    let config = Config::load();
+    let mut problems: Vec<String> = Vec::new();
+
+    if let Err(e) = alan::open_db(&config.alan_db_path) {
+        problems.push(format!("alan db: {}", e));
+    }
+
+    let opts = executor::ExecOptions {
+        shell: config.shell_path.clone(),
+        shell_kind: Some(config.shell_kind()),
+        ..Default::default()
+    };
+    match executor::execute_pipe("true", 5, &opts) {
+        Ok(r) if r.exit_code == 0 && !r.timed_out => {}
+        Ok(r) => problems.push(format!("shell: exit {}", r.exit_code)),
+        Err(e) => problems.push(format!("shell: {}", e)),
+    }
+
+    if problems.is_empty() {
+        println!(
+            "healthy v{} db={}",
+            env!("CARGO_PKG_VERSION"),
+            config.alan_db_path
+        );
+        process::exit(0);
+    }
+    println!("unhealthy: {}", problems.join("; "));
+    process::exit(1);
+}
+
+/// Snapshot the configured ALAN database to `path`, or restore it from `path`.
+fn run_alan_snapshot(path: &str, restore: bool) {
+    let config = Config::load();
+    let db_path = config.alan_db_path.as_str();
+    let (result, action, src, dst) = if restore {
+        (alan::backup::restore(db_path, path), "restored", path, db_path)
+    } else {
+        (alan::backup::backup(db_path, path), "backed up", db_path, path)
+    };
+    if let Err(e) = result {
+        eprintln!("zsh-tool: {}", e);
+        process::exit(1);
+    }
+    let count = alan::open_db(dst)
+        .map(|conn| alan::backup::observation_count(&conn))
+        .unwrap_or(0);
+    println!("{} {} observations ({} -> {})", action, count, src, dst);
+}
+
+/// Check the configured ALAN database and print the report.
+fn run_alan_check() {
+    let config = Config::load();
+    let report = match alan::maintenance::check_path(&config.alan_db_path) {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("zsh-tool: {}", e);
+            process::exit(1);
+        }
+    };
+    println!("integrity: {}", report.integrity.join("; "));
+    for t in &report.tables {
+        println!("{}: {} rows", t.table, t.rows);
+    }
+    println!("orphaned ssh_observations: {}", report.orphaned_ssh_observations);
+    process::exit(if report.ok { 0 } else { 1 });
+}
+
 fn main() {
     let args: Vec<String> = env::args().collect();
 
@@ -149,9 +313,23 @@ fn main() {
     }
 
     match args[1].as_str() {
-        "serve" => {
-            serve::run_server();
+        "serve" => match args.get(2).map(String::as_str) {
+            None => serve::run_server(),
+            Some("--tcp") => match args.get(3) {
+                Some(addr) => serve::run_server_tcp(addr),
+                None => print_usage(),
+            },
+            Some(_) => print_usage(),
+        },
+        "health" => run_health(),
+        "alan-backup" | "alan-restore" => {
+            let path = args.get(2).cloned().unwrap_or_else(|| {
+                print_usage();
+                unreachable!()
+            });
+            run_alan_snapshot(&path, args[1] == "alan-restore");
         }
+        "alan-check" => run_alan_check(),
         "exec" => {
             let exec_args = parse_exec_args(&args[2..]);
             run_exec(exec_args);
@@ -8,6 +8,12 @@ pub struct ExecResult {
     pub exit_code: i32,
     pub elapsed_ms: u64,
     pub timed_out: bool,
+    /// Captured stderr when run with separate stderr; otherwise it's merged into stdout.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stderr: Option<String>,
+    /// First [`crate::alan::SNIPPET_BYTES`] bytes of stdout, for ALAN.
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub output_snippet: String,
 }
 
 pub fn write_meta(path: &str, result: &ExecResult) -> Result<(), String> {
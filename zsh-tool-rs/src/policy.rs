@@ -0,0 +1,197 @@
+//! Command allow/deny lists.
+//!
+//! Patterns are globs matched against the whole (trimmed) command line:
+//! `*` matches any run of characters, `?` exactly one. `sudo *` denies every
+//! sudo invocation; `*rm -rf*` catches it anywhere in a chain. The deny list
+//! wins over the allow list.
+//!
+//! [`is_destructive`] backs the separate guard against deleting files from
+//! `$HOME` or `/`; [`chain_commands`] splits a chain for per-command checks.
+
+/// Outcome of checking a command against the configured lists.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verdict {
+    /// Runs. Carries the allow pattern that admitted it, if an allow list is set.
+    Allowed(Option<String>),
+    /// Matched this deny pattern.
+    Denied(String),
+    /// An allow list is set and nothing on it matched.
+    NotAllowlisted,
+}
+
+impl Verdict {
+    pub fn is_allowed(&self) -> bool {
+        matches!(self, Self::Allowed(_))
+    }
+
+    /// The pattern that decided the verdict, if any.
+    pub fn pattern(&self) -> Option<&str> {
+        match self {
+            Self::Allowed(p) => p.as_deref(),
+            Self::Denied(p) => Some(p),
+            Self::NotAllowlisted => None,
+        }
+    }
+}
+
+pub fn check(command: &str, deny: &[String], allow: Option<&[String]>) -> Verdict {
+    let command = command.trim();
+    if let Some(p) = deny.iter().find(|p| glob_match(p, command)) {
+        return Verdict::Denied(p.clone());
+    }
+    match allow {
+        None => Verdict::Allowed(None),
+        Some(list) => match list.iter().find(|p| glob_match(p, command)) {
+            Some(p) => Verdict::Allowed(Some(p.clone())),
+            None => Verdict::NotAllowlisted,
+        },
+    }
+}
+
+/// Whether any command in a `;`, `&&`, `||` or `|` chain deletes files:
+/// `rm`, `find` with `-delete` or `-exec rm`, or `git clean`. Leading `sudo`
+/// and `NAME=value` assignments are looked through.
+pub fn is_destructive(command: &str) -> bool {
+    !destructive_cwds(command).is_empty()
+}
+
+/// For each command in the chain that deletes files (see [`is_destructive`]),
+/// the directory an earlier `cd` in the chain moved to, unquoted; None when
+/// it runs where the chain started. A bare `cd` is reported as `~`.
+pub fn destructive_cwds(command: &str) -> Vec<Option<&str>> {
+    let mut cwd = None;
+    let mut found = Vec::new();
+    for words in chain_words(command) {
+        let first = words[0];
+        let deletes = match first.rsplit('/').next().unwrap_or(first) {
+            "cd" => {
+                cwd = Some(words.get(1).map_or("~", |dir| dir.trim_matches(['"', '\''])));
+                false
+            }
+            "rm" => true,
+            "find" => words.contains(&"-delete")
+                || words.windows(2).any(|w| w[0] == "-exec" && w[1].rsplit('/').next() == Some("rm")),
+            "git" => words.get(1) == Some(&"clean"),
+            _ => false,
+        };
+        if deletes {
+            found.push(cwd);
+        }
+    }
+    found
+}
+
+/// Each command in a `;`, `&&`, `||` or `|` chain, from its base command
+/// on: leading `sudo` and `NAME=value` assignments are dropped and
+/// whitespace is collapsed.
+pub fn chain_commands(command: &str) -> Vec<String> {
+    chain_words(command).map(|words| words.join(" ")).collect()
+}
+
+/// The words of each non-empty command in a chain, see [`chain_commands`].
+fn chain_words(command: &str) -> impl Iterator<Item = Vec<&str>> {
+    command
+        .split([';', '&', '|', '\n'])
+        .map(|segment| {
+            segment
+                .split_whitespace()
+                .skip_while(|w| *w == "sudo" || (w.contains('=') && !w.starts_with('-')))
+                .collect::<Vec<_>>()
+        })
+        .filter(|words| !words.is_empty())
+}
+
+/// Match `text` against a glob with `*` and `?` wildcards.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let p: Vec<char> = pattern.chars().collect();
+    let t: Vec<char> = text.chars().collect();
+    let (mut pi, mut ti) = (0, 0);
+    // Position of the last `*` and the text index it is currently absorbing up to
+    let mut star: Option<(usize, usize)> = None;
+    while ti < t.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == t[ti]) {
+            pi += 1;
+            ti += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            star = Some((pi, ti));
+            pi += 1;
+        } else if let Some((sp, st)) = star {
+            pi = sp + 1;
+            ti = st + 1;
+            star = Some((sp, st + 1));
+        } else {
+            return false;
+        }
+    }
+    p[pi..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn list(items: &[&str]) -> Vec<String> {
+        items.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("sudo *", "sudo rm x"));
+        assert!(!glob_match("sudo *", "echo sudo x"));
+        assert!(glob_match("*rm -rf*", "cd / && rm -rf tmp"));
+        assert!(glob_match("git ??sh", "git push"));
+        assert!(!glob_match("git ??sh", "git pull"));
+        assert!(glob_match("ls", "ls"));
+        assert!(!glob_match("ls", "lsof"));
+        assert!(glob_match("*", ""));
+    }
+
+    #[test]
+    fn test_deny_wins_over_allow() {
+        let deny = list(&["git push --force*"]);
+        let allow = list(&["git *"]);
+        assert_eq!(
+            check("git push --force origin", &deny, Some(&allow)),
+            Verdict::Denied("git push --force*".into())
+        );
+        assert_eq!(check("git status", &deny, Some(&allow)), Verdict::Allowed(Some("git *".into())));
+        assert_eq!(check("make", &deny, Some(&allow)), Verdict::NotAllowlisted);
+        assert_eq!(check("make", &deny, None), Verdict::Allowed(None));
+    }
+
+    #[test]
+    fn test_is_destructive() {
+        for cmd in [
+            "rm -rf *",
+            "sudo /bin/rm file",
+            "ls && rm x",
+            "find . -name '*.o' -delete",
+            "find . -type f -exec rm {} +",
+            "git clean -fdx",
+            "LC_ALL=C rm x",
+        ] {
+            assert!(is_destructive(cmd), "{}", cmd);
+        }
+        for cmd in ["ls -la", "find . -name '*.rs'", "git status", "echo rm", "grep -r rm .", "cd /"] {
+            assert!(!is_destructive(cmd), "{}", cmd);
+        }
+    }
+
+    #[test]
+    fn test_chain_commands_start_at_the_base_command() {
+        assert_eq!(chain_commands("cargo build;  sleep 1e9"), vec!["cargo build", "sleep 1e9"]);
+        assert_eq!(chain_commands("x=cargo build && nc -l 9999"), vec!["build", "nc -l 9999"]);
+        assert_eq!(chain_commands("sudo FOO=1 make | tee log"), vec!["make", "tee log"]);
+        assert!(chain_commands(" ; ").is_empty());
+    }
+
+    #[test]
+    fn test_destructive_cwds_follow_cd() {
+        assert_eq!(destructive_cwds("rm -rf *"), vec![None]);
+        assert_eq!(destructive_cwds("cd ~ && rm -rf *"), vec![Some("~")]);
+        assert_eq!(destructive_cwds("cd; rm x"), vec![Some("~")]);
+        assert_eq!(destructive_cwds("rm a; cd \"$HOME\" && git clean -fdx"), vec![None, Some("$HOME")]);
+        assert_eq!(destructive_cwds("cd / && ls && cd /tmp/build && rm -rf out"), vec![Some("/tmp/build")]);
+        assert!(destructive_cwds("cd / && ls").is_empty());
+    }
+}
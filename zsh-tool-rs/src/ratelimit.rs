@@ -0,0 +1,88 @@
+//! Spawn rate limiter.
+//!
+//! Token bucket guarding exec subprocess spawns, so a burst of `zsh` calls
+//! can't exhaust PIDs on the host.
+
+use std::time::Instant;
+
+pub struct RateLimiter {
+    /// Tokens added per second. 0 disables limiting.
+    pub rate: f64,
+    /// Bucket size — the largest burst allowed at once.
+    pub capacity: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    /// Bucket refilling at `rate` tokens/sec, allowing bursts of up to one
+    /// second's worth (at least one spawn).
+    pub fn new(rate: f64) -> Self {
+        let capacity = rate.max(1.0);
+        Self {
+            rate,
+            capacity,
+            tokens: capacity,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last_refill = now;
+    }
+
+    /// Take one token. On rejection, returns the seconds until one is available.
+    pub fn try_acquire(&mut self) -> Result<(), f64> {
+        self.try_acquire_at(Instant::now())
+    }
+
+    fn try_acquire_at(&mut self, now: Instant) -> Result<(), f64> {
+        if self.rate <= 0.0 {
+            return Ok(());
+        }
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err((1.0 - self.tokens) / self.rate)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_burst_then_reject() {
+        let mut rl = RateLimiter::new(2.0);
+        let now = Instant::now();
+        assert!(rl.try_acquire_at(now).is_ok());
+        assert!(rl.try_acquire_at(now).is_ok());
+        let retry = rl.try_acquire_at(now).unwrap_err();
+        assert!(retry > 0.0 && retry <= 0.5, "retry={}", retry);
+    }
+
+    #[test]
+    fn test_refills_over_time() {
+        let mut rl = RateLimiter::new(2.0);
+        let now = Instant::now();
+        let _ = rl.try_acquire_at(now);
+        let _ = rl.try_acquire_at(now);
+        assert!(rl.try_acquire_at(now).is_err());
+        assert!(rl.try_acquire_at(now + Duration::from_millis(600)).is_ok());
+    }
+
+    #[test]
+    fn test_zero_rate_disables() {
+        let mut rl = RateLimiter::new(0.0);
+        let now = Instant::now();
+        for _ in 0..100 {
+            assert!(rl.try_acquire_at(now).is_ok());
+        }
+    }
+}
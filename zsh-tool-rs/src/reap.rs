@@ -0,0 +1,136 @@
+//! Cleanup of processes that escape the command's process group.
+//!
+//! The timeout kill targets the process group, but a command that calls
+//! `setsid` (daemons, `nohup`-style wrappers) lands in a new session and
+//! survives it. Every executed shell is tagged with a unique token in its
+//! environment; descendants inherit it even after reparenting to init, so a
+//! `/proc` scan for the token finds them. On systems without `/proc` the
+//! scan finds nothing and only the group kill applies.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Environment variable carrying the reap token into the command's processes.
+pub const TOKEN_VAR: &str = "ZSH_TOOL_REAP_TOKEN";
+
+static TOKEN_SEQ: AtomicU64 = AtomicU64::new(0);
+
+/// A token unique to this process and call.
+pub fn new_token() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    format!(
+        "{}-{}-{}",
+        std::process::id(),
+        nanos,
+        TOKEN_SEQ.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+/// PIDs of processes whose environment carries `token`, excluding our own.
+pub fn tagged_pids(token: &str) -> Vec<i32> {
+    if token.is_empty() {
+        return Vec::new();
+    }
+    let needle = format!("{}={}", TOKEN_VAR, token);
+    let own = std::process::id() as i32;
+    let entries = match std::fs::read_dir("/proc") {
+        Ok(e) => e,
+        Err(_) => return Vec::new(),
+    };
+    entries
+        .flatten()
+        .filter_map(|e| e.file_name().to_str()?.parse::<i32>().ok())
+        .filter(|&pid| pid != own)
+        .filter(|pid| {
+            // Unreadable (other users, already exited) means not ours
+            std::fs::read(format!("/proc/{}/environ", pid))
+                .map(|env| env.split(|b| *b == 0).any(|var| var == needle.as_bytes()))
+                .unwrap_or(false)
+        })
+        .collect()
+}
+
+/// SIGKILL every process tagged with `token`. Returns how many were signalled.
+/// Repeats until a scan comes back empty so a process forking mid-sweep is caught.
+pub fn kill_tagged(token: &str) -> usize {
+    let mut killed = 0;
+    for _ in 0..5 {
+        let pids = tagged_pids(token);
+        if pids.is_empty() {
+            break;
+        }
+        for pid in pids {
+            if unsafe { libc::kill(pid, libc::SIGKILL) } == 0 {
+                killed += 1;
+            }
+        }
+        std::thread::sleep(std::time::Duration::from_millis(20));
+    }
+    killed
+}
+
+/// Resident set size of one process, from `/proc/<pid>/statm`.
+pub fn rss_bytes(pid: i32) -> Option<u64> {
+    let statm = std::fs::read_to_string(format!("/proc/{}/statm", pid)).ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    Some(pages * page_size.max(0) as u64)
+}
+
+/// Combined RSS of every process tagged with `token`.
+pub fn tagged_rss_bytes(token: &str) -> u64 {
+    tagged_pids(token).into_iter().filter_map(rss_bytes).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    #[test]
+    fn test_tokens_are_unique() {
+        assert_ne!(new_token(), new_token());
+    }
+
+    #[test]
+    fn test_kill_tagged_kills_only_tagged_processes() {
+        let token = new_token();
+        let mut tagged = Command::new("sleep")
+            .arg("30")
+            .env(TOKEN_VAR, &token)
+            .spawn()
+            .unwrap();
+        let mut other = Command::new("sleep")
+            .arg("30")
+            .env(TOKEN_VAR, new_token())
+            .spawn()
+            .unwrap();
+
+        // The environment only shows up in /proc once the child has exec'd
+        let mut found = Vec::new();
+        for _ in 0..100 {
+            found = tagged_pids(&token);
+            if !found.is_empty() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(found, vec![tagged.id() as i32]);
+        assert_eq!(kill_tagged(&token), 1);
+        let status = tagged.wait().unwrap();
+        assert!(!status.success());
+        assert!(other.try_wait().unwrap().is_none(), "untagged process was killed");
+
+        let _ = other.kill();
+        let _ = other.wait();
+    }
+
+    #[test]
+    fn test_rss_bytes_of_self() {
+        let rss = rss_bytes(std::process::id() as i32).unwrap();
+        assert!(rss > 0);
+    }
+}
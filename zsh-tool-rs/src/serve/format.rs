@@ -100,6 +100,21 @@ pub fn status_error(task_id: &str, elapsed: f64) -> String {
     format!("{}✘ ERROR{}  {:.1}s  task={}", C_RED, C_RESET, elapsed, task_id)
 }
 
+/// Status line for a command refused before it ran. `reason` is the
+/// machine-readable `blocked_reason`.
+pub fn status_blocked(reason: &str, retry_after: Option<f64>) -> String {
+    let mut line = format!("{}⛔ BLOCKED{}  reason={}", C_YELLOW, C_RESET, reason);
+    if let Some(secs) = retry_after {
+        line.push_str(&format!("  retry in {:.1}s", secs));
+    }
+    line
+}
+
+/// Status line for a `dry_run` call: the command was only looked up in ALAN.
+pub fn status_dry_run() -> String {
+    format!("{}[DRY-RUN]{}  not executed", C_CYAN, C_RESET)
+}
+
 // ── Progress bar ──────────────────────────────────────────────
 
 /// Render a progress bar: `████████████░░░░░░░░  58%`
@@ -176,6 +191,36 @@ pub fn format_notification(task_id: &str, exit_code: i32, elapsed: f64) -> Strin
     }
 }
 
+// ── Pipe segments ─────────────────────────────────────────────
+
+/// Render pipeline segments with their exit codes: `│ false → 1 · true → 0`
+pub fn format_segments(segments: &[(String, i32)]) -> String {
+    let rendered: Vec<String> = segments
+        .iter()
+        .map(|(cmd, code)| format!("{} → {}", command_preview(cmd, 40), color_exit(*code)))
+        .collect();
+    format!("{}│{} {}", C_DIM, C_RESET, rendered.join(" · "))
+}
+
+/// `│ sha256=<hex>` line for results carrying an `output_hash`.
+pub fn format_output_hash(hash: &str) -> String {
+    format!("{}│ sha256={}{}", C_DIM, hash, C_RESET)
+}
+
+/// `│ full output: <path>` line for running tasks spooled to disk.
+pub fn format_spool_path(path: &str) -> String {
+    format!("{}│ full output: {}{}", C_DIM, path, C_RESET)
+}
+
+fn command_preview(command: &str, max_chars: usize) -> String {
+    if command.chars().count() > max_chars {
+        let head: String = command.chars().take(max_chars).collect();
+        format!("{}…", head)
+    } else {
+        command.to_string()
+    }
+}
+
 // ── ALAN insights ─────────────────────────────────────────────
 
 pub fn format_insight(level: &str, messages: &[&str]) -> String {
@@ -189,8 +234,23 @@ pub fn format_insight(level: &str, messages: &[&str]) -> String {
 
 // ── Placeholders ──────────────────────────────────────────────
 
-pub fn no_output() -> String {
-    format!("{}(no output){}", C_DIM, C_RESET)
+/// Default placeholder for a silent command.
+pub const NO_OUTPUT_TEXT: &str = "(no output)";
+
+pub fn no_output(text: &str) -> String {
+    format!("{}{}{}", C_DIM, text, C_RESET)
+}
+
+/// Separately captured stderr, each line marked so it reads apart from stdout.
+pub fn format_stderr(stderr: &str) -> Vec<String> {
+    let mut lines = vec![format!("{}stderr:{}", C_YELLOW, C_RESET)];
+    lines.extend(
+        stderr
+            .trim_end_matches('\n')
+            .split('\n')
+            .map(|l| format!("{}│{} {}", C_YELLOW, C_RESET, l)),
+    );
+    lines
 }
 
 pub fn format_error(msg: &str) -> String {
@@ -215,6 +275,54 @@ pub fn command_header(command: &str) -> String {
 /// Prefix on every tool result — reinforces the relay instruction at point of attention.
 const RELAY_HEADER: &str = "[RELAY TO USER — tool output is not visible to the user. You MUST include this output verbatim in your response.]";
 
+/// Rendering knobs that come from server config.
+pub struct FormatOptions<'a> {
+    /// Placeholder shown for silent commands. Empty string omits the line.
+    pub empty_output_text: &'a str,
+    /// Expand tabs in command output to this many columns. None keeps them.
+    pub tab_width: Option<usize>,
+    /// Lines put before and after the output body so transcript parsers can
+    /// find where it starts and ends. Empty strings are left out.
+    pub output_markers: (&'a str, &'a str),
+}
+
+impl Default for FormatOptions<'_> {
+    fn default() -> Self {
+        Self {
+            empty_output_text: NO_OUTPUT_TEXT,
+            tab_width: None,
+            output_markers: ("", ""),
+        }
+    }
+}
+
+/// Replace tabs with spaces up to the next multiple of `width` columns.
+/// Columns restart after each newline. A width of 0 drops tabs.
+pub fn expand_tabs(text: &str, width: usize) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut col = 0;
+    for ch in text.chars() {
+        match ch {
+            '\t' => {
+                if width > 0 {
+                    let pad = width - col % width;
+                    out.extend(std::iter::repeat_n(' ', pad));
+                    col += pad;
+                }
+            }
+            '\n' => {
+                out.push(ch);
+                col = 0;
+            }
+            _ => {
+                out.push(ch);
+                col += 1;
+            }
+        }
+    }
+    out
+}
+
 /// Format a complete task result into rich, structured output.
 ///
 /// Layout for completed:
@@ -227,6 +335,14 @@ const RELAY_HEADER: &str = "[RELAY TO USER — tool output is not visible to the
 /// ✔ exit=0  0.1s  task=abc12345
 /// ```
 pub fn format_rich_output(result: &serde_json::Map<String, Value>) -> String {
+    format_rich_output_with(result, &FormatOptions::default())
+}
+
+/// Like [`format_rich_output`], with explicit rendering options.
+pub fn format_rich_output_with(
+    result: &serde_json::Map<String, Value>,
+    opts: &FormatOptions,
+) -> String {
     let mut parts: Vec<String> = Vec::new();
 
     let status = result.get("status").and_then(|v| v.as_str()).unwrap_or("unknown");
@@ -246,19 +362,42 @@ pub fn format_rich_output(result: &serde_json::Map<String, Value>) -> String {
     // Separator before output
     parts.push(separator_styled(SEP_WIDTH));
 
+    let output_empty = result
+        .get("output_empty")
+        .and_then(|v| v.as_bool())
+        .unwrap_or_else(|| output.trim().is_empty());
+
     // Output body
-    if !output.trim().is_empty() {
+    if !output_empty {
         let lines: Vec<String> = output
             .trim_end_matches('\n')
             .split('\n')
-            .map(|s| s.to_string())
+            .map(|s| match opts.tab_width {
+                Some(width) => expand_tabs(s, width),
+                None => s.to_string(),
+            })
             .collect();
         let consolidated = consolidate_progress(lines);
-        for line in consolidated {
-            parts.push(line);
+        let (start, end) = opts.output_markers;
+        if !start.is_empty() {
+            parts.push(start.to_string());
+        }
+        parts.extend(consolidated);
+        if !end.is_empty() {
+            parts.push(end.to_string());
+        }
+    } else if (status == "completed" || status == "error") && !opts.empty_output_text.is_empty() {
+        parts.push(no_output(opts.empty_output_text));
+    }
+
+    // Separately captured stderr
+    if let Some(stderr) = result.get("stderr").and_then(|v| v.as_str()) {
+        if !stderr.trim().is_empty() {
+            match opts.tab_width {
+                Some(width) => parts.extend(format_stderr(&expand_tabs(stderr, width))),
+                None => parts.extend(format_stderr(stderr)),
+            }
         }
-    } else if status == "completed" || status == "error" {
-        parts.push(no_output());
     }
 
     // Error field
@@ -290,12 +429,37 @@ pub fn format_rich_output(result: &serde_json::Map<String, Value>) -> String {
                 .map(|a| a.iter().filter_map(|v| v.as_i64().map(|n| n as i32)).collect())
                 .unwrap_or_else(|| vec![0]);
             parts.push(status_completed(task_id, elapsed, &pipestatus));
+            if let Some(segments) = result.get("segments").and_then(|v| v.as_array()) {
+                let pairs: Vec<(String, i32)> = segments
+                    .iter()
+                    .filter_map(|s| {
+                        let cmd = s.get("command")?.as_str()?;
+                        let code = s.get("exit_code")?.as_i64()?;
+                        Some((cmd.to_string(), code as i32))
+                    })
+                    .collect();
+                if !pairs.is_empty() {
+                    parts.push(format_segments(&pairs));
+                }
+            }
         }
         "timeout" => parts.push(status_timeout(task_id, elapsed)),
         "killed" => parts.push(status_killed(task_id, elapsed)),
         "error" => parts.push(status_error(task_id, elapsed)),
+        "blocked" => {
+            let reason = result.get("blocked_reason").and_then(|v| v.as_str()).unwrap_or("unknown");
+            let retry = result.get("retry_after_seconds").and_then(|v| v.as_f64());
+            parts.push(status_blocked(reason, retry));
+        }
+        "dry_run" => parts.push(status_dry_run()),
         _ => {}
     }
+    if let Some(hash) = result.get("output_hash").and_then(|v| v.as_str()) {
+        parts.push(format_output_hash(hash));
+    }
+    if let Some(path) = result.get("spool_path").and_then(|v| v.as_str()) {
+        parts.push(format_spool_path(path));
+    }
 
     // ALAN insights
     if let Some(insights) = result.get("insights").and_then(|v| v.as_object()) {
@@ -503,7 +667,7 @@ mod tests {
 
     #[test]
     fn test_no_output_placeholder() {
-        let s = no_output();
+        let s = no_output(NO_OUTPUT_TEXT);
         assert!(s.contains("(no output)"));
         assert!(s.contains("\x1b[2m"));
     }
@@ -562,6 +726,33 @@ mod tests {
         assert!(text.contains("✔"));
     }
 
+    #[test]
+    fn test_rich_output_custom_empty_placeholder() {
+        let result = make_result(json!({"output": "", "output_empty": true, "command": "true"}));
+        let text = format_rich_output_with(&result, &FormatOptions { empty_output_text: "<silent>", ..FormatOptions::default() });
+        assert!(text.contains("<silent>"));
+        assert!(!text.contains("(no output)"));
+
+        let text = format_rich_output_with(&result, &FormatOptions { empty_output_text: "", ..FormatOptions::default() });
+        assert!(!text.contains("(no output)"));
+        assert_eq!(text.matches(&separator_styled(SEP_WIDTH)).count(), 2);
+    }
+
+    #[test]
+    fn test_rich_output_separate_stderr() {
+        let result = make_result(json!({
+            "output": "compiled\n",
+            "stderr": "warning: unused variable\nwarning: dead code\n",
+            "pipestatus": [0],
+            "command": "cargo build"
+        }));
+        let text = format_rich_output(&result);
+        let out_at = text.find("compiled").unwrap();
+        let err_at = text.find("stderr:").unwrap();
+        assert!(out_at < err_at);
+        assert!(text.contains("│\u{1b}[0m warning: dead code"), "got: {}", text);
+    }
+
     #[test]
     fn test_rich_output_failed() {
         let result = make_result(json!({
@@ -603,6 +794,83 @@ mod tests {
         assert!(text.contains("spawn failed"));
     }
 
+    #[test]
+    fn test_expand_tabs_aligns_to_tab_stops() {
+        assert_eq!(expand_tabs("a\tb", 4), "a   b");
+        assert_eq!(expand_tabs("abcd\te", 4), "abcd    e");
+        assert_eq!(expand_tabs("ab\n\tc", 4), "ab\n    c");
+        assert_eq!(expand_tabs("x\ty", 0), "xy");
+    }
+
+    #[test]
+    fn test_rich_output_expands_tabs_when_configured() {
+        let result = make_result(json!({
+            "output": "name\tsize\nlongername\t12\n",
+            "command": "cat table.tsv"
+        }));
+        let opts = FormatOptions { tab_width: Some(8), ..FormatOptions::default() };
+        let text = format_rich_output_with(&result, &opts);
+        assert!(text.contains("name    size"), "got: {}", text);
+        assert!(text.contains("longername      12"), "got: {}", text);
+        assert!(!text.contains('\t'));
+
+        let text = format_rich_output(&result);
+        assert!(text.contains("name\tsize"));
+    }
+
+    #[test]
+    fn test_rich_output_markers_bracket_output() {
+        let result = make_result(json!({
+            "output": "$ rm -rf build\ndone\n",
+            "command": "cat notes.txt"
+        }));
+        let opts = FormatOptions { output_markers: ("<<<OUTPUT", "OUTPUT>>>"), ..FormatOptions::default() };
+        let text = format_rich_output_with(&result, &opts);
+        let start = text.find("<<<OUTPUT\n").expect("start marker");
+        let end = text.find("\nOUTPUT>>>").expect("end marker");
+        let body = &text[start..end];
+        assert!(body.contains("$ rm -rf build") && body.contains("done"), "got: {}", text);
+        assert!(text[..start].contains("cat notes.txt"), "command header stays outside: {}", text);
+        assert!(text[end..].contains("task=abc12345"), "status line stays outside: {}", text);
+
+        let only_end = FormatOptions { output_markers: ("", "--end--"), ..FormatOptions::default() };
+        let text = format_rich_output_with(&result, &only_end);
+        assert!(text.contains("done\n--end--"), "got: {}", text);
+        assert!(!format_rich_output(&result).contains("OUTPUT>>>"));
+    }
+
+    #[test]
+    fn test_rich_output_blocked() {
+        let result = make_result(json!({
+            "error": "Spawn rate limit exceeded (2/s). Retry in 0.5s.",
+            "status": "blocked",
+            "blocked_reason": "rate_limited",
+            "retry_after_seconds": 0.5,
+            "output": "",
+            "command": "true"
+        }));
+        let text = format_rich_output(&result);
+        assert!(text.contains("⛔ BLOCKED"));
+        assert!(text.contains("reason=rate_limited"));
+        assert!(text.contains("retry in 0.5s"));
+        assert!(text.contains("Spawn rate limit exceeded"));
+    }
+
+    #[test]
+    fn test_rich_output_dry_run() {
+        let result = make_result(json!({
+            "status": "dry_run",
+            "output": "",
+            "command": "git push",
+            "insights": {"warning": ["Failed 3 times in a row"]}
+        }));
+        let text = format_rich_output(&result);
+        assert!(text.contains("[DRY-RUN]"), "got: {}", text);
+        assert!(text.contains("not executed"));
+        assert!(text.contains("Failed 3 times in a row"));
+        assert!(!text.contains("no output"), "got: {}", text);
+    }
+
     #[test]
     fn test_rich_output_with_insights() {
         let result = make_result(json!({
@@ -631,6 +899,30 @@ mod tests {
         assert!(text.contains("Done."));
     }
 
+    #[test]
+    fn test_format_segments() {
+        let s = format_segments(&[("false".to_string(), 1), ("true".to_string(), 0)]);
+        assert!(s.contains("false → "));
+        assert!(s.contains("true → "));
+        assert!(s.contains(&color_exit(1)));
+        assert!(s.contains(&color_exit(0)));
+    }
+
+    #[test]
+    fn test_rich_output_with_segments() {
+        let result = make_result(json!({
+            "command": "false | true",
+            "pipestatus": [1, 0],
+            "segments": [
+                {"command": "false", "exit_code": 1},
+                {"command": "true", "exit_code": 0}
+            ]
+        }));
+        let text = format_rich_output(&result);
+        assert!(text.contains("false → "));
+        assert!(text.contains("true → "));
+    }
+
     #[test]
     fn test_format_notifications_block() {
         let events = vec![
@@ -3,22 +3,35 @@
 //! Handles initialize, tools/list, tools/call, and notifications.
 
 pub mod format;
+pub mod prompts;
 pub mod protocol;
+pub mod resources;
+pub mod terminal;
 pub mod tools;
 
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
 use std::io;
 use std::process::{Child, ChildStdin, ChildStdout};
-use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 
 use serde_json::Value;
 
 use crate::alan;
-use crate::circuit::CircuitBreaker;
-use crate::config::Config;
+use crate::alan::insights::RetryInfo;
+use crate::circuit::{CircuitBreaker, CircuitState};
+use crate::envdiff;
+use crate::executor;
+use crate::policy::{self, Verdict};
+use crate::ratelimit::RateLimiter;
+use crate::reap;
+use crate::config::{BlockDetail, Config, LineKeep, TimestampFormat, TruncateMode};
 
 use protocol::{
-    error_content, initialize_result, read_message, text_content, write_message, JsonRpcResponse,
+    error_content, initialize_result, read_message, server_log, text_content, write_message,
+    JsonRpcNotification, JsonRpcResponse, LogLevel,
 };
 
 /// A background task that finished while the caller wasn't watching.
@@ -27,23 +40,128 @@ pub struct CompletedEvent {
     pub task_id: String,
     pub exit_code: i32,
     pub elapsed: f64,
+    /// Connection that started the task; only that client is told.
+    pub connection: u64,
 }
 
+/// A connection's output stream and the framing its client uses.
+pub struct ConnectionOutput {
+    pub stream: Box<dyn io::Write + Send>,
+    pub framing: protocol::Framing,
+}
+
+impl ConnectionOutput {
+    pub fn new(stream: Box<dyn io::Write + Send>) -> ConnectionWriter {
+        Arc::new(Mutex::new(ConnectionOutput { stream, framing: protocol::Framing::default() }))
+    }
+
+    /// Write `message` in the client's framing.
+    fn send(&mut self, message: &impl serde::Serialize) -> io::Result<()> {
+        write_message(&mut self.stream, self.framing, message)
+    }
+}
+
+/// A connection's output, shared by its request loop and the push notifier.
+pub type ConnectionWriter = Arc<Mutex<ConnectionOutput>>;
+
 /// Shared server state.
 pub struct ServerState {
     pub config: Config,
+    /// Executor binary spawned for each command, resolved at startup.
+    pub exec_path: std::path::PathBuf,
+    /// Shell the executor runs commands under, resolved at startup.
+    pub shell_path: std::path::PathBuf,
     pub circuit_breaker: Mutex<CircuitBreaker>,
+    pub spawn_limiter: Mutex<RateLimiter>,
     pub session_id: String,
+    pub started_at_epoch: f64,
     pub db_path: String,
     pub tasks: Mutex<TaskRegistry>,
     pub event_queue: Mutex<Vec<CompletedEvent>>,
+    /// Environment captured by `zsh_env_snapshot`, compared by `zsh_env_diff`.
+    pub env_baseline: Mutex<Option<envdiff::EnvMap>>,
+    /// Finished runs whose output hit the output cap, per command hash.
+    pub truncations: Mutex<HashMap<String, usize>>,
+    /// Working directory set by `zsh_cwd` for every later command. None runs
+    /// commands in the server's own directory.
+    pub cwd: Mutex<Option<String>>,
+    /// Variables set by `zsh_env`, passed to every later command.
+    pub session_env: Mutex<HashMap<String, String>>,
+    /// When the last request arrived (or a task was last seen running), for
+    /// the idle shutdown in [`spawn_idle_watcher`].
+    pub last_activity: Mutex<std::time::Instant>,
+    /// Set while the stdio push notifier runs: completion events go out as
+    /// notifications instead of being prepended to the next tool result.
+    pub pushing_events: AtomicBool,
+    /// Read-held while a request is answered when pushing events, so the
+    /// notifier never finalizes a task a request is waiting on.
+    pub request_gate: RwLock<()>,
+    /// Where the push notifier writes each open connection's events.
+    pub push_writers: Mutex<HashMap<u64, ConnectionWriter>>,
+    /// Task spawned by each `tools/call`, keyed by connection and the JSON of
+    /// its request id, so `notifications/cancelled` can stop it. Only running
+    /// tasks are kept.
+    pub request_tasks: Mutex<HashMap<(u64, String), String>>,
+    /// `timeout_exempt_patterns`, compiled once at startup.
+    pub timeout_exempt: Vec<regex::Regex>,
+}
+
+thread_local! {
+    /// Request id (as JSON) of the `tools/call` this thread is answering.
+    static CURRENT_REQUEST: RefCell<Option<String>> = const { RefCell::new(None) };
+    /// Connection this thread is serving. Request ids are only unique per
+    /// client, so they are always paired with it.
+    static CURRENT_CONNECTION: Cell<u64> = const { Cell::new(0) };
+    /// MCP revision agreed with this thread's client at `initialize`.
+    static PROTOCOL_VERSION: Cell<&'static str> = const { Cell::new(protocol::PROTOCOL_VERSIONS[0]) };
+    /// Set while `run_zsh_with_retries` runs its attempts: results finalized
+    /// meanwhile leave the circuit breaker to it, so a call counts once.
+    static DEFER_BREAKER: Cell<bool> = const { Cell::new(false) };
+    /// Backoff the next exec spawned on this thread waits out before running
+    /// its command, in ms. Taken (reset) by the spawn.
+    static SPAWN_DELAY_MS: Cell<u64> = const { Cell::new(0) };
 }
 
+/// Source of connection ids for [`CURRENT_CONNECTION`].
+static NEXT_CONNECTION: AtomicU64 = AtomicU64::new(1);
+
+/// How often the idle watcher checks, at most.
+const IDLE_CHECK_INTERVAL_MS: u64 = 1000;
+
+/// How often the push notifier looks for finished background tasks.
+const PUSH_CHECK_INTERVAL_MS: u64 = 250;
+
 /// Active task registry.
 pub struct TaskRegistry {
     pub tasks: HashMap<String, TaskInfo>,
 }
 
+impl TaskRegistry {
+    /// Register `task`, then evict the oldest finished tasks while the
+    /// registry holds more than `max_tasks` (0: no limit). Running tasks are
+    /// never evicted, so they alone may exceed the limit. Returns the evicted ids.
+    pub fn insert(&mut self, task: TaskInfo, max_tasks: usize) -> Vec<String> {
+        let new_id = task.task_id.clone();
+        self.tasks.insert(new_id.clone(), task);
+        if max_tasks == 0 || self.tasks.len() <= max_tasks {
+            return Vec::new();
+        }
+        let mut finished: Vec<(std::time::Instant, String)> = self
+            .tasks
+            .values()
+            .filter(|t| t.status != "running" && t.task_id != new_id)
+            .map(|t| (t.started_at, t.task_id.clone()))
+            .collect();
+        finished.sort();
+        let excess = self.tasks.len() - max_tasks;
+        let evicted: Vec<String> = finished.into_iter().take(excess).map(|(_, id)| id).collect();
+        for id in &evicted {
+            self.tasks.remove(id);
+        }
+        evicted
+    }
+}
+
 /// A live or completed task with process handles.
 pub struct TaskInfo {
     pub task_id: String,
@@ -59,55 +177,432 @@ pub struct TaskInfo {
     pub pid: Option<u32>,
     pub is_pty: bool,
     pub meta_path: String,
+    /// Tags the task's processes so kill can find ones that left the group.
+    pub reap_token: String,
+    /// Combined RSS of the task's processes at the last sweep (0 when not tracked).
+    pub rss_bytes: u64,
     pub pre_insights: Vec<(String, String)>,
+    /// Structured retry counts behind the retry insight, echoed as `retry_info`.
+    pub retry_info: Option<RetryInfo>,
+    /// Timeout the command runs under, in seconds.
+    pub timeout_secs: u64,
+    /// The pattern's average duration from ALAN, when there is enough history.
+    pub usual_secs: Option<f64>,
+    /// Keys of runtime insights already reported by `zsh_poll`.
+    pub raised_insights: std::collections::HashSet<String>,
+    /// Parse the finished output as JSON (`extract_json`).
+    pub extract_json: bool,
+    /// Return the rendered screen instead of the raw PTY stream (`render_terminal`).
+    pub render_terminal: bool,
+    /// Add the SHA-256 of the finished output (`output_hash`).
+    pub output_hash: bool,
+    /// When `timestamp_lines` is on, when each line of `output_buffer`
+    /// started, in ms since the command did. The buffer itself stays raw.
+    pub line_stamps: Option<Vec<u64>>,
+    /// Bytes of `output_buffer` already appended to the spool file.
+    pub spooled_bytes: usize,
+    /// JSON-RPC id (as JSON text) of the `tools/call` that started the task,
+    /// also recorded with the command in ALAN.
+    pub request_id: Option<String>,
+    /// Connection the task was started from, for its completion event.
+    pub connection: u64,
     // Live process handles — None after process completes
     pub child: Option<Child>,
-    pub stdout: Option<ChildStdout>,
+    /// Thread draining the exec process's stdout into `output_buffer`.
+    pub reader: Option<JoinHandle<()>>,
     pub stdin: Option<ChildStdin>,
 }
 
-/// Run the MCP server on stdio.
-pub fn run_server() {
-    eprintln!("[zsh-tool] Starting MCP server v{}", env!("CARGO_PKG_VERSION"));
+/// Build the shared state from config. Common to every transport.
+fn init_server() -> Arc<ServerState> {
+    server_log!(LogLevel::Info, "Starting MCP server v{}", env!("CARGO_PKG_VERSION"));
+    // Client disconnects must surface as write errors, not kill the process
+    unsafe {
+        libc::signal(libc::SIGPIPE, libc::SIG_IGN);
+    }
     let config = Config::load();
-    eprintln!("[zsh-tool] Config loaded: db={}, timeout={}, yield_after={}",
+    if config.protocol_debug {
+        protocol::set_verbose_logging(true);
+    }
+    server_log!(LogLevel::Info, "Config loaded: db={}, timeout={}, yield_after={}",
         config.alan_db_path, config.neverhang_timeout_default, config.yield_after_default);
-    let cb = CircuitBreaker::new(
+    // Every command spawns the executor; without it the server is useless
+    let exec_path = match resolve_exec_path(config.exec_path.as_deref()) {
+        Ok(path) => path,
+        Err(e) => {
+            server_log!(LogLevel::Error, "{}", e);
+            std::process::exit(1);
+        }
+    };
+    let shell_path = match resolve_shell_path(config.shell_path.as_deref(), config.shell_kind()) {
+        Ok(path) => path,
+        Err(e) => {
+            server_log!(LogLevel::Error, "{}", e);
+            std::process::exit(1);
+        }
+    };
+    server_log!(LogLevel::Debug, "Executor: {}, shell: {}", exec_path.display(), shell_path.display());
+    let mut cb = CircuitBreaker::new(
         config.neverhang_failure_threshold,
         config.neverhang_recovery_timeout,
         config.neverhang_sample_window,
     );
+    cb.trip_exit_codes = config.circuit_trip_exit_codes.clone();
+    cb.half_open_required_successes = config.half_open_required_successes;
+    cb.global_failure_threshold = config.circuit_global_failure_threshold;
 
     let state = Arc::new(ServerState {
+        exec_path,
+        shell_path,
         db_path: config.alan_db_path.clone(),
         session_id: uuid::Uuid::new_v4().to_string(),
+        started_at_epoch: now_epoch(),
         circuit_breaker: Mutex::new(cb),
+        spawn_limiter: Mutex::new(RateLimiter::new(config.max_spawns_per_sec)),
         tasks: Mutex::new(TaskRegistry {
             tasks: HashMap::new(),
         }),
         event_queue: Mutex::new(Vec::new()),
+        env_baseline: Mutex::new(None),
+        truncations: Mutex::new(HashMap::new()),
+        cwd: Mutex::new(None),
+        session_env: Mutex::new(HashMap::new()),
+        last_activity: Mutex::new(std::time::Instant::now()),
+        pushing_events: AtomicBool::new(false),
+        request_gate: RwLock::new(()),
+        push_writers: Mutex::new(HashMap::new()),
+        request_tasks: Mutex::new(HashMap::new()),
+        timeout_exempt: compile_timeout_exempt(&config.timeout_exempt_patterns),
         config,
     });
+    spawn_idle_watcher(&state);
+    state
+}
+
+/// Resolve the executor binary: `override_path` (`exec_path` /
+/// `ZSH_TOOL_EXEC_PATH`) when given, otherwise this server's own executable,
+/// which doubles as the executor. It must be an executable file.
+pub fn resolve_exec_path(override_path: Option<&str>) -> Result<std::path::PathBuf, String> {
+    let (path, origin) = match override_path {
+        Some(p) => (std::path::PathBuf::from(p), "ZSH_TOOL_EXEC_PATH"),
+        None => (
+            std::env::current_exe().map_err(|e| {
+                format!("Cannot determine the server's own executable ({}); set ZSH_TOOL_EXEC_PATH to the zsh-tool-exec binary", e)
+            })?,
+            "server executable",
+        ),
+    };
+    check_executable("Executor", path, origin)
+}
+
+/// Resolve the shell commands run under: `override_path` (`shell_path` /
+/// `ZSH_TOOL_SHELL_PATH`) when given, otherwise `kind`'s binary from `PATH` or
+/// `/bin`. Checked up front so a missing shell stops the server instead
+/// of failing every command with exit 127.
+pub fn resolve_shell_path(override_path: Option<&str>, kind: executor::ShellKind) -> Result<std::path::PathBuf, String> {
+    let (path, origin) = match override_path {
+        Some(p) => (p.to_string(), "ZSH_TOOL_SHELL_PATH"),
+        None => (executor::default_shell(kind), "PATH lookup"),
+    };
+    check_executable("Shell", std::path::PathBuf::from(path), origin)
+        .map_err(|e| format!("{}; install {} or set ZSH_TOOL_SHELL_PATH", e, kind.name()))
+}
 
-    eprintln!("[zsh-tool] Session {} — waiting for requests on stdin", state.session_id);
+/// `path` if it is an executable file, otherwise an error naming `what` and
+/// where the path came from.
+fn check_executable(what: &str, path: std::path::PathBuf, origin: &str) -> Result<std::path::PathBuf, String> {
+    use std::os::unix::fs::PermissionsExt;
+    let meta = std::fs::metadata(&path)
+        .map_err(|e| format!("{} {} ({}) is unusable: {}", what, path.display(), origin, e))?;
+    if !meta.is_file() || meta.permissions().mode() & 0o111 == 0 {
+        return Err(format!("{} {} ({}) is not an executable file", what, path.display(), origin));
+    }
+    Ok(path)
+}
+
+/// Run the MCP server on stdio.
+pub fn run_server() {
+    let state = init_server();
+    server_log!(LogLevel::Info, "Session {} — waiting for requests on stdin", state.session_id);
+    if state.config.push_notifications {
+        spawn_push_notifier(&state);
+    }
     let stdin = io::stdin();
-    let mut reader = stdin.lock();
-    let stdout = io::stdout();
-    let mut writer = stdout.lock();
+    let stdout = ConnectionOutput::new(Box::new(io::stdout()));
+    if serve_connection(&state, &mut stdin.lock(), stdout) {
+        server_log!(LogLevel::Info, "stdin closed — shutting down");
+    } else {
+        // Client closed its read end — nobody is listening any more
+        server_log!(LogLevel::Warning, "Client disconnected — shutting down");
+    }
+    shutdown(&state);
+}
+
+/// Run the MCP server on a TCP listener. Each connection gets its own thread;
+/// all of them share one session (tasks, ALAN, circuit breaker). Runs until
+/// killed or the idle timeout fires; a client leaving does not stop tasks.
+/// Completion events go to the client that started the task.
+pub fn run_server_tcp(addr: &str) {
+    let state = init_server();
+    if state.config.push_notifications {
+        spawn_push_notifier(&state);
+    }
+    let listener = match std::net::TcpListener::bind(addr) {
+        Ok(l) => l,
+        Err(e) => {
+            server_log!(LogLevel::Error, "Cannot listen on {}: {}", addr, e);
+            std::process::exit(1);
+        }
+    };
+    let local = listener.local_addr().map(|a| a.to_string()).unwrap_or_else(|_| addr.to_string());
+    server_log!(LogLevel::Info, "Session {} — listening on {}", state.session_id, local);
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(s) => s,
+            Err(e) => {
+                server_log!(LogLevel::Warning, "Accept failed: {}", e);
+                continue;
+            }
+        };
+        let peer = stream.peer_addr().map(|a| a.to_string()).unwrap_or_default();
+        let reader = match stream.try_clone() {
+            Ok(r) => r,
+            Err(e) => {
+                server_log!(LogLevel::Warning, "Connection {}: {}", peer, e);
+                continue;
+            }
+        };
+        let state = Arc::clone(&state);
+        std::thread::spawn(move || {
+            server_log!(LogLevel::Info, "Client {} connected", peer);
+            let writer = ConnectionOutput::new(Box::new(stream));
+            serve_connection(&state, &mut io::BufReader::new(reader), writer);
+            server_log!(LogLevel::Info, "Client {} disconnected", peer);
+        });
+    }
+}
+
+/// Answer requests from one client until it closes its input (true) or
+/// stops reading responses (false).
+fn serve_connection(
+    state: &Arc<ServerState>,
+    reader: &mut impl io::BufRead,
+    writer: ConnectionWriter,
+) -> bool {
+    let connection = NEXT_CONNECTION.fetch_add(1, Ordering::Relaxed);
+    CURRENT_CONNECTION.with(|c| c.set(connection));
+    if state.pushing_events.load(Ordering::Relaxed) {
+        state.push_writers.lock().unwrap().insert(connection, Arc::clone(&writer));
+    }
+    let open = answer_requests(state, reader, &writer);
+    state.push_writers.lock().unwrap().remove(&connection);
+    // Nobody is left to hear about this client's tasks
+    state.event_queue.lock().unwrap().retain(|ev| ev.connection != connection);
+    open
+}
 
-    while let Some(request) = read_message(&mut reader) {
-        // Notifications (no id) — just acknowledge
+/// The request loop of [`serve_connection`].
+fn answer_requests(state: &Arc<ServerState>, reader: &mut impl io::BufRead, writer: &ConnectionWriter) -> bool {
+    let mut framing = protocol::Framing::default();
+    while let Some(request) = read_message(reader, &mut framing) {
+        *state.last_activity.lock().unwrap() = std::time::Instant::now();
+        // Notifications (no id) — only cancellation needs acting on
         if request.id.is_none() {
-            eprintln!("[zsh-tool] Notification: {}", request.method);
+            server_log!(LogLevel::Debug, "Notification: {}", request.method);
+            if request.method == "notifications/cancelled" {
+                cancel_request(state, request.params.as_ref());
+            }
+            continue;
+        }
+
+        server_log!(LogLevel::Debug, "Request: {} (id={:?})", request.method, request.id);
+        let _gate = state
+            .pushing_events
+            .load(Ordering::Relaxed)
+            .then(|| state.request_gate.read().unwrap());
+        let response = handle_request(state, &request.method, request.id.clone(), request.params);
+        let mut writer = writer.lock().unwrap();
+        writer.framing = framing;
+        let sent = writer.send(&response).and_then(|()| {
+            server_log!(LogLevel::Debug, "Response sent for: {}", request.method);
+            // Log entries queued while handling, and the line above, go out
+            // right after the response rather than after the next one
+            protocol::take_log_notifications()
+                .iter()
+                .try_for_each(|n| writer.send(n))
+        });
+        drop(writer);
+        if sent.is_err() {
+            return false;
+        }
+    }
+    true
+}
+
+/// Push completion events to their connections as they happen. Each round
+/// finalizes finished background tasks, skipping rounds while a request is
+/// in flight.
+fn spawn_push_notifier(state: &Arc<ServerState>) {
+    state.pushing_events.store(true, Ordering::Relaxed);
+    let state = Arc::clone(state);
+    let interval = std::time::Duration::from_millis(PUSH_CHECK_INTERVAL_MS);
+    std::thread::spawn(move || loop {
+        std::thread::sleep(interval);
+        let Ok(_gate) = state.request_gate.try_write() else {
             continue;
+        };
+        check_and_finalize_background_tasks(&state);
+        for (connection, notification) in take_event_notifications(&state) {
+            let writer = state.push_writers.lock().unwrap().get(&connection).cloned();
+            let Some(writer) = writer else {
+                continue;
+            };
+            if writer.lock().unwrap().send(&notification).is_err() {
+                state.push_writers.lock().unwrap().remove(&connection);
+            }
+        }
+    });
+}
+
+/// Remember `task_id` as spawned by the request this thread is answering,
+/// dropping entries whose tasks are no longer running.
+fn register_request_task(state: &Arc<ServerState>, task_id: &str) {
+    let Some(request) = CURRENT_REQUEST.with(|r| r.borrow().clone()) else {
+        return;
+    };
+    let running: std::collections::HashSet<String> = state.tasks.lock().unwrap().tasks.values()
+        .filter(|t| t.status == "running")
+        .map(|t| t.task_id.clone())
+        .collect();
+    let mut map = state.request_tasks.lock().unwrap();
+    map.retain(|_, tid| running.contains(tid));
+    map.insert((CURRENT_CONNECTION.with(Cell::get), request), task_id.to_string());
+}
+
+/// Handle `notifications/cancelled`: kill the task the cancelled request
+/// spawned on this connection, if it is still running. Requests are answered
+/// one at a time, so this lands once the request has returned, typically
+/// after a yield.
+fn cancel_request(state: &Arc<ServerState>, params: Option<&Value>) {
+    let Some(request) = params.and_then(|p| p.get("requestId")).map(Value::to_string) else {
+        return;
+    };
+    let key = (CURRENT_CONNECTION.with(Cell::get), request);
+    let Some(task_id) = state.request_tasks.lock().unwrap().remove(&key) else {
+        return;
+    };
+    let running = state.tasks.lock().unwrap().tasks.get(&task_id)
+        .is_some_and(|t| t.status == "running");
+    if running {
+        server_log!(LogLevel::Info, "Request {} cancelled — killing task {}", key.1, task_id);
+        handle_kill(state, &serde_json::json!({"task_id": task_id}));
+    }
+}
+
+/// This server's meta file directory: `meta_dir` plus a subdirectory keyed
+/// by pid, so concurrent servers sharing `meta_dir` never collide.
+fn session_meta_dir(state: &Arc<ServerState>) -> std::path::PathBuf {
+    std::path::Path::new(&state.config.meta_dir)
+        .join(format!("zsh-tool-meta-{}", std::process::id()))
+}
+
+/// Meta file path for `task_id`, creating the directory if needed. It must
+/// be private to us: its name is predictable, and whoever can write it
+/// could forge a task's pipestatus.
+fn task_meta_path(state: &Arc<ServerState>, task_id: &str) -> Result<String, String> {
+    let dir = session_meta_dir(state);
+    std::fs::create_dir_all(&state.config.meta_dir)
+        .map_err(|e| e.to_string())
+        .and_then(|()| resources::create_private_dir(&dir))
+        .map_err(|e| format!("Cannot create meta directory {}: {}", dir.display(), e))?;
+    Ok(dir.join(format!("{}.json", task_id)).to_string_lossy().into_owned())
+}
+
+/// Remove this server's meta file directory.
+fn remove_meta_dir(state: &Arc<ServerState>) {
+    let _ = std::fs::remove_dir_all(session_meta_dir(state));
+}
+
+/// Stop running tasks and drop this session's output resources.
+fn shutdown(state: &Arc<ServerState>) {
+    shutdown_tasks(state);
+    resources::remove_all(&state.session_id);
+    remove_meta_dir(state);
+}
+
+/// Exit the process once the session has been idle for
+/// `session_idle_timeout_secs`. Does nothing when the timeout is 0.
+fn spawn_idle_watcher(state: &Arc<ServerState>) {
+    let timeout = state.config.session_idle_timeout_secs;
+    if timeout == 0 {
+        return;
+    }
+    let state = Arc::clone(state);
+    let interval = std::time::Duration::from_millis(IDLE_CHECK_INTERVAL_MS.min(timeout * 1000));
+    std::thread::spawn(move || loop {
+        std::thread::sleep(interval);
+        if idle_expired(&state) {
+            server_log!(LogLevel::Info, "No requests for {}s and no running tasks — shutting down", timeout);
+            shutdown(&state);
+            std::process::exit(0);
         }
+    });
+}
+
+/// Whether no request has arrived for `session_idle_timeout_secs`. A live
+/// task counts as activity, so the clock restarts when the last one exits.
+fn idle_expired(state: &Arc<ServerState>) -> bool {
+    let timeout = state.config.session_idle_timeout_secs;
+    if timeout == 0 {
+        return false;
+    }
+    let busy = state.tasks.lock().unwrap().tasks.values_mut().any(|t| {
+        t.status == "running"
+            && t.child.as_mut().is_some_and(|c| matches!(c.try_wait(), Ok(None)))
+    });
+    let mut last = state.last_activity.lock().unwrap();
+    if busy {
+        *last = std::time::Instant::now();
+        return false;
+    }
+    last.elapsed() >= std::time::Duration::from_secs(timeout)
+}
 
-        eprintln!("[zsh-tool] Request: {} (id={:?})", request.method, request.id);
-        let response = handle_request(&state, &request.method, request.id.clone(), request.params);
-        write_message(&mut writer, &response);
-        eprintln!("[zsh-tool] Response sent for: {}", request.method);
+/// Kill and reap every running task so none outlive the server.
+fn shutdown_tasks(state: &Arc<ServerState>) {
+    let mut tasks = state.tasks.lock().unwrap();
+    let running: Vec<&mut TaskInfo> = tasks
+        .tasks
+        .values_mut()
+        .filter(|t| t.status == "running")
+        .collect();
+    if running.is_empty() {
+        return;
+    }
+    for task in &running {
+        if let Some(pid) = task.pid {
+            unsafe {
+                libc::kill(pid as i32, libc::SIGTERM);
+            }
+        }
+    }
+    std::thread::sleep(std::time::Duration::from_millis(100));
+    for task in running {
+        if let Some(pid) = task.pid {
+            unsafe {
+                libc::kill(pid as i32, libc::SIGKILL);
+            }
+        }
+        if let Some(ref mut child) = task.child {
+            let _ = child.wait();
+        }
+        reap::kill_tagged(&task.reap_token);
+        let _ = std::fs::remove_file(&task.meta_path);
+        task.child = None;
+        task.reader = None;
+        task.stdin = None;
+        task.status = "killed".to_string();
     }
-    eprintln!("[zsh-tool] stdin closed — shutting down");
 }
 
 fn handle_request(
@@ -118,15 +613,25 @@ fn handle_request(
 ) -> JsonRpcResponse {
     match method {
         "initialize" => {
-            let result = initialize_result("zsh-tool", env!("CARGO_PKG_VERSION"));
+            let requested = params.as_ref().and_then(|p| p.get("protocolVersion")).and_then(|v| v.as_str());
+            let protocol_version = protocol::negotiate_protocol_version(requested);
+            PROTOCOL_VERSION.with(|v| v.set(protocol_version));
+            let result = initialize_result(
+                "zsh-tool",
+                env!("CARGO_PKG_VERSION"),
+                protocol_version,
+                state.pushing_events.load(Ordering::Relaxed),
+            );
             JsonRpcResponse::success(id, result)
         }
         "tools/list" => {
-            let result = tools::list_tools(
+            let mut result = tools::list_tools(
                 state.config.neverhang_timeout_default,
                 state.config.neverhang_timeout_max,
                 state.config.yield_after_default,
             );
+            tools::apply_description_overrides(&mut result, &state.config.tool_descriptions);
+            tools::retain_enabled(&mut result, &state.config);
             JsonRpcResponse::success(id, result)
         }
         "tools/call" => {
@@ -140,54 +645,128 @@ fn handle_request(
                 .cloned()
                 .unwrap_or(Value::Object(serde_json::Map::new()));
 
+            CURRENT_REQUEST.with(|r| *r.borrow_mut() = id.as_ref().map(Value::to_string));
             let result = handle_tool_call(state, tool_name, &arguments);
+            CURRENT_REQUEST.with(|r| r.borrow_mut().take());
             JsonRpcResponse::success(id, result)
         }
+        "resources/list" => JsonRpcResponse::success(id, resources::list(&state.session_id)),
+        "resources/read" => {
+            let uri = params
+                .as_ref()
+                .and_then(|p| p.get("uri"))
+                .and_then(|v| v.as_str())
+                .unwrap_or("");
+            if uri == resources::ALAN_STATS_URI {
+                return match alan::open_db(&state.db_path) {
+                    Ok(conn) => {
+                        let stats = alan::stats::get_stats(&conn, &state.session_id);
+                        let value = serde_json::to_value(stats).unwrap_or(Value::Null);
+                        JsonRpcResponse::success(id, resources::json_contents(uri, &value))
+                    }
+                    Err(e) => JsonRpcResponse::error(id, -32603, format!("ALAN DB error: {}", e)),
+                };
+            }
+            match resources::read(&state.session_id, uri) {
+                Some(result) => JsonRpcResponse::success(id, result),
+                None => JsonRpcResponse::error(id, -32002, format!("Resource not found: {}", uri)),
+            }
+        }
+        "prompts/list" => JsonRpcResponse::success(id, prompts::list()),
+        "prompts/get" => {
+            let name = params
+                .as_ref()
+                .and_then(|p| p.get("name"))
+                .and_then(|v| v.as_str())
+                .unwrap_or("");
+            if name != prompts::DIAGNOSE_FAILURE {
+                return JsonRpcResponse::error(id, -32602, format!("Unknown prompt: {}", name));
+            }
+            match alan::open_db(&state.db_path) {
+                Ok(conn) => {
+                    let failure = alan::stats::get_session_failures(&conn, &state.session_id, 1).pop();
+                    let insights = failure.as_ref().map_or_else(Vec::new, |f| failure_insights(state, &conn, f));
+                    JsonRpcResponse::success(id, prompts::diagnose_failure(failure.as_ref(), &insights))
+                }
+                Err(e) => JsonRpcResponse::error(id, -32603, format!("ALAN DB error: {}", e)),
+            }
+        }
         "ping" => JsonRpcResponse::success(id, serde_json::json!({})),
+        "logging/setLevel" => {
+            let level = params
+                .as_ref()
+                .and_then(|p| p.get("level"))
+                .and_then(|v| v.as_str())
+                .unwrap_or("");
+            match LogLevel::parse(level) {
+                Some(level) => {
+                    protocol::set_client_log_level(level);
+                    server_log!(LogLevel::Info, "Client log level set to {}", level.as_str());
+                    JsonRpcResponse::success(id, serde_json::json!({}))
+                }
+                None => JsonRpcResponse::error(id, -32602, format!("Invalid log level: {:?}", level)),
+            }
+        }
         _ => JsonRpcResponse::error(id, -32601, format!("Method not found: {}", method)),
     }
 }
 
+/// What ALAN has to say about a failed command: its history (retries,
+/// streaks) and the meaning of its exit code. History is looked up by the
+/// stored hash, since `command` may be a truncated preview.
+fn failure_insights(
+    state: &Arc<ServerState>,
+    conn: &rusqlite::Connection,
+    failure: &alan::stats::SessionFailure,
+) -> Vec<(String, String)> {
+    let mut insights = alan::insights::get_pre_insights_for_hash(
+        conn,
+        &failure.command,
+        &failure.command_hash,
+        &failure.command_template,
+        &state.session_id,
+        state.config.alan_streak_threshold,
+        state.config.alan_recent_window_minutes,
+    );
+    if let Some(code) = failure.last_exit_code.filter(|_| !failure.timed_out) {
+        insights.extend(alan::insights::get_post_insights(&failure.command, &[code as i32], ""));
+    }
+    insights
+}
+
 /// Data needed to finalize a completed task outside the tasks lock.
 type FinalizeArgs = (String, String, String, f64, Vec<(String, String)>, String);
 
-/// If `task_id` is running and its child has exited, drain stdout, mark completed,
-/// and return finalization arguments. Returns None if still running or not found.
+/// If `task_id` is running and its child has exited, wait for the reader to hit
+/// EOF, mark completed, and return finalization arguments. Returns None if still
+/// running or not found.
 fn collect_if_done(
     state: &Arc<ServerState>,
     task_id: &str,
 ) -> Option<FinalizeArgs> {
-    let mut tasks = state.tasks.lock().unwrap();
-    let task = tasks.tasks.get_mut(task_id)?;
-    if task.status != "running" {
-        return None;
-    }
-    let done = task.child.as_mut()
-        .and_then(|c| c.try_wait().ok().flatten())
-        .is_some();
-    if !done {
-        return None;
-    }
-    // Drain remaining output (switch to blocking for clean EOF)
-    if let Some(ref mut stdout) = task.stdout {
-        use std::io::Read;
-        #[cfg(unix)]
-        {
-            use std::os::unix::io::AsRawFd;
-            let fd = stdout.as_raw_fd();
-            unsafe {
-                let flags = libc::fcntl(fd, libc::F_GETFL);
-                libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_NONBLOCK);
-            }
+    let reader = {
+        let mut tasks = state.tasks.lock().unwrap();
+        let task = tasks.tasks.get_mut(task_id)?;
+        if task.status != "running" {
+            return None;
+        }
+        let done = task.child.as_mut()
+            .and_then(|c| c.try_wait().ok().flatten())
+            .is_some();
+        if !done {
+            return None;
         }
-        let mut remaining = String::new();
-        let _ = stdout.read_to_string(&mut remaining);
-        task.output_buffer.push_str(&remaining);
+        task.child = None;
+        task.stdin = None;
+        task.status = "completed".to_string();
+        task.reader.take()
+    };
+    // The reader appends under the tasks lock, so join without holding it
+    if let Some(handle) = reader {
+        let _ = handle.join();
     }
-    task.child = None;
-    task.stdout = None;
-    task.stdin = None;
-    task.status = "completed".to_string();
+    let tasks = state.tasks.lock().unwrap();
+    let task = tasks.tasks.get(task_id)?;
     Some((
         task.task_id.clone(),
         task.command.clone(),
@@ -212,23 +791,69 @@ fn check_and_finalize_background_tasks(state: &Arc<ServerState>) {
         if let Some((tid, cmd, output, elapsed, pre, meta)) = collect_if_done(state, &task_id) {
             // suppress_notification=false: background completion, enqueue notification
             finalize_task(state, &tid, &cmd, &output, elapsed, &pre, &meta, false, None);
+        } else if state.config.memory_warn_mb > 0 {
+            let mut tasks = state.tasks.lock().unwrap();
+            if let Some(task) = tasks.tasks.get_mut(&task_id) {
+                task.rss_bytes = reap::tagged_rss_bytes(&task.reap_token);
+            }
         }
     }
 }
 
+/// Warning for a running task whose RSS crossed the soft `memory_warn_mb` threshold.
+fn memory_insight(state: &Arc<ServerState>, task: &TaskInfo) -> Option<(String, String)> {
+    let limit_mb = state.config.memory_warn_mb;
+    let used_mb = task.rss_bytes / (1024 * 1024);
+    if limit_mb == 0 || used_mb < limit_mb {
+        return None;
+    }
+    Some((
+        "warning".to_string(),
+        format!(
+            "Using {} MB — approaching limit (soft threshold {} MB). Kill with zsh_kill if it keeps growing.",
+            used_mb, limit_mb
+        ),
+    ))
+}
+
 fn handle_tool_call(state: &Arc<ServerState>, tool_name: &str, args: &Value) -> Value {
+    if !state.config.tool_enabled(tool_name) {
+        return error_content(&format!("Tool disabled by enabled_tools config: {}", tool_name));
+    }
     check_and_finalize_background_tasks(state);
     let result = match tool_name {
         "zsh" => handle_zsh(state, args),
         "zsh_poll" => handle_poll(state, args),
         "zsh_send" => handle_send(state, args),
         "zsh_kill" => handle_kill(state, args),
+        "zsh_output" => handle_output(state, args),
         "zsh_tasks" => handle_list_tasks(state),
+        "zsh_session_summary" => handle_session_summary(state),
         "zsh_health" => handle_health(state),
         "zsh_alan_stats" => handle_alan_stats(state),
         "zsh_alan_query" => handle_alan_query(state, args),
+        "zsh_estimate" => handle_estimate(state, args),
+        "zsh_alan_templates" => handle_alan_templates(state, args),
+        "zsh_alan_timeouts" => handle_alan_timeouts(state, args),
+        "zsh_alan_check" => handle_alan_check(state),
+        "zsh_alan_export" => handle_alan_export(state, args),
+        "zsh_alan_import" => handle_alan_import(state, args),
+        "zsh_alan_pin" => handle_alan_pin(state, args),
+        "zsh_alan_next" => handle_alan_next(state, args),
+        "zsh_manopt_warm" => handle_manopt_warm(state, args),
+        "zsh_config" => handle_config(state, args),
         "zsh_neverhang_status" => handle_neverhang_status(state),
+        "zsh_debug" => handle_debug(args),
+        "zsh_sequence" => handle_sequence(state, args),
+        "zsh_benchmark" => handle_benchmark(state, args),
+        "zsh_check" => handle_check(state, args),
+        "zsh_cwd" => handle_cwd(state, args),
+        "zsh_env" => handle_env(state, args),
+        "zsh_env_snapshot" => handle_env_snapshot(state),
+        "zsh_env_diff" => handle_env_diff(state),
+        "zsh_retry_verbose" => handle_retry_verbose(state, args),
         "zsh_neverhang_reset" => handle_neverhang_reset(state),
+        "zsh_circuit_trip" => handle_circuit_trip(state),
         _ => return error_content(&format!("Unknown tool: {}", tool_name)),
     };
     prepend_events(state, result)
@@ -236,34 +861,94 @@ fn handle_tool_call(state: &Arc<ServerState>, tool_name: &str, args: &Value) ->
 
 // --- Tool handlers ---
 
-/// Non-blocking read of available bytes from a ChildStdout.
-/// Sets O_NONBLOCK on the fd, reads what's available, returns it.
-fn read_available(stdout: &mut ChildStdout) -> String {
-    use std::io::Read;
-    #[cfg(unix)]
-    {
-        use std::os::unix::io::AsRawFd;
-        let fd = stdout.as_raw_fd();
-        unsafe {
-            let flags = libc::fcntl(fd, libc::F_GETFL);
-            libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK);
-        }
-        let mut collected = Vec::new();
+/// Drain a task's stdout into its `output_buffer` as it arrives, so output is
+/// captured regardless of poll timing and the command never stalls on a full
+/// pipe. Runs until EOF, i.e. until every process holding the pipe is gone.
+fn spawn_output_reader(
+    state: &Arc<ServerState>,
+    task_id: &str,
+    mut stdout: ChildStdout,
+) -> JoinHandle<()> {
+    let state = Arc::clone(state);
+    let task_id = task_id.to_string();
+    std::thread::spawn(move || {
+        use std::io::Read;
         let mut buf = [0u8; 65536];
+        // Bytes of a UTF-8 sequence split across reads
+        let mut pending: Vec<u8> = Vec::new();
         loop {
-            match stdout.read(&mut buf) {
-                Ok(0) => break,   // EOF
-                Ok(n) => collected.extend_from_slice(&buf[..n]),
-                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
-                Err(_) => break,
+            let n = match stdout.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => n,
+            };
+            pending.extend_from_slice(&buf[..n]);
+            let text = take_utf8_prefix(&mut pending);
+            if !text.is_empty() {
+                append_output(&state, &task_id, &text);
             }
         }
-        String::from_utf8_lossy(&collected).to_string()
+        if !pending.is_empty() {
+            append_output(&state, &task_id, &String::from_utf8_lossy(&pending));
+        }
+    })
+}
+
+fn append_output(state: &Arc<ServerState>, task_id: &str, text: &str) {
+    if let Some(task) = state.tasks.lock().unwrap().tasks.get_mut(task_id) {
+        if let Some(ref mut stamps) = task.line_stamps {
+            let ms = task.started_at.elapsed().as_millis() as u64;
+            record_line_stamps(stamps, &task.output_buffer, text, ms);
+        }
+        task.output_buffer.push_str(text);
     }
-    #[cfg(not(unix))]
-    {
-        String::new()
+}
+
+/// Stamp every line that starts in `text`, which is about to be appended to
+/// `buffer`, with `ms`. A line starts at its first byte, so a trailing
+/// newline leaves the next line to be stamped when its output arrives.
+fn record_line_stamps(stamps: &mut Vec<u64>, buffer: &str, text: &str, ms: u64) {
+    let mut at_line_start = buffer.is_empty() || buffer.ends_with('\n');
+    for byte in text.bytes() {
+        if at_line_start {
+            stamps.push(ms);
+        }
+        at_line_start = byte == b'\n';
+    }
+}
+
+/// `text`, the lines of a task's output from line index `first_line` on,
+/// with each line prefixed by its `timestamp_lines` stamp.
+fn stamp_lines(text: &str, first_line: usize, stamps: Option<&[u64]>) -> String {
+    let Some(stamps) = stamps else {
+        return text.to_string();
+    };
+    let mut stamped = String::with_capacity(text.len() + stamps.len() * 12);
+    for (i, line) in text.split_inclusive('\n').enumerate() {
+        if let Some(ms) = stamps.get(first_line + i) {
+            stamped.push_str(&line_stamp(*ms));
+        }
+        stamped.push_str(line);
     }
+    stamped
+}
+
+/// Prefix marking a line captured `ms` after the command started.
+fn line_stamp(ms: u64) -> String {
+    format!("[+{}ms] ", ms)
+}
+
+/// Remove and decode the longest prefix of `bytes` that does not end inside a
+/// UTF-8 sequence. Invalid bytes are replaced; an incomplete tail is kept.
+fn take_utf8_prefix(bytes: &mut Vec<u8>) -> String {
+    let cut = match std::str::from_utf8(bytes) {
+        Ok(_) => bytes.len(),
+        Err(e) if e.error_len().is_none() => e.valid_up_to(),
+        Err(_) => bytes.len(),
+    };
+    let rest = bytes.split_off(cut);
+    let text = String::from_utf8_lossy(bytes).into_owned();
+    *bytes = rest;
+    text
 }
 
 /// Finalize a completed task: read meta, compute insights, update circuit breaker, prune.
@@ -281,6 +966,26 @@ fn finalize_task(
     meta_path: &str,
     suppress_notification: bool,
     output_override: Option<(&str, usize, usize)>,  // (numbered_output, from_line, to_line)
+) -> Value {
+    let result = finalize_result(
+        state, task_id, command, output, elapsed, pre_insights, meta_path,
+        suppress_notification, output_override,
+    );
+    rich_content(state, &result)
+}
+
+/// [`finalize_task`] without the rich rendering.
+#[allow(clippy::too_many_arguments)]
+fn finalize_result(
+    state: &Arc<ServerState>,
+    task_id: &str,
+    command: &str,
+    output: &str,
+    elapsed: f64,
+    pre_insights: &[(String, String)],
+    meta_path: &str,
+    suppress_notification: bool,
+    output_override: Option<(&str, usize, usize)>,
 ) -> Value {
     // Read meta.json for pipestatus
     let meta = std::fs::read_to_string(meta_path)
@@ -297,25 +1002,49 @@ fn finalize_task(
                 .collect()
         })
         .unwrap_or_else(|| vec![0]);
+    let timed_out = meta
+        .as_ref()
+        .and_then(|m| m.get("timed_out"))
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
 
-    let overall_exit = *pipestatus.last().unwrap_or(&0);
+    // Timed-out commands carry no pipestatus; fall back to the exec exit code
+    let overall_exit = pipestatus.last().copied().unwrap_or_else(|| {
+        meta.as_ref()
+            .and_then(|m| m.get("exit_code"))
+            .and_then(|v| v.as_i64())
+            .map(|n| n as i32)
+            .unwrap_or(-1)
+    });
+    let status = if timed_out { "timeout" } else { "completed" };
+
+    // Keep pipestatus on the registry entry for later zsh_poll/zsh_output calls
+    let (retry_info, extract_json, render_terminal, want_hash, line_stamps) =
+        match state.tasks.lock().unwrap().tasks.get_mut(task_id) {
+            Some(task) => {
+                task.pipestatus = pipestatus.clone();
+                task.status = status.to_string();
+                close_spool(state, task);
+                (task.retry_info.clone(), task.extract_json, task.render_terminal, task.output_hash, task.line_stamps.clone())
+            }
+            None => (None, false, false, false, None),
+        };
+    let rendered;
+    let output = if render_terminal {
+        rendered = terminal::render(output);
+        rendered.as_str()
+    } else {
+        output
+    };
 
-    let post_insights = alan::insights::get_post_insights(command, &pipestatus, output);
+    let mut post_insights = alan::insights::get_post_insights_with_silent(
+        command, &pipestatus, output, &state.config.silent_commands,
+    );
+    post_insights.extend(truncation_insight(state, command, output));
     let insights = combine_insights(pre_insights, &post_insights);
 
-    // Circuit breaker
-    {
-        let mut cb = state.circuit_breaker.lock().unwrap();
-        let timed_out = meta
-            .as_ref()
-            .and_then(|m| m.get("timed_out"))
-            .and_then(|v| v.as_bool())
-            .unwrap_or(false);
-        if timed_out {
-            cb.record_timeout(&alan::hash::hash_command(command));
-        } else {
-            cb.record_success();
-        }
+    if !DEFER_BREAKER.with(Cell::get) {
+        record_breaker(state, command, timed_out, overall_exit);
     }
 
     // Maybe prune
@@ -338,7 +1067,7 @@ fn finalize_task(
     let (final_output, from_line, to_line) = match output_override {
         Some((numbered, fl, tl)) => (numbered.to_string(), fl, tl),
         None => {
-            let out = truncate_output(output, state.config.truncate_output_at);
+            let out = cap_output(state, &stamp_lines(output, 0, line_stamps.as_deref()));
             (out, 0, 0)
         }
     };
@@ -347,73 +1076,613 @@ fn finalize_task(
         "success": overall_exit == 0,
         "task_id": task_id,
         "command": command,
-        "status": "completed",
+        "status": status,
         "output": final_output,
         "elapsed_seconds": format!("{:.1}", elapsed).parse::<f64>().unwrap_or(elapsed),
         "pipestatus": pipestatus,
         "insights": insights,
+        "output_empty": output.trim().is_empty(),
+        "started_at": format_timestamp(state.config.timestamp_format, now_epoch() - elapsed),
+        "finished_at": format_timestamp(state.config.timestamp_format, now_epoch()),
     });
     if from_line > 0 {
         result["from_line"] = serde_json::json!(from_line);
         result["to_line"] = serde_json::json!(to_line);
     }
-    text_content(&format::format_rich_output(result.as_object().unwrap()))
+    if let Some(segments) = pipe_segments(command, &pipestatus) {
+        result["segments"] = segments;
+    }
+    if timed_out {
+        result["timed_out"] = serde_json::json!(true);
+        result["error"] = serde_json::json!(format!(
+            "NEVERHANG: killed after {:.0}s timeout; per-segment exit statuses unavailable",
+            elapsed
+        ));
+    }
+    if let Some(ms) = meta.as_ref().and_then(|m| m.get("elapsed_ms")).and_then(|v| v.as_u64()) {
+        result["exec_elapsed_ms"] = serde_json::json!(ms);
+    }
+    if let Some(stderr) = meta.as_ref().and_then(|m| m.get("stderr")).and_then(|v| v.as_str()) {
+        result["stderr"] = serde_json::json!(cap_output(state, stderr));
+    }
+    if exceeds_output_cap(state, output) {
+        // Full output stays reachable through resources/read
+        match resources::write_output(&state.session_id, task_id, output) {
+            Ok(uri) => result["output_resource"] = resources::link(&uri, task_id, output.len() as u64),
+            Err(e) => server_log!(LogLevel::Warning, "Output resource for {}: {}", task_id, e),
+        }
+    }
+    if extract_json && !timed_out {
+        attach_json(&mut result, output);
+    }
+    if want_hash {
+        result["output_hash"] = Value::String(output_sha256(output));
+    }
+    attach_retry_info(&mut result, &retry_info);
+    result
 }
 
-fn handle_zsh(state: &Arc<ServerState>, args: &Value) -> Value {
-    let command = match args.get("command").and_then(|v| v.as_str()) {
-        Some(c) => c,
-        None => return error_content("Missing required parameter: command"),
-    };
-
-    let use_pty = args.get("pty").and_then(|v| v.as_bool()).unwrap_or(false);
-    let timeout = args
-        .get("timeout")
-        .and_then(|v| v.as_u64())
-        .unwrap_or(state.config.neverhang_timeout_default)
-        .min(state.config.neverhang_timeout_max);
-    let yield_after = args
-        .get("yield_after")
-        .and_then(|v| v.as_f64())
-        .unwrap_or(state.config.yield_after_default);
-
-    // Circuit breaker check
-    {
-        let mut cb = state.circuit_breaker.lock().unwrap();
-        let (allowed, msg) = cb.should_allow();
-        if !allowed {
-            let error_msg = msg.unwrap_or_else(|| "NEVERHANG: Circuit OPEN".into());
-            let result = serde_json::json!({
-                "success": false,
-                "error": error_msg,
-                "command": command,
-                "task_id": "",
-                "status": "error",
-                "output": "",
-                "elapsed_seconds": 0,
-            });
-            return text_content(&format::format_rich_output(result.as_object().unwrap()));
+/// Parse a finished command's output for `extract_json`: the value goes in
+/// `json`, or a warning insight explains why the text is all there is.
+fn attach_json(result: &mut Value, output: &str) {
+    match serde_json::from_str::<Value>(output.trim()) {
+        Ok(json) => result["json"] = json,
+        Err(e) => {
+            let note = Value::String(format!("extract_json: output is not valid JSON ({}); returned as text", e));
+            match result["insights"]["warning"].as_array_mut() {
+                Some(warnings) => warnings.push(note),
+                None => result["insights"]["warning"] = Value::Array(vec![note]),
+            }
         }
     }
+}
 
-    // Get pre-insights from ALAN
-    let pre_insights = if let Ok(conn) = alan::open_db(&state.db_path) {
-        alan::insights::get_pre_insights(
-            &conn,
-            command,
-            &state.session_id,
-            state.config.alan_streak_threshold,
-            state.config.alan_recent_window_minutes,
-        )
-    } else {
-        Vec::new()
-    };
+/// Hex SHA-256 of a command's full, untruncated output (`output_hash`).
+fn output_sha256(output: &str) -> String {
+    use sha2::{Digest, Sha256};
+    format!("{:x}", Sha256::digest(output.as_bytes()))
+}
 
-    // Execute command via spawning self as `exec`
-    let task_id = uuid::Uuid::new_v4().to_string()[..8].to_string();
-    let exec_path = std::env::current_exe().unwrap_or_else(|_| "zsh-tool-exec".into());
+/// Pair each pipeline segment with its exit code: `[{"command": "false", "exit_code": 1}, ...]`.
+/// Returns None for single commands, or when the segment count doesn't match
+/// pipestatus (e.g. `a | b && c`, where pipestatus covers only the last pipeline).
+fn pipe_segments(command: &str, pipestatus: &[i32]) -> Option<Value> {
+    if pipestatus.len() < 2 {
+        return None;
+    }
+    let segments = alan::pipeline::parse_pipeline(command);
+    if segments.len() != pipestatus.len() {
+        return None;
+    }
+    Some(Value::Array(
+        segments
+            .iter()
+            .zip(pipestatus.iter())
+            .map(|(seg, &code)| serde_json::json!({"command": seg, "exit_code": code}))
+            .collect(),
+    ))
+}
+
+/// Whether to capture stderr apart from stdout: the caller's `separate_stderr`
+/// arg wins, then the configured default for the base command, else merged.
+fn effective_separate_stderr(state: &Arc<ServerState>, command: &str, args: &Value) -> bool {
+    if let Some(explicit) = args.get("separate_stderr").and_then(|v| v.as_bool()) {
+        return explicit;
+    }
+    let base = alan::insights::extract_base_command(command);
+    state.config.separate_stderr.get(&base).copied().unwrap_or(false)
+}
+
+/// Why `handle_zsh` refused to run a command.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BlockReason {
+    Denied,
+    NotAllowlisted,
+    CircuitOpen,
+    RateLimited,
+    DangerousCwd,
+}
+
+impl BlockReason {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Denied => "denied",
+            Self::NotAllowlisted => "not_allowlisted",
+            Self::CircuitOpen => "circuit_open",
+            Self::RateLimited => "rate_limited",
+            Self::DangerousCwd => "dangerous_cwd",
+        }
+    }
+}
+
+/// Result for a command refused before it ran. Every guard produces the same
+/// shape: `status: "blocked"`, a stable `blocked_reason` and a human `detail`
+/// (mirrored in `error`). `extra` fields are merged in; insights are only
+/// attached with `blocked_detail: full`.
+fn blocked_result(
+    state: &Arc<ServerState>,
+    command: &str,
+    reason: BlockReason,
+    detail: &str,
+    insights: &[(String, String)],
+    extra: Value,
+) -> Value {
+    let detail = match state.config.blocked_detail {
+        BlockDetail::Brief => detail.lines().next().unwrap_or(""),
+        BlockDetail::Full => detail,
+    };
+    let mut result = serde_json::json!({
+        "success": false,
+        "blocked": true,
+        "blocked_reason": reason.as_str(),
+        "detail": detail,
+        "error": detail,
+        "command": command,
+        "task_id": "",
+        "status": "blocked",
+        "output": "",
+        "elapsed_seconds": 0,
+    });
+    if state.config.blocked_detail == BlockDetail::Full {
+        result["insights"] = Value::Object(combine_insights(insights, &[]));
+    }
+    if let (Some(map), Some(extra)) = (result.as_object_mut(), extra.as_object()) {
+        map.extend(extra.clone());
+    }
+    result
+}
+
+/// ALAN (and opt-in network) insights for a command before it runs, plus the
+/// retry counts behind them (None when the ALAN DB is unavailable).
+fn pre_insights_for(
+    state: &Arc<ServerState>,
+    command: &str,
+) -> (Vec<(String, String)>, Option<RetryInfo>) {
+    let (mut insights, retry_info) = if let Ok(conn) = alan::open_db(&state.db_path) {
+        let (insights, retry_info) = alan::insights::get_pre_insights_with_retry(
+            &conn,
+            command,
+            &state.session_id,
+            state.config.alan_streak_threshold,
+            state.config.alan_recent_window_minutes,
+        );
+        (insights, Some(retry_info))
+    } else {
+        (Vec::new(), None)
+    };
+    if state.config.network_check_enabled {
+        insights.extend(alan::network::get_network_insights(
+            command,
+            &state.config.network_check_host,
+            std::time::Duration::from_millis(state.config.network_check_timeout_ms),
+        ));
+    }
+    (insights, retry_info)
+}
+
+/// Add `retry_info` to a `zsh` result when ALAN produced it.
+fn attach_retry_info(result: &mut Value, retry_info: &Option<RetryInfo>) {
+    if let Some(info) = retry_info {
+        result["retry_info"] = serde_json::to_value(info).unwrap_or(Value::Null);
+    }
+}
+
+/// The allow/deny list guard shared by `zsh` and `zsh_check`. Returns the
+/// block reason, a detail line and the deciding pattern when the command
+/// may not run.
+fn policy_block(
+    state: &Arc<ServerState>,
+    command: &str,
+) -> Option<(BlockReason, String, Option<String>)> {
+    match policy::check(
+        command,
+        &state.config.deny_commands,
+        state.config.allow_commands.as_deref(),
+    ) {
+        Verdict::Allowed(_) => None,
+        Verdict::Denied(pattern) => Some((
+            BlockReason::Denied,
+            format!("Command matches deny_commands pattern '{}'", pattern),
+            Some(pattern),
+        )),
+        Verdict::NotAllowlisted => Some((
+            BlockReason::NotAllowlisted,
+            "Command matches no allow_commands pattern".to_string(),
+            None,
+        )),
+    }
+}
+
+/// With `home_root_guard` on, a detail line when `command` deletes files and
+/// would run in `$HOME` or `/` without `confirm: true`, whether it starts
+/// there or gets there with a `cd` earlier in the chain.
+fn home_root_block(state: &Arc<ServerState>, command: &str, args: &Value) -> Option<String> {
+    if !state.config.home_root_guard || args.get("confirm").and_then(|v| v.as_bool()) == Some(true) {
+        return None;
+    }
+    let dirs = policy::destructive_cwds(command);
+    if dirs.is_empty() {
+        return None;
+    }
+    let start = state.cwd.lock().unwrap().clone().map(std::path::PathBuf::from)
+        .or_else(|| std::env::current_dir().ok())?;
+    let home = std::env::var_os("HOME").map(std::path::PathBuf::from);
+    let resolve = |dir: Option<&str>| {
+        let path = match dir {
+            None => start.clone(),
+            Some(dir) => {
+                let under_home = ["~", "${HOME}", "$HOME"].iter().find_map(|prefix| {
+                    let rest = dir.strip_prefix(prefix)?;
+                    (rest.is_empty() || rest.starts_with('/')).then(|| rest.trim_start_matches('/'))
+                });
+                match (under_home, &home) {
+                    (Some(rest), Some(home)) => home.join(rest),
+                    _ => start.join(dir),
+                }
+            }
+        };
+        path.canonicalize().unwrap_or(path)
+    };
+    let real_home = home.as_ref().map(|h| h.canonicalize().unwrap_or_else(|_| h.clone()));
+    let cwd = dirs
+        .into_iter()
+        .map(resolve)
+        .find(|dir| dir == std::path::Path::new("/") || Some(dir) == real_home.as_ref())?;
+    Some(format!(
+        "Destructive command in {}. Pass confirm: true to run it there anyway, or set a safer directory with zsh_cwd.",
+        cwd.display()
+    ))
+}
+
+/// With `pty_fallback` on, the arguments for re-running a non-PTY command in
+/// PTY mode when it finished with a TTY error.
+fn pty_retry_args(state: &Arc<ServerState>, args: &Value, result: &Value) -> Option<Value> {
+    if !state.config.pty_fallback
+        || args.get("pty").and_then(|v| v.as_bool()).unwrap_or(false)
+        || args.get("stdin_fifo").is_some()
+        || result["status"] != "completed"
+        || result["success"] != false
+    {
+        return None;
+    }
+    let output = result["output"].as_str().unwrap_or("");
+    let stderr = result["stderr"].as_str().unwrap_or("");
+    if !crate::executor::is_tty_error(output) && !crate::executor::is_tty_error(stderr) {
+        return None;
+    }
+    let mut retry = args.clone();
+    retry["pty"] = Value::Bool(true);
+    Some(retry)
+}
+
+/// `run_zsh`, re-run once in PTY mode when `pty_retry_args` says so.
+fn run_zsh_with_fallback(state: &Arc<ServerState>, args: &Value) -> Result<Value, String> {
+    let result = run_zsh(state, args)?;
+    let retry = match pty_retry_args(state, args, &result) {
+        Some(retry) => retry,
+        None => return Ok(result),
+    };
+    let mut result = run_zsh(state, &retry)?;
+    result["pty_fallback"] = Value::Bool(true);
+    let note = Value::String("Failed with a TTY error without a terminal; re-ran in PTY mode".to_string());
+    match result["insights"]["info"].as_array_mut() {
+        Some(info) => info.insert(0, note),
+        None => result["insights"]["info"] = Value::Array(vec![note]),
+    }
+    Ok(result)
+}
+
+/// Most re-runs the `zsh` tool's `retries` argument allows.
+const MAX_RETRIES: u64 = 5;
+
+/// Pause before the Nth retry: N times this.
+const RETRY_BACKOFF_MS: u64 = 500;
+
+/// How a finished `zsh` result failed, with a description for notes. None
+/// when there is nothing to retry: it succeeded, was blocked or is still
+/// running.
+fn retry_class(state: &Arc<ServerState>, result: &Value) -> Option<(alan::insights::FailureClass, String)> {
+    if result["success"] != false || !(result["status"] == "completed" || result["status"] == "timeout") {
+        return None;
+    }
+    let timed_out = result["timed_out"] == true;
+    let exit_code = result["pipestatus"].as_array().and_then(|p| p.last()).and_then(|c| c.as_i64()).unwrap_or(-1) as i32;
+    let class = alan::insights::classify_failure(
+        exit_code,
+        timed_out,
+        &state.config.retry_exit_codes,
+        state.config.retry_timeouts,
+    );
+    let what = if timed_out { "timeout".to_string() } else { alan::insights::describe_exit(exit_code) };
+    Some((class, what))
+}
+
+/// Feed one finished command's outcome to the circuit breaker.
+fn record_breaker(state: &Arc<ServerState>, command: &str, timed_out: bool, exit_code: i32) {
+    let mut cb = state.circuit_breaker.lock().unwrap();
+    let command_hash = alan::hash::hash_command(command);
+    if timed_out {
+        cb.record_timeout(&command_hash);
+    } else if exit_code != 0 {
+        cb.record_failure(&command_hash, exit_code);
+    } else {
+        cb.record_success(&command_hash);
+    }
+}
+
+/// `run_zsh_with_fallback`, re-run up to the call's `retries` times while
+/// it fails transiently (see `alan::insights::classify_failure`). Permanent
+/// and other failures return at once, as do runs still going when they yield.
+/// The call feeds the circuit breaker once, with its final outcome; a run
+/// that yields is recorded when it finishes, like any other.
+fn run_zsh_with_retries(state: &Arc<ServerState>, args: &Value) -> Result<Value, String> {
+    DEFER_BREAKER.with(|d| d.set(true));
+    let result = run_attempts(state, args);
+    DEFER_BREAKER.with(|d| d.set(false));
+    SPAWN_DELAY_MS.with(|d| d.set(0));
+    let result = result?;
+    if result["status"] == "completed" || result["status"] == "timeout" {
+        let exit_code = result["pipestatus"].as_array().and_then(|p| p.last()).and_then(|c| c.as_i64());
+        let exit_code = exit_code.unwrap_or(if result["success"] == true { 0 } else { -1 }) as i32;
+        let command = result["command"].as_str().unwrap_or_default();
+        record_breaker(state, command, result["timed_out"] == true, exit_code);
+    }
+    Ok(result)
+}
+
+/// The attempts of [`run_zsh_with_retries`]. A retry's backoff is waited out
+/// by its exec process, not here, so the request loop stays free for other
+/// requests (`zsh_kill`, cancellation) once the attempt yields.
+fn run_attempts(state: &Arc<ServerState>, args: &Value) -> Result<Value, String> {
+    use alan::insights::FailureClass;
+    let retries = args.get("retries").and_then(|v| v.as_u64()).unwrap_or(0).min(MAX_RETRIES);
+    let mut result = run_zsh_with_fallback(state, args)?;
+    if retries == 0 {
+        return Ok(result);
+    }
+    let mut attempts = 1;
+    let mut transient = Vec::new();
+    let note = loop {
+        match retry_class(state, &result) {
+            Some((FailureClass::Transient, what)) if attempts <= retries => {
+                transient.push(what);
+                SPAWN_DELAY_MS.with(|d| d.set(RETRY_BACKOFF_MS * attempts));
+                result = run_zsh_with_fallback(state, args)?;
+                attempts += 1;
+            }
+            Some((FailureClass::Transient, what)) => {
+                break Some(format!("Still failing after {} retries: {}", retries, what));
+            }
+            Some((FailureClass::Permanent, what)) => {
+                break Some(format!("Not retried: {} is a permanent failure", what));
+            }
+            Some((FailureClass::Other, what)) => {
+                break Some(format!("Not retried: {} is not a transient failure (retry_exit_codes)", what));
+            }
+            None => break None,
+        }
+    };
+    let mut notes = Vec::new();
+    if attempts > 1 {
+        result["attempts"] = serde_json::json!(attempts);
+        notes.push(format!("Retried {} time(s) after transient failures: {}", attempts - 1, transient.join(", ")));
+    }
+    notes.extend(note);
+    for note in notes {
+        match result["insights"]["info"].as_array_mut() {
+            Some(info) => info.push(Value::String(note)),
+            None => result["insights"]["info"] = Value::Array(vec![Value::String(note)]),
+        }
+    }
+    Ok(result)
+}
+
+fn handle_zsh(state: &Arc<ServerState>, args: &Value) -> Value {
+    match run_zsh_with_retries(state, args) {
+        Ok(result) => rich_content(state, &result),
+        Err(e) => error_content(&e),
+    }
+}
+
+/// Whether `name` is usable as a shell variable name.
+fn valid_env_name(name: &str) -> bool {
+    name.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Parse an optional `{NAME: value}` argument (`param` names it in errors)
+/// into name/value pairs. Names must be shell identifiers and values strings;
+/// an empty value unsets.
+fn parse_env_object(value: Option<&Value>, param: &str) -> Result<Vec<(String, String)>, String> {
+    let obj = match value {
+        None | Some(Value::Null) => return Ok(Vec::new()),
+        Some(Value::Object(obj)) => obj,
+        Some(_) => return Err(format!("{} must be an object of NAME: value strings", param)),
+    };
+    let mut env = Vec::with_capacity(obj.len());
+    for (key, value) in obj {
+        if !valid_env_name(key) {
+            return Err(format!("Invalid env variable name: {}", key));
+        }
+        let value = match value.as_str() {
+            Some(v) if !v.contains('\0') => v,
+            _ => return Err(format!("{} value for {} must be a string without NUL bytes", param, key)),
+        };
+        env.push((key.clone(), value.to_string()));
+    }
+    Ok(env)
+}
+
+/// Run (or yield) one `zsh` call and return the structured result, before
+/// rich rendering. `Err` is a bad request.
+fn run_zsh(state: &Arc<ServerState>, args: &Value) -> Result<Value, String> {
+    let command = match args.get("command").and_then(|v| v.as_str()) {
+        Some(c) => c,
+        None => return Err("Missing required parameter: command".to_string()),
+    };
+    let (normalized, path_changes) = if state.config.normalize_windows_paths {
+        crate::winpath::normalize(command)
+    } else {
+        (String::new(), Vec::new())
+    };
+    let command = if path_changes.is_empty() { command } else { normalized.as_str() };
+
+    let use_pty = args.get("pty").and_then(|v| v.as_bool()).unwrap_or(false);
+    let nice = args
+        .get("nice")
+        .and_then(|v| v.as_i64())
+        .map(|n| crate::executor::clamp_nice(n.clamp(i32::MIN as i64, i32::MAX as i64) as i32));
+    let separate_stderr = !use_pty && effective_separate_stderr(state, command, args);
+    let stream_socket = args
+        .get("stream_socket")
+        .and_then(|v| v.as_str())
+        .filter(|p| !p.is_empty())
+        .map(str::to_string);
+    let timeout = effective_timeout(state, command, args);
+    let yield_after = effective_yield_after(state, command, args);
+    let timeout_signal = match args.get("timeout_signal").and_then(|v| v.as_str()) {
+        Some(name) => match crate::executor::parse_signal(name) {
+            Some(sig) => Some(sig),
+            None => return Err(format!("Unknown timeout_signal: {}", name)),
+        },
+        None => None,
+    };
+    let stdin_fifo = args.get("stdin_fifo").and_then(|v| v.as_str());
+    if let Some(path) = stdin_fifo {
+        use std::os::unix::fs::FileTypeExt;
+        if use_pty {
+            return Err("stdin_fifo cannot be combined with pty".to_string());
+        }
+        match std::fs::metadata(path) {
+            Ok(m) if m.file_type().is_fifo() => {}
+            Ok(_) => return Err(format!("stdin_fifo is not a FIFO: {}", path)),
+            Err(e) => return Err(format!("stdin_fifo {}: {}", path, e)),
+        }
+    }
+
+    // Session variables from zsh_env first so per-call ones override them
+    let mut env: Vec<(String, String)> = {
+        let session_env = state.session_env.lock().unwrap();
+        let mut vars: Vec<_> = session_env.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+        vars.sort();
+        vars
+    };
+    env.extend(parse_env_object(args.get("env"), "env")?);
+
+    // Get pre-insights from ALAN (before the block check, so rejected
+    // commands still carry their history)
+    let (mut pre_insights, retry_info) = pre_insights_for(state, command);
+    if !path_changes.is_empty() {
+        let rewritten: Vec<String> = path_changes
+            .iter()
+            .map(|(from, to)| format!("{} → {}", from, to))
+            .collect();
+        pre_insights.push((
+            "warning".to_string(),
+            format!("Converted Windows-style paths: {}", rewritten.join(", ")),
+        ));
+    }
 
-    let meta_path = format!("/tmp/zsh-tool-meta-{}.json", task_id);
+    // Preview only: no spawn, no circuit breaker, nothing recorded
+    if args.get("dry_run").and_then(|v| v.as_bool()).unwrap_or(false) {
+        let mut result = serde_json::json!({
+            "success": true,
+            "dry_run": true,
+            "command": command,
+            "task_id": "",
+            "status": "dry_run",
+            "output": "",
+            "elapsed_seconds": 0,
+            "insights": Value::Object(combine_insights(&pre_insights, &[])),
+        });
+        attach_retry_info(&mut result, &retry_info);
+        return Ok(result);
+    }
+
+    if let Some((reason, detail, pattern)) = policy_block(state, command) {
+        let mut result = blocked_result(
+            state,
+            command,
+            reason,
+            &detail,
+            &pre_insights,
+            serde_json::json!({"matched_pattern": pattern}),
+        );
+        attach_retry_info(&mut result, &retry_info);
+        return Ok(result);
+    }
+
+    if let Some(detail) = home_root_block(state, command, args) {
+        let mut result = blocked_result(
+            state,
+            command,
+            BlockReason::DangerousCwd,
+            &detail,
+            &pre_insights,
+            serde_json::json!({}),
+        );
+        attach_retry_info(&mut result, &retry_info);
+        return Ok(result);
+    }
+
+    // Circuit breaker check: the global circuit, then this pattern's
+    let blocked = {
+        let mut cb = state.circuit_breaker.lock().unwrap();
+        let command_hash = alan::hash::hash_command(command);
+        let (allowed, msg) = cb.should_allow(&command_hash);
+        if allowed {
+            None
+        } else {
+            let first = state.config.circuit_cooldown_notice && cb.take_first_block();
+            let hashes = match (first, &cb.state) {
+                (false, _) => Vec::new(),
+                (true, CircuitState::Open) => cb.failing_hashes(),
+                (true, _) => vec![command_hash],
+            };
+            Some((msg.unwrap_or_else(|| "NEVERHANG: Circuit OPEN".into()), hashes))
+        }
+    };
+    if let Some((error_msg, failing_hashes)) = blocked {
+        // Cooldown notice: on the first block, explain what tripped the circuit
+        if !failing_hashes.is_empty() && state.config.blocked_detail == BlockDetail::Full {
+            if let Ok(conn) = alan::open_db(&state.db_path) {
+                pre_insights.extend(alan::insights::get_timeout_remediation(&conn, &failing_hashes));
+            }
+        }
+        let mut result = blocked_result(
+            state,
+            command,
+            BlockReason::CircuitOpen,
+            &error_msg,
+            &pre_insights,
+            serde_json::json!({}),
+        );
+        attach_retry_info(&mut result, &retry_info);
+        return Ok(result);
+    }
+
+    // Spawn rate limit — reject bursts rather than queueing them
+    if let Err(retry_after) = state.spawn_limiter.lock().unwrap().try_acquire() {
+        let retry_after = (retry_after * 10.0).ceil() / 10.0;
+        let detail = format!(
+            "Spawn rate limit exceeded ({}/s). Retry in {:.1}s.",
+            state.config.max_spawns_per_sec, retry_after
+        );
+        let mut result = blocked_result(
+            state,
+            command,
+            BlockReason::RateLimited,
+            &detail,
+            &pre_insights,
+            serde_json::json!({"retry_after_seconds": retry_after}),
+        );
+        attach_retry_info(&mut result, &retry_info);
+        return Ok(result);
+    }
+
+    // Execute command via spawning self as `exec`
+    let task_id = uuid::Uuid::new_v4().to_string()[..8].to_string();
+    let meta_path = task_meta_path(state, &task_id)?;
 
     let mut cmd_args = vec![
         "exec".to_string(),
@@ -426,19 +1695,80 @@ fn handle_zsh(state: &Arc<ServerState>, args: &Value) -> Value {
         "--session-id".to_string(),
         state.session_id.clone(),
     ];
+    if state.config.alan_dedup_window_secs > 0 {
+        cmd_args.push("--dedup-window".to_string());
+        cmd_args.push(state.config.alan_dedup_window_secs.to_string());
+    }
+    let request_id = CURRENT_REQUEST.with(|r| r.borrow().clone());
+    if let Some(ref id) = request_id {
+        cmd_args.push("--request-id".to_string());
+        cmd_args.push(id.clone());
+    }
     if use_pty {
         cmd_args.push("--pty".to_string());
+        cmd_args.push("--pty-drain-ms".to_string());
+        cmd_args.push(state.config.pty_drain_ms.to_string());
+    }
+    if let Some(n) = nice {
+        cmd_args.push("--nice".to_string());
+        cmd_args.push(n.to_string());
+    }
+    if separate_stderr {
+        cmd_args.push("--separate-stderr".to_string());
+    }
+    if let Some(sig) = timeout_signal {
+        cmd_args.push("--timeout-signal".to_string());
+        cmd_args.push(sig.to_string());
+    }
+    let reap_token = reap::new_token();
+    cmd_args.push("--reap-token".to_string());
+    cmd_args.push(reap_token.clone());
+    if let Some(mask) = state.config.umask {
+        cmd_args.push("--umask".to_string());
+        cmd_args.push(format!("{:03o}", mask));
+    }
+    cmd_args.push("--shell".to_string());
+    cmd_args.push(state.shell_path.to_string_lossy().into_owned());
+    cmd_args.push("--shell-kind".to_string());
+    cmd_args.push(state.config.shell_kind().name().to_string());
+    if let Some(dir) = state.cwd.lock().unwrap().clone() {
+        cmd_args.push("--cwd".to_string());
+        cmd_args.push(dir);
+    }
+    if let Some(path) = stdin_fifo {
+        cmd_args.push("--stdin-fifo".to_string());
+        cmd_args.push(path.to_string());
+    }
+    let delay_ms = SPAWN_DELAY_MS.with(|d| d.replace(0));
+    if delay_ms > 0 {
+        cmd_args.push("--delay-ms".to_string());
+        cmd_args.push(delay_ms.to_string());
+    }
+    if let Some(ref path) = stream_socket {
+        cmd_args.push("--stream-socket".to_string());
+        cmd_args.push(path.clone());
     }
     cmd_args.push("--".to_string());
     cmd_args.push(command.to_string());
 
     let start = std::time::Instant::now();
 
-    // Spawn exec process
-    let child = std::process::Command::new(&exec_path)
+    // Spawn exec process. Injected env (often secrets) goes through its
+    // environment, which the shell inherits, not argv where `ps` shows it
+    let mut exec = std::process::Command::new(&state.exec_path);
+    for (key, value) in &env {
+        if value.is_empty() {
+            exec.env_remove(key);
+        } else {
+            exec.env(key, value);
+        }
+    }
+    let child = exec
         .args(&cmd_args)
         .stdout(std::process::Stdio::piped())
-        .stderr(std::process::Stdio::piped())
+        // Nothing reads a pipe here, so exec's own diagnostics would fill it
+        // and stall the task; they belong in the server log anyway
+        .stderr(std::process::Stdio::inherit())
         .stdin(if use_pty {
             std::process::Stdio::piped()
         } else {
@@ -458,145 +1788,664 @@ fn handle_zsh(state: &Arc<ServerState>, args: &Value) -> Value {
                 "output": "",
                 "elapsed_seconds": 0,
             });
-            return text_content(&format::format_rich_output(result.as_object().unwrap()));
+            return Ok(result);
         }
     };
 
     let pid = child.id();
 
     // Take handles from the child
-    let mut stdout_handle = child.stdout.take();
+    let stdout_handle = child.stdout.take();
     let stdin_handle = child.stdin.take();
+    let has_stdin = stdin_handle.is_some();
+
+    let render_terminal = use_pty
+        && args
+            .get("render_terminal")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(state.config.render_terminal);
+    // Register right away so the reader thread has a buffer to fill
+    let evicted = state.tasks.lock().unwrap().insert(
+        TaskInfo {
+            task_id: task_id.clone(),
+            command: command.to_string(),
+            started_at: start,
+            started_at_epoch: now_epoch(),
+            status: "running".to_string(),
+            output_buffer: String::new(),
+            last_poll_offset: 0,
+            last_poll_line: 0,
+            has_stdin,
+            pipestatus: Vec::new(),
+            pid: Some(pid),
+            is_pty: use_pty,
+            meta_path: meta_path.clone(),
+            reap_token: reap_token.clone(),
+            rss_bytes: 0,
+            pre_insights: pre_insights.clone(),
+            retry_info: retry_info.clone(),
+            timeout_secs: timeout,
+            usual_secs: usual_duration_secs(state, command),
+            raised_insights: Default::default(),
+            extract_json: args.get("extract_json").and_then(|v| v.as_bool()).unwrap_or(false),
+            render_terminal,
+            output_hash: args
+                .get("output_hash")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(state.config.output_hash),
+            // A rendered screen no longer lines up with the captured lines
+            line_stamps: (!render_terminal
+                && args
+                    .get("timestamp_lines")
+                    .and_then(|v| v.as_bool())
+                    .unwrap_or(state.config.timestamp_lines))
+            .then(Vec::new),
+            spooled_bytes: 0,
+            request_id,
+            connection: CURRENT_CONNECTION.with(Cell::get),
+            child: Some(child),
+            reader: None,
+            stdin: stdin_handle,
+        },
+        state.config.max_tasks,
+    );
+    if !evicted.is_empty() {
+        server_log!(LogLevel::Debug, "Evicted {} finished task(s) over max_tasks: {}", evicted.len(), evicted.join(", "));
+    }
+    if let Some(stdout) = stdout_handle {
+        let reader = spawn_output_reader(state, &task_id, stdout);
+        if let Some(task) = state.tasks.lock().unwrap().tasks.get_mut(&task_id) {
+            task.reader = Some(reader);
+        }
+    }
+    register_request_task(state, &task_id);
 
-    // Wait for yield_after or completion
-    let yield_dur = std::time::Duration::from_secs_f64(yield_after);
-    std::thread::sleep(yield_dur);
+    // Wait for yield_after or completion (scaled down under ZSH_TOOL_TEST_CLOCK);
+    // a zero window checks once and returns, normally as RUNNING
+    let yield_dur = std::time::Duration::from_secs_f64(yield_after * state.config.yield_time_scale);
+    let yield_on_output = args
+        .get("yield_on_output")
+        .and_then(|v| v.as_u64())
+        .map_or(state.config.yield_on_output_bytes, |n| n as usize);
+    if yield_on_output > 0 {
+        wait_for_output(state, &task_id, yield_dur, yield_on_output);
+    } else if !yield_dur.is_zero() {
+        std::thread::sleep(yield_dur);
+    }
 
     let elapsed = start.elapsed().as_secs_f64();
 
-    // Check if process completed
-    match child.try_wait() {
-        Ok(Some(_exit_status)) => {
-            // Process completed — read all remaining output
-            let mut output = String::new();
-            if let Some(ref mut stdout) = stdout_handle {
-                use std::io::Read;
-                // Reset to blocking for final drain
-                #[cfg(unix)]
-                {
-                    use std::os::unix::io::AsRawFd;
-                    let fd = stdout.as_raw_fd();
-                    unsafe {
-                        let flags = libc::fcntl(fd, libc::F_GETFL);
-                        libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_NONBLOCK);
-                    }
-                }
-                let _ = stdout.read_to_string(&mut output);
-            }
-
-            // Caller receives this result directly — no background notification needed.
-            finalize_task(state, &task_id, command, &output, elapsed, &pre_insights, &meta_path, true, None)
+    // Completed within the yield window — the task stays registered as
+    // completed so the full buffer remains reachable via zsh_output
+    if let Some((_, _, output, _, _, _)) = collect_if_done(state, &task_id) {
+        if let Some(task) = state.tasks.lock().unwrap().tasks.get_mut(&task_id) {
+            task.last_poll_offset = output.len();
+            task.last_poll_line = output.matches('\n').count();
         }
-        Ok(None) => {
-            // Still running — collect partial output and register task
-            let output_so_far = if let Some(ref mut stdout) = stdout_handle {
-                read_available(stdout)
-            } else {
-                String::new()
-            };
+        // Caller receives this result directly — no background notification needed.
+        return Ok(finalize_result(state, &task_id, command, &output, elapsed, &pre_insights, &meta_path, true, None));
+    }
 
-            let now_epoch = std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap_or_default()
-                .as_secs_f64();
-
-            let has_stdin = stdin_handle.is_some();
-
-            {
-                let mut tasks = state.tasks.lock().unwrap();
-                tasks.tasks.insert(
-                    task_id.clone(),
-                    TaskInfo {
-                        task_id: task_id.clone(),
-                        command: command.to_string(),
-                        started_at: start,
-                        started_at_epoch: now_epoch,
-                        status: "running".to_string(),
-                        output_buffer: output_so_far.clone(),
-                        last_poll_offset: 0,
-                        last_poll_line: 0,
-                        has_stdin,
-                        pipestatus: Vec::new(),
-                        pid: Some(pid),
-                        is_pty: use_pty,
-                        meta_path: meta_path.clone(),
-                        pre_insights: pre_insights.clone(),
-                        child: Some(child),
-                        stdout: stdout_handle,
-                        stdin: stdin_handle,
-                    },
-                );
+    // Still running — return what the reader has captured so far
+    let (output_so_far, spool) = match state.tasks.lock().unwrap().tasks.get_mut(&task_id) {
+        Some(task) => (
+            stamp_lines(&task.output_buffer, 0, task.line_stamps.as_deref()),
+            spool_output(state, task, false),
+        ),
+        None => (String::new(), None),
+    };
+    let insights = combine_insights(&pre_insights, &[]);
+
+    let mut result = serde_json::json!({
+        "task_id": task_id,
+        "command": command,
+        "status": "running",
+        "output": cap_output(state, &output_so_far),
+        "elapsed_seconds": format!("{:.1}", elapsed).parse::<f64>().unwrap_or(elapsed),
+        "has_stdin": has_stdin,
+        "insights": insights,
+    });
+    if let Some(path) = spool {
+        result["spool_path"] = Value::String(path.display().to_string());
+    }
+    attach_retry_info(&mut result, &retry_info);
+    Ok(result)
+}
+
+/// Timeout for a `zsh` call: the requested value (or the default, see
+/// `ssh_default_timeout` for SSH commands), capped at `neverhang_timeout_max`,
+/// or at `timeout_exempt_max` when the command matches
+/// `timeout_exempt_patterns`.
+fn effective_timeout(state: &Arc<ServerState>, command: &str, args: &Value) -> u64 {
+    let requested = match args.get("timeout").and_then(|v| v.as_u64()) {
+        Some(t) => t,
+        None => ssh_default_timeout(state, command).unwrap_or(state.config.neverhang_timeout_default),
+    };
+    if timeout_exempt(state, command) {
+        requested.min(state.config.timeout_exempt_max.max(state.config.neverhang_timeout_max))
+    } else {
+        requested.min(state.config.neverhang_timeout_max)
+    }
+}
+
+/// Default timeout for an SSH command: the host's `ssh_timeouts` entry, else
+/// the configured default raised to 1.5x the slowest recent completed run
+/// ALAN has seen to that host. None for other commands and hosts without
+/// enough history.
+fn ssh_default_timeout(state: &Arc<ServerState>, command: &str) -> Option<u64> {
+    let host = alan::ssh::parse_ssh_command(command)?.host;
+    if let Some(&secs) = state.config.ssh_timeouts.get(&host) {
+        return Some(secs);
+    }
+    let conn = alan::open_db(&state.db_path).ok()?;
+    let slowest_ms = alan::ssh::host_slowest_ms(&conn, &host, MIN_HISTORY_OBSERVATIONS)?;
+    Some(state.config.neverhang_timeout_default.max((slowest_ms * 3).div_ceil(2000)))
+}
+
+/// Compile `timeout_exempt_patterns`. Invalid patterns are logged and
+/// dropped, so they never match.
+fn compile_timeout_exempt(patterns: &[String]) -> Vec<regex::Regex> {
+    patterns
+        .iter()
+        .filter_map(|pattern| match regex::Regex::new(pattern) {
+            Ok(re) => Some(re),
+            Err(e) => {
+                server_log!(LogLevel::Warning, "Invalid timeout_exempt_patterns entry '{}': {}", pattern, e);
+                None
             }
+        })
+        .collect()
+}
 
-            let insights = combine_insights(&pre_insights, &[]);
+/// Whether every command in `command`'s chain, from its base command on,
+/// matches a `timeout_exempt_patterns` regex, so nothing else rides along
+/// on the raised timeout.
+fn timeout_exempt(state: &Arc<ServerState>, command: &str) -> bool {
+    let commands = policy::chain_commands(command);
+    !state.timeout_exempt.is_empty()
+        && !commands.is_empty()
+        && commands.iter().all(|c| state.timeout_exempt.iter().any(|re| re.is_match(c)))
+}
 
-            let result = serde_json::json!({
-                "task_id": task_id,
-                "command": command,
-                "status": "running",
-                "output": truncate_output(&output_so_far, state.config.truncate_output_at),
-                "elapsed_seconds": format!("{:.1}", elapsed).parse::<f64>().unwrap_or(elapsed),
-                "has_stdin": has_stdin,
-                "insights": insights,
-            });
-            text_content(&format::format_rich_output(result.as_object().unwrap()))
-        }
-        Err(e) => {
-            let result = serde_json::json!({
-                "success": false,
-                "error": format!("Process wait error: {}", e),
-                "command": command,
-                "task_id": task_id,
-                "status": "error",
-                "output": "",
-                "elapsed_seconds": 0,
-            });
-            text_content(&format::format_rich_output(result.as_object().unwrap()))
-        }
+/// Minimum observations before a pattern's average duration is trusted.
+const MIN_HISTORY_OBSERVATIONS: i64 = 3;
+
+/// Yield window for a `zsh` call. An explicit `yield_after` always wins (`0`
+/// returns RUNNING without waiting; negative values count as `0`); otherwise
+/// the configured default, adapted to the pattern's history when enabled.
+fn effective_yield_after(state: &Arc<ServerState>, command: &str, args: &Value) -> f64 {
+    if let Some(explicit) = args.get("yield_after").and_then(|v| v.as_f64()) {
+        return explicit.max(0.0);
+    }
+    let default = state.config.yield_after_default;
+    if !state.config.adaptive_yield {
+        return default;
     }
+    usual_duration_secs(state, command).map_or(default, |avg| adaptive_yield(default, avg))
 }
 
-fn handle_poll(state: &Arc<ServerState>, args: &Value) -> Value {
-    let task_id = match args.get("task_id").and_then(|v| v.as_str()) {
-        Some(id) => id,
-        None => return error_content("Missing required parameter: task_id"),
-    };
+/// Average duration of `command`'s pattern, once it has enough history.
+fn usual_duration_secs(state: &Arc<ServerState>, command: &str) -> Option<f64> {
+    let conn = alan::open_db(&state.db_path).ok()?;
+    let stats = alan::stats::query_pattern(&conn, command);
+    match (stats.observations, stats.avg_duration_ms) {
+        (Some(n), Some(avg_ms)) if n >= MIN_HISTORY_OBSERVATIONS => Some(avg_ms / 1000.0),
+        _ => None,
+    }
+}
 
-    let full_output = args
-        .get("full_output")
-        .and_then(|v| v.as_bool())
-        .unwrap_or(false);
+/// Adapt `default` to a pattern's average duration:
+/// - slow (avg > 5x default): return RUNNING early, at a quarter of the default
+/// - just past the default (avg <= 1.5x default): wait a little longer to
+///   return the completed result inline
+/// - otherwise: unchanged
+fn adaptive_yield(default: f64, avg_secs: f64) -> f64 {
+    if avg_secs > default * 5.0 {
+        (default / 4.0).max(0.25).min(default)
+    } else if avg_secs > default && avg_secs <= default * 1.5 {
+        avg_secs * 1.1
+    } else {
+        default
+    }
+}
 
-    let mut tasks = state.tasks.lock().unwrap();
-    let task = match tasks.tasks.get_mut(task_id) {
-        Some(t) => t,
+/// Re-run a command, by default this session's most recent failure, with the
+/// verbosity setting `verbose_flags` has for its base command.
+fn handle_retry_verbose(state: &Arc<ServerState>, args: &Value) -> Value {
+    let command = match args.get("command").and_then(|v| v.as_str()) {
+        Some(c) if !c.trim().is_empty() => c.to_string(),
+        _ => match last_failed_command(state) {
+            Some(c) => c,
+            None => return error_content("No failed command in this session; pass command"),
+        },
+    };
+    let verbose = match crate::verbose::augment(&command, &state.config.verbose_flags) {
+        Some(v) => v,
         None => {
-            return error_content(&format!("Unknown task: {}", task_id));
+            return error_content(&format!(
+                "No verbosity setting for '{}'; add one under verbose_flags",
+                alan::insights::extract_base_command(&command)
+            ))
         }
     };
+    let mut zsh_args = serde_json::json!({"command": verbose});
+    for key in ["timeout", "yield_after"] {
+        if let Some(v) = args.get(key) {
+            zsh_args[key] = v.clone();
+        }
+    }
+    handle_zsh(state, &zsh_args)
+}
 
-    // If already finalized, return delta from where we left off
-    if task.status != "running" {
-        let (numbered_output, from_line, to_line) = number_lines(
+/// Command of the most recently started task that finished unsuccessfully.
+fn last_failed_command(state: &Arc<ServerState>) -> Option<String> {
+    let tasks = state.tasks.lock().unwrap();
+    tasks.tasks.values()
+        .filter(|t| {
+            t.status == "timeout"
+                || (t.status == "completed" && t.pipestatus.last().is_some_and(|&code| code != 0))
+        })
+        .max_by_key(|t| t.started_at)
+        .map(|t| t.command.clone())
+}
+
+/// Run `steps` one after another through [`run_zsh`], waiting out each one.
+/// The first failing step aborts the rest unless it sets `continue_on_error`.
+fn handle_sequence(state: &Arc<ServerState>, args: &Value) -> Value {
+    let steps = match args.get("steps").and_then(|v| v.as_array()) {
+        Some(s) if !s.is_empty() => s,
+        _ => return error_content("Missing required parameter: steps (non-empty array)"),
+    };
+    // Validate every step before running any of them
+    let mut parsed: Vec<(&str, bool)> = Vec::new();
+    for (i, step) in steps.iter().enumerate() {
+        let command = step.as_str().or_else(|| step.get("command").and_then(|v| v.as_str()));
+        let continue_on_error = step
+            .get("continue_on_error")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
+        match command {
+            Some(c) if !c.trim().is_empty() => parsed.push((c, continue_on_error)),
+            _ => return error_content(&format!("Step {} has no command", i)),
+        }
+    }
+
+    let mut results = Vec::new();
+    let mut aborted_at: Option<usize> = None;
+    for (i, (command, continue_on_error)) in parsed.into_iter().enumerate() {
+        if aborted_at.is_some() {
+            results.push(serde_json::json!({"index": i, "command": command, "status": "skipped"}));
+            continue;
+        }
+        let mut step_args = serde_json::json!({"command": command});
+        if let Some(t) = args.get("timeout") {
+            step_args["timeout"] = t.clone();
+        }
+        let mut result = match run_zsh(state, &step_args) {
+            Ok(r) => r,
+            Err(e) => serde_json::json!({"success": false, "status": "error", "error": e}),
+        };
+        if result["status"] == "running" {
+            let task_id = result["task_id"].as_str().unwrap_or("").to_string();
+            result = wait_for_task(state, &task_id);
+        }
+
+        let success = result["success"].as_bool().unwrap_or(false);
+        let mut summary = serde_json::json!({
+            "index": i,
+            "command": command,
+            "task_id": result["task_id"],
+            "status": result["status"],
+            "success": success,
+            "exit_code": result["pipestatus"].as_array().and_then(|p| p.last()).cloned().unwrap_or(Value::Null),
+            "elapsed_seconds": result["elapsed_seconds"],
+            "output": result["output"],
+        });
+        for key in ["error", "blocked_reason"] {
+            if let Some(v) = result.get(key) {
+                summary[key] = v.clone();
+            }
+        }
+        results.push(summary);
+        if !success && !continue_on_error {
+            aborted_at = Some(i);
+        }
+    }
+
+    let result = serde_json::json!({
+        "success": aborted_at.is_none(),
+        "aborted_at": aborted_at,
+        "steps": results,
+    });
+    text_content(&serde_json::to_string_pretty(&result).unwrap_or_default())
+}
+
+/// Block until a yielded task finishes and return its finalized result.
+/// Commands are bounded by their NEVERHANG timeout, so this terminates.
+fn wait_for_task(state: &Arc<ServerState>, task_id: &str) -> Value {
+    loop {
+        if let Some((tid, cmd, output, elapsed, pre, meta)) = collect_if_done(state, task_id) {
+            return finalize_result(state, &tid, &cmd, &output, elapsed, &pre, &meta, true, None);
+        }
+        let status = state.tasks.lock().unwrap().tasks.get(task_id).map(|t| t.status.clone());
+        match status {
+            Some(s) if s == "running" => {}
+            Some(s) => return serde_json::json!({"success": false, "task_id": task_id, "status": s}),
+            None => {
+                return serde_json::json!({
+                    "success": false,
+                    "task_id": task_id,
+                    "status": "error",
+                    "error": format!("Unknown task: {}", task_id),
+                })
+            }
+        }
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+}
+
+/// Most runs one `zsh_benchmark` call makes.
+const BENCHMARK_MAX_RUNS: u64 = 50;
+
+/// Wall-clock budget of one `zsh_benchmark` call; no run starts after it and
+/// each run's timeout is clamped to what is left of it.
+const BENCHMARK_MAX_SECONDS: f64 = 300.0;
+
+/// Timeout for the next benchmark run: the normal `zsh` timeout, but no
+/// longer than the budget left (and at least 1s).
+fn benchmark_run_timeout(state: &Arc<ServerState>, command: &str, args: &Value, remaining: f64) -> u64 {
+    effective_timeout(state, command, args).min(remaining.ceil().max(1.0) as u64)
+}
+
+/// Run a command repeatedly through `run_zsh` and report timing statistics.
+/// Durations are the exec-measured run times, not including the time spent
+/// yielding and polling for the result. Every run is a normal `zsh` run: it is recorded in ALAN and subject to the
+/// timeout, rate limit and circuit breaker. A rate-limited run waits and is
+/// retried; any other block ends the benchmark.
+fn handle_benchmark(state: &Arc<ServerState>, args: &Value) -> Value {
+    let command = match args.get("command").and_then(|v| v.as_str()) {
+        Some(c) if !c.trim().is_empty() => c,
+        _ => return error_content("Missing required parameter: command"),
+    };
+    let requested = args.get("runs").and_then(|v| v.as_u64()).unwrap_or(5).max(1);
+    let runs = requested.min(BENCHMARK_MAX_RUNS);
+    let mut run_args = serde_json::json!({"command": command});
+
+    let started = std::time::Instant::now();
+    let mut durations = Vec::new();
+    let mut exit_codes = Vec::new();
+    let mut stopped: Option<String> = None;
+    while (durations.len() as u64) < runs {
+        let remaining = BENCHMARK_MAX_SECONDS - started.elapsed().as_secs_f64();
+        if remaining <= 0.0 {
+            stopped = Some(format!("time budget of {}s used up", BENCHMARK_MAX_SECONDS));
+            break;
+        }
+        run_args["timeout"] = serde_json::json!(benchmark_run_timeout(state, command, args, remaining));
+        let run_start = std::time::Instant::now();
+        let mut result = match run_zsh(state, &run_args) {
+            Ok(r) => r,
+            Err(e) => return error_content(&e),
+        };
+        if result["status"] == "running" {
+            let task_id = result["task_id"].as_str().unwrap_or("").to_string();
+            result = wait_for_task(state, &task_id);
+        }
+        if result["status"] == "blocked" {
+            if result["blocked_reason"] == "rate_limited" {
+                let wait = result["retry_after_seconds"].as_f64().unwrap_or(0.1).clamp(0.01, 1.0);
+                std::thread::sleep(std::time::Duration::from_secs_f64(wait));
+                continue;
+            }
+            stopped = Some(format!("blocked: {}", result["blocked_reason"].as_str().unwrap_or("unknown")));
+            break;
+        }
+        durations.push(match result["exec_elapsed_ms"].as_u64() {
+            Some(ms) => ms as f64 / 1000.0,
+            None => run_start.elapsed().as_secs_f64(),
+        });
+        exit_codes.push(
+            result["pipestatus"].as_array().and_then(|p| p.last()).cloned().unwrap_or(Value::Null),
+        );
+    }
+
+    let result = serde_json::json!({
+        "command": command,
+        "runs_requested": requested,
+        "runs_completed": durations.len(),
+        "successes": exit_codes.iter().filter(|c| c.as_i64() == Some(0)).count(),
+        "exit_codes": exit_codes,
+        "durations_seconds": durations.iter().map(|d| round_ms(*d)).collect::<Vec<_>>(),
+        "stats": duration_stats(&durations),
+        "stopped": stopped,
+    });
+    text_content(&serde_json::to_string_pretty(&result).unwrap_or_default())
+}
+
+/// min/max/mean/p50/p90 of durations in seconds, with nearest-rank
+/// percentiles. Null when there are none.
+fn duration_stats(durations: &[f64]) -> Value {
+    if durations.is_empty() {
+        return Value::Null;
+    }
+    let mut sorted = durations.to_vec();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    let n = sorted.len();
+    let percentile = |p: f64| sorted[((p / 100.0 * n as f64).ceil() as usize).clamp(1, n) - 1];
+    serde_json::json!({
+        "min": round_ms(sorted[0]),
+        "max": round_ms(sorted[n - 1]),
+        "mean": round_ms(sorted.iter().sum::<f64>() / n as f64),
+        "p50": round_ms(percentile(50.0)),
+        "p90": round_ms(percentile(90.0)),
+    })
+}
+
+fn round_ms(secs: f64) -> f64 {
+    (secs * 1000.0).round() / 1000.0
+}
+
+/// How often `wait_for_exit` checks the child.
+const POLL_WAIT_INTERVAL_MS: u64 = 50;
+
+/// Block until the task's process exits or `wait` elapses. The reader thread
+/// keeps draining output meanwhile; the tasks lock is only held per check.
+fn wait_for_exit(state: &Arc<ServerState>, task_id: &str, wait: std::time::Duration) {
+    let deadline = std::time::Instant::now() + wait;
+    loop {
+        {
+            let mut tasks = state.tasks.lock().unwrap();
+            let exited = match tasks.tasks.get_mut(task_id) {
+                Some(task) if task.status == "running" => task
+                    .child
+                    .as_mut()
+                    .is_none_or(|c| !matches!(c.try_wait(), Ok(None))),
+                _ => true,
+            };
+            if exited {
+                return;
+            }
+        }
+        let now = std::time::Instant::now();
+        if now >= deadline {
+            return;
+        }
+        std::thread::sleep((deadline - now).min(std::time::Duration::from_millis(POLL_WAIT_INTERVAL_MS)));
+    }
+}
+
+/// How long output must stay unchanged before `yield_on_output` returns early.
+const YIELD_OUTPUT_SETTLE_MS: u64 = 150;
+
+/// Yield wait for `yield_on_output`: like `wait_for_exit`, but also returns
+/// once the task has at least `min_bytes` of output and nothing new has
+/// arrived for `YIELD_OUTPUT_SETTLE_MS` (it printed its result and went idle).
+fn wait_for_output(state: &Arc<ServerState>, task_id: &str, wait: std::time::Duration, min_bytes: usize) {
+    let deadline = std::time::Instant::now() + wait;
+    let settle = std::time::Duration::from_millis(YIELD_OUTPUT_SETTLE_MS);
+    let mut last_len = 0;
+    let mut last_change = std::time::Instant::now();
+    loop {
+        let now = std::time::Instant::now();
+        {
+            let mut tasks = state.tasks.lock().unwrap();
+            let (exited, len) = match tasks.tasks.get_mut(task_id) {
+                Some(task) if task.status == "running" => (
+                    task.child.as_mut().is_none_or(|c| !matches!(c.try_wait(), Ok(None))),
+                    task.output_buffer.len(),
+                ),
+                _ => return,
+            };
+            if exited {
+                return;
+            }
+            if len != last_len {
+                last_len = len;
+                last_change = now;
+            } else if len >= min_bytes && now - last_change >= settle {
+                return;
+            }
+        }
+        if now >= deadline {
+            return;
+        }
+        std::thread::sleep((deadline - now).min(std::time::Duration::from_millis(POLL_WAIT_INTERVAL_MS)));
+    }
+}
+
+/// Spool file for `task_id`, when `spool_dir` is configured.
+fn spool_path(state: &Arc<ServerState>, task_id: &str) -> Option<std::path::PathBuf> {
+    let dir = state.config.spool_dir.as_deref()?;
+    Some(std::path::Path::new(dir).join(format!("{}.log", task_id)))
+}
+
+/// Append the output not yet on disk to the task's spool file, once the
+/// output has outgrown the inline cap (or regardless, with `force`).
+/// Returns the path while the file holds the whole buffer. Output can hold
+/// secrets, so directories we create and the files are owner-only.
+fn spool_output(state: &Arc<ServerState>, task: &mut TaskInfo, force: bool) -> Option<std::path::PathBuf> {
+    use std::io::Write;
+    use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+    let path = spool_path(state, &task.task_id)?;
+    if task.spooled_bytes == 0 && !force && !exceeds_output_cap(state, &task.output_buffer) {
+        return None;
+    }
+    let pending = &task.output_buffer[task.spooled_bytes..];
+    let written = path
+        .parent()
+        .map_or(Ok(()), |dir| std::fs::DirBuilder::new().recursive(true).mode(0o700).create(dir))
+        .and_then(|_| std::fs::OpenOptions::new().create(true).append(true).mode(0o600).open(&path))
+        .and_then(|mut file| file.write_all(pending.as_bytes()));
+    match written {
+        Ok(()) => {
+            task.spooled_bytes = task.output_buffer.len();
+            Some(path)
+        }
+        Err(e) => {
+            server_log!(LogLevel::Warning, "Spool {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+/// A task has ended: with `keep_logs`, bring its spool file up to date;
+/// otherwise delete it.
+fn close_spool(state: &Arc<ServerState>, task: &mut TaskInfo) {
+    if state.config.keep_logs {
+        spool_output(state, task, false);
+    } else if task.spooled_bytes > 0 {
+        if let Some(path) = spool_path(state, &task.task_id) {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// `zsh_poll` with `full: true`: the spool file holding the task's whole
+/// output, instead of the (possibly truncated) buffer.
+fn poll_spool(state: &Arc<ServerState>, task_id: &str) -> Value {
+    if state.config.spool_dir.is_none() {
+        return error_content("full requires spool_dir to be configured; zsh_output returns the full buffer");
+    }
+    let mut tasks = state.tasks.lock().unwrap();
+    let task = match tasks.tasks.get_mut(task_id) {
+        Some(t) => t,
+        None => return error_content(&format!("Unknown task: {}", task_id)),
+    };
+    let path = if task.status == "running" {
+        spool_output(state, task, true)
+    } else {
+        spool_path(state, task_id).filter(|p| task.spooled_bytes > 0 && p.exists())
+    };
+    match path {
+        Some(path) => text_content(
+            &serde_json::to_string_pretty(&serde_json::json!({
+                "task_id": task_id,
+                "status": task.status,
+                "spool_path": path.display().to_string(),
+                "bytes": task.spooled_bytes,
+            }))
+            .unwrap_or_default(),
+        ),
+        None => error_content(&format!(
+            "No spool file for task {}: its output fit inline, or the file was removed when it finished (set keep_logs to keep it)",
+            task_id
+        )),
+    }
+}
+
+fn handle_poll(state: &Arc<ServerState>, args: &Value) -> Value {
+    let task_id = match args.get("task_id").and_then(|v| v.as_str()) {
+        Some(id) => id,
+        None => return error_content("Missing required parameter: task_id"),
+    };
+
+    let full_output = args
+        .get("full_output")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+    let wait_ms = args
+        .get("wait_ms")
+        .and_then(|v| v.as_u64())
+        .map(|ms| ms.min(state.config.neverhang_timeout_max * 1000));
+
+    if let Some(ms) = wait_ms {
+        wait_for_exit(state, task_id, std::time::Duration::from_millis(ms));
+    }
+    if args.get("full").and_then(|v| v.as_bool()).unwrap_or(false) {
+        return poll_spool(state, task_id);
+    }
+    let filter = match args.get("filter").and_then(|v| v.as_str()).filter(|f| !f.is_empty()) {
+        Some(pattern) => match regex::Regex::new(pattern) {
+            Ok(re) => Some(re),
+            Err(e) => return error_content(&format!("Invalid filter regex: {}", e)),
+        },
+        None => None,
+    };
+
+    let mut tasks = state.tasks.lock().unwrap();
+    let task = match tasks.tasks.get_mut(task_id) {
+        Some(t) => t,
+        None => {
+            return error_content(&format!("Unknown task: {}", task_id));
+        }
+    };
+
+    // If already finalized, return delta from where we left off
+    if task.status != "running" {
+        let (numbered_output, from_line, to_line) = number_lines(
             &task.output_buffer,
             task.last_poll_offset,
             task.last_poll_line,
             full_output,
             state.config.truncate_output_at,
+            state.config.truncate_mode,
+            filter.as_ref(),
+            task.line_stamps.as_deref(),
         );
 
-        // Update cursors for subsequent re-polls
-        if !full_output {
+        // Update cursors for subsequent re-polls; a filtered poll leaves them
+        // so the lines it hid still reach the next unfiltered delta
+        if !full_output && filter.is_none() {
             let delta_line_count = task.output_buffer[task.last_poll_offset..].matches('\n').count();
             let new_offset = task.output_buffer.len();
             task.last_poll_line += delta_line_count;
@@ -619,49 +2468,19 @@ fn handle_poll(state: &Arc<ServerState>, args: &Value) -> Value {
         // Caller is observing this task directly — clear any pending [notify] for it.
         drop(tasks);
         suppress_event_for_task(state, task_id);
-        return text_content(&format::format_rich_output(result.as_object().unwrap()));
+        return rich_content(state, &result);
     }
 
-    // Read any new output
-    if let Some(ref mut stdout) = task.stdout {
-        let new_output = read_available(stdout);
-        if !new_output.is_empty() {
-            task.output_buffer.push_str(&new_output);
-        }
-    }
-
-    let elapsed = task.started_at.elapsed().as_secs_f64();
-
-    // Check if process completed
-    let completed = if let Some(ref mut child) = task.child {
-        child.try_wait().ok().flatten().is_some()
-    } else {
-        false
-    };
-
-    if completed {
-        // Drain remaining output (switch to blocking)
-        if let Some(ref mut stdout) = task.stdout {
-            use std::io::Read;
-            #[cfg(unix)]
-            {
-                use std::os::unix::io::AsRawFd;
-                let fd = stdout.as_raw_fd();
-                unsafe {
-                    let flags = libc::fcntl(fd, libc::F_GETFL);
-                    libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_NONBLOCK);
-                }
-            }
-            let mut remaining = String::new();
-            let _ = stdout.read_to_string(&mut remaining);
-            task.output_buffer.push_str(&remaining);
-        }
-
-        // Drop handles
-        task.child = None;
-        task.stdout = None;
-        task.stdin = None;
-        task.status = "completed".to_string();
+    // Output arrives via the reader thread; see whether the process is done
+    drop(tasks);
+    if let Some((task_id_str, command, output, elapsed, pre_insights, meta_path)) =
+        collect_if_done(state, task_id)
+    {
+        let mut tasks = state.tasks.lock().unwrap();
+        let task = match tasks.tasks.get_mut(task_id) {
+            Some(t) => t,
+            None => return error_content(&format!("Unknown task: {}", task_id)),
+        };
 
         // Compute delta output with line numbers before dropping lock
         let (numbered_output, from_line, to_line) = number_lines(
@@ -670,22 +2489,19 @@ fn handle_poll(state: &Arc<ServerState>, args: &Value) -> Value {
             task.last_poll_line,
             full_output,
             state.config.truncate_output_at,
+            state.config.truncate_mode,
+            filter.as_ref(),
+            task.line_stamps.as_deref(),
         );
 
-        // Update cursors
-        if !full_output {
+        // Update cursors (not for filtered polls, see above)
+        if !full_output && filter.is_none() {
             let delta_line_count = task.output_buffer[task.last_poll_offset..].matches('\n').count();
             let new_offset = task.output_buffer.len();
             task.last_poll_line += delta_line_count;
             task.last_poll_offset = new_offset;
         }
 
-        let output = task.output_buffer.clone();
-        let command = task.command.clone();
-        let pre_insights = task.pre_insights.clone();
-        let meta_path = task.meta_path.clone();
-        let task_id_str = task.task_id.clone();
-
         // Drop the lock before finalize (it accesses circuit_breaker)
         drop(tasks);
 
@@ -699,6 +2515,13 @@ fn handle_poll(state: &Arc<ServerState>, args: &Value) -> Value {
         );
     }
 
+    let mut tasks = state.tasks.lock().unwrap();
+    let task = match tasks.tasks.get_mut(task_id) {
+        Some(t) => t,
+        None => return error_content(&format!("Unknown task: {}", task_id)),
+    };
+    let elapsed = task.started_at.elapsed().as_secs_f64();
+
     // Still running — compute output delta since last poll
     let new_bytes = task.output_buffer.len().saturating_sub(task.last_poll_offset);
 
@@ -708,10 +2531,26 @@ fn handle_poll(state: &Arc<ServerState>, args: &Value) -> Value {
         task.last_poll_line,
         full_output,
         state.config.truncate_output_at,
+        state.config.truncate_mode,
+        filter.as_ref(),
+        task.line_stamps.as_deref(),
+    );
+
+    let mut post_insights: Vec<(String, String)> = memory_insight(state, task).into_iter().collect();
+    let runtime = alan::insights::get_runtime_insights(
+        elapsed,
+        task.usual_secs,
+        task.timeout_secs,
+        &task.output_buffer[task.last_poll_offset..],
     );
+    for (key, insight) in runtime {
+        if task.raised_insights.insert(key) {
+            post_insights.push(insight);
+        }
+    }
 
-    // Update cursors (only when returning delta, not full)
-    if !full_output {
+    // Update cursors (only when returning the whole delta, not full or filtered)
+    if !full_output && filter.is_none() {
         // Count lines in the delta slice for next poll
         let delta_line_count = task.output_buffer[task.last_poll_offset..].matches('\n').count();
         let new_offset = task.output_buffer.len();
@@ -719,7 +2558,8 @@ fn handle_poll(state: &Arc<ServerState>, args: &Value) -> Value {
         task.last_poll_offset = new_offset;
     }
 
-    let insights = combine_insights(&task.pre_insights, &[]);
+    let insights = combine_insights(&task.pre_insights, &post_insights);
+    let spool = spool_output(state, task, false);
     let mut result = serde_json::json!({
         "task_id": task.task_id,
         "command": task.command,
@@ -730,11 +2570,14 @@ fn handle_poll(state: &Arc<ServerState>, args: &Value) -> Value {
         "new_bytes": new_bytes,
         "insights": insights,
     });
+    if let Some(path) = spool {
+        result["spool_path"] = Value::String(path.display().to_string());
+    }
     if from_line > 0 {
         result["from_line"] = serde_json::json!(from_line);
         result["to_line"] = serde_json::json!(to_line);
     }
-    text_content(&format::format_rich_output(result.as_object().unwrap()))
+    rich_content(state, &result)
 }
 
 fn handle_send(state: &Arc<ServerState>, args: &Value) -> Value {
@@ -796,38 +2639,100 @@ fn handle_kill(state: &Arc<ServerState>, args: &Value) -> Value {
             if let Some(ref mut child) = task.child {
                 let _ = child.wait();
             }
-
-            // Drain any remaining output
-            if let Some(ref mut stdout) = task.stdout {
-                let remaining = read_available(stdout);
-                task.output_buffer.push_str(&remaining);
-            }
+            // The shell and anything it spawned outlive the exec process
+            // unless swept up by their tag
+            reap::kill_tagged(&task.reap_token);
 
             // Clean up meta file
             let _ = std::fs::remove_file(&task.meta_path);
 
+            // Keep the task (and its full buffer) for zsh_output; drop live handles
+            task.child = None;
+            task.stdin = None;
+            task.status = "killed".to_string();
+            let reader = task.reader.take();
+
+            // Everything holding the pipe is dead, so the reader reaches EOF
+            // once it has appended the rest. It needs the lock to do so.
+            drop(tasks);
+            if let Some(handle) = reader {
+                let _ = handle.join();
+            }
+            let mut tasks = state.tasks.lock().unwrap();
+            let task = match tasks.tasks.get_mut(task_id) {
+                Some(t) => t,
+                None => return error_content(&format!("Unknown task: {}", task_id)),
+            };
+            close_spool(state, task);
             let elapsed = task.started_at.elapsed().as_secs_f64();
-            let output = task.output_buffer.clone();
+            let output = stamp_lines(&task.output_buffer, 0, task.line_stamps.as_deref());
             let tid = task.task_id.clone();
             let cmd = task.command.clone();
 
-            // Remove from registry
-            tasks.tasks.remove(task_id);
-
             let result = serde_json::json!({
                 "task_id": tid,
                 "command": cmd,
                 "status": "killed",
-                "output": truncate_output(&output, state.config.truncate_output_at),
+                "output": cap_output(state, &output),
                 "elapsed_seconds": format!("{:.1}", elapsed).parse::<f64>().unwrap_or(elapsed),
             });
-            text_content(&format::format_rich_output(result.as_object().unwrap()))
+            rich_content(state, &result)
         }
         Some(_) => error_content(&format!("Task {} is not running", task_id)),
         None => error_content(&format!("Unknown task: {}", task_id)),
     }
 }
 
+/// Return a task's complete output buffer — untruncated, for running,
+/// completed, and killed tasks alike. `tail` limits to the last N lines.
+fn handle_output(state: &Arc<ServerState>, args: &Value) -> Value {
+    let task_id = match args.get("task_id").and_then(|v| v.as_str()) {
+        Some(id) => id,
+        None => return error_content("Missing required parameter: task_id"),
+    };
+    let tail = args.get("tail").and_then(|v| v.as_u64()).map(|n| n as usize);
+
+    let mut tasks = state.tasks.lock().unwrap();
+    let task = match tasks.tasks.get_mut(task_id) {
+        Some(t) => t,
+        None => return error_content(&format!("Unknown task: {}", task_id)),
+    };
+
+    let buffer = task.output_buffer.trim_end_matches('\n');
+    let total_lines = if buffer.is_empty() { 0 } else { buffer.split('\n').count() };
+    let skip = tail.map(|n| total_lines.saturating_sub(n)).unwrap_or(0);
+    let (numbered, from_line, to_line) = if total_lines == 0 {
+        (String::new(), 0, 0)
+    } else {
+        let offset = buffer.split('\n').take(skip).map(|l| l.len() + 1).sum::<usize>();
+        number_lines(buffer, offset, skip, false, usize::MAX, TruncateMode::Head, None, None)
+    };
+
+    let elapsed = task.started_at.elapsed().as_secs_f64();
+    let mut result = serde_json::json!({
+        "task_id": task.task_id,
+        "command": task.command,
+        "status": task.status,
+        "output": numbered,
+        "elapsed_seconds": format!("{:.1}", elapsed).parse::<f64>().unwrap_or(elapsed),
+        "pipestatus": task.pipestatus,
+        "has_stdin": task.has_stdin,
+    });
+    if from_line > 0 {
+        result["from_line"] = serde_json::json!(from_line);
+        result["to_line"] = serde_json::json!(to_line);
+    }
+    rich_content(state, &result)
+}
+
+/// A task's request id as the client sent it (number or string), or null.
+fn request_id_value(task: &TaskInfo) -> Value {
+    task.request_id
+        .as_deref()
+        .and_then(|id| serde_json::from_str(id).ok())
+        .unwrap_or(Value::Null)
+}
+
 fn handle_list_tasks(state: &Arc<ServerState>) -> Value {
     let tasks = state.tasks.lock().unwrap();
     let task_list: Vec<Value> = tasks
@@ -845,6 +2750,8 @@ fn handle_list_tasks(state: &Arc<ServerState>) -> Value {
                 "command": cmd,
                 "status": t.status,
                 "elapsed_seconds": format!("{:.1}", elapsed).parse::<f64>().unwrap_or(elapsed),
+                "started_at": format_timestamp(state.config.timestamp_format, t.started_at_epoch),
+                "request_id": request_id_value(t),
             })
         })
         .collect();
@@ -855,6 +2762,57 @@ fn handle_list_tasks(state: &Arc<ServerState>) -> Value {
     )
 }
 
+/// One-shot session report for handoff or logging: ALAN session stats, top
+/// patterns and failures, running tasks and circuit state.
+fn handle_session_summary(state: &Arc<ServerState>) -> Value {
+    let (session, patterns, failures) = match alan::open_db(&state.db_path) {
+        Ok(conn) => (
+            Some(alan::stats::get_session_stats(&conn, &state.session_id)),
+            alan::stats::get_hot_patterns(&conn, &state.session_id, 5),
+            alan::stats::get_session_failures(&conn, &state.session_id, 5),
+        ),
+        Err(_) => (None, Vec::new(), Vec::new()),
+    };
+    let running: Vec<Value> = {
+        let tasks = state.tasks.lock().unwrap();
+        let mut running: Vec<&TaskInfo> = tasks.tasks.values().filter(|t| t.status == "running").collect();
+        running.sort_by_key(|t| t.started_at);
+        running
+            .iter()
+            .map(|t| {
+                let elapsed = t.started_at.elapsed().as_secs_f64();
+                serde_json::json!({
+                    "task_id": t.task_id,
+                    "command": t.command,
+                    "elapsed_seconds": format!("{:.1}", elapsed).parse::<f64>().unwrap_or(elapsed),
+                })
+            })
+            .collect()
+    };
+    let circuit = state.circuit_breaker.lock().unwrap().get_status();
+
+    let headline = match &session {
+        Some(s) => format!(
+            "{} commands ({} ok, {} failed, {} timed out), {} running, circuit {}",
+            s.total_commands, s.successes, s.failures, s.timeouts, running.len(), circuit.state
+        ),
+        None => format!("ALAN unavailable, {} running, circuit {}", running.len(), circuit.state),
+    };
+    let uptime = now_epoch() - state.started_at_epoch;
+    let result = serde_json::json!({
+        "headline": headline,
+        "session_id": state.session_id,
+        "started_at": format_timestamp(state.config.timestamp_format, state.started_at_epoch),
+        "uptime_seconds": format!("{:.0}", uptime).parse::<f64>().unwrap_or(uptime),
+        "session": session.map(|s| serde_json::to_value(s).unwrap_or(Value::Null)),
+        "top_patterns": serde_json::to_value(patterns).unwrap_or(Value::Null),
+        "notable_failures": serde_json::to_value(failures).unwrap_or(Value::Null),
+        "running_tasks": running,
+        "circuit": serde_json::to_value(&circuit).unwrap_or(Value::Null),
+    });
+    text_content(&serde_json::to_string_pretty(&result).unwrap_or_default())
+}
+
 fn handle_health(state: &Arc<ServerState>) -> Value {
     let cb_status = state.circuit_breaker.lock().unwrap().get_status();
     let alan_stats = alan::open_db(&state.db_path)
@@ -865,6 +2823,7 @@ fn handle_health(state: &Arc<ServerState>) -> Value {
 
     let result = serde_json::json!({
         "status": "healthy",
+        "started_at": format_timestamp(state.config.timestamp_format, state.started_at_epoch),
         "neverhang": serde_json::to_value(&cb_status).unwrap_or(Value::Null),
         "alan": alan_stats.map(|s| serde_json::to_value(s).unwrap_or(Value::Null)),
         "active_tasks": active_tasks,
@@ -905,31 +2864,463 @@ fn handle_alan_query(state: &Arc<ServerState>, args: &Value) -> Value {
     }
 }
 
-fn handle_neverhang_status(state: &Arc<ServerState>) -> Value {
-    let status = state.circuit_breaker.lock().unwrap().get_status();
-    text_content(
-        &serde_json::to_string_pretty(&serde_json::to_value(status).unwrap_or(Value::Null))
-            .unwrap_or_default(),
-    )
+fn handle_estimate(state: &Arc<ServerState>, args: &Value) -> Value {
+    let command = match args.get("command").and_then(|v| v.as_str()) {
+        Some(c) => c,
+        None => return error_content("Missing required parameter: command"),
+    };
+
+    match alan::open_db(&state.db_path) {
+        Ok(conn) => {
+            let result = alan::stats::estimate_duration(&conn, command);
+            text_content(
+                &serde_json::to_string_pretty(
+                    &serde_json::to_value(result).unwrap_or(Value::Null),
+                )
+                .unwrap_or_default(),
+            )
+        }
+        Err(e) => error_content(&format!("ALAN DB error: {}", e)),
+    }
 }
 
-fn handle_neverhang_reset(state: &Arc<ServerState>) -> Value {
-    state.circuit_breaker.lock().unwrap().reset();
-    text_content(
-        &serde_json::to_string_pretty(&serde_json::json!({
-            "success": true,
-            "message": "Circuit breaker reset to CLOSED state"
-        }))
-        .unwrap_or_default(),
-    )
+/// Policy dry run: would `zsh` accept this command? Nothing is executed and
+/// no circuit or rate-limit state is consumed.
+fn handle_check(state: &Arc<ServerState>, args: &Value) -> Value {
+    let command = match args.get("command").and_then(|v| v.as_str()) {
+        Some(c) => c,
+        None => return error_content("Missing required parameter: command"),
+    };
+    let verdict = policy::check(
+        command,
+        &state.config.deny_commands,
+        state.config.allow_commands.as_deref(),
+    );
+    let (pre_insights, retry_info) = pre_insights_for(state, command);
+    let mut result = serde_json::json!({
+        "command": command,
+        "allowed": verdict.is_allowed(),
+        "matched_pattern": verdict.pattern(),
+        "insights": combine_insights(&pre_insights, &[]),
+    });
+    attach_retry_info(&mut result, &retry_info);
+    if let Some((reason, detail, _)) = policy_block(state, command) {
+        result["blocked_reason"] = serde_json::json!(reason.as_str());
+        result["detail"] = serde_json::json!(detail);
+    }
+    text_content(&serde_json::to_string_pretty(&result).unwrap_or_default())
 }
 
-/// Combine pre and post insights into grouped {level: [messages]} map.
-fn combine_insights(
-    pre: &[(String, String)],
-    post: &[(String, String)],
-) -> serde_json::Map<String, Value> {
-    let mut map: std::collections::HashMap<String, Vec<String>> =
+/// Set (or, without `path`, report) the working directory used by every later
+/// `zsh` call. Relative paths resolve against the current one, like `cd`.
+fn handle_cwd(state: &Arc<ServerState>, args: &Value) -> Value {
+    let mut cwd = state.cwd.lock().unwrap();
+    let current = cwd.clone().or_else(|| {
+        std::env::current_dir()
+            .ok()
+            .map(|p| p.to_string_lossy().into_owned())
+    });
+    let path = match args.get("path").and_then(|v| v.as_str()) {
+        Some(p) => p,
+        None => return text_content(&format!("Working directory: {}", current.unwrap_or_default())),
+    };
+    let expanded = crate::config::expand_tilde(path);
+    let target = match current {
+        Some(base) => std::path::Path::new(&base).join(&expanded),
+        None => std::path::PathBuf::from(&expanded),
+    };
+    match std::fs::canonicalize(&target) {
+        Ok(dir) if dir.is_dir() => {
+            let dir = dir.to_string_lossy().into_owned();
+            *cwd = Some(dir.clone());
+            text_content(&format!("Working directory: {}", dir))
+        }
+        Ok(_) => error_content(&format!("Not a directory: {}", path)),
+        Err(e) => error_content(&format!("Cannot use {} as working directory: {}", path, e)),
+    }
+}
+
+/// Set and unset session-wide variables for later `zsh` calls, then report
+/// the full set. Unsetting an unknown name is a no-op.
+fn handle_env(state: &Arc<ServerState>, args: &Value) -> Value {
+    let set = match parse_env_object(args.get("set"), "set") {
+        Ok(set) => set,
+        Err(e) => return error_content(&e),
+    };
+    let unset: Vec<&str> = match args.get("unset") {
+        None | Some(Value::Null) => Vec::new(),
+        Some(Value::Array(names)) => match names.iter().map(|n| n.as_str()).collect::<Option<Vec<_>>>() {
+            Some(names) => names,
+            None => return error_content("unset must be an array of variable names"),
+        },
+        Some(_) => return error_content("unset must be an array of variable names"),
+    };
+    let mut session_env = state.session_env.lock().unwrap();
+    session_env.extend(set);
+    for name in unset {
+        session_env.remove(name);
+    }
+    let sorted: std::collections::BTreeMap<_, _> = session_env.iter().collect();
+    text_content(
+        &serde_json::to_string_pretty(&serde_json::json!({ "env": sorted })).unwrap_or_default(),
+    )
+}
+
+fn handle_env_snapshot(state: &Arc<ServerState>) -> Value {
+    match envdiff::capture(&state.shell_path) {
+        Ok(env) => {
+            let count = env.len();
+            *state.env_baseline.lock().unwrap() = Some(env);
+            text_content(&format!("Environment baseline captured: {} variables", count))
+        }
+        Err(e) => error_content(&format!("Failed to capture environment: {}", e)),
+    }
+}
+
+fn handle_env_diff(state: &Arc<ServerState>) -> Value {
+    let current = match envdiff::capture(&state.shell_path) {
+        Ok(env) => env,
+        Err(e) => return error_content(&format!("Failed to capture environment: {}", e)),
+    };
+    let baseline = state.env_baseline.lock().unwrap();
+    let baseline = match baseline.as_ref() {
+        Some(b) => b,
+        None => return error_content("No environment baseline; call zsh_env_snapshot first"),
+    };
+    let diff = envdiff::diff(baseline, &current);
+    text_content(
+        &serde_json::to_string_pretty(&serde_json::to_value(diff).unwrap_or(Value::Null))
+            .unwrap_or_default(),
+    )
+}
+
+fn handle_alan_templates(state: &Arc<ServerState>, args: &Value) -> Value {
+    let limit = args.get("limit").and_then(|v| v.as_i64()).unwrap_or(50).max(1);
+    let offset = args.get("offset").and_then(|v| v.as_i64()).unwrap_or(0).max(0);
+
+    match alan::open_db(&state.db_path) {
+        Ok(conn) => {
+            let result = alan::stats::list_templates(&conn, limit, offset);
+            text_content(
+                &serde_json::to_string_pretty(
+                    &serde_json::to_value(result).unwrap_or(Value::Null),
+                )
+                .unwrap_or_default(),
+            )
+        }
+        Err(e) => error_content(&format!("ALAN DB error: {}", e)),
+    }
+}
+
+/// Dump the learning tables as JSON, inline or to `path`. Inline exports
+/// larger than `truncate_output_at` are refused rather than cut mid-JSON.
+fn handle_alan_export(state: &Arc<ServerState>, args: &Value) -> Value {
+    let since = args.get("since").and_then(|v| v.as_str());
+    let export = match alan::open_db(&state.db_path).and_then(|conn| alan::stats::export(&conn, since)) {
+        Ok(export) => export,
+        Err(e) => return error_content(&format!("ALAN export failed: {}", e)),
+    };
+    let counts = format!(
+        "{} observations, {} streaks, {} ssh observations",
+        export.observations.len(),
+        export.streaks.len(),
+        export.ssh_observations.len()
+    );
+    let json = serde_json::to_string_pretty(&export).unwrap_or_default();
+
+    if let Some(path) = args.get("path").and_then(|v| v.as_str()) {
+        let path = crate::config::expand_tilde(path);
+        return match std::fs::write(&path, &json) {
+            Ok(()) => text_content(&format!("Exported {} to {} ({} bytes)", counts, path, json.len())),
+            Err(e) => error_content(&format!("Cannot write {}: {}", path, e)),
+        };
+    }
+    if json.len() > state.config.truncate_output_at {
+        return error_content(&format!(
+            "Export is {} bytes ({}), over the {}-byte output limit. Pass path to write it to a file, or narrow it with since.",
+            json.len(),
+            counts,
+            state.config.truncate_output_at
+        ));
+    }
+    text_content(&json)
+}
+
+/// Merge a `zsh_alan_export` dump, given inline as `data` or read from `path`.
+fn handle_alan_import(state: &Arc<ServerState>, args: &Value) -> Value {
+    let parsed = match (args.get("data"), args.get("path").and_then(|v| v.as_str())) {
+        (Some(Value::String(text)), _) => serde_json::from_str::<alan::stats::AlanExport>(text),
+        (Some(data @ Value::Object(_)), _) => serde_json::from_value(data.clone()),
+        (Some(_), _) => return error_content("data must be the export object or its JSON text"),
+        (None, Some(path)) => {
+            let path = crate::config::expand_tilde(path);
+            match std::fs::read_to_string(&path) {
+                Ok(text) => serde_json::from_str(&text),
+                Err(e) => return error_content(&format!("Cannot read {}: {}", path, e)),
+            }
+        }
+        (None, None) => return error_content("Missing required parameter: data or path"),
+    };
+    let export = match parsed {
+        Ok(export) => export,
+        Err(e) => return error_content(&format!("Import rejected, nothing was written: {}", e)),
+    };
+    let summary = alan::open_db(&state.db_path).and_then(|mut conn| alan::backup::merge_export(&mut conn, &export));
+    match summary {
+        Ok(summary) => text_content(
+            &serde_json::to_string_pretty(&serde_json::to_value(summary).unwrap_or(Value::Null))
+                .unwrap_or_default(),
+        ),
+        Err(e) => error_content(&format!("Import rolled back: {}", e)),
+    }
+}
+
+fn handle_alan_timeouts(state: &Arc<ServerState>, args: &Value) -> Value {
+    let limit = args.get("limit").and_then(|v| v.as_i64()).unwrap_or(10).max(1);
+
+    match alan::open_db(&state.db_path) {
+        Ok(conn) => {
+            let result = alan::stats::timeout_ranking(&conn, limit);
+            text_content(
+                &serde_json::to_string_pretty(&serde_json::json!({ "patterns": result }))
+                    .unwrap_or_default(),
+            )
+        }
+        Err(e) => error_content(&format!("ALAN DB error: {}", e)),
+    }
+}
+
+fn handle_alan_check(state: &Arc<ServerState>) -> Value {
+    match alan::maintenance::check_path(&state.db_path) {
+        Ok(report) => text_content(
+            &serde_json::to_string_pretty(&serde_json::to_value(report).unwrap_or(Value::Null))
+                .unwrap_or_default(),
+        ),
+        Err(e) => error_content(&format!("ALAN DB error: {}", e)),
+    }
+}
+
+/// Pin (or with `unpin`, release) a command's template so its observations
+/// keep full weight through decay and pruning. Without `command`, lists the
+/// pinned templates.
+fn handle_alan_pin(state: &Arc<ServerState>, args: &Value) -> Value {
+    let conn = match alan::open_db(&state.db_path) {
+        Ok(conn) => conn,
+        Err(e) => return error_content(&format!("ALAN DB error: {}", e)),
+    };
+    let mut result = serde_json::json!({});
+    if let Some(command) = args.get("command").and_then(|v| v.as_str()).filter(|c| !c.trim().is_empty()) {
+        let pinned = !args.get("unpin").and_then(|v| v.as_bool()).unwrap_or(false);
+        let template = alan::hash::template_command(command);
+        match alan::prune::set_pinned(&conn, &template, pinned) {
+            Ok(changed) => {
+                result["template"] = Value::String(template);
+                result["pinned"] = Value::Bool(pinned);
+                result["observations_changed"] = serde_json::json!(changed);
+            }
+            Err(e) => return error_content(&format!("ALAN DB error: {}", e)),
+        }
+    }
+    match alan::prune::pinned_templates(&conn) {
+        Ok(templates) => result["pinned_templates"] = serde_json::json!(templates),
+        Err(e) => return error_content(&format!("ALAN DB error: {}", e)),
+    }
+    text_content(&serde_json::to_string_pretty(&result).unwrap_or_default())
+}
+
+fn handle_alan_next(state: &Arc<ServerState>, args: &Value) -> Value {
+    let command = match args.get("command").and_then(|v| v.as_str()) {
+        Some(c) => c,
+        None => return error_content("Missing required parameter: command"),
+    };
+    let limit = args.get("limit").and_then(|v| v.as_i64()).unwrap_or(5).max(1);
+
+    match alan::open_db(&state.db_path) {
+        Ok(conn) => {
+            let result = alan::sequence::predict_next(&conn, command, limit);
+            text_content(
+                &serde_json::to_string_pretty(
+                    &serde_json::to_value(result).unwrap_or(Value::Null),
+                )
+                .unwrap_or_default(),
+            )
+        }
+        Err(e) => error_content(&format!("ALAN DB error: {}", e)),
+    }
+}
+
+/// Pre-build manopt tables so failure insights can show options right away
+/// instead of waiting for repeated failures to trigger the parse.
+fn handle_manopt_warm(state: &Arc<ServerState>, args: &Value) -> Value {
+    let commands: Vec<&str> = match args.get("commands").and_then(|v| v.as_array()) {
+        Some(list) => list.iter().filter_map(|v| v.as_str()).collect(),
+        None => return error_content("Missing required parameter: commands"),
+    };
+    // Names reach `man` as an argument; keep them to plain command names
+    if let Some(bad) = commands.iter().find(|c| {
+        c.is_empty()
+            || c.starts_with('-')
+            || !c.chars().all(|ch| ch.is_ascii_alphanumeric() || "._+-".contains(ch))
+    }) {
+        return error_content(&format!("Invalid command name: {:?}", bad));
+    }
+    let conn = match alan::open_db(&state.db_path) {
+        Ok(c) => c,
+        Err(e) => return error_content(&format!("ALAN DB error: {}", e)),
+    };
+
+    let timeout = std::time::Duration::from_secs_f64(state.config.alan_manopt_timeout.max(0.0));
+    let (mut warmed, mut cached, mut unavailable) = (Vec::new(), Vec::new(), Vec::new());
+    for command in commands {
+        if alan::manopt::get_cached(&conn, command).is_some() {
+            cached.push(command);
+        } else if alan::manopt::run_and_cache_within(&conn, command, Some(timeout)).is_some() {
+            warmed.push(command);
+        } else {
+            unavailable.push(command);
+        }
+    }
+    text_content(
+        &serde_json::to_string_pretty(&serde_json::json!({
+            "warmed": warmed,
+            "already_cached": cached,
+            "unavailable": unavailable,
+        }))
+        .unwrap_or_default(),
+    )
+}
+
+/// Toggle verbose protocol logging; `verbose` sets it explicitly.
+fn handle_debug(args: &Value) -> Value {
+    let enabled = args
+        .get("verbose")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(!protocol::verbose_logging());
+    let previous = protocol::set_verbose_logging(enabled);
+    server_log!(LogLevel::Info, "Verbose protocol logging {}", if enabled { "on" } else { "off" });
+    text_content(
+        &serde_json::to_string_pretty(&serde_json::json!({
+            "verbose_protocol_logging": enabled,
+            "previous": previous,
+        }))
+        .unwrap_or_default(),
+    )
+}
+
+/// Effective config values, each with the source that set it.
+fn handle_config(state: &Arc<ServerState>, args: &Value) -> Value {
+    let key = args.get("key").and_then(|v| v.as_str());
+    let mut report = serde_json::Map::new();
+    for (field, value) in state.config.entries() {
+        if key.is_some_and(|k| k != field) {
+            continue;
+        }
+        report.insert(
+            field.to_string(),
+            serde_json::json!({"value": value, "source": state.config.source(field).as_str()}),
+        );
+    }
+    if report.is_empty() {
+        if let Some(k) = key {
+            return error_content(&format!("Unknown config field: {}", k));
+        }
+    }
+    text_content(&serde_json::to_string_pretty(&Value::Object(report)).unwrap_or_default())
+}
+
+fn handle_neverhang_status(state: &Arc<ServerState>) -> Value {
+    let status = state.circuit_breaker.lock().unwrap().get_status();
+    text_content(
+        &serde_json::to_string_pretty(&serde_json::to_value(status).unwrap_or(Value::Null))
+            .unwrap_or_default(),
+    )
+}
+
+fn handle_circuit_trip(state: &Arc<ServerState>) -> Value {
+    let status = {
+        let mut cb = state.circuit_breaker.lock().unwrap();
+        cb.trip();
+        cb.get_status()
+    };
+    text_content(
+        &serde_json::to_string_pretty(&serde_json::to_value(status).unwrap_or(Value::Null))
+            .unwrap_or_default(),
+    )
+}
+
+fn handle_neverhang_reset(state: &Arc<ServerState>) -> Value {
+    state.circuit_breaker.lock().unwrap().reset();
+    text_content(
+        &serde_json::to_string_pretty(&serde_json::json!({
+            "success": true,
+            "message": "Circuit breaker reset to CLOSED state"
+        }))
+        .unwrap_or_default(),
+    )
+}
+
+/// Render a result object as rich text content, applying config-driven format options.
+fn rich_content(state: &Arc<ServerState>, result: &Value) -> Value {
+    let opts = format::FormatOptions {
+        empty_output_text: &state.config.empty_output_text,
+        tab_width: state.config.expand_tabs.then_some(state.config.tab_width),
+        output_markers: (&state.config.output_marker_start, &state.config.output_marker_end),
+    };
+    let mut content = text_content(&format::format_rich_output_with(result.as_object().unwrap(), &opts));
+    if let (Some(link), Some(items)) = (result.get("output_resource"), content["content"].as_array_mut()) {
+        if PROTOCOL_VERSION.with(Cell::get) >= protocol::RESOURCE_LINK_VERSION {
+            let mut link = link.clone();
+            link["type"] = serde_json::json!("resource_link");
+            items.push(link);
+        } else {
+            // Older clients reject resource_link content; name the URI instead
+            items.push(serde_json::json!({
+                "type": "text",
+                "text": format!(
+                    "Full output: {} ({} bytes), read it with resources/read",
+                    link["uri"].as_str().unwrap_or(""),
+                    link["size"]
+                ),
+            }));
+        }
+    }
+    // structuredContent must be an object, so other JSON values are wrapped
+    match result.get("json") {
+        Some(json @ Value::Object(_)) => content["structuredContent"] = json.clone(),
+        Some(json) => content["structuredContent"] = serde_json::json!({"value": json}),
+        None => {}
+    }
+    content
+}
+
+/// Current time as seconds since the Unix epoch.
+fn now_epoch() -> f64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+/// Render an epoch timestamp per the configured `timestamp_format`.
+/// Epoch values are rounded to milliseconds; ISO 8601 is UTC with millisecond precision.
+fn format_timestamp(fmt: TimestampFormat, epoch: f64) -> Value {
+    match fmt {
+        TimestampFormat::Epoch => serde_json::json!((epoch * 1000.0).round() / 1000.0),
+        TimestampFormat::Iso8601 => {
+            let millis = (epoch * 1000.0).round() as i64;
+            chrono::DateTime::from_timestamp_millis(millis)
+                .map(|dt| Value::String(dt.to_rfc3339_opts(chrono::SecondsFormat::Millis, true)))
+                .unwrap_or(Value::Null)
+        }
+    }
+}
+
+/// Combine pre and post insights into grouped {level: [messages]} map.
+fn combine_insights(
+    pre: &[(String, String)],
+    post: &[(String, String)],
+) -> serde_json::Map<String, Value> {
+    let mut map: std::collections::HashMap<String, Vec<String>> =
         std::collections::HashMap::new();
     for (level, msg) in pre.iter().chain(post.iter()) {
         map.entry(level.clone()).or_default().push(msg.clone());
@@ -946,10 +3337,12 @@ fn combine_insights(
 
 /// Enqueue a background task completion event for notification on next tool call.
 fn enqueue_event(state: &Arc<ServerState>, task_id: &str, exit_code: i32, elapsed: f64) {
+    let connection = state.tasks.lock().unwrap().tasks.get(task_id).map_or(0, |t| t.connection);
     state.event_queue.lock().unwrap().push(CompletedEvent {
         task_id: task_id.to_string(),
         exit_code,
         elapsed,
+        connection,
     });
 }
 
@@ -960,60 +3353,225 @@ fn suppress_event_for_task(state: &Arc<ServerState>, task_id: &str) {
     queue.retain(|ev| ev.task_id != task_id);
 }
 
-/// Drain all pending completion events and return formatted notification lines.
-/// Events are consumed — each fires exactly once.
+/// Drain this connection's pending completion events and return formatted
+/// notification lines. Events are consumed — each fires exactly once.
 fn drain_events(state: &Arc<ServerState>) -> String {
+    let connection = CURRENT_CONNECTION.with(Cell::get);
     let mut queue = state.event_queue.lock().unwrap();
-    if queue.is_empty() {
+    let (mine, others): (Vec<CompletedEvent>, Vec<CompletedEvent>) =
+        queue.drain(..).partition(|ev| ev.connection == connection);
+    *queue = others;
+    if mine.is_empty() {
         return String::new();
     }
-    let events: Vec<(String, i32, f64)> = queue
-        .drain(..)
-        .map(|ev| (ev.task_id.clone(), ev.exit_code, ev.elapsed))
+    let events: Vec<(String, i32, f64)> = mine
+        .into_iter()
+        .map(|ev| (ev.task_id, ev.exit_code, ev.elapsed))
         .collect();
     format::format_notifications(&events)
 }
 
+/// Drain pending completion events as `notifications/message` notifications,
+/// at `warning` for a nonzero exit and `info` otherwise, each paired with the
+/// connection it is for.
+fn take_event_notifications(state: &Arc<ServerState>) -> Vec<(u64, JsonRpcNotification)> {
+    let events: Vec<CompletedEvent> = state.event_queue.lock().unwrap().drain(..).collect();
+    events
+        .into_iter()
+        .map(|ev| {
+            let (level, outcome) = if ev.exit_code == 0 {
+                (LogLevel::Info, "completed")
+            } else {
+                (LogLevel::Warning, "failed")
+            };
+            (ev.connection, JsonRpcNotification {
+                jsonrpc: "2.0".into(),
+                method: "notifications/message".into(),
+                params: serde_json::json!({
+                    "level": level.as_str(),
+                    "logger": "zsh-tool",
+                    "data": {
+                        "event": "task_completed",
+                        "task_id": ev.task_id,
+                        "exit_code": ev.exit_code,
+                        "elapsed_seconds": (ev.elapsed * 10.0).round() / 10.0,
+                        "message": format!(
+                            "task '{}' {} (exit={}, {:.1}s)",
+                            ev.task_id, outcome, ev.exit_code, ev.elapsed
+                        ),
+                    },
+                }),
+            })
+        })
+        .collect()
+}
+
 /// Prepend any pending background task notifications to a tool response.
+/// With the push notifier running the events are left for it instead.
 fn prepend_events(state: &Arc<ServerState>, response: Value) -> Value {
+    if state.pushing_events.load(Ordering::Relaxed) {
+        return response;
+    }
     let notifications = drain_events(state);
     if notifications.is_empty() {
         return response;
     }
-    if let Some(text) = response.get("content")
-        .and_then(|c| c.as_array())
-        .and_then(|a| a.first())
-        .and_then(|v| v.get("text"))
-        .and_then(|t| t.as_str())
+    let mut response = response;
+    // Edit the first text item in place so other content (resource links) survives
+    if let Some(text) = response.get_mut("content")
+        .and_then(|c| c.as_array_mut())
+        .and_then(|a| a.first_mut())
+        .and_then(|v| v.get_mut("text"))
     {
-        return text_content(&format!("{}\n\n{}", notifications, text));
+        if let Some(body) = text.as_str() {
+            *text = Value::String(format!("{}\n\n{}", notifications, body));
+        }
     }
     response
 }
 
-fn truncate_output(output: &str, max_len: usize) -> String {
-    if output.len() <= max_len {
-        output.to_string()
-    } else {
-        let truncated = &output[..max_len];
-        format!(
-            "{}\n\n[OUTPUT TRUNCATED - {} bytes total, showing first {}]",
-            truncated,
-            output.len(),
-            max_len
+/// Count a finished run whose output overflows the cap and, once a pattern
+/// has overflowed `truncation_hint_after` times, suggest trimming it at the
+/// source instead of re-running it.
+fn truncation_insight(state: &Arc<ServerState>, command: &str, output: &str) -> Option<(String, String)> {
+    let threshold = state.config.truncation_hint_after;
+    if threshold == 0 || !exceeds_output_cap(state, output) {
+        return None;
+    }
+    let count = {
+        let mut truncations = state.truncations.lock().unwrap();
+        let count = truncations.entry(alan::hash::hash_command(command)).or_insert(0);
+        *count += 1;
+        *count
+    };
+    (count >= threshold).then(|| {
+        (
+            "warning".to_string(),
+            format!(
+                "Output truncated on {} runs of this pattern. Narrow it (| grep PATTERN, | tail -n 50) or redirect to a file and read the part you need.",
+                count
+            ),
         )
+    })
+}
+
+/// Whether [`cap_output`] would cut anything from `output`.
+fn exceeds_output_cap(state: &Arc<ServerState>, output: &str) -> bool {
+    let max_lines = state.config.max_output_lines;
+    let lines = output.strip_suffix('\n').unwrap_or(output).split('\n').count();
+    output.len() > state.config.truncate_output_at || (max_lines > 0 && lines > max_lines)
+}
+
+/// Apply the configured line cap, then the byte limit, to output being returned.
+fn cap_output(state: &Arc<ServerState>, output: &str) -> String {
+    let limited = limit_lines(output, state.config.max_output_lines, state.config.output_lines_keep);
+    truncate_output(&limited, state.config.truncate_output_at, state.config.truncate_mode)
+}
+
+/// Keep at most `max_lines` lines from one end of the output, noting how many
+/// were dropped. 0 means no limit.
+fn limit_lines(output: &str, max_lines: usize, keep: LineKeep) -> String {
+    let body = output.strip_suffix('\n').unwrap_or(output);
+    let total = if body.is_empty() { 0 } else { body.split('\n').count() };
+    if max_lines == 0 || total <= max_lines {
+        return output.to_string();
+    }
+    let omitted = total - max_lines;
+    let lines: Vec<&str> = body.split('\n').collect();
+    match keep {
+        LineKeep::Head => format!(
+            "{}\n[{} lines omitted]",
+            lines[..max_lines].join("\n"),
+            omitted
+        ),
+        LineKeep::Tail => format!(
+            "[{} lines omitted]\n{}",
+            omitted,
+            lines[omitted..].join("\n")
+        ),
+    }
+}
+
+/// Cut output to at most `max_len` bytes, keeping the part `mode` selects.
+/// Cuts never split a multi-byte UTF-8 sequence.
+fn truncate_output(output: &str, max_len: usize, mode: TruncateMode) -> String {
+    if output.len() <= max_len {
+        return output.to_string();
+    }
+    match mode {
+        TruncateMode::Head => {
+            let head = head_bytes(output, max_len);
+            format!(
+                "{}\n\n[OUTPUT TRUNCATED - {} bytes total, showing first {}]",
+                head,
+                output.len(),
+                head.len()
+            )
+        }
+        TruncateMode::Tail => {
+            let tail = tail_bytes(output, max_len);
+            format!(
+                "[OUTPUT TRUNCATED - {} bytes total, showing last {}]\n\n{}",
+                output.len(),
+                tail.len(),
+                tail
+            )
+        }
+        TruncateMode::Middle => {
+            let head = head_bytes(output, max_len / 2);
+            let tail = tail_bytes(output, max_len - max_len / 2);
+            format!(
+                "{}\n\n[OUTPUT TRUNCATED - {} bytes total, {} elided from the middle]\n\n{}",
+                head,
+                output.len(),
+                output.len() - head.len() - tail.len(),
+                tail
+            )
+        }
     }
 }
 
+/// Longest prefix of at most `max_len` bytes that ends on a char boundary.
+fn head_bytes(output: &str, max_len: usize) -> &str {
+    let mut end = max_len.min(output.len());
+    while !output.is_char_boundary(end) {
+        end -= 1;
+    }
+    &output[..end]
+}
+
+/// Longest suffix of at most `max_len` bytes that starts on a char boundary,
+/// moved up to the next line start when the cut lands mid-line.
+fn tail_bytes(output: &str, max_len: usize) -> &str {
+    let mut start = output.len().saturating_sub(max_len);
+    while !output.is_char_boundary(start) {
+        start += 1;
+    }
+    let tail = &output[start..];
+    if start > 0 && !output[..start].ends_with('\n') {
+        if let Some(pos) = tail.find('\n').filter(|&pos| pos + 1 < tail.len()) {
+            return &tail[pos + 1..];
+        }
+    }
+    tail
+}
+
 /// Slice output to a delta or full range, prepend global line numbers,
 /// and apply truncation. Returns (numbered_output, from_line, to_line).
 /// `from_line` and `to_line` are 1-based. Returns (empty, 0, 0) if slice is empty.
+/// With `filter`, only matching lines are kept (numbers stay global) and a
+/// note after the output says how many were elided. With `stamps`, each line
+/// also carries its `timestamp_lines` prefix after the number.
+#[allow(clippy::too_many_arguments)]
 fn number_lines(
     full_buffer: &str,
     byte_offset: usize,
     line_offset: usize,
     full_output: bool,
     max_len: usize,
+    mode: TruncateMode,
+    filter: Option<&regex::Regex>,
+    stamps: Option<&[u64]>,
 ) -> (String, usize, usize) {
     let slice = if full_output {
         full_buffer
@@ -1029,11 +3587,18 @@ fn number_lines(
 
     let mut numbered = String::new();
     let mut line_num = start_line;
+    let mut elided = 0;
     for line in slice.split('\n') {
+        if filter.is_some_and(|re| !re.is_match(line)) {
+            elided += 1;
+            line_num += 1;
+            continue;
+        }
         if !numbered.is_empty() {
             numbered.push('\n');
         }
-        numbered.push_str(&format!("{}: {}", line_num, line));
+        let stamp = stamps.and_then(|s| s.get(line_num - 1)).map(|ms| line_stamp(*ms));
+        numbered.push_str(&format!("{}: {}{}", line_num, stamp.unwrap_or_default(), line));
         line_num += 1;
     }
     // split('\n') on "a\n" gives ["a", ""] — the trailing empty element
@@ -1044,28 +3609,2045 @@ fn number_lines(
         line_num - 1
     };
     let from_line = start_line;
+    let filter_note = |mut out: String| {
+        if let Some(re) = filter {
+            // The trailing split artifact was never a line, so it doesn't count
+            let elided = elided - usize::from(slice.ends_with('\n') && !re.is_match(""));
+            if !out.is_empty() {
+                out.push('\n');
+            }
+            out.push_str(&format!("[filter '{}': {} non-matching lines elided]", re.as_str(), elided));
+        }
+        out
+    };
 
     // Apply truncation to the numbered output
-    let truncated = truncate_output(&numbered, max_len);
+    let truncated = truncate_output(&numbered, max_len, mode);
+    if numbered.len() <= max_len {
+        return (filter_note(truncated), from_line, to_line);
+    }
 
-    // If truncated, recalculate to_line from what's actually returned
-    let actual_to_line = if truncated.len() > numbered.len() || truncated.contains("[OUTPUT TRUNCATED") {
-        // Count actual lines in the truncated output (before the truncation notice)
-        let content_before_notice = if let Some(pos) = truncated.find("\n\n[OUTPUT TRUNCATED") {
-            &truncated[..pos]
-        } else {
-            &truncated
-        };
-        let last_numbered_line = content_before_notice.lines().last().unwrap_or("");
-        // Parse the line number from "NNN: content"
-        last_numbered_line
-            .split(':')
-            .next()
-            .and_then(|s| s.trim().parse::<usize>().ok())
-            .unwrap_or(to_line)
-    } else {
-        to_line
+    // Parse the line number from "NNN: content"
+    let line_number = |line: &str| line.split(':').next().and_then(|s| s.trim().parse::<usize>().ok());
+    // Recalculate the range from what's actually returned; middle keeps both ends
+    let (from_line, to_line) = match mode {
+        TruncateMode::Head => {
+            let content_before_notice = match truncated.find("\n\n[OUTPUT TRUNCATED") {
+                Some(pos) => &truncated[..pos],
+                None => &truncated,
+            };
+            let last = content_before_notice.lines().last().unwrap_or("");
+            (from_line, line_number(last).unwrap_or(to_line))
+        }
+        TruncateMode::Tail => {
+            let content_after_notice = match truncated.find("]\n\n") {
+                Some(pos) => &truncated[pos + 3..],
+                None => &truncated,
+            };
+            let first = content_after_notice.lines().next().unwrap_or("");
+            (line_number(first).unwrap_or(from_line), to_line)
+        }
+        TruncateMode::Middle => (from_line, to_line),
     };
+    (filter_note(truncated), from_line, to_line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_state(db_path: &str) -> Arc<ServerState> {
+        test_state_with(Config {
+            alan_db_path: db_path.to_string(),
+            ..Config::default()
+        })
+    }
+
+    fn test_state_with(config: Config) -> Arc<ServerState> {
+        let db_path = config.alan_db_path.clone();
+        let mut cb = CircuitBreaker::new(
+            config.neverhang_failure_threshold,
+            config.neverhang_recovery_timeout,
+            config.neverhang_sample_window,
+        );
+        cb.trip_exit_codes = config.circuit_trip_exit_codes.clone();
+        cb.half_open_required_successes = config.half_open_required_successes;
+        cb.global_failure_threshold = config.circuit_global_failure_threshold;
+        Arc::new(ServerState {
+            exec_path: std::env::current_exe().unwrap(),
+            shell_path: std::path::PathBuf::from(executor::default_shell(executor::ShellKind::Zsh)),
+            db_path,
+            session_id: "test-session".to_string(),
+            started_at_epoch: now_epoch(),
+            circuit_breaker: Mutex::new(cb),
+            spawn_limiter: Mutex::new(RateLimiter::new(config.max_spawns_per_sec)),
+            tasks: Mutex::new(TaskRegistry {
+                tasks: HashMap::new(),
+            }),
+            event_queue: Mutex::new(Vec::new()),
+            env_baseline: Mutex::new(None),
+            truncations: Mutex::new(HashMap::new()),
+            cwd: Mutex::new(None),
+            session_env: Mutex::new(HashMap::new()),
+            last_activity: Mutex::new(std::time::Instant::now()),
+            pushing_events: AtomicBool::new(false),
+            request_gate: RwLock::new(()),
+            push_writers: Mutex::new(HashMap::new()),
+            request_tasks: Mutex::new(HashMap::new()),
+            timeout_exempt: compile_timeout_exempt(&config.timeout_exempt_patterns),
+            config,
+        })
+    }
+
+    fn result_text(result: &Value) -> String {
+        result["content"][0]["text"].as_str().unwrap_or("").to_string()
+    }
+
+    #[test]
+    fn test_circuit_blocked_command_carries_insights() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("alan.db");
+        let db_path = db_path.to_str().unwrap();
+        let state = test_state(db_path);
+
+        // Build a failing streak for the pattern
+        let conn = alan::open_db(db_path).unwrap();
+        for _ in 0..5 {
+            alan::record(&conn, "other-session", "make deploy", 1, 100, false, "", &[1]).unwrap();
+        }
+
+        {
+            let mut cb = state.circuit_breaker.lock().unwrap();
+            for _ in 0..cb.failure_threshold {
+                cb.record_timeout(&alan::hash::hash_command("make deploy"));
+            }
+        }
 
-    (truncated, from_line, actual_to_line)
+        let result = handle_zsh(&state, &serde_json::json!({"command": "make deploy"}));
+        let text = result_text(&result);
+        assert!(text.contains("Circuit OPEN"), "got: {}", text);
+        assert!(text.contains("Failing streak: 5"), "got: {}", text);
+    }
+
+    #[test]
+    fn test_pipe_segments_map_exit_codes() {
+        let segments = pipe_segments("false | true", &[1, 0]).unwrap();
+        assert_eq!(
+            segments,
+            serde_json::json!([
+                {"command": "false", "exit_code": 1},
+                {"command": "true", "exit_code": 0}
+            ])
+        );
+        assert!(pipe_segments("false", &[1]).is_none());
+        assert!(pipe_segments("a | b && c", &[0, 0, 1]).is_none());
+    }
+
+    #[test]
+    fn test_finalize_pipeline_includes_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("alan.db");
+        let state = test_state(db_path.to_str().unwrap());
+        let meta_path = dir.path().join("meta.json");
+        std::fs::write(&meta_path, r#"{"pipestatus":[1,0],"exit_code":0,"elapsed_ms":5,"timed_out":false}"#)
+            .unwrap();
+
+        let result = finalize_task(
+            &state, "seg1", "false | true", "", 0.1, &[],
+            meta_path.to_str().unwrap(), true, None,
+        );
+        let text = result_text(&result);
+        assert!(text.contains("false → "), "got: {}", text);
+        assert!(text.contains("true → "), "got: {}", text);
+    }
+
+    #[test]
+    fn test_finalize_expands_tabs_but_keeps_raw_buffer() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state_with(Config {
+            alan_db_path: dir.path().join("alan.db").to_str().unwrap().to_string(),
+            expand_tabs: true,
+            tab_width: 4,
+            ..Config::default()
+        });
+        let meta_path = dir.path().join("meta.json");
+        std::fs::write(&meta_path, r#"{"pipestatus":[0],"exit_code":0,"elapsed_ms":5,"timed_out":false}"#)
+            .unwrap();
+        insert_task(&state, "tab1", "running", "id\tname\n7\tx\n");
+
+        let result = finalize_task(
+            &state, "tab1", "cut -f1,2 t.tsv", "id\tname\n7\tx\n", 0.1, &[],
+            meta_path.to_str().unwrap(), true, None,
+        );
+        let text = result_text(&result);
+        assert!(text.contains("id  name"), "got: {}", text);
+        assert!(text.contains("7   x"), "got: {}", text);
+        let tasks = state.tasks.lock().unwrap();
+        assert_eq!(tasks.tasks["tab1"].output_buffer, "id\tname\n7\tx\n");
+    }
+
+    #[test]
+    fn test_finalize_silent_uses_configured_placeholder() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state_with(Config {
+            alan_db_path: dir.path().join("alan.db").to_str().unwrap().to_string(),
+            empty_output_text: "<silent>".to_string(),
+            ..Config::default()
+        });
+        let meta_path = dir.path().join("meta.json");
+        std::fs::write(&meta_path, r#"{"pipestatus":[0],"exit_code":0,"elapsed_ms":5,"timed_out":false}"#)
+            .unwrap();
+
+        let result = finalize_task(
+            &state, "quiet1", "true", "", 0.1, &[],
+            meta_path.to_str().unwrap(), true, None,
+        );
+        let text = result_text(&result);
+        assert!(text.contains("<silent>"), "got: {}", text);
+        assert!(!text.contains("(no output)"), "got: {}", text);
+    }
+
+    #[test]
+    fn test_format_timestamp_epoch_and_iso() {
+        let epoch = 1_700_000_000.123_4;
+        assert_eq!(
+            format_timestamp(TimestampFormat::Epoch, epoch),
+            serde_json::json!(1_700_000_000.123)
+        );
+        assert_eq!(
+            format_timestamp(TimestampFormat::Iso8601, epoch),
+            serde_json::json!("2023-11-14T22:13:20.123Z")
+        );
+    }
+
+    #[test]
+    fn test_health_uses_configured_timestamp_format() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state_with(Config {
+            alan_db_path: dir.path().join("alan.db").to_str().unwrap().to_string(),
+            timestamp_format: TimestampFormat::Iso8601,
+            ..Config::default()
+        });
+        let health: Value = serde_json::from_str(&result_text(&handle_health(&state))).unwrap();
+        let started = health["started_at"].as_str().expect("iso string");
+        assert!(chrono::DateTime::parse_from_rfc3339(started).is_ok(), "got: {}", started);
+
+        let state = test_state(dir.path().join("alan.db").to_str().unwrap());
+        let health: Value = serde_json::from_str(&result_text(&handle_health(&state))).unwrap();
+        assert!(health["started_at"].is_f64());
+    }
+
+    #[test]
+    fn test_network_check_warns_when_offline() {
+        let dir = tempfile::tempdir().unwrap();
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let state = test_state_with(Config {
+            alan_db_path: dir.path().join("alan.db").to_str().unwrap().to_string(),
+            network_check_enabled: true,
+            network_check_host: format!("127.0.0.1:{}", port),
+            network_check_timeout_ms: 200,
+            ..Config::default()
+        });
+        state.circuit_breaker.lock().unwrap().state = CircuitState::Open;
+        state.circuit_breaker.lock().unwrap().opened_at = Some(now_epoch());
+
+        // Circuit-blocked, so nothing spawns, but pre-insights are still attached.
+        let result = handle_zsh(&state, &serde_json::json!({"command": "curl https://example.com"}));
+        let text = result_text(&result);
+        assert!(text.contains("Network appears offline"), "got: {}", text);
+    }
+
+    #[test]
+    fn test_adaptive_yield() {
+        assert_eq!(adaptive_yield(2.0, 60.0), 0.5);
+        assert_eq!(adaptive_yield(0.5, 60.0), 0.25);
+        assert!((adaptive_yield(2.0, 2.5) - 2.75).abs() < 1e-9);
+        assert_eq!(adaptive_yield(2.0, 0.1), 2.0);
+        assert_eq!(adaptive_yield(2.0, 5.0), 2.0);
+    }
+
+    #[test]
+    fn test_slow_pattern_yields_early() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("alan.db");
+        let db_path = db_path.to_str().unwrap();
+        let state = test_state(db_path);
+
+        let conn = alan::open_db(db_path).unwrap();
+        for _ in 0..3 {
+            alan::record(&conn, "s", "make world", 0, 60_000, false, "", &[0]).unwrap();
+        }
+
+        let args = serde_json::json!({"command": "make world"});
+        assert_eq!(effective_yield_after(&state, "make world", &args), 0.5);
+        // Unknown pattern keeps the default
+        assert_eq!(effective_yield_after(&state, "make other", &args), 2.0);
+        // Explicit yield_after overrides
+        let args = serde_json::json!({"command": "make world", "yield_after": 3.0});
+        assert_eq!(effective_yield_after(&state, "make world", &args), 3.0);
+        let args = serde_json::json!({"command": "make world", "yield_after": -1.0});
+        assert_eq!(effective_yield_after(&state, "make world", &args), 0.0);
+    }
+
+    fn insert_task(state: &Arc<ServerState>, task_id: &str, status: &str, output: &str) {
+        state.tasks.lock().unwrap().tasks.insert(task_id.to_string(), test_task(task_id, status, output));
+    }
+
+    fn test_task(task_id: &str, status: &str, output: &str) -> TaskInfo {
+        TaskInfo {
+            task_id: task_id.to_string(),
+            command: "tail -f app.log".to_string(),
+            started_at: std::time::Instant::now(),
+            started_at_epoch: now_epoch(),
+            status: status.to_string(),
+            output_buffer: output.to_string(),
+            last_poll_offset: 0,
+            last_poll_line: 0,
+            has_stdin: false,
+            pipestatus: Vec::new(),
+            pid: None,
+            is_pty: false,
+            meta_path: String::new(),
+            reap_token: String::new(),
+            rss_bytes: 0,
+            pre_insights: Vec::new(),
+            retry_info: None,
+            timeout_secs: 0,
+            usual_secs: None,
+            raised_insights: Default::default(),
+            extract_json: false,
+            render_terminal: false,
+            output_hash: false,
+            line_stamps: None,
+            spooled_bytes: 0,
+            request_id: None,
+            connection: 0,
+            child: None,
+            reader: None,
+            stdin: None,
+        }
+    }
+
+    #[test]
+    fn test_registry_evicts_oldest_finished_tasks() {
+        let mut registry = TaskRegistry { tasks: HashMap::new() };
+        for (id, status) in [("r1", "running"), ("c1", "completed"), ("k1", "killed"), ("r2", "running"), ("c2", "completed")] {
+            assert!(registry.insert(test_task(id, status, ""), 5).is_empty());
+            std::thread::sleep(std::time::Duration::from_millis(2));
+        }
+
+        assert_eq!(registry.insert(test_task("c3", "completed", ""), 5), vec!["c1"]);
+        assert_eq!(registry.insert(test_task("r3", "running", ""), 5), vec!["k1"]);
+        let mut ids: Vec<&str> = registry.tasks.keys().map(String::as_str).collect();
+        ids.sort_unstable();
+        assert_eq!(ids, vec!["c2", "c3", "r1", "r2", "r3"]);
+
+        // Only running tasks left to evict: the registry grows past the limit
+        let mut running = TaskRegistry { tasks: HashMap::new() };
+        for id in ["a", "b", "c"] {
+            assert!(running.insert(test_task(id, "running", ""), 2).is_empty());
+        }
+        assert_eq!(running.tasks.len(), 3);
+        // 0 means unlimited
+        assert!(registry.insert(test_task("c4", "completed", ""), 0).is_empty());
+        assert_eq!(registry.tasks.len(), 6);
+    }
+
+    #[test]
+    fn test_output_returns_full_buffer_for_killed_task() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state_with(Config {
+            alan_db_path: dir.path().join("alan.db").to_str().unwrap().to_string(),
+            truncate_output_at: 20,
+            ..Config::default()
+        });
+        let output: String = (1..=50).map(|i| format!("log line {}\n", i)).collect();
+        insert_task(&state, "k1", "killed", &output);
+
+        let text = result_text(&handle_output(&state, &serde_json::json!({"task_id": "k1"})));
+        assert!(text.contains("1: log line 1\n"), "got: {}", text);
+        assert!(text.contains("50: log line 50"), "got: {}", text);
+        assert!(!text.contains("OUTPUT TRUNCATED"));
+        assert!(text.contains("KILLED"));
+
+        let text = result_text(&handle_output(&state, &serde_json::json!({"task_id": "k1", "tail": 2})));
+        assert!(!text.contains("48: log line 48"), "got: {}", text);
+        assert!(text.contains("49: log line 49"), "got: {}", text);
+        assert!(text.contains("50: log line 50"), "got: {}", text);
+    }
+
+    #[test]
+    fn test_output_unknown_task() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(dir.path().join("alan.db").to_str().unwrap());
+        let result = handle_output(&state, &serde_json::json!({"task_id": "nope"}));
+        assert_eq!(result["isError"], true);
+    }
+
+    #[test]
+    fn test_circuit_trip_blocks_until_reset() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(dir.path().join("alan.db").to_str().unwrap());
+
+        let status: Value = serde_json::from_str(&result_text(&handle_circuit_trip(&state))).unwrap();
+        assert_eq!(status["state"], "open");
+        assert!(status["time_until_retry"].as_u64().unwrap() > 0);
+
+        let text = result_text(&handle_zsh(&state, &serde_json::json!({"command": "git status"})));
+        assert!(text.contains("Circuit OPEN"), "got: {}", text);
+
+        handle_neverhang_reset(&state);
+        let status = state.circuit_breaker.lock().unwrap().get_status();
+        assert_eq!(status.state, "closed");
+    }
+
+    #[test]
+    fn test_first_circuit_block_explains_timeouts() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("alan.db");
+        let db_path = db_path.to_str().unwrap();
+        let state = test_state(db_path);
+
+        let conn = alan::open_db(db_path).unwrap();
+        alan::record(&conn, "s", "npm install", 0, 40_000, false, "", &[0]).unwrap();
+        alan::record(&conn, "s", "npm install", -1, 120_000, true, "", &[-1]).unwrap();
+        {
+            let mut cb = state.circuit_breaker.lock().unwrap();
+            for _ in 0..cb.failure_threshold {
+                cb.record_timeout(&alan::hash::hash_command("npm install"));
+            }
+        }
+
+        let text = result_text(&handle_zsh(&state, &serde_json::json!({"command": "npm install"})));
+        assert!(text.contains("Circuit OPEN"), "got: {}", text);
+        assert!(text.contains("Timed out: 'npm install'"), "got: {}", text);
+        assert!(text.contains("Completes in ~40s"), "got: {}", text);
+        assert!(text.contains("zsh_neverhang_reset"), "got: {}", text);
+
+        // Only the first block carries the notice
+        let text = result_text(&handle_zsh(&state, &serde_json::json!({"command": "npm install"})));
+        assert!(text.contains("Circuit OPEN"), "got: {}", text);
+        assert!(!text.contains("Timed out:"), "got: {}", text);
+    }
+
+    #[test]
+    fn test_disabled_tool_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state_with(Config {
+            alan_db_path: dir.path().join("alan.db").to_str().unwrap().to_string(),
+            enabled_tools: Some(vec!["zsh_alan_stats".to_string(), "zsh_alan_query".to_string()]),
+            ..Config::default()
+        });
+
+        let result = handle_tool_call(&state, "zsh", &serde_json::json!({"command": "ls"}));
+        assert_eq!(result["isError"], true);
+        assert!(result_text(&result).contains("disabled"), "got: {}", result_text(&result));
+
+        let result = handle_tool_call(&state, "zsh_alan_stats", &serde_json::json!({}));
+        assert!(result.get("isError").is_none_or(|v| v == false), "got: {}", result);
+    }
+
+    #[test]
+    fn test_separate_stderr_defaults_from_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state_with(Config {
+            alan_db_path: dir.path().join("alan.db").to_str().unwrap().to_string(),
+            separate_stderr: HashMap::from([
+                ("cargo".to_string(), true),
+                ("grep".to_string(), false),
+            ]),
+            ..Config::default()
+        });
+        let no_args = serde_json::json!({});
+
+        assert!(effective_separate_stderr(&state, "cargo build --release", &no_args));
+        assert!(effective_separate_stderr(&state, "/usr/bin/cargo test", &no_args));
+        assert!(!effective_separate_stderr(&state, "grep -r foo .", &no_args));
+        assert!(!effective_separate_stderr(&state, "ls", &no_args));
+        // An explicit arg overrides the configured default
+        assert!(!effective_separate_stderr(&state, "cargo build", &serde_json::json!({"separate_stderr": false})));
+        assert!(effective_separate_stderr(&state, "ls", &serde_json::json!({"separate_stderr": true})));
+    }
+
+    #[test]
+    fn test_limit_lines_head_and_tail() {
+        let output: String = (1..=10).map(|i| format!("line {}\n", i)).collect();
+
+        let head = limit_lines(&output, 3, LineKeep::Head);
+        assert_eq!(head, "line 1\nline 2\nline 3\n[7 lines omitted]");
+
+        let tail = limit_lines(&output, 2, LineKeep::Tail);
+        assert_eq!(tail, "[8 lines omitted]\nline 9\nline 10");
+
+        assert_eq!(limit_lines(&output, 0, LineKeep::Head), output);
+        assert_eq!(limit_lines(&output, 10, LineKeep::Head), output);
+    }
+
+    #[test]
+    fn test_completed_output_capped_by_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state_with(Config {
+            alan_db_path: dir.path().join("alan.db").to_str().unwrap().to_string(),
+            max_output_lines: 5,
+            output_lines_keep: LineKeep::Tail,
+            ..Config::default()
+        });
+        let output: String = (1..=200).map(|i| format!("row {}\n", i)).collect();
+        let meta_path = dir.path().join("meta.json");
+        std::fs::write(&meta_path, r#"{"pipestatus":[0],"exit_code":0,"elapsed_ms":5,"timed_out":false}"#).unwrap();
+
+        let result = finalize_task(
+            &state, "t1", "seq 200", &output, 0.1, &[],
+            meta_path.to_str().unwrap(), true, None,
+        );
+        let text = result_text(&result);
+        assert!(text.contains("[195 lines omitted]"), "got: {}", text);
+        assert!(text.contains("row 200"));
+        assert!(!text.contains("row 195\n"), "got: {}", text);
+        assert!(text.contains("row 196"));
+    }
+
+    #[test]
+    fn test_debug_tool_toggles_verbose_logging() {
+        let initial = protocol::verbose_logging();
+
+        handle_debug(&serde_json::json!({"verbose": true}));
+        assert!(protocol::verbose_logging());
+
+        let result = handle_debug(&serde_json::json!({}));
+        assert!(!protocol::verbose_logging());
+        assert!(result_text(&result).contains("\"verbose_protocol_logging\": false"));
+
+        handle_debug(&serde_json::json!({}));
+        assert!(protocol::verbose_logging());
+
+        protocol::set_verbose_logging(initial);
+    }
+
+    #[test]
+    fn test_timed_out_pipeline_reports_timeout_not_fake_status() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(dir.path().join("alan.db").to_str().unwrap());
+        let meta_path = dir.path().join("meta.json");
+        std::fs::write(&meta_path, r#"{"pipestatus":[],"exit_code":-1,"elapsed_ms":2000,"timed_out":true}"#).unwrap();
+
+        let result = finalize_task(
+            &state, "t1", "sleep 60 | cat", "", 2.0, &[],
+            meta_path.to_str().unwrap(), true, None,
+        );
+        let text = result_text(&result);
+        assert!(text.contains("TIMEOUT"), "got: {}", text);
+        assert!(text.contains("per-segment exit statuses unavailable"), "got: {}", text);
+        assert!(!text.contains("✔"), "got: {}", text);
+    }
+
+    fn assert_blocked_shape(result: &Value, reason: &str) {
+        assert_eq!(result["success"], false);
+        assert_eq!(result["blocked"], true);
+        assert_eq!(result["status"], "blocked");
+        assert_eq!(result["blocked_reason"], reason);
+        assert_eq!(result["task_id"], "");
+        let detail = result["detail"].as_str().unwrap();
+        assert!(!detail.is_empty());
+        assert_eq!(result["error"], detail);
+    }
+
+    #[test]
+    fn test_blocked_result_shape_for_each_reason() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(dir.path().join("alan.db").to_str().unwrap());
+        let insights = vec![("warning".to_string(), "Failing streak: 3".to_string())];
+
+        let circuit = blocked_result(
+            &state,
+            "make",
+            BlockReason::CircuitOpen,
+            "NEVERHANG: Circuit OPEN",
+            &insights,
+            serde_json::json!({}),
+        );
+        assert_blocked_shape(&circuit, "circuit_open");
+        assert_eq!(circuit["insights"]["warning"][0], "Failing streak: 3");
+
+        let limited = blocked_result(
+            &state,
+            "make",
+            BlockReason::RateLimited,
+            "Spawn rate limit exceeded (1/s). Retry in 0.4s.",
+            &insights,
+            serde_json::json!({"retry_after_seconds": 0.4}),
+        );
+        assert_blocked_shape(&limited, "rate_limited");
+        assert_eq!(limited["retry_after_seconds"], 0.4);
+    }
+
+    #[test]
+    fn test_brief_blocked_detail_drops_insights() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state_with(Config {
+            alan_db_path: dir.path().join("alan.db").to_str().unwrap().to_string(),
+            blocked_detail: BlockDetail::Brief,
+            ..Config::default()
+        });
+        let insights = vec![("info".to_string(), "Fix the cause".to_string())];
+        let result = blocked_result(
+            &state,
+            "ls",
+            BlockReason::CircuitOpen,
+            "NEVERHANG: Circuit OPEN\nsecond line",
+            &insights,
+            serde_json::json!({}),
+        );
+        assert_blocked_shape(&result, "circuit_open");
+        assert_eq!(result["detail"], "NEVERHANG: Circuit OPEN");
+        assert!(result.get("insights").is_none());
+    }
+
+    #[test]
+    fn test_zsh_blocked_by_circuit_renders_reason() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(dir.path().join("alan.db").to_str().unwrap());
+        {
+            let mut cb = state.circuit_breaker.lock().unwrap();
+            for i in 0..cb.global_failure_threshold {
+                cb.record_timeout(&format!("hash{}", i));
+            }
+        }
+        let text = result_text(&handle_zsh(&state, &serde_json::json!({"command": "ls"})));
+        assert!(text.contains("BLOCKED"), "got: {}", text);
+        assert!(text.contains("reason=circuit_open"), "got: {}", text);
+    }
+
+    #[test]
+    fn test_retry_class_retries_ssh_failures_not_missing_commands() {
+        use alan::insights::FailureClass;
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(dir.path().join("alan.db").to_str().unwrap());
+        let finished = |code: i32| serde_json::json!({"success": code == 0, "status": "completed", "pipestatus": [code]});
+
+        assert_eq!(
+            retry_class(&state, &finished(255)),
+            Some((FailureClass::Transient, "SSH connection failed (exit 255)".to_string()))
+        );
+        assert_eq!(
+            retry_class(&state, &finished(127)),
+            Some((FailureClass::Permanent, "command not found (exit 127)".to_string()))
+        );
+        assert_eq!(retry_class(&state, &finished(1)).unwrap().0, FailureClass::Other);
+        assert_eq!(retry_class(&state, &finished(0)), None);
+
+        let timed_out = serde_json::json!({"success": false, "status": "timeout", "timed_out": true, "pipestatus": []});
+        assert_eq!(retry_class(&state, &timed_out), Some((FailureClass::Transient, "timeout".to_string())));
+        let running = serde_json::json!({"success": false, "status": "running"});
+        assert_eq!(retry_class(&state, &running), None);
+    }
+
+    #[test]
+    fn test_open_pattern_circuit_does_not_block_other_commands() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state_with(Config {
+            alan_db_path: dir.path().join("alan.db").to_str().unwrap().to_string(),
+            max_spawns_per_sec: 1.0,
+            ..Config::default()
+        });
+        {
+            let mut cb = state.circuit_breaker.lock().unwrap();
+            for _ in 0..cb.failure_threshold {
+                cb.record_timeout(&alan::hash::hash_command("ssh deadhost uptime"));
+            }
+        }
+        // Drain the spawn limiter so a command that passes the circuit stops there
+        while state.spawn_limiter.lock().unwrap().try_acquire().is_ok() {}
+
+        let blocked = run_zsh(&state, &serde_json::json!({"command": "ssh deadhost uptime"})).unwrap();
+        assert_eq!(blocked["blocked_reason"], "circuit_open");
+        assert!(blocked["detail"].as_str().unwrap().contains("for this command pattern"), "got: {}", blocked);
+        let other = run_zsh(&state, &serde_json::json!({"command": "git status"})).unwrap();
+        assert_eq!(other["blocked_reason"], "rate_limited", "got: {}", other);
+
+        let status: Value = serde_json::from_str(&result_text(&handle_neverhang_status(&state))).unwrap();
+        assert_eq!(status["state"], "closed");
+        assert_eq!(status["patterns"][0]["state"], "open");
+    }
+
+    #[test]
+    fn test_zsh_blocked_by_rate_limit_renders_reason() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state_with(Config {
+            alan_db_path: dir.path().join("alan.db").to_str().unwrap().to_string(),
+            max_spawns_per_sec: 1.0,
+            ..Config::default()
+        });
+        while state.spawn_limiter.lock().unwrap().try_acquire().is_ok() {}
+        let text = result_text(&handle_zsh(&state, &serde_json::json!({"command": "ls"})));
+        assert!(text.contains("reason=rate_limited"), "got: {}", text);
+        assert!(text.contains("retry in"), "got: {}", text);
+        assert!(text.contains("Spawn rate limit exceeded"), "got: {}", text);
+    }
+
+    #[test]
+    fn test_env_diff_against_snapshot() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        // The shell exports whatever vars.sh holds, so the test never touches
+        // this process's environment (other tests spawn processes in parallel)
+        let shell = dir.path().join("shell");
+        let vars = dir.path().join("vars.sh");
+        std::fs::write(&shell, format!("#!/bin/sh\nset -a\n. {}\nexec /bin/sh \"$@\"\n", vars.display())).unwrap();
+        std::fs::set_permissions(&shell, std::fs::Permissions::from_mode(0o755)).unwrap();
+        std::fs::write(&vars, "").unwrap();
+        let mut state = test_state(dir.path().join("alan.db").to_str().unwrap());
+        Arc::get_mut(&mut state).unwrap().shell_path = shell;
+
+        let text = result_text(&handle_env_diff(&state));
+        assert!(text.contains("call zsh_env_snapshot first"), "got: {}", text);
+
+        std::fs::write(&vars, "ZSH_TOOL_TEST_ENV_GONE=bye\n").unwrap();
+        let text = result_text(&handle_env_snapshot(&state));
+        assert!(text.contains("baseline captured"), "got: {}", text);
+
+        std::fs::write(&vars, "ZSH_TOOL_TEST_ENV_ADDED=hello\n").unwrap();
+        let diff: Value = serde_json::from_str(&result_text(&handle_env_diff(&state))).unwrap();
+
+        assert_eq!(diff["added"]["ZSH_TOOL_TEST_ENV_ADDED"], "hello");
+        assert_eq!(diff["removed"]["ZSH_TOOL_TEST_ENV_GONE"], "bye");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_poll_warns_when_rss_crosses_soft_threshold() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state_with(Config {
+            alan_db_path: dir.path().join("alan.db").to_str().unwrap().to_string(),
+            memory_warn_mb: 20,
+            ..Config::default()
+        });
+        let token = reap::new_token();
+        // Holds ~40 MB in a shell variable, then idles
+        let child = std::process::Command::new("sh")
+            .args(["-c", "x=$(head -c 40000000 /dev/zero | tr '\\0' a); sleep 30"])
+            .env(reap::TOKEN_VAR, &token)
+            .spawn()
+            .unwrap();
+        insert_task(&state, "m1", "running", "");
+        {
+            let mut tasks = state.tasks.lock().unwrap();
+            let task = tasks.tasks.get_mut("m1").unwrap();
+            task.reap_token = token.clone();
+            task.child = Some(child);
+        }
+
+        let mut text = String::new();
+        for _ in 0..100 {
+            check_and_finalize_background_tasks(&state);
+            text = result_text(&handle_poll(&state, &serde_json::json!({"task_id": "m1"})));
+            if text.contains("approaching limit") {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+        reap::kill_tagged(&token);
+        if let Some(mut child) = state.tasks.lock().unwrap().tasks.get_mut("m1").unwrap().child.take() {
+            let _ = child.wait();
+        }
+
+        assert!(text.contains("approaching limit (soft threshold 20 MB)"), "got: {}", text);
+        assert!(text.contains("RUNNING"), "got: {}", text);
+    }
+
+    #[test]
+    fn test_sequence_rejects_step_without_command() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(dir.path().join("alan.db").to_str().unwrap());
+        let result = handle_sequence(&state, &serde_json::json!({"steps": ["ls", {"continue_on_error": true}]}));
+        assert_eq!(result["isError"], true);
+        assert!(result_text(&result).contains("Step 1 has no command"));
+    }
+
+    #[test]
+    fn test_check_reports_matching_deny_pattern() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state_with(Config {
+            alan_db_path: dir.path().join("alan.db").to_str().unwrap().to_string(),
+            deny_commands: vec!["sudo *".to_string(), "*rm -rf /*".to_string()],
+            ..Config::default()
+        });
+
+        let text = result_text(&handle_check(&state, &serde_json::json!({"command": "cd x && rm -rf /tmp"})));
+        let v: Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(v["allowed"], false);
+        assert_eq!(v["matched_pattern"], "*rm -rf /*");
+        assert_eq!(v["blocked_reason"], "denied");
+
+        let text = result_text(&handle_check(&state, &serde_json::json!({"command": "ls -la"})));
+        let v: Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(v["allowed"], true);
+        assert!(v["matched_pattern"].is_null());
+        assert!(v.get("blocked_reason").is_none());
+    }
+
+    #[test]
+    fn test_blocked_result_carries_retry_info() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state_with(Config {
+            alan_db_path: dir.path().join("alan.db").to_str().unwrap().to_string(),
+            deny_commands: vec!["sudo *".to_string()],
+            ..Config::default()
+        });
+        {
+            let conn = alan::open_db(&state.db_path).unwrap();
+            for exit_code in [1, 1] {
+                alan::record(&conn, &state.session_id, "sudo reboot", exit_code, 10, false, "", &[exit_code]).unwrap();
+            }
+        }
+
+        let result = run_zsh(&state, &serde_json::json!({"command": "sudo reboot"})).unwrap();
+        assert_eq!(
+            result["retry_info"],
+            serde_json::json!({
+                "retry_count": 2,
+                "recent_successes": 0,
+                "recent_failures": 2,
+                "window_minutes": state.config.alan_recent_window_minutes,
+            })
+        );
+    }
+
+    #[test]
+    fn test_zsh_refuses_denied_and_unlisted_commands() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state_with(Config {
+            alan_db_path: dir.path().join("alan.db").to_str().unwrap().to_string(),
+            deny_commands: vec!["git push --force*".to_string()],
+            allow_commands: Some(vec!["git *".to_string()]),
+            ..Config::default()
+        });
+
+        let denied = run_zsh(&state, &serde_json::json!({"command": "git push --force"})).unwrap();
+        assert_blocked_shape(&denied, "denied");
+        assert_eq!(denied["matched_pattern"], "git push --force*");
+
+        let unlisted = run_zsh(&state, &serde_json::json!({"command": "make"})).unwrap();
+        assert_blocked_shape(&unlisted, "not_allowlisted");
+
+        let text = result_text(&handle_zsh(&state, &serde_json::json!({"command": "make"})));
+        assert!(text.contains("reason=not_allowlisted"), "got: {}", text);
+    }
+
+    #[test]
+    fn test_zsh_rejects_unknown_timeout_signal() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(dir.path().join("alan.db").to_str().unwrap());
+        let result = handle_zsh(&state, &serde_json::json!({"command": "sleep 1", "timeout_signal": "SIGNOPE"}));
+        assert_eq!(result["isError"], true);
+        assert!(result_text(&result).contains("Unknown timeout_signal: SIGNOPE"));
+    }
+
+    #[test]
+    fn test_reader_drains_bursty_output_without_polling() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(dir.path().join("alan.db").to_str().unwrap());
+        // Far more than a pipe buffer holds, in bursts
+        let mut child = std::process::Command::new("sh")
+            .args(["-c", "for i in 1 2 3 4; do head -c 500000 /dev/zero | tr '\\0' x; sleep 0.05; done; echo; echo DONE"])
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        let stdout = child.stdout.take().unwrap();
+        insert_task(&state, "burst", "running", "");
+        state.tasks.lock().unwrap().tasks.get_mut("burst").unwrap().child = Some(child);
+        let reader = spawn_output_reader(&state, "burst", stdout);
+        state.tasks.lock().unwrap().tasks.get_mut("burst").unwrap().reader = Some(reader);
+
+        // Nobody polls; the command must still run to completion
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+        let (_, _, output, _, _, _) = loop {
+            if let Some(args) = collect_if_done(&state, "burst") {
+                break args;
+            }
+            assert!(std::time::Instant::now() < deadline, "command stalled on a full pipe");
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        };
+        assert_eq!(output.len(), 2_000_000 + "\nDONE\n".len());
+        assert!(output.ends_with("x\nDONE\n"));
+        assert_eq!(state.tasks.lock().unwrap().tasks["burst"].status, "completed");
+    }
+
+    #[test]
+    fn test_take_utf8_prefix_keeps_split_sequence() {
+        let mut bytes = "aé".as_bytes().to_vec();
+        bytes.pop();
+        assert_eq!(take_utf8_prefix(&mut bytes), "a");
+        assert_eq!(bytes, vec![0xC3]);
+        bytes.push(0xA9);
+        assert_eq!(take_utf8_prefix(&mut bytes), "é");
+        assert!(bytes.is_empty());
+    }
+
+    #[test]
+    fn test_manopt_warm_populates_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("alan.db").to_str().unwrap().to_string();
+        let state = test_state(&db_path);
+
+        let result = handle_tool_call(&state, "zsh_manopt_warm", &serde_json::json!({"commands": ["ls"]}));
+        let parsed: Value = serde_json::from_str(&result_text(&result)).unwrap();
+        let conn = alan::open_db(&db_path).unwrap();
+        // Like test_parse_manopt_ls, only assert the table when man pages exist
+        let has_man = std::process::Command::new("man")
+            .arg("ls")
+            .output()
+            .map(|o| o.status.success())
+            .unwrap_or(false);
+        if has_man {
+            assert_eq!(parsed["warmed"], serde_json::json!(["ls"]), "got: {}", parsed);
+            assert!(alan::manopt::get_cached(&conn, "ls").unwrap().contains("ls options"));
+        } else {
+            assert_eq!(parsed["unavailable"], serde_json::json!(["ls"]), "got: {}", parsed);
+        }
+
+        // Cached entries are not parsed again
+        conn.execute(
+            "INSERT OR REPLACE INTO manopt_cache (base_command, options_text, created_at)
+             VALUES ('tar', 'tar options', '2026-01-01T00:00:00Z')",
+            [],
+        )
+        .unwrap();
+        let result = handle_tool_call(&state, "zsh_manopt_warm", &serde_json::json!({"commands": ["tar"]}));
+        let parsed: Value = serde_json::from_str(&result_text(&result)).unwrap();
+        assert_eq!(parsed["already_cached"], serde_json::json!(["tar"]));
+
+        // Option-like names never reach man
+        let result = handle_tool_call(&state, "zsh_manopt_warm", &serde_json::json!({"commands": ["-H"]}));
+        assert_eq!(result["isError"], true);
+    }
+
+    #[test]
+    fn test_repeated_truncation_suggests_filtering() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state_with(Config {
+            alan_db_path: dir.path().join("alan.db").to_str().unwrap().to_string(),
+            truncate_output_at: 100,
+            truncation_hint_after: 2,
+            ..Config::default()
+        });
+        let big = "line\n".repeat(100);
+        let hint = |result: &Value| {
+            result["insights"]["warning"]
+                .as_array()
+                .is_some_and(|w| w.iter().any(|m| m.as_str().unwrap().contains("Output truncated on")))
+        };
+
+        let finish = |task_id: &str, command: &str, output: &str| {
+            insert_task(&state, task_id, "running", output);
+            finalize_result(&state, task_id, command, output, 0.1, &[], "/nonexistent-meta.json", true, None)
+        };
+        assert!(!hint(&finish("t1", "cat app.log", &big)));
+        // Output that fits doesn't count
+        assert!(!hint(&finish("t2", "cat app.log", "short\n")));
+        assert!(!hint(&finish("t3", "cat other.log", &big)));
+        let result = finish("t4", "cat app.log", &big);
+        assert!(hint(&result), "got: {}", result["insights"]);
+        resources::remove_all(&state.session_id);
+    }
+
+    #[test]
+    fn test_neverhang_status_shows_thresholds() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state_with(Config {
+            alan_db_path: dir.path().join("alan.db").to_str().unwrap().to_string(),
+            neverhang_failure_threshold: 4,
+            neverhang_recovery_timeout: 90,
+            neverhang_sample_window: 600,
+            ..Config::default()
+        });
+        let status: Value =
+            serde_json::from_str(&result_text(&handle_tool_call(&state, "zsh_neverhang_status", &serde_json::json!({}))))
+                .unwrap();
+        assert_eq!(status["failure_threshold"], 4);
+        assert_eq!(status["recovery_timeout"], 90);
+        assert_eq!(status["sample_window"], 600);
+    }
+
+    #[test]
+    fn test_cwd_validates_and_resolves_relative_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap();
+        std::fs::create_dir(root.join("sub")).unwrap();
+        std::fs::write(root.join("file"), "").unwrap();
+        let state = test_state(root.join("alan.db").to_str().unwrap());
+
+        let set = handle_tool_call(&state, "zsh_cwd", &serde_json::json!({"path": root.to_str().unwrap()}));
+        assert!(set.get("isError").is_none(), "got: {}", set);
+        let sub = handle_tool_call(&state, "zsh_cwd", &serde_json::json!({"path": "sub"}));
+        assert!(result_text(&sub).ends_with("/sub"), "got: {}", result_text(&sub));
+        assert_eq!(*state.cwd.lock().unwrap(), Some(root.join("sub").to_string_lossy().into_owned()));
+
+        // Rejections leave the current directory in place
+        let missing = handle_tool_call(&state, "zsh_cwd", &serde_json::json!({"path": "../nope"}));
+        assert_eq!(missing["isError"], true);
+        let file = handle_tool_call(&state, "zsh_cwd", &serde_json::json!({"path": "../file"}));
+        assert_eq!(file["isError"], true);
+        assert!(result_text(&file).contains("Not a directory"));
+        let current = handle_tool_call(&state, "zsh_cwd", &serde_json::json!({}));
+        assert!(result_text(&current).ends_with("/sub"));
+    }
+
+    #[test]
+    fn test_zsh_rejects_stdin_fifo_that_is_not_a_fifo() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(dir.path().join("alan.db").to_str().unwrap());
+        let plain = dir.path().join("plain");
+        std::fs::write(&plain, "").unwrap();
+        let result = handle_zsh(&state, &serde_json::json!({"command": "cat", "stdin_fifo": plain.to_str().unwrap()}));
+        assert_eq!(result["isError"], true);
+        assert!(result_text(&result).contains("stdin_fifo is not a FIFO"));
+
+        let with_pty = handle_zsh(&state, &serde_json::json!({"command": "cat", "stdin_fifo": plain.to_str().unwrap(), "pty": true}));
+        assert!(result_text(&with_pty).contains("cannot be combined with pty"));
+    }
+
+    #[test]
+    fn test_parse_env_object() {
+        let parse = |args: Value| parse_env_object(args.get("env"), "env");
+        let env = parse(serde_json::json!({"env": {"FOO": "bar", "_X1": ""}})).unwrap();
+        assert_eq!(env, vec![("FOO".to_string(), "bar".to_string()), ("_X1".to_string(), String::new())]);
+        assert!(parse(serde_json::json!({})).unwrap().is_empty());
+        assert!(parse(serde_json::json!({"env": "FOO=bar"})).is_err());
+        assert!(parse(serde_json::json!({"env": {"1FOO": "x"}})).is_err());
+        assert!(parse(serde_json::json!({"env": {"A-B": "x"}})).is_err());
+        assert!(parse(serde_json::json!({"env": {"FOO": 1}})).is_err());
+    }
+
+    #[test]
+    fn test_idle_expired_after_timeout_without_running_tasks() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state_with(Config {
+            alan_db_path: dir.path().join("alan.db").to_str().unwrap().to_string(),
+            session_idle_timeout_secs: 1,
+            ..Config::default()
+        });
+        assert!(!idle_expired(&state));
+
+        let past = std::time::Instant::now() - std::time::Duration::from_secs(2);
+        *state.last_activity.lock().unwrap() = past;
+        assert!(idle_expired(&state));
+
+        // Disabled by default
+        let state = test_state(dir.path().join("alan2.db").to_str().unwrap());
+        *state.last_activity.lock().unwrap() = past;
+        assert!(!idle_expired(&state));
+    }
+
+    #[test]
+    fn test_env_tool_sets_unsets_and_round_trips_values() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(dir.path().join("alan.db").to_str().unwrap());
+
+        let set = handle_tool_call(&state, "zsh_env", &serde_json::json!({
+            "set": {"OPTS": "a=b c=d", "MULTI": "line1\nline2", "GONE": "x"}
+        }));
+        assert!(set.get("isError").is_none(), "got: {}", set);
+        let removed = handle_tool_call(&state, "zsh_env", &serde_json::json!({"unset": ["GONE", "NEVER_SET"]}));
+        assert!(removed.get("isError").is_none(), "got: {}", removed);
+
+        let shown: Value = serde_json::from_str(&result_text(&removed)).unwrap();
+        assert_eq!(shown["env"], serde_json::json!({"MULTI": "line1\nline2", "OPTS": "a=b c=d"}));
+        assert_eq!(state.session_env.lock().unwrap().get("MULTI").map(String::as_str), Some("line1\nline2"));
+
+        let bad = handle_tool_call(&state, "zsh_env", &serde_json::json!({"unset": "GONE"}));
+        assert_eq!(bad["isError"], true);
+        let bad = handle_tool_call(&state, "zsh_env", &serde_json::json!({"set": {"NO-DASH": "x"}}));
+        assert_eq!(bad["isError"], true);
+    }
+
+    #[test]
+    fn test_truncate_output_backs_off_to_char_boundary() {
+        // 4-byte emoji: a 10-byte cut lands mid-sequence
+        let output = "😀".repeat(5);
+        let truncated = truncate_output(&output, 10, TruncateMode::Head);
+        assert!(truncated.starts_with("😀😀\n\n"), "got: {}", truncated);
+        assert!(truncated.contains("20 bytes total, showing first 8"));
+        assert_eq!(truncate_output("日本語", 3, TruncateMode::Head), "日\n\n[OUTPUT TRUNCATED - 9 bytes total, showing first 3]");
+    }
+
+    #[test]
+    fn test_finalize_feeds_only_trip_exit_codes_to_circuit() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state_with(Config {
+            alan_db_path: dir.path().join("alan.db").to_str().unwrap().to_string(),
+            neverhang_failure_threshold: 2,
+            ..Config::default()
+        });
+        let finalize_exit = |id: &str, code: i32| {
+            let meta_path = dir.path().join(format!("{}.json", id));
+            let meta = serde_json::json!({"pipestatus": [code], "exit_code": code, "timed_out": false});
+            std::fs::write(&meta_path, meta.to_string()).unwrap();
+            insert_task(&state, id, "running", "");
+            finalize_result(&state, id, "./serve", "", 0.1, &[], meta_path.to_str().unwrap(), true, None);
+        };
+
+        finalize_exit("x1", 1);
+        finalize_exit("x2", 2);
+        assert!(state.circuit_breaker.lock().unwrap().failures.is_empty());
+        finalize_exit("x3", 137);
+        finalize_exit("x4", 143);
+        let hash = alan::hash::hash_command("./serve");
+        assert_eq!(state.circuit_breaker.lock().unwrap().patterns[&hash].state, CircuitState::Open);
+        resources::remove_all(&state.session_id);
+    }
+
+    #[test]
+    fn test_finalize_truncates_multibyte_output_without_panicking() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state_with(Config {
+            alan_db_path: dir.path().join("alan.db").to_str().unwrap().to_string(),
+            truncate_output_at: 1001,
+            ..Config::default()
+        });
+        let meta_path = dir.path().join("meta.json");
+        std::fs::write(&meta_path, r#"{"pipestatus":[0],"exit_code":0,"elapsed_ms":5,"timed_out":false}"#)
+            .unwrap();
+        let output = "漢字🎉".repeat(500);
+        insert_task(&state, "utf1", "running", &output);
+
+        let result = finalize_result(
+            &state, "utf1", "cat unicode.txt", &output, 0.1, &[],
+            meta_path.to_str().unwrap(), true, None,
+        );
+        let shown = result["output"].as_str().unwrap();
+        assert!(shown.contains("OUTPUT TRUNCATED"));
+        assert!(std::str::from_utf8(shown.as_bytes()).is_ok());
+        assert!(!shown.contains('\u{FFFD}'));
+        resources::remove_all(&state.session_id);
+    }
+
+    #[test]
+    fn test_zsh_normalizes_windows_paths_when_enabled() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_with = |normalize: bool, db: &str| {
+            // Deny cat so the resulting command comes back without spawning
+            test_state_with(Config {
+                alan_db_path: dir.path().join(db).to_str().unwrap().to_string(),
+                normalize_windows_paths: normalize,
+                deny_commands: vec!["cat *".to_string()],
+                ..Config::default()
+            })
+        };
+        let args = serde_json::json!({"command": r"cat logs\app.log"});
+
+        let result = run_zsh(&state_with(true, "a.db"), &args).unwrap();
+        assert_eq!(result["command"], "cat logs/app.log");
+        let warnings = result["insights"]["warning"].as_array().unwrap();
+        assert!(
+            warnings.iter().any(|w| w == r"Converted Windows-style paths: logs\app.log → logs/app.log"),
+            "got: {:?}", warnings
+        );
+
+        let result = run_zsh(&state_with(false, "b.db"), &args).unwrap();
+        assert_eq!(result["command"], r"cat logs\app.log");
+    }
+
+    #[test]
+    fn test_truncate_output_tail_and_middle_modes() {
+        let output: String = (1..=100).map(|n| format!("line {}\n", n)).collect();
+        let tail = truncate_output(&output, 30, TruncateMode::Tail);
+        assert!(tail.starts_with(&format!("[OUTPUT TRUNCATED - {} bytes total, showing last", output.len())));
+        // Starts on a whole line
+        assert!(tail.ends_with("\n\nline 98\nline 99\nline 100\n"), "got: {:?}", tail);
+
+        let middle = truncate_output(&output, 30, TruncateMode::Middle);
+        assert!(middle.starts_with("line 1\nline 2\n"), "got: {:?}", middle);
+        assert!(middle.contains("elided from the middle]"));
+        assert!(middle.ends_with("middle]\n\nline 100\n"), "got: {:?}", middle);
+
+        // Multi-byte characters are never split in any mode
+        let emoji = "😀".repeat(10);
+        for mode in [TruncateMode::Head, TruncateMode::Tail, TruncateMode::Middle] {
+            let cut = truncate_output(&emoji, 11, mode);
+            let kept = cut.matches('😀').count();
+            assert!((1..=2).contains(&kept), "{:?}: {:?}", mode, cut);
+        }
+        assert_eq!(truncate_output(&emoji, 11, TruncateMode::Tail), "[OUTPUT TRUNCATED - 40 bytes total, showing last 8]\n\n😀😀");
+    }
+
+    #[test]
+    fn test_number_lines_tail_mode_reports_shown_range() {
+        let buffer: String = (1..=50).map(|n| format!("out {}\n", n)).collect();
+        let (numbered, from, to) = number_lines(&buffer, 0, 0, true, 40, TruncateMode::Tail, None, None);
+        assert_eq!(to, 50);
+        assert!(from > 1 && from < 50, "from: {}", from);
+        assert!(numbered.contains(&format!("{}: out {}", from, from)), "got: {}", numbered);
+
+        let (_, from, to) = number_lines(&buffer, 0, 0, true, 40, TruncateMode::Middle, None, None);
+        assert_eq!((from, to), (1, 50));
+    }
+
+    #[test]
+    fn test_poll_filter_keeps_matching_lines() {
+        let state = test_state_with(Config::default());
+        let buffer: String = (1..=100)
+            .map(|n| if n % 25 == 0 { format!("FAIL test_{}\n", n) } else { format!("ok test_{}\n", n) })
+            .collect();
+        insert_task(&state, "f1", "completed", &buffer);
+
+        let text = result_text(&handle_poll(&state, &serde_json::json!({"task_id": "f1", "filter": "^FAIL"})));
+        assert!(text.contains("25: FAIL test_25") && text.contains("100: FAIL test_100"), "{}", text);
+        assert!(!text.contains("ok test_"), "{}", text);
+        assert!(text.contains("[filter '^FAIL': 96 non-matching lines elided]"), "{}", text);
+
+        // The buffer is untouched, so an unfiltered poll still sees everything
+        let text = result_text(&handle_poll(&state, &serde_json::json!({"task_id": "f1", "full_output": true})));
+        assert!(text.contains("1: ok test_1\n") && text.contains("100: FAIL test_100"), "{}", text);
+        assert!(!text.contains("[filter"));
+
+        // and so does the next delta poll: the filtered one left the cursors alone
+        let text = result_text(&handle_poll(&state, &serde_json::json!({"task_id": "f1"})));
+        assert!(text.contains("1: ok test_1\n") && text.contains("99: ok test_99"), "{}", text);
+        let text = result_text(&handle_poll(&state, &serde_json::json!({"task_id": "f1"})));
+        assert!(!text.contains("test_"), "{}", text);
+
+        let bad = handle_poll(&state, &serde_json::json!({"task_id": "f1", "filter": "("}));
+        assert_eq!(bad["isError"], true);
+    }
+
+    #[test]
+    fn test_resolve_exec_path_validates_override() {
+        let own = std::env::current_exe().unwrap();
+        assert_eq!(resolve_exec_path(None).unwrap(), own);
+        assert_eq!(resolve_exec_path(Some(own.to_str().unwrap())).unwrap(), own);
+
+        let missing = resolve_exec_path(Some("/nonexistent/zsh-tool-exec")).unwrap_err();
+        assert!(missing.contains("/nonexistent/zsh-tool-exec (ZSH_TOOL_EXEC_PATH) is unusable"), "{}", missing);
+
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("not-executable");
+        std::fs::write(&plain, "").unwrap();
+        for bad in [plain.as_path(), dir.path()] {
+            let err = resolve_exec_path(Some(bad.to_str().unwrap())).unwrap_err();
+            assert!(err.ends_with("is not an executable file"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_ssh_timeout_defaults_from_host_history_and_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("alan.db");
+        let state = test_state_with(Config {
+            alan_db_path: db_path.to_str().unwrap().to_string(),
+            neverhang_timeout_default: 30,
+            neverhang_timeout_max: 600,
+            ssh_timeouts: [("configured".to_string(), 400)].into_iter().collect(),
+            ..Config::default()
+        });
+        let conn = alan::open_db(db_path.to_str().unwrap()).unwrap();
+        for (i, ms) in [40_000, 100_000, 70_000].into_iter().enumerate() {
+            alan::ssh::record_ssh(&conn, &format!("obs{}", i), "ssh slowhost uptime", 0, ms, false).unwrap();
+        }
+        alan::ssh::record_ssh(&conn, "obs-new", "ssh newhost uptime", 0, 100_000, false).unwrap();
+        let no_args = serde_json::json!({});
+
+        // 1.5x the slowest of three runs
+        assert_eq!(effective_timeout(&state, "ssh slowhost uptime", &no_args), 150);
+        // One run is not enough history
+        assert_eq!(effective_timeout(&state, "ssh newhost uptime", &no_args), 30);
+        assert_eq!(effective_timeout(&state, "ssh -p 2222 user@configured ls", &no_args), 400);
+        assert_eq!(effective_timeout(&state, "ssh slowhost uptime", &serde_json::json!({"timeout": 10})), 10);
+        assert_eq!(effective_timeout(&state, "echo slowhost", &no_args), 30);
+
+        // Timeouts don't raise the next default
+        alan::ssh::record_ssh(&conn, "obs-hung", "ssh slowhost uptime", -1, 500_000, true).unwrap();
+        assert_eq!(effective_timeout(&state, "ssh slowhost uptime", &no_args), 150);
+        // Only recent runs count, so an old outlier ages out
+        for i in 0..20 {
+            alan::ssh::record_ssh(&conn, &format!("fast{}", i), "ssh slowhost uptime", 0, 2_000, false).unwrap();
+        }
+        assert_eq!(effective_timeout(&state, "ssh slowhost uptime", &no_args), 30);
+    }
+
+    #[test]
+    fn test_list_tasks_shows_request_id_as_sent() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(dir.path().join("alan.db").to_str().unwrap());
+        for (task_id, request_id) in [("r1", Some("7")), ("r2", Some("\"call-abc\"")), ("r3", None)] {
+            insert_task(&state, task_id, "completed", "");
+            state.tasks.lock().unwrap().tasks.get_mut(task_id).unwrap().request_id = request_id.map(String::from);
+        }
+        let text = result_text(&handle_list_tasks(&state));
+        let listed: Value = serde_json::from_str(&text).unwrap();
+        let id_of = |task_id: &str| {
+            listed["tasks"].as_array().unwrap().iter().find(|t| t["task_id"] == task_id).unwrap()["request_id"].clone()
+        };
+        assert_eq!(id_of("r1"), serde_json::json!(7));
+        assert_eq!(id_of("r2"), serde_json::json!("call-abc"));
+        assert_eq!(id_of("r3"), Value::Null);
+    }
+
+    #[test]
+    fn test_diagnose_failure_prompt_uses_last_session_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(dir.path().join("alan.db").to_str().unwrap());
+        let get = |id: u64, name: &str| {
+            handle_request(&state, "prompts/get", Some(serde_json::json!(id)), Some(serde_json::json!({"name": name})))
+        };
+        let empty = get(1, "diagnose_failure").result.unwrap();
+        assert!(empty["messages"][0]["content"]["text"].as_str().unwrap().contains("nothing to diagnose"));
+
+        {
+            let conn = alan::open_db(&state.db_path).unwrap();
+            alan::record(&conn, &state.session_id, "make test", 2, 100, false, "", &[2]).unwrap();
+            alan::record(&conn, &state.session_id, "nosuchcmd --version", 127, 10, false, "", &[127]).unwrap();
+            alan::record(&conn, &state.session_id, "echo ok", 0, 10, false, "ok", &[0]).unwrap();
+            alan::record(&conn, "other-session", "make deploy", 1, 100, false, "", &[1]).unwrap();
+        }
+        let result = get(2, "diagnose_failure").result.unwrap();
+        let text = result["messages"][0]["content"]["text"].as_str().unwrap();
+        assert!(text.contains("$ nosuchcmd --version") && text.contains("exited with code 127"), "{}", text);
+        assert!(text.contains("- [warning] command not found (exit 127)"), "{}", text);
+
+        let listed = handle_request(&state, "prompts/list", Some(serde_json::json!(3)), None);
+        assert_eq!(listed.result.unwrap()["prompts"][0]["name"], "diagnose_failure");
+        assert_eq!(get(4, "nope").error.unwrap().code, -32602);
+    }
+
+    #[test]
+    fn test_diagnose_failure_prompt_describes_latest_run_of_full_command() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(dir.path().join("alan.db").to_str().unwrap());
+        let get = |id: u64| {
+            let result = handle_request(
+                &state,
+                "prompts/get",
+                Some(serde_json::json!(id)),
+                Some(serde_json::json!({"name": "diagnose_failure"})),
+            )
+            .result
+            .unwrap();
+            result["messages"][0]["content"]["text"].as_str().unwrap().to_string()
+        };
+
+        // An earlier timeout and another session's stderr must not leak into the latest failure
+        {
+            let conn = alan::open_db(&state.db_path).unwrap();
+            let sid = &state.session_id;
+            alan::record(&conn, sid, "make slow", -1, 5000, true, "", &[]).unwrap();
+            alan::record_with_stderr(&conn, sid, "make slow", 2, 100, false, "", "", &[2]).unwrap();
+            alan::record_with_stderr(&conn, "other-session", "make slow", 2, 100, false, "", "elsewhere", &[2])
+                .unwrap();
+        }
+        let text = get(1);
+        assert!(text.contains("exited with code 2. It has failed 2 times"), "{}", text);
+        assert!(!text.contains("timed out") && !text.contains("elsewhere"), "{}", text);
+
+        // Commands longer than the preview are looked up by their stored hash
+        let long = format!("ls {} missing", "a/".repeat(150));
+        {
+            let conn = alan::open_db(&state.db_path).unwrap();
+            for _ in 0..2 {
+                alan::record_with_stderr(&conn, &state.session_id, &long, 2, 10, false, "", "No such file", &[2])
+                    .unwrap();
+            }
+        }
+        let text = get(2);
+        assert!(text.contains("the rest is cut off") && text.contains("    No such file"), "{}", text);
+        assert!(text.contains("Previous 2 all failed") && !text.contains("New pattern"), "{}", text);
+    }
+
+    #[test]
+    fn test_alan_stats_resource_reads_live_stats() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(dir.path().join("alan.db").to_str().unwrap());
+        let listed = handle_request(&state, "resources/list", Some(serde_json::json!(1)), None);
+        assert_eq!(listed.result.unwrap()["resources"][0]["uri"], resources::ALAN_STATS_URI);
+
+        let params = serde_json::json!({"uri": resources::ALAN_STATS_URI});
+        let read = handle_request(&state, "resources/read", Some(serde_json::json!(2)), Some(params));
+        let contents = &read.result.unwrap()["contents"][0];
+        assert_eq!(contents["mimeType"], "application/json");
+        let stats: Value = serde_json::from_str(contents["text"].as_str().unwrap()).unwrap();
+        assert_eq!(stats["total_observations"], 0);
+        assert_eq!(stats["session"]["session_id"], state.session_id.as_str());
+        assert_eq!(resources::ALAN_STATS_URI, "zsh://alan/stats");
+
+        // Subscriptions are not offered
+        let params = serde_json::json!({"uri": resources::ALAN_STATS_URI});
+        let subscribed = handle_request(&state, "resources/subscribe", Some(serde_json::json!(3)), Some(params));
+        assert_eq!(subscribed.error.unwrap().code, -32601);
+    }
+
+    #[test]
+    fn test_output_link_follows_negotiated_protocol() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(dir.path().join("alan.db").to_str().unwrap());
+        let result = serde_json::json!({
+            "status": "completed", "output": "", "insights": {},
+            "output_resource": resources::link("zsh-tool://output/abc", "abc", 42),
+        });
+        let init = |version: Option<&str>| {
+            let params = version.map(|v| serde_json::json!({"protocolVersion": v}));
+            handle_request(&state, "initialize", Some(serde_json::json!(1)), params).result.unwrap()
+        };
+
+        assert_eq!(init(Some("2024-11-05"))["protocolVersion"], "2024-11-05");
+        let content = rich_content(&state, &result);
+        assert_eq!(content["content"][1]["type"], "text");
+        assert!(content["content"][1]["text"].as_str().unwrap().contains("zsh-tool://output/abc (42 bytes)"));
+
+        assert_eq!(init(Some("2099-01-01"))["protocolVersion"], protocol::PROTOCOL_VERSIONS[0]);
+        assert_eq!(init(None)["protocolVersion"], "2025-06-18");
+        assert_eq!(rich_content(&state, &result)["content"][1]["type"], "resource_link");
+    }
+
+    #[test]
+    fn test_resolve_shell_path_validates_override() {
+        assert_eq!(resolve_shell_path(Some("/bin/sh"), executor::ShellKind::Sh).unwrap(), std::path::PathBuf::from("/bin/sh"));
+
+        let missing = resolve_shell_path(Some("/nonexistent/zsh"), executor::ShellKind::Zsh).unwrap_err();
+        assert!(missing.starts_with("Shell /nonexistent/zsh (ZSH_TOOL_SHELL_PATH) is unusable"), "{}", missing);
+        assert!(missing.ends_with("install zsh or set ZSH_TOOL_SHELL_PATH"), "{}", missing);
+    }
+
+    /// Register a running `sh -c script` task with a live reader thread.
+    fn spawn_running_task(state: &Arc<ServerState>, task_id: &str, script: &str) {
+        let mut child = std::process::Command::new("sh")
+            .args(["-c", script])
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        let stdout = child.stdout.take().unwrap();
+        insert_task(state, task_id, "running", "");
+        {
+            let mut tasks = state.tasks.lock().unwrap();
+            let task = tasks.tasks.get_mut(task_id).unwrap();
+            task.pid = Some(child.id());
+            task.child = Some(child);
+        }
+        let reader = spawn_output_reader(state, task_id, stdout);
+        state.tasks.lock().unwrap().tasks.get_mut(task_id).unwrap().reader = Some(reader);
+    }
+
+    #[test]
+    fn test_wait_for_output_returns_once_output_settles() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(dir.path().join("alan.db").to_str().unwrap());
+        spawn_running_task(&state, "y1", "echo ready; sleep 5");
+
+        let started = std::time::Instant::now();
+        wait_for_output(&state, "y1", std::time::Duration::from_secs(4), 1);
+        assert!(started.elapsed() < std::time::Duration::from_secs(2), "waited {:?}", started.elapsed());
+        assert_eq!(state.tasks.lock().unwrap().tasks["y1"].output_buffer, "ready\n");
+
+        // Below the threshold it waits out the window
+        spawn_running_task(&state, "y2", "echo hi; sleep 5");
+        let started = std::time::Instant::now();
+        wait_for_output(&state, "y2", std::time::Duration::from_millis(600), 1000);
+        assert!(started.elapsed() >= std::time::Duration::from_millis(600));
+
+        for id in ["y1", "y2"] {
+            if let Some(child) = state.tasks.lock().unwrap().tasks.get_mut(id).unwrap().child.as_mut() {
+                let _ = child.kill();
+            }
+        }
+    }
+
+    #[test]
+    fn test_spool_writes_full_output_and_cleans_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let spool = dir.path().join("spool");
+        let state = test_state_with(Config {
+            alan_db_path: dir.path().join("alan.db").to_str().unwrap().to_string(),
+            spool_dir: Some(spool.to_str().unwrap().to_string()),
+            truncate_output_at: 100,
+            ..Config::default()
+        });
+        spawn_running_task(&state, "s1", "seq 1 200; exec sleep 5");
+        std::thread::sleep(std::time::Duration::from_millis(300));
+
+        let polled = handle_poll(&state, &serde_json::json!({"task_id": "s1"}));
+        let log = spool.join("s1.log");
+        assert!(result_text(&polled).contains(&format!("full output: {}", log.display())));
+        let expected: String = (1..=200).map(|n| format!("{}\n", n)).collect();
+        assert_eq!(std::fs::read_to_string(&log).unwrap(), expected);
+        use std::os::unix::fs::PermissionsExt;
+        assert_eq!(std::fs::metadata(&spool).unwrap().permissions().mode() & 0o777, 0o700);
+        assert_eq!(std::fs::metadata(&log).unwrap().permissions().mode() & 0o777, 0o600);
+
+        let full: Value =
+            serde_json::from_str(&result_text(&handle_poll(&state, &serde_json::json!({"task_id": "s1", "full": true}))))
+                .unwrap();
+        assert_eq!(full["spool_path"], log.display().to_string());
+        assert_eq!(full["bytes"], expected.len());
+
+        handle_kill(&state, &serde_json::json!({"task_id": "s1"}));
+        assert!(!log.exists(), "spool file should be removed on kill");
+        assert_eq!(handle_poll(&state, &serde_json::json!({"task_id": "s1", "full": true}))["isError"], true);
+
+        // keep_logs leaves the file in place
+        let kept = test_state_with(Config {
+            alan_db_path: dir.path().join("alan.db").to_str().unwrap().to_string(),
+            spool_dir: Some(spool.to_str().unwrap().to_string()),
+            truncate_output_at: 100,
+            keep_logs: true,
+            ..Config::default()
+        });
+        spawn_running_task(&kept, "s2", "seq 1 200; exec sleep 5");
+        std::thread::sleep(std::time::Duration::from_millis(300));
+        handle_poll(&kept, &serde_json::json!({"task_id": "s2"}));
+        handle_kill(&kept, &serde_json::json!({"task_id": "s2"}));
+        assert_eq!(std::fs::read_to_string(spool.join("s2.log")).unwrap(), expected);
+
+        let unset = test_state_with(Config::default());
+        insert_task(&unset, "s3", "running", "x");
+        assert_eq!(handle_poll(&unset, &serde_json::json!({"task_id": "s3", "full": true}))["isError"], true);
+    }
+
+    #[test]
+    fn test_poll_wait_ms_blocks_until_completion() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(dir.path().join("alan.db").to_str().unwrap());
+        spawn_running_task(&state, "w1", "echo start; sleep 0.3; echo end");
+
+        let started = std::time::Instant::now();
+        let text = result_text(&handle_poll(&state, &serde_json::json!({"task_id": "w1", "wait_ms": 10000})));
+        assert!(started.elapsed() < std::time::Duration::from_secs(5));
+        assert!(text.contains("start") && text.contains("end"), "got: {}", text);
+        assert_eq!(state.tasks.lock().unwrap().tasks["w1"].status, "completed");
+    }
+
+    #[test]
+    fn test_poll_wait_ms_returns_running_at_deadline() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(dir.path().join("alan.db").to_str().unwrap());
+        spawn_running_task(&state, "w2", "echo partial; sleep 5");
+
+        let started = std::time::Instant::now();
+        let text = result_text(&handle_poll(&state, &serde_json::json!({"task_id": "w2", "wait_ms": 300})));
+        let waited = started.elapsed();
+        assert!(waited >= std::time::Duration::from_millis(300), "returned after {:?}", waited);
+        assert!(waited < std::time::Duration::from_secs(3), "returned after {:?}", waited);
+        assert!(text.contains("partial"), "got: {}", text);
+        assert_eq!(state.tasks.lock().unwrap().tasks["w2"].status, "running");
+
+        let mut tasks = state.tasks.lock().unwrap();
+        let _ = tasks.tasks.get_mut("w2").unwrap().child.as_mut().unwrap().kill();
+    }
+
+    #[test]
+    fn test_event_notifications_replace_piggyback_when_pushing() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(dir.path().join("alan.db").to_str().unwrap());
+        state.pushing_events.store(true, Ordering::Relaxed);
+        enqueue_event(&state, "ok1", 0, 1.26);
+        enqueue_event(&state, "bad1", 2, 0.5);
+
+        let response = prepend_events(&state, text_content("body"));
+        assert_eq!(result_text(&response), "body");
+
+        let notes: Vec<JsonRpcNotification> = take_event_notifications(&state).into_iter().map(|(_, n)| n).collect();
+        assert_eq!(notes.len(), 2);
+        assert_eq!(notes[0].method, "notifications/message");
+        assert_eq!(notes[0].params["level"], "info");
+        assert_eq!(notes[0].params["data"]["task_id"], "ok1");
+        assert_eq!(notes[0].params["data"]["elapsed_seconds"], 1.3);
+        assert_eq!(notes[0].params["data"]["message"], "task 'ok1' completed (exit=0, 1.3s)");
+        assert_eq!(notes[1].params["level"], "warning");
+        assert_eq!(notes[1].params["data"]["exit_code"], 2);
+        assert!(take_event_notifications(&state).is_empty());
+    }
+
+    #[test]
+    fn test_events_go_to_the_connection_that_started_the_task() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(dir.path().join("alan.db").to_str().unwrap());
+        let here = CURRENT_CONNECTION.with(Cell::get);
+        for (id, connection) in [("mine", here), ("theirs", here + 1)] {
+            insert_task(&state, id, "completed", "");
+            state.tasks.lock().unwrap().tasks.get_mut(id).unwrap().connection = connection;
+            enqueue_event(&state, id, 0, 0.1);
+        }
+
+        let text = drain_events(&state);
+        assert!(text.contains("mine") && !text.contains("theirs"), "got: {}", text);
+
+        let notes = take_event_notifications(&state);
+        assert_eq!(notes.len(), 1);
+        assert_eq!(notes[0].0, here + 1);
+        assert_eq!(notes[0].1.params["data"]["task_id"], "theirs");
+    }
+
+    #[test]
+    fn test_cancelled_request_kills_its_task() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(dir.path().join("alan.db").to_str().unwrap());
+        spawn_running_task(&state, "c1", "exec sleep 30");
+        CURRENT_REQUEST.with(|r| *r.borrow_mut() = Some("7".to_string()));
+        register_request_task(&state, "c1");
+        CURRENT_REQUEST.with(|r| r.borrow_mut().take());
+
+        // Other ids, and the same number as a string, are not this request
+        cancel_request(&state, Some(&serde_json::json!({"requestId": 8})));
+        cancel_request(&state, Some(&serde_json::json!({"requestId": "7"})));
+        assert_eq!(state.tasks.lock().unwrap().tasks["c1"].status, "running");
+
+        // Nor is id 7 from another connection
+        CURRENT_CONNECTION.with(|c| c.set(c.get() + 1));
+        cancel_request(&state, Some(&serde_json::json!({"requestId": 7})));
+        CURRENT_CONNECTION.with(|c| c.set(c.get() - 1));
+        assert_eq!(state.tasks.lock().unwrap().tasks["c1"].status, "running");
+
+        cancel_request(&state, Some(&serde_json::json!({"requestId": 7, "reason": "user abort"})));
+        assert_eq!(state.tasks.lock().unwrap().tasks["c1"].status, "killed");
+        assert!(state.request_tasks.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_meta_path_under_configured_dir_per_pid() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state_with(Config {
+            alan_db_path: dir.path().join("alan.db").to_string_lossy().into_owned(),
+            meta_dir: dir.path().join("meta").to_string_lossy().into_owned(),
+            ..Config::default()
+        });
+
+        let path = task_meta_path(&state, "abcd1234").unwrap();
+        let session_dir = dir.path().join("meta").join(format!("zsh-tool-meta-{}", std::process::id()));
+        assert_eq!(std::path::Path::new(&path), session_dir.join("abcd1234.json"));
+        let mode = std::fs::metadata(&session_dir).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+
+        // Not shutdown(): that also clears the resources every test shares
+        remove_meta_dir(&state);
+        assert!(!session_dir.exists());
+
+        // A directory someone else could have planted is refused
+        std::fs::create_dir_all(&session_dir).unwrap();
+        std::fs::set_permissions(&session_dir, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let err = task_meta_path(&state, "abcd1234").unwrap_err();
+        assert!(err.contains("not a private directory"), "{}", err);
+    }
+
+    #[test]
+    fn test_poll_reports_slow_task_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(dir.path().join("alan.db").to_str().unwrap());
+        spawn_running_task(&state, "s1", "echo start; exec sleep 30");
+        state.tasks.lock().unwrap().tasks.get_mut("s1").unwrap().usual_secs = Some(0.1);
+        std::thread::sleep(std::time::Duration::from_millis(400));
+
+        let text = result_text(&handle_poll(&state, &serde_json::json!({"task_id": "s1"})));
+        assert!(text.contains("its usual 0.1s"), "got: {}", text);
+        let text = result_text(&handle_poll(&state, &serde_json::json!({"task_id": "s1"})));
+        assert!(!text.contains("its usual"), "reported twice: {}", text);
+
+        handle_kill(&state, &serde_json::json!({"task_id": "s1"}));
+    }
+
+    #[test]
+    fn test_retry_verbose_reruns_last_failure_with_flag() {
+        let dir = tempfile::tempdir().unwrap();
+        // Deny ssh so the augmented command comes back without spawning
+        let state = test_state_with(Config {
+            alan_db_path: dir.path().join("alan.db").to_str().unwrap().to_string(),
+            deny_commands: vec!["ssh *".to_string()],
+            ..Config::default()
+        });
+        let text = result_text(&handle_retry_verbose(&state, &serde_json::json!({})));
+        assert!(text.contains("No failed command"), "got: {}", text);
+
+        insert_task(&state, "f1", "completed", "");
+        {
+            let mut tasks = state.tasks.lock().unwrap();
+            let task = tasks.tasks.get_mut("f1").unwrap();
+            task.command = "ssh build-host make".to_string();
+            task.pipestatus = vec![255];
+        }
+        insert_task(&state, "ok1", "completed", "");
+        state.tasks.lock().unwrap().tasks.get_mut("ok1").unwrap().pipestatus = vec![0];
+
+        let text = result_text(&handle_retry_verbose(&state, &serde_json::json!({})));
+        assert!(text.contains("ssh -vvv build-host make"), "got: {}", text);
+
+        let text = result_text(&handle_retry_verbose(&state, &serde_json::json!({"command": "ls -la"})));
+        assert!(text.contains("No verbosity setting for 'ls'"), "got: {}", text);
+    }
+
+    #[test]
+    fn test_home_root_guard_gates_rm_in_home() {
+        let dir = tempfile::tempdir().unwrap();
+        let home = std::env::var("HOME").unwrap();
+        let state = test_state_with(Config {
+            alan_db_path: dir.path().join("alan.db").to_str().unwrap().to_string(),
+            home_root_guard: true,
+            ..Config::default()
+        });
+        *state.cwd.lock().unwrap() = Some(home.clone());
+
+        // Only the guard is asked; nothing is ever run against the real home
+        let detail = home_root_block(&state, "rm -rf *", &serde_json::json!({})).unwrap();
+        assert!(detail.contains("confirm: true"), "{}", detail);
+
+        let args = serde_json::json!({"command": "rm -rf *", "confirm": true});
+        assert!(home_root_block(&state, "rm -rf *", &args).is_none());
+        assert!(home_root_block(&state, "ls -la", &serde_json::json!({})).is_none());
+        *state.cwd.lock().unwrap() = Some("/".to_string());
+        assert!(home_root_block(&state, "git clean -fdx", &serde_json::json!({})).is_some());
+        // run_zsh turns the block into a result; the glob matches nothing
+        // should the guard ever regress
+        let result = run_zsh(&state, &serde_json::json!({"command": "rm -rf nonexistent-zsh-tool-*"})).unwrap();
+        assert_eq!(result["status"], "blocked");
+        assert_eq!(result["blocked_reason"], "dangerous_cwd");
+        *state.cwd.lock().unwrap() = Some(dir.path().to_string_lossy().into_owned());
+        assert!(home_root_block(&state, "rm -rf *", &serde_json::json!({})).is_none());
+
+        // A cd earlier in the chain counts as where the deletion runs
+        for cmd in ["cd ~ && rm -rf *", "cd / && rm -rf tmp", "cd \"$HOME\"; git clean -fdx", "cd && rm x"] {
+            assert!(home_root_block(&state, cmd, &serde_json::json!({})).is_some(), "{}", cmd);
+        }
+        let sub = dir.path().join("build");
+        std::fs::create_dir(&sub).unwrap();
+        for cmd in ["cd ~/.cache/nope && rm -rf *", "cd build && rm -rf *", "rm x && cd /"] {
+            assert!(home_root_block(&state, cmd, &serde_json::json!({})).is_none(), "{}", cmd);
+        }
+    }
+
+    #[test]
+    fn test_dry_run_returns_insights_without_running() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state_with(Config {
+            alan_db_path: dir.path().join("alan.db").to_str().unwrap().to_string(),
+            ..Config::default()
+        });
+        let marker = dir.path().join("ran");
+        let command = format!("touch {}", marker.display());
+        let args = serde_json::json!({"command": command, "dry_run": true});
+
+        let result = run_zsh(&state, &args).unwrap();
+        assert_eq!(result["status"], "dry_run");
+        assert!(result["insights"].is_object(), "{}", result);
+        assert!(result["retry_info"].is_object(), "{}", result);
+        assert!(state.tasks.lock().unwrap().tasks.is_empty());
+        assert!(!marker.exists());
+
+        let conn = alan::open_db(&state.db_path).unwrap();
+        let recorded: i64 = conn
+            .query_row("SELECT COUNT(*) FROM observations", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(recorded, 0);
+
+        let text = handle_zsh(&state, &args)["content"][0]["text"].as_str().unwrap().to_string();
+        assert!(text.contains("[DRY-RUN]"), "got: {}", text);
+    }
+
+    #[test]
+    fn test_config_reports_value_and_source() {
+        let mut config = Config { yield_after_default: 4.0, ..Config::default() };
+        config.sources.insert("yield_after_default".to_string(), crate::config::ConfigSource::Env);
+        let state = test_state_with(config);
+
+        let all: Value = serde_json::from_str(&result_text(&handle_config(&state, &serde_json::json!({})))).unwrap();
+        assert_eq!(all["yield_after_default"]["value"], 4.0);
+        assert_eq!(all["yield_after_default"]["source"], "env");
+        assert_eq!(all["tab_width"]["source"], "default");
+
+        let one: Value =
+            serde_json::from_str(&result_text(&handle_config(&state, &serde_json::json!({"key": "tab_width"})))).unwrap();
+        assert_eq!(one.as_object().unwrap().len(), 1);
+
+        let unknown = handle_config(&state, &serde_json::json!({"key": "nope"}));
+        assert_eq!(unknown["isError"], true);
+    }
+
+    #[test]
+    fn test_duration_stats_nearest_rank() {
+        let durations: Vec<f64> = (1..=10).rev().map(|n| n as f64 / 10.0).collect();
+        let stats = duration_stats(&durations);
+        assert_eq!(stats["min"], 0.1);
+        assert_eq!(stats["max"], 1.0);
+        assert_eq!(stats["mean"], 0.55);
+        assert_eq!(stats["p50"], 0.5);
+        assert_eq!(stats["p90"], 0.9);
+
+        assert_eq!(duration_stats(&[0.25])["p90"], 0.25);
+        assert!(duration_stats(&[]).is_null());
+    }
+
+    #[test]
+    fn test_benchmark_run_timeout_fits_the_budget() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(dir.path().join("alan.db").to_str().unwrap());
+        let args = serde_json::json!({"command": "make", "timeout": 60});
+        assert_eq!(benchmark_run_timeout(&state, "make", &args, 300.0), 60);
+        assert_eq!(benchmark_run_timeout(&state, "make", &args, 12.3), 13);
+        assert_eq!(benchmark_run_timeout(&state, "make", &args, 0.2), 1);
+        let default = state.config.neverhang_timeout_default;
+        assert_eq!(benchmark_run_timeout(&state, "make", &serde_json::json!({}), 300.0), default.min(300));
+    }
+
+    #[test]
+    fn test_exec_elapsed_comes_from_meta() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(dir.path().join("alan.db").to_str().unwrap());
+        let meta_path = dir.path().join("meta.json");
+        std::fs::write(&meta_path, r#"{"pipestatus":[0],"exit_code":0,"elapsed_ms":1234,"timed_out":false}"#).unwrap();
+        let result = finalize_result(&state, "t1", "true", "", 1.9, &[], meta_path.to_str().unwrap(), true, None);
+        assert_eq!(result["exec_elapsed_ms"], 1234);
+    }
+
+    #[test]
+    fn test_benchmark_stops_when_blocked() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state_with(Config {
+            alan_db_path: dir.path().join("alan.db").to_str().unwrap().to_string(),
+            deny_commands: vec!["rm *".to_string()],
+            ..Config::default()
+        });
+        let text = result_text(&handle_benchmark(&state, &serde_json::json!({"command": "rm -rf x", "runs": 3})));
+        let v: Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(v["runs_completed"], 0);
+        assert!(v["stopped"].as_str().unwrap().starts_with("blocked:"), "{}", text);
+        assert!(v["stats"].is_null());
+
+        assert_eq!(handle_benchmark(&state, &serde_json::json!({}))["isError"], true);
+    }
+
+    #[test]
+    fn test_record_line_stamps_across_chunks() {
+        let mut stamps = Vec::new();
+        let mut buffer = String::new();
+        for (chunk, ms) in [("one\ntw", 5), ("o\n", 9), ("three\n", 12), ("\n", 20)] {
+            record_line_stamps(&mut stamps, &buffer, chunk, ms);
+            buffer.push_str(chunk);
+        }
+        // "two" started in the first chunk; the last line is empty but real
+        assert_eq!(stamps, vec![5, 5, 12, 20]);
+        assert_eq!(stamp_lines(&buffer, 0, Some(&stamps)), "[+5ms] one\n[+5ms] two\n[+12ms] three\n[+20ms] \n");
+        assert_eq!(stamp_lines("three\n\n", 2, Some(&stamps)), "[+12ms] three\n[+20ms] \n");
+        assert_eq!(stamp_lines(&buffer, 0, None), buffer);
+    }
+
+    #[test]
+    fn test_timestamp_lines_shows_when_output_arrived() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(dir.path().join("alan.db").to_str().unwrap());
+        spawn_running_task(&state, "t1", "sleep 0.2; echo first; sleep 0.5; echo second");
+        state.tasks.lock().unwrap().tasks.get_mut("t1").unwrap().line_stamps = Some(Vec::new());
+        wait_for_exit(&state, "t1", std::time::Duration::from_secs(5));
+
+        let text = result_text(&handle_poll(&state, &serde_json::json!({"task_id": "t1", "full_output": true})));
+        let stamp_of = |word: &str| -> u64 {
+            let line = text.lines().find(|l| l.ends_with(word)).unwrap_or_else(|| panic!("{}", text));
+            let ms = line.split("[+").nth(1).and_then(|r| r.split("ms]").next()).unwrap();
+            ms.parse().unwrap()
+        };
+        assert!(text.contains("1: [+"), "{}", text);
+        assert!(stamp_of("second") >= stamp_of("first") + 400, "{}", text);
+
+        // The buffer, and so zsh_output, stays raw
+        assert_eq!(state.tasks.lock().unwrap().tasks["t1"].output_buffer, "first\nsecond\n");
+        let raw = result_text(&handle_output(&state, &serde_json::json!({"task_id": "t1"})));
+        assert!(raw.contains("1: first") && !raw.contains("[+"), "{}", raw);
+    }
+
+    #[test]
+    fn test_output_hash_tracks_output_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(dir.path().join("alan.db").to_str().unwrap());
+        let finish = |id: &str, output: &str| {
+            insert_task(&state, id, "running", output);
+            state.tasks.lock().unwrap().tasks.get_mut(id).unwrap().output_hash = true;
+            finalize_result(&state, id, "cat config", output, 0.1, &[], "/nonexistent-meta.json", true, None)
+        };
+        let first = finish("h1", "port: 8080\n");
+        let same = finish("h2", "port: 8080\n");
+        let changed = finish("h3", "port: 9090\n");
+        let hash = first["output_hash"].as_str().unwrap();
+        assert_eq!(hash.len(), 64);
+        assert_eq!(same["output_hash"], hash);
+        assert_ne!(changed["output_hash"], hash);
+        assert!(result_text(&rich_content(&state, &first)).contains(&format!("sha256={}", hash)));
+
+        // Off unless asked for
+        insert_task(&state, "h4", "running", "port: 8080\n");
+        let plain = finalize_result(&state, "h4", "cat config", "port: 8080\n", 0.1, &[], "/nonexistent-meta.json", true, None);
+        assert!(plain.get("output_hash").is_none());
+    }
+
+    #[test]
+    fn test_timeout_exempt_patterns_lift_the_cap() {
+        let state = test_state_with(Config {
+            neverhang_timeout_max: 600,
+            timeout_exempt_patterns: vec!["^cargo build".to_string(), "(".to_string()],
+            ..Config::default()
+        });
+        // The invalid pattern is dropped at startup
+        assert_eq!(state.timeout_exempt.len(), 1);
+        let args = serde_json::json!({"timeout": 1800});
+        assert_eq!(effective_timeout(&state, "cargo build --release", &args), 1800);
+        assert_eq!(effective_timeout(&state, "make all", &args), 600);
+        assert_eq!(effective_timeout(&state, "echo cargo build", &args), 600);
+        // Every command in the chain must be exempt
+        assert_eq!(effective_timeout(&state, "cargo build; sleep 1e9", &args), 600);
+        assert_eq!(effective_timeout(&state, "x=cargo build && nc -l 9999", &args), 600);
+        assert_eq!(effective_timeout(&state, "RUSTFLAGS=-g cargo build && cargo build --tests", &args), 1800);
+        // Exempt commands still have a ceiling
+        let huge = serde_json::json!({"timeout": 1_000_000_000_000u64});
+        assert_eq!(effective_timeout(&state, "cargo build", &huge), state.config.timeout_exempt_max);
+        assert_eq!(
+            effective_timeout(&state, "cargo build", &serde_json::json!({})),
+            state.config.neverhang_timeout_default
+        );
+    }
+
+    #[test]
+    fn test_attach_json_parses_or_warns() {
+        let state = test_state_with(Config::default());
+        let mut result = serde_json::json!({"status": "completed", "output": "", "insights": {}});
+        attach_json(&mut result, "  {\"name\": \"zsh-tool\", \"deps\": 3}\n");
+        assert_eq!(result["json"]["deps"], 3);
+        let content = rich_content(&state, &result);
+        assert_eq!(content["structuredContent"]["name"], "zsh-tool");
+
+        let mut list = serde_json::json!({"status": "completed", "output": "", "insights": {}});
+        attach_json(&mut list, "[1, 2, 3]");
+        assert_eq!(rich_content(&state, &list)["structuredContent"]["value"], serde_json::json!([1, 2, 3]));
+
+        let mut bad = serde_json::json!({"status": "completed", "output": "oops", "insights": {"warning": ["slow"]}});
+        attach_json(&mut bad, "oops {");
+        assert!(bad.get("json").is_none());
+        let warnings = bad["insights"]["warning"].as_array().unwrap();
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[1].as_str().unwrap().starts_with("extract_json: output is not valid JSON"));
+        assert!(rich_content(&state, &bad).get("structuredContent").is_none());
+    }
+
+    #[test]
+    fn test_alan_export_inline_file_and_size_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("alan.db").to_str().unwrap().to_string();
+        {
+            let conn = alan::open_db(&db_path).unwrap();
+            alan::record(&conn, "s", "make test", 0, 1200, false, "", &[0]).unwrap();
+        }
+        let state = test_state(&db_path);
+
+        let inline: Value =
+            serde_json::from_str(&result_text(&handle_alan_export(&state, &serde_json::json!({})))).unwrap();
+        assert_eq!(inline["observations"][0]["command_preview"], "make test");
+
+        let out = dir.path().join("export.json");
+        let written = handle_alan_export(&state, &serde_json::json!({"path": out.to_str().unwrap()}));
+        assert!(result_text(&written).starts_with("Exported 1 observations"), "{}", written);
+        let file: Value = serde_json::from_str(&std::fs::read_to_string(&out).unwrap()).unwrap();
+        assert_eq!(file["observations"].as_array().unwrap().len(), 1);
+
+        let small = test_state_with(Config { alan_db_path: db_path, truncate_output_at: 100, ..Config::default() });
+        let refused = handle_alan_export(&small, &serde_json::json!({}));
+        assert_eq!(refused["isError"], true);
+        assert!(result_text(&refused).contains("Pass path"));
+
+        let bad = handle_alan_export(&state, &serde_json::json!({"since": "last week"}));
+        assert_eq!(bad["isError"], true);
+    }
+
+    #[test]
+    fn test_pty_retry_only_for_tty_errors_when_enabled() {
+        let state = test_state_with(Config { pty_fallback: true, ..Config::default() });
+        let args = serde_json::json!({"command": "ssh -t host top"});
+        let failed = serde_json::json!({
+            "status": "completed",
+            "success": false,
+            "output": "Pseudo-terminal will not be allocated because stdin is not a terminal.",
+        });
+        let retry = pty_retry_args(&state, &args, &failed).unwrap();
+        assert_eq!(retry["pty"], true);
+        assert_eq!(retry["command"], "ssh -t host top");
+
+        let other = serde_json::json!({"status": "completed", "success": false, "output": "Permission denied"});
+        assert!(pty_retry_args(&state, &args, &other).is_none());
+        let ok = serde_json::json!({"status": "completed", "success": true, "output": "not a tty"});
+        assert!(pty_retry_args(&state, &args, &ok).is_none());
+        let already_pty = serde_json::json!({"command": "top", "pty": true});
+        assert!(pty_retry_args(&state, &already_pty, &failed).is_none());
+
+        let off = test_state_with(Config::default());
+        assert!(pty_retry_args(&off, &args, &failed).is_none());
+    }
+
+    #[test]
+    fn test_session_summary_reflects_session_activity() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(dir.path().join("alan.db").to_str().unwrap());
+        {
+            let conn = alan::open_db(&state.db_path).unwrap();
+            let sid = &state.session_id;
+            alan::record(&conn, sid, "cargo build", 0, 900, false, "", &[0]).unwrap();
+            alan::record(&conn, sid, "cargo build", 0, 800, false, "", &[0]).unwrap();
+            alan::record_with_stderr(&conn, sid, "cargo test", 101, 2000, false, "", "test failed: parse", &[101])
+                .unwrap();
+            alan::record(&conn, "other-session", "make", 2, 10, false, "", &[2]).unwrap();
+        }
+        insert_task(&state, "t1", "running", "");
+        insert_task(&state, "t2", "completed", "");
+
+        let summary: Value =
+            serde_json::from_str(&result_text(&handle_session_summary(&state))).unwrap();
+        assert_eq!(summary["session"]["total_commands"], 3);
+        assert_eq!(summary["session"]["successes"], 2);
+        assert_eq!(summary["session"]["failures"], 1);
+        assert_eq!(summary["top_patterns"][0]["pattern"], "cargo build");
+        let failures = summary["notable_failures"].as_array().unwrap();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0]["command"], "cargo test");
+        assert_eq!(failures[0]["last_exit_code"], 101);
+        assert_eq!(failures[0]["last_error"], "test failed: parse");
+        let running = summary["running_tasks"].as_array().unwrap();
+        assert_eq!(running.len(), 1);
+        assert_eq!(running[0]["task_id"], "t1");
+        assert_eq!(summary["circuit"]["state"], "closed");
+        assert!(summary["headline"].as_str().unwrap().starts_with("3 commands (2 ok, 1 failed"), "{}", summary);
+    }
+
+    #[test]
+    fn test_alan_pin_marks_template_and_later_observations() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("alan.db").to_str().unwrap().to_string();
+        {
+            let conn = alan::open_db(&db_path).unwrap();
+            alan::record(&conn, "s", "terraform apply prod", 0, 1200, false, "", &[0]).unwrap();
+        }
+        let state = test_state(&db_path);
+        let template = alan::hash::template_command("terraform apply prod");
+
+        let pinned: Value = serde_json::from_str(&result_text(&handle_alan_pin(
+            &state,
+            &serde_json::json!({"command": "terraform apply prod"}),
+        )))
+        .unwrap();
+        assert_eq!(pinned["template"], template.as_str());
+        assert_eq!(pinned["observations_changed"], 1);
+        assert_eq!(pinned["pinned_templates"], serde_json::json!([template]));
+
+        // A new observation of the same pattern inherits the pin
+        let conn = alan::open_db(&db_path).unwrap();
+        alan::record(&conn, "s", "terraform apply staging", 1, 900, false, "", &[1]).unwrap();
+        let unpinned: i64 = conn
+            .query_row("SELECT COUNT(*) FROM observations WHERE pinned = 0", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(unpinned, 0);
+
+        let released: Value = serde_json::from_str(&result_text(&handle_alan_pin(
+            &state,
+            &serde_json::json!({"command": "terraform apply prod", "unpin": true}),
+        )))
+        .unwrap();
+        assert_eq!(released["observations_changed"], 2);
+        let listed: Value =
+            serde_json::from_str(&result_text(&handle_alan_pin(&state, &serde_json::json!({})))).unwrap();
+        assert_eq!(listed["pinned_templates"], serde_json::json!([]));
+    }
 }
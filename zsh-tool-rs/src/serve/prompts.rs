@@ -0,0 +1,122 @@
+//! MCP prompts: reusable prompt templates filled from ALAN data.
+//!
+//! `diagnose_failure` asks the model to analyze the session's most recent
+//! failed command, with what ALAN knows about it.
+
+use serde_json::Value;
+
+use crate::alan::stats::SessionFailure;
+
+/// Name of the prompt built from the session's last failed command.
+pub const DIAGNOSE_FAILURE: &str = "diagnose_failure";
+
+/// `prompts/list` result.
+pub fn list() -> Value {
+    serde_json::json!({
+        "prompts": [{
+            "name": DIAGNOSE_FAILURE,
+            "description": "Analyze the most recent failed command in this session, with its exit code, last error output and ALAN insights",
+            "arguments": []
+        }]
+    })
+}
+
+/// `prompts/get` result for [`DIAGNOSE_FAILURE`]. `failure` is the session's
+/// most recent failed command, None when nothing has failed yet.
+pub fn diagnose_failure(failure: Option<&SessionFailure>, insights: &[(String, String)]) -> Value {
+    let text = match failure {
+        Some(f) => diagnose_text(f, insights),
+        None => "No command has failed in this zsh-tool session yet, so there is nothing to diagnose.".to_string(),
+    };
+    serde_json::json!({
+        "description": "Diagnose the last failed command",
+        "messages": [{
+            "role": "user",
+            "content": { "type": "text", "text": text }
+        }]
+    })
+}
+
+fn diagnose_text(failure: &SessionFailure, insights: &[(String, String)]) -> String {
+    let mut text = format!("This command failed in my zsh-tool session:\n\n    $ {}\n\n", failure.command);
+    if failure.truncated {
+        text.push_str("(Only the start of the command was recorded; the rest is cut off.)\n\n");
+    }
+    if failure.timed_out {
+        text.push_str("It timed out.");
+    } else {
+        match failure.last_exit_code {
+            Some(code) => text.push_str(&format!("It exited with code {}.", code)),
+            None => text.push_str("Its exit code was not recorded."),
+        }
+    }
+    if failure.failures > 1 {
+        text.push_str(&format!(" It has failed {} times this session.", failure.failures));
+    }
+    text.push('\n');
+    if let Some(ref error) = failure.last_error {
+        text.push_str("\nLast error output:\n\n");
+        for line in error.trim_end().lines() {
+            text.push_str(&format!("    {}\n", line));
+        }
+    }
+    if !insights.is_empty() {
+        text.push_str("\nALAN insights:\n");
+        for (level, message) in insights {
+            text.push_str(&format!("- [{}] {}\n", level, message));
+        }
+    }
+    text.push_str(
+        "\nDiagnose why it failed and suggest a fix. If more information would help, gather it with read-only commands first.",
+    );
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn failure(timed_out: bool, last_error: Option<&str>) -> SessionFailure {
+        SessionFailure {
+            command: "cargo build".into(),
+            truncated: false,
+            command_hash: String::new(),
+            command_template: String::new(),
+            failures: 2,
+            last_exit_code: Some(101),
+            timed_out,
+            last_error: last_error.map(String::from),
+        }
+    }
+
+    #[test]
+    fn test_diagnose_failure_embeds_command_error_and_insights() {
+        let insights = vec![("warning".to_string(), "Failed 2 times in the last 10 minutes".to_string())];
+        let result = diagnose_failure(Some(&failure(false, Some("error[E0308]: mismatched types\n"))), &insights);
+        let text = result["messages"][0]["content"]["text"].as_str().unwrap();
+        assert_eq!(result["messages"][0]["role"], "user");
+        assert!(text.contains("    $ cargo build\n"), "{}", text);
+        assert!(text.contains("exited with code 101. It has failed 2 times"), "{}", text);
+        assert!(text.contains("    error[E0308]: mismatched types\n"), "{}", text);
+        assert!(text.contains("- [warning] Failed 2 times in the last 10 minutes\n"), "{}", text);
+    }
+
+    #[test]
+    fn test_diagnose_failure_without_failure_or_extras() {
+        let text = diagnose_failure(Some(&failure(true, None)), &[])["messages"][0]["content"]["text"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        assert!(text.contains("It timed out. It has failed 2 times"), "{}", text);
+        assert!(!text.contains("Last error output") && !text.contains("ALAN insights"), "{}", text);
+        assert!(!text.contains("cut off"), "{}", text);
+
+        let mut long = failure(false, None);
+        long.truncated = true;
+        let text = diagnose_failure(Some(&long), &[])["messages"][0]["content"]["text"].as_str().unwrap().to_string();
+        assert!(text.contains("    $ cargo build\n\n(Only the start of the command was recorded"), "{}", text);
+
+        let none = diagnose_failure(None, &[]);
+        assert!(none["messages"][0]["content"]["text"].as_str().unwrap().contains("nothing to diagnose"));
+    }
+}
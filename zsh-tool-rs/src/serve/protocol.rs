@@ -1,14 +1,139 @@
 //! MCP JSON-RPC 2.0 protocol types and framing.
 //!
 //! Supports both Content-Length framed and bare newline-delimited JSON.
-//! Auto-detects on first message; responds in the same format.
+//! Auto-detects per connection; responds in the same format.
 
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use std::cell::{Cell, RefCell};
 use std::sync::atomic::{AtomicBool, Ordering};
 
-/// Whether the client uses bare JSON (no Content-Length framing).
-static BARE_JSON_MODE: AtomicBool = AtomicBool::new(false);
+/// How a client frames its messages. Each connection starts out
+/// Content-Length framed and switches for good once it sends bare JSON.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Framing {
+    #[default]
+    ContentLength,
+    BareJson,
+}
+
+/// Per-message framing logs and raw bodies on stderr. Errors are always logged.
+/// Toggled at runtime by the `zsh_debug` tool.
+static VERBOSE_LOGGING: AtomicBool = AtomicBool::new(false);
+
+macro_rules! verbose {
+    ($($arg:tt)*) => {
+        if VERBOSE_LOGGING.load(Ordering::Relaxed) {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+/// Turn verbose protocol logging on or off. Returns the previous setting.
+pub fn set_verbose_logging(enabled: bool) -> bool {
+    VERBOSE_LOGGING.swap(enabled, Ordering::Relaxed)
+}
+
+pub fn verbose_logging() -> bool {
+    VERBOSE_LOGGING.load(Ordering::Relaxed)
+}
+
+/// MCP log severity (RFC 5424 levels), lowest first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Debug = 1,
+    Info,
+    Notice,
+    Warning,
+    Error,
+    Critical,
+    Alert,
+    Emergency,
+}
+
+impl LogLevel {
+    const ALL: [LogLevel; 8] = [
+        Self::Debug,
+        Self::Info,
+        Self::Notice,
+        Self::Warning,
+        Self::Error,
+        Self::Critical,
+        Self::Alert,
+        Self::Emergency,
+    ];
+
+    pub fn parse(value: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|l| l.as_str() == value)
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Debug => "debug",
+            Self::Info => "info",
+            Self::Notice => "notice",
+            Self::Warning => "warning",
+            Self::Error => "error",
+            Self::Critical => "critical",
+            Self::Alert => "alert",
+            Self::Emergency => "emergency",
+        }
+    }
+}
+
+// Each connection is answered on its own thread, so its client's log level
+// and queue live there. Lines logged on other threads (push notifier, output
+// readers) only reach stderr.
+thread_local! {
+    /// Minimum level forwarded to this thread's client as
+    /// `notifications/message`. 0 until it sends `logging/setLevel`.
+    static CLIENT_LOG_LEVEL: Cell<u8> = const { Cell::new(0) };
+    /// Log entries waiting to be written after the current response.
+    static PENDING_LOGS: RefCell<Vec<(LogLevel, String)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Set this thread's client level from `logging/setLevel`.
+pub fn set_client_log_level(level: LogLevel) {
+    CLIENT_LOG_LEVEL.with(|l| l.set(level as u8));
+}
+
+/// Log a server line to stderr and, at or above the level of the client this
+/// thread serves, queue it for that client. Debug lines only reach stderr
+/// with verbose logging on.
+pub fn log(level: LogLevel, message: String) {
+    if level > LogLevel::Debug || verbose_logging() {
+        eprintln!("[zsh-tool] {}", message);
+    }
+    let min = CLIENT_LOG_LEVEL.with(Cell::get);
+    if min != 0 && level as u8 >= min {
+        PENDING_LOGS.with(|p| p.borrow_mut().push((level, message)));
+    }
+}
+
+/// Drain this thread's queued log entries as `notifications/message`
+/// notifications.
+pub fn take_log_notifications() -> Vec<JsonRpcNotification> {
+    PENDING_LOGS.with(|p| std::mem::take(&mut *p.borrow_mut()))
+        .into_iter()
+        .map(|(level, message)| JsonRpcNotification {
+            jsonrpc: "2.0".into(),
+            method: "notifications/message".into(),
+            params: serde_json::json!({
+                "level": level.as_str(),
+                "logger": "zsh-tool",
+                "data": message,
+            }),
+        })
+        .collect()
+}
+
+/// `log(level, format!(...))`.
+macro_rules! server_log {
+    ($level:expr, $($arg:tt)*) => {
+        $crate::serve::protocol::log($level, format!($($arg)*))
+    };
+}
+pub(crate) use server_log;
 
 /// JSON-RPC 2.0 request.
 #[derive(Debug, Deserialize)]
@@ -32,6 +157,14 @@ pub struct JsonRpcResponse {
     pub error: Option<JsonRpcError>,
 }
 
+/// JSON-RPC 2.0 notification (server to client, no id).
+#[derive(Debug, Serialize)]
+pub struct JsonRpcNotification {
+    pub jsonrpc: String,
+    pub method: String,
+    pub params: Value,
+}
+
 #[derive(Debug, Serialize)]
 pub struct JsonRpcError {
     pub code: i64,
@@ -64,18 +197,47 @@ impl JsonRpcResponse {
     }
 }
 
-/// MCP initialize result.
-pub fn initialize_result(server_name: &str, version: &str) -> Value {
-    serde_json::json!({
-        "protocolVersion": "2024-11-05",
+/// MCP revisions this server speaks, newest first.
+pub const PROTOCOL_VERSIONS: &[&str] = &["2025-06-18", "2025-03-26", "2024-11-05"];
+
+/// First revision with `resource_link` content in tool results.
+pub const RESOURCE_LINK_VERSION: &str = "2025-06-18";
+
+/// The revision to answer `initialize` with: the client's if this server
+/// speaks it, otherwise the newest.
+pub fn negotiate_protocol_version(requested: Option<&str>) -> &'static str {
+    PROTOCOL_VERSIONS
+        .iter()
+        .copied()
+        .find(|v| Some(*v) == requested)
+        .unwrap_or(PROTOCOL_VERSIONS[0])
+}
+
+/// MCP initialize result. `push_notifications` advertises that task
+/// completions arrive as unsolicited `notifications/message`.
+pub fn initialize_result(
+    server_name: &str,
+    version: &str,
+    protocol_version: &str,
+    push_notifications: bool,
+) -> Value {
+    let mut result = serde_json::json!({
+        "protocolVersion": protocol_version,
         "capabilities": {
-            "tools": {}
+            "tools": {},
+            "resources": {},
+            "prompts": {},
+            "logging": {}
         },
         "serverInfo": {
             "name": server_name,
             "version": version
         }
-    })
+    });
+    if push_notifications {
+        result["capabilities"]["notifications"] = serde_json::json!({});
+    }
+    result
 }
 
 /// MCP tool definition.
@@ -108,14 +270,14 @@ pub fn error_content(text: &str) -> Value {
     })
 }
 
-/// Read a JSON-RPC message from stdin.
-/// Auto-detects bare JSON lines vs Content-Length framing.
-/// Returns None on EOF.
-pub fn read_message(reader: &mut impl std::io::BufRead) -> Option<JsonRpcRequest> {
+/// Read a JSON-RPC message from one client.
+/// Auto-detects bare JSON lines vs Content-Length framing, recording the
+/// former in `framing`. Returns None on EOF.
+pub fn read_message(reader: &mut impl std::io::BufRead, framing: &mut Framing) -> Option<JsonRpcRequest> {
     let mut line = String::new();
     match reader.read_line(&mut line) {
         Ok(0) => {
-            eprintln!("[zsh-tool:proto] EOF on stdin");
+            verbose!("[zsh-tool:proto] EOF on stdin");
             return None;
         }
         Ok(_) => {}
@@ -128,16 +290,17 @@ pub fn read_message(reader: &mut impl std::io::BufRead) -> Option<JsonRpcRequest
     let trimmed = line.trim();
     if trimmed.is_empty() {
         // Blank line — try again (could be between messages)
-        return read_message(reader);
+        return read_message(reader, framing);
     }
 
     // Detect: does this line start with '{' (bare JSON) or 'Content-Length:' (framed)?
     if trimmed.starts_with('{') {
         // Bare JSON mode
-        if !BARE_JSON_MODE.load(Ordering::Relaxed) {
-            eprintln!("[zsh-tool:proto] Detected bare JSON mode");
-            BARE_JSON_MODE.store(true, Ordering::Relaxed);
+        if *framing != Framing::BareJson {
+            verbose!("[zsh-tool:proto] Detected bare JSON mode");
+            *framing = Framing::BareJson;
         }
+        verbose!("[zsh-tool:proto] <- {}", trimmed);
         match serde_json::from_str(trimmed) {
             Ok(req) => Some(req),
             Err(e) => {
@@ -154,7 +317,7 @@ pub fn read_message(reader: &mut impl std::io::BufRead) -> Option<JsonRpcRequest
                 return None;
             }
         };
-        eprintln!("[zsh-tool:proto] Content-Length: {}", content_length);
+        verbose!("[zsh-tool:proto] Content-Length: {}", content_length);
 
         // Read remaining headers until empty line
         loop {
@@ -183,6 +346,7 @@ pub fn read_message(reader: &mut impl std::io::BufRead) -> Option<JsonRpcRequest
             return None;
         }
 
+        verbose!("[zsh-tool:proto] <- {}", String::from_utf8_lossy(&body));
         match serde_json::from_slice(&body) {
             Ok(req) => Some(req),
             Err(e) => {
@@ -197,35 +361,36 @@ pub fn read_message(reader: &mut impl std::io::BufRead) -> Option<JsonRpcRequest
     }
 }
 
-/// Write a JSON-RPC response to stdout.
-/// Uses bare JSON or Content-Length framing to match the client.
-pub fn write_message(writer: &mut impl std::io::Write, response: &JsonRpcResponse) {
+/// Write a JSON-RPC response or notification to a client.
+/// Uses bare JSON or Content-Length framing to match it.
+pub fn write_message(
+    writer: &mut impl std::io::Write,
+    framing: Framing,
+    response: &impl Serialize,
+) -> std::io::Result<()> {
     let body = serde_json::to_string(response).unwrap_or_default();
-    eprintln!("[zsh-tool:proto] Writing {} bytes (bare={})", body.len(), BARE_JSON_MODE.load(Ordering::Relaxed));
+    verbose!("[zsh-tool:proto] Writing {} bytes ({:?})", body.len(), framing);
+    verbose!("[zsh-tool:proto] -> {}", body);
 
-    if BARE_JSON_MODE.load(Ordering::Relaxed) {
+    if framing == Framing::BareJson {
         // Bare JSON: one line + newline
-        if let Err(e) = writer.write_all(body.as_bytes()) {
+        writer.write_all(body.as_bytes()).inspect_err(|e| {
             eprintln!("[zsh-tool:proto] Write error: {}", e);
-            return;
-        }
-        if let Err(e) = writer.write_all(b"\n") {
+        })?;
+        writer.write_all(b"\n").inspect_err(|e| {
             eprintln!("[zsh-tool:proto] Newline write error: {}", e);
-            return;
-        }
+        })?;
     } else {
         // Content-Length framed
         let header = format!("Content-Length: {}\r\n\r\n", body.len());
-        if let Err(e) = writer.write_all(header.as_bytes()) {
+        writer.write_all(header.as_bytes()).inspect_err(|e| {
             eprintln!("[zsh-tool:proto] Header write error: {}", e);
-            return;
-        }
-        if let Err(e) = writer.write_all(body.as_bytes()) {
+        })?;
+        writer.write_all(body.as_bytes()).inspect_err(|e| {
             eprintln!("[zsh-tool:proto] Body write error: {}", e);
-            return;
-        }
+        })?;
     }
-    if let Err(e) = writer.flush() {
+    writer.flush().inspect_err(|e| {
         eprintln!("[zsh-tool:proto] Flush error: {}", e);
-    }
+    })
 }
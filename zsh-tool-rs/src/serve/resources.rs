@@ -0,0 +1,192 @@
+//! MCP resources: full output of truncated tasks, served by task_id, and
+//! the ALAN learning stats.
+//!
+//! When a finished task's output is too big to inline, the whole output is
+//! written to a per-session directory and the result carries a
+//! `resource_link` to `zsh-tool://output/<task_id>` for `resources/read`.
+//! `zsh://alan/stats` is read live from the database by the server. There
+//! is no `resources/subscribe`: clients re-read it when they want fresh
+//! numbers.
+
+use std::path::PathBuf;
+
+use serde_json::Value;
+
+/// URI prefix of task output resources; the task_id follows.
+pub const OUTPUT_URI_PREFIX: &str = "zsh-tool://output/";
+
+/// URI of the ALAN stats resource, the same data as `zsh_alan_stats`.
+pub const ALAN_STATS_URI: &str = "zsh://alan/stats";
+
+/// Directory holding this session's output files.
+pub fn output_dir(session_id: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("zsh-tool-{}", session_id))
+}
+
+/// Task ids are generated hex; anything else could escape the directory.
+fn valid_task_id(task_id: &str) -> bool {
+    !task_id.is_empty() && task_id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+fn output_path(session_id: &str, task_id: &str) -> Option<PathBuf> {
+    valid_task_id(task_id).then(|| output_dir(session_id).join(format!("{}.log", task_id)))
+}
+
+/// Create this session's output directory, owner-only (see
+/// [`create_private_dir`]).
+fn create_output_dir(session_id: &str) -> Result<PathBuf, String> {
+    let dir = output_dir(session_id);
+    create_private_dir(&dir).map_err(|e| format!("create output dir: {}", e))?;
+    Ok(dir)
+}
+
+/// Create `dir` (not its parents) owner-only. Our directory names are
+/// predictable, so an existing directory that isn't ours alone is refused
+/// rather than written into.
+pub fn create_private_dir(dir: &std::path::Path) -> Result<(), String> {
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+    match std::fs::DirBuilder::new().mode(0o700).create(dir) {
+        Err(e) if e.kind() != std::io::ErrorKind::AlreadyExists => return Err(e.to_string()),
+        _ => {}
+    }
+    let meta = std::fs::symlink_metadata(dir).map_err(|e| e.to_string())?;
+    if !meta.is_dir() || meta.uid() != unsafe { libc::geteuid() } || meta.permissions().mode() & 0o077 != 0 {
+        return Err(format!("{} is not a private directory of this user", dir.display()));
+    }
+    Ok(())
+}
+
+/// Write `output` for `task_id` (owner-only) and return its resource URI.
+pub fn write_output(session_id: &str, task_id: &str, output: &str) -> Result<String, String> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+    let path = output_path(session_id, task_id).ok_or_else(|| format!("Invalid task_id: {}", task_id))?;
+    create_output_dir(session_id)?;
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&path)
+        .and_then(|mut f| f.write_all(output.as_bytes()))
+        .map_err(|e| format!("write {}: {}", path.display(), e))?;
+    Ok(format!("{}{}", OUTPUT_URI_PREFIX, task_id))
+}
+
+/// `resources/read` result for `uri`, or None if no such output exists.
+pub fn read(session_id: &str, uri: &str) -> Option<Value> {
+    let task_id = uri.strip_prefix(OUTPUT_URI_PREFIX)?;
+    let text = std::fs::read_to_string(output_path(session_id, task_id)?).ok()?;
+    Some(serde_json::json!({
+        "contents": [{
+            "uri": uri,
+            "mimeType": "text/plain",
+            "text": text
+        }]
+    }))
+}
+
+/// `resources/read` result holding `value` as JSON text.
+pub fn json_contents(uri: &str, value: &Value) -> Value {
+    serde_json::json!({
+        "contents": [{
+            "uri": uri,
+            "mimeType": "application/json",
+            "text": serde_json::to_string_pretty(value).unwrap_or_default()
+        }]
+    })
+}
+
+/// `resources/list` result: the ALAN stats, then every output written this
+/// session.
+pub fn list(session_id: &str) -> Value {
+    let mut resources: Vec<Value> = std::fs::read_dir(output_dir(session_id))
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let task_id = name.strip_suffix(".log")?.to_string();
+            let size = entry.metadata().ok()?.len();
+            Some(link(&format!("{}{}", OUTPUT_URI_PREFIX, task_id), &task_id, size))
+        })
+        .collect();
+    resources.sort_by(|a, b| a["uri"].as_str().cmp(&b["uri"].as_str()));
+    resources.insert(
+        0,
+        serde_json::json!({
+            "uri": ALAN_STATS_URI,
+            "name": "ALAN stats",
+            "description": "Learning database totals, this session's counts and the hottest command patterns",
+            "mimeType": "application/json"
+        }),
+    );
+    serde_json::json!({ "resources": resources })
+}
+
+/// Resource descriptor for a task's output; with `"type": "resource_link"`
+/// added it doubles as tool result content.
+pub fn link(uri: &str, task_id: &str, size: u64) -> Value {
+    serde_json::json!({
+        "uri": uri,
+        "name": format!("task {} output", task_id),
+        "mimeType": "text/plain",
+        "size": size
+    })
+}
+
+/// Delete this session's output files.
+pub fn remove_all(session_id: &str) {
+    let _ = std::fs::remove_dir_all(output_dir(session_id));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_read_list_round_trip() {
+        let session = format!("test-{}", uuid::Uuid::new_v4());
+        let uri = write_output(&session, "abc123", "line 1\nline 2\n").unwrap();
+        assert_eq!(uri, "zsh-tool://output/abc123");
+
+        let read_back = read(&session, &uri).unwrap();
+        assert_eq!(read_back["contents"][0]["text"], "line 1\nline 2\n");
+        assert_eq!(read_back["contents"][0]["mimeType"], "text/plain");
+
+        let listed = list(&session);
+        assert_eq!(listed["resources"][0]["uri"], ALAN_STATS_URI);
+        assert_eq!(listed["resources"][1]["uri"], uri);
+        assert_eq!(listed["resources"][1]["size"], 14);
+
+        remove_all(&session);
+        assert!(read(&session, &uri).is_none());
+        assert_eq!(list(&session)["resources"].as_array().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_output_is_private_to_the_user() {
+        use std::os::unix::fs::PermissionsExt;
+        let session = format!("test-{}", uuid::Uuid::new_v4());
+        write_output(&session, "abc123", "secret\n").unwrap();
+        let mode = |p: PathBuf| std::fs::metadata(p).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(output_dir(&session)), 0o700);
+        assert_eq!(mode(output_dir(&session).join("abc123.log")), 0o600);
+        remove_all(&session);
+
+        // A pre-created directory others can read is not written into
+        std::fs::create_dir(output_dir(&session)).unwrap();
+        std::fs::set_permissions(output_dir(&session), std::fs::Permissions::from_mode(0o755)).unwrap();
+        assert!(write_output(&session, "abc123", "secret\n").is_err());
+        remove_all(&session);
+    }
+
+    #[test]
+    fn test_rejects_unknown_and_traversing_uris() {
+        let session = format!("test-{}", uuid::Uuid::new_v4());
+        assert!(read(&session, "zsh-tool://output/../../etc/passwd").is_none());
+        assert!(read(&session, "file:///etc/passwd").is_none());
+        assert!(read(&session, "zsh-tool://output/missing").is_none());
+        assert!(write_output(&session, "../x", "nope").is_err());
+    }
+}
@@ -0,0 +1,63 @@
+//! Terminal rendering for PTY output (`render_terminal`).
+//!
+//! TUI programs draw with cursor movement and screen clears, so the raw PTY
+//! stream reads as noise. Feeding it through a vt100 emulator and returning
+//! the final screen gives what a person at the terminal would have seen.
+
+/// Screen size TUI programs assume when the PTY reports none.
+const MIN_ROWS: u16 = 24;
+const MIN_COLS: u16 = 80;
+/// Plain line-oriented output grows the screen up to these limits so it
+/// isn't wrapped or scrolled away; anything beyond keeps the last rows.
+const MAX_ROWS: u16 = 2000;
+const MAX_COLS: u16 = 500;
+
+/// Interpret the escape sequences in `raw` and return the rendered screen as
+/// plain text, with trailing blank lines and trailing spaces dropped.
+pub fn render(raw: &str) -> String {
+    let lines = raw.lines().count().saturating_add(1);
+    let widest = raw.lines().map(|l| l.chars().count()).max().unwrap_or(0);
+    let rows = lines.clamp(MIN_ROWS as usize, MAX_ROWS as usize) as u16;
+    let cols = widest.clamp(MIN_COLS as usize, MAX_COLS as usize) as u16;
+
+    let mut parser = vt100::Parser::new(rows, cols, 0);
+    parser.process(raw.as_bytes());
+    let contents = parser.screen().contents();
+    let mut rendered: Vec<&str> = contents.lines().map(str::trim_end).collect();
+    while rendered.last().is_some_and(|l| l.is_empty()) {
+        rendered.pop();
+    }
+    rendered.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_applies_cursor_movement() {
+        // Draw "world" on row 2, then jump back to row 1 and overwrite
+        let raw = "\x1b[2J\x1b[H\x1b[2;1Hworld\x1b[1;1Hhello\x1b[1;1HJ";
+        assert_eq!(render(raw), "Jello\nworld");
+    }
+
+    #[test]
+    fn test_render_carriage_return_overwrites_progress() {
+        assert_eq!(render("progress 10%\rprogress 100%\r\ndone\r\n"), "progress 100%\ndone");
+    }
+
+    #[test]
+    fn test_render_keeps_plain_output_and_long_lines() {
+        let long = "x".repeat(200);
+        let raw = format!("{}\r\n{}\r\n", long, (1..=40).map(|n| n.to_string()).collect::<Vec<_>>().join("\r\n"));
+        let rendered = render(&raw);
+        assert!(rendered.starts_with(&long), "a wide line must not wrap: {:?}", &rendered[..210.min(rendered.len())]);
+        assert!(rendered.ends_with("39\n40"));
+        assert_eq!(rendered.lines().count(), 41);
+    }
+
+    #[test]
+    fn test_render_strips_colors() {
+        assert_eq!(render("\x1b[1;31mred\x1b[0m plain"), "red plain");
+    }
+}
@@ -1,8 +1,10 @@
-//! MCP tool definitions — the 10 tools exposed to Claude Code.
+//! MCP tool definitions — the tools exposed to Claude Code.
 
 use serde_json::{json, Value};
+use std::collections::HashMap;
 
 use super::protocol::tool_def;
+use crate::config::Config;
 
 pub fn list_tools(timeout_default: u64, timeout_max: u64, yield_after: f64) -> Value {
     json!({
@@ -18,11 +20,15 @@ pub fn list_tools(timeout_default: u64, timeout_max: u64, yield_after: f64) -> V
                         },
                         "timeout": {
                             "type": "integer",
-                            "description": format!("Max execution time in seconds (default: {}, max: {})", timeout_default, timeout_max)
+                            "description": format!("Max execution time in seconds (default: {}, max: {} unless every command in it matches timeout_exempt_patterns, then timeout_exempt_max)", timeout_default, timeout_max)
                         },
                         "yield_after": {
                             "type": "number",
-                            "description": format!("Return control after this many seconds if still running (default: {})", yield_after)
+                            "description": format!("Return control after this many seconds if still running (default: {}, adapted to the command's history when omitted). 0 registers the task and returns immediately, for clients that always poll.", yield_after)
+                        },
+                        "yield_on_output": {
+                            "type": "integer",
+                            "description": "Return early from the yield_after wait once the command has printed at least this many bytes and gone quiet (e.g. printed its result and is lingering). 0 disables."
                         },
                         "description": {
                             "type": "string",
@@ -31,6 +37,59 @@ pub fn list_tools(timeout_default: u64, timeout_max: u64, yield_after: f64) -> V
                         "pty": {
                             "type": "boolean",
                             "description": "Use PTY (pseudo-terminal) mode for full terminal emulation. Enables proper handling of interactive prompts, colors, and programs that require a TTY."
+                        },
+                        "output_hash": {
+                            "type": "boolean",
+                            "description": "Add output_hash, the SHA-256 of the full output, so repeated runs can be compared for changes without diffing the text."
+                        },
+                        "timestamp_lines": {
+                            "type": "boolean",
+                            "description": "Prefix each output line with [+Nms], the time since the command started when the line arrived, to see where a long command spends its time. zsh_output still returns the raw lines."
+                        },
+                        "render_terminal": {
+                            "type": "boolean",
+                            "description": "PTY mode only: interpret cursor movement and screen clears and return the final rendered screen as text instead of the raw escape stream. Use for TUI programs (htop, vim, progress bars)."
+                        },
+                        "nice": {
+                            "type": "integer",
+                            "description": "Scheduling niceness for the command (0-19; higher = lower priority). Use for long batch work so interactive commands aren't starved."
+                        },
+                        "extract_json": {
+                            "type": "boolean",
+                            "description": "Parse the finished output as JSON and return it as structuredContent (objects as-is, other values under \"value\"). Invalid JSON falls back to text with a warning. Use with separate_stderr so stderr doesn't break parsing."
+                        },
+                        "retries": {
+                            "type": "integer",
+                            "description": "Re-run the command up to this many times (max 5, with a short backoff) while it fails transiently: a timeout or an exit in retry_exit_codes (default 255, SSH connection failure). Permanent failures such as 127 command not found return at once. Only runs that finish within the yield window (which includes the backoff) are retried; the call counts once toward the circuit breaker."
+                        },
+                        "dry_run": {
+                            "type": "boolean",
+                            "description": "Don't run the command; return what ALAN knows about it (retry streaks, usual duration, SSH and network warnings) as a preview."
+                        },
+                        "confirm": {
+                            "type": "boolean",
+                            "description": "Run a destructive command (rm, find -delete, git clean) even though the working directory is $HOME or /. Only consulted when the home/root guard is enabled."
+                        },
+                        "separate_stderr": {
+                            "type": "boolean",
+                            "description": "Capture stderr apart from stdout, returned in its own section on completion. Defaults per command from config, otherwise merged. Ignored with pty."
+                        },
+                        "stream_socket": {
+                            "type": "string",
+                            "description": "Unix socket path to also stream live output to. The command binds it; external processes can connect and follow output without polling. Removed when the command finishes."
+                        },
+                        "timeout_signal": {
+                            "type": "string",
+                            "description": "Signal sent to the command's process group on timeout instead of SIGKILL (e.g. INT, TERM, QUIT). SIGKILL follows if it has not exited after a short grace period."
+                        },
+                        "stdin_fifo": {
+                            "type": "string",
+                            "description": "Path of an existing FIFO (mkfifo) to read the command's stdin from, so another process can feed input while you poll output. The command sees EOF when the last writer closes the FIFO. Not available with pty."
+                        },
+                        "env": {
+                            "type": "object",
+                            "additionalProperties": { "type": "string" },
+                            "description": "Extra environment variables for this call only, e.g. {\"FOO\": \"bar\"}. Not persisted and not part of the command recorded by A.L.A.N. An empty string unsets the variable."
                         }
                     },
                     "required": ["command"]
@@ -48,6 +107,18 @@ pub fn list_tools(timeout_default: u64, timeout_max: u64, yield_after: f64) -> V
                         "full_output": {
                             "type": "boolean",
                             "description": "Return entire output buffer with line numbers instead of just the delta since last poll (default: false)"
+                        },
+                        "filter": {
+                            "type": "string",
+                            "description": "Regex; return only matching output lines (line numbers kept) plus a count of the elided ones. The stored output and the delta cursor are not changed, so the next unfiltered poll still sees the hidden lines."
+                        },
+                        "full": {
+                            "type": "boolean",
+                            "description": "Return the path of the spool file holding the task's complete output instead of the buffer (requires spool_dir)"
+                        },
+                        "wait_ms": {
+                            "type": "integer",
+                            "description": "Block up to this many milliseconds for the task to finish before returning, instead of polling in a loop. Capped at the maximum timeout."
                         }
                     },
                     "required": ["task_id"]
@@ -83,10 +154,31 @@ pub fn list_tools(timeout_default: u64, timeout_max: u64, yield_after: f64) -> V
                     "required": ["task_id"]
                 })
             ),
+            tool_def("zsh_output",
+                "Get the complete, untruncated output buffer of a task — running, completed, or killed. Use after zsh_kill to retrieve everything captured up to the kill.\n\nIMPORTANT: Tool output is NOT visible to the user. You MUST relay the output in your response. Show it verbatim.",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "task_id": {
+                            "type": "string",
+                            "description": "Task ID to retrieve output for"
+                        },
+                        "tail": {
+                            "type": "integer",
+                            "description": "Only return the last N lines"
+                        }
+                    },
+                    "required": ["task_id"]
+                })
+            ),
             tool_def("zsh_tasks",
                 "List all active tasks with their status.",
                 json!({"type": "object", "properties": {}})
             ),
+            tool_def("zsh_session_summary",
+                "One-shot report on this session for handoff or logging: command counts and success rate, top patterns, notable failures (with their last error), running tasks and NEVERHANG circuit state.",
+                json!({"type": "object", "properties": {}})
+            ),
             tool_def("zsh_health",
                 "Get health status of zsh-tool including NEVERHANG and A.L.A.N. status",
                 json!({"type": "object", "properties": {}})
@@ -108,8 +200,284 @@ pub fn list_tools(timeout_default: u64, timeout_max: u64, yield_after: f64) -> V
                     "required": ["command"]
                 })
             ),
+            tool_def("zsh_estimate",
+                "Estimate how long a command will take from A.L.A.N. history, without running it. Returns average and p90 duration with a confidence level; 'unknown' for new patterns. Use it to pick a timeout or decide whether to background.",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "command": {
+                            "type": "string",
+                            "description": "Command to estimate"
+                        }
+                    },
+                    "required": ["command"]
+                })
+            ),
+            tool_def("zsh_alan_templates",
+                "List all known A.L.A.N. command templates with observation counts and success rates, most frequent first",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "limit": {
+                            "type": "integer",
+                            "description": "Maximum templates to return (default: 50)"
+                        },
+                        "offset": {
+                            "type": "integer",
+                            "description": "Number of templates to skip, for paging (default: 0)"
+                        }
+                    }
+                })
+            ),
+            tool_def("zsh_alan_timeouts",
+                "Rank A.L.A.N. command templates by how often they hit the NEVERHANG timeout, with average and longest durations, to spot commands that need a longer timeout",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "limit": {
+                            "type": "integer",
+                            "description": "Maximum templates to return (default: 10)"
+                        }
+                    }
+                })
+            ),
+            tool_def("zsh_alan_check",
+                "Check the A.L.A.N. database's health: runs SQLite's integrity check and reports the row count of every table and any orphaned ssh_observations rows",
+                json!({"type": "object", "properties": {}})
+            ),
+            tool_def("zsh_alan_export",
+                "Export the A.L.A.N. learning tables (observations, streaks, ssh_observations) as JSON for backup or inspection. Returned as text when it fits within the output limit; otherwise pass path to write it to a file.",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "since": {
+                            "type": "string",
+                            "description": "Only rows created or updated at or after this RFC 3339 timestamp, e.g. 2026-01-01T00:00:00Z"
+                        },
+                        "path": {
+                            "type": "string",
+                            "description": "Write the export to this file instead of returning it"
+                        }
+                    }
+                })
+            ),
+            tool_def("zsh_alan_import",
+                "Merge a zsh_alan_export dump into this machine's A.L.A.N. database, all or nothing. An observation for a command already seen with the same outcome is folded into it, weights summed; re-importing a file is a no-op; streaks for the same command hash are combined. Exports with unknown top-level keys are rejected.",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "data": {
+                            "description": "The export, as an object or its JSON text"
+                        },
+                        "path": {
+                            "type": "string",
+                            "description": "File written by zsh_alan_export (used when data is absent)"
+                        }
+                    }
+                })
+            ),
+            tool_def("zsh_alan_pin",
+                "Pin a command pattern so A.L.A.N. never decays or prunes what it learned about it, for rarely-run but critical commands. Pinning applies to the command's template, including observations recorded later; only its newest 100 observations are kept. Call without command to list pinned templates.",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "command": {
+                            "type": "string",
+                            "description": "Command (or template) whose pattern to pin"
+                        },
+                        "unpin": {
+                            "type": "boolean",
+                            "description": "Release the pin instead (default: false)"
+                        }
+                    }
+                })
+            ),
+            tool_def("zsh_alan_next",
+                "Predict likely next commands after a given one, from how often commands have followed it in past sessions",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "command": {
+                            "type": "string",
+                            "description": "Command just run (or about to be run)"
+                        },
+                        "limit": {
+                            "type": "integer",
+                            "description": "Maximum predictions to return (default: 5)"
+                        }
+                    },
+                    "required": ["command"]
+                })
+            ),
+            tool_def("zsh_manopt_warm",
+                "Pre-build the man page option tables A.L.A.N. shows after repeated failures, for commands you expect to use. Each parse is bounded by the manopt timeout; results report which commands were warmed, already cached, or have no usable man page.",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "commands": {
+                            "type": "array",
+                            "items": {"type": "string"},
+                            "description": "Base command names, e.g. [\"tar\", \"rsync\"]"
+                        }
+                    },
+                    "required": ["commands"]
+                })
+            ),
+            tool_def("zsh_debug",
+                "Toggle verbose protocol logging on the server's stderr (framing, raw request/response bodies). Pass verbose to set it explicitly; omit to flip it.",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "verbose": {
+                            "type": "boolean",
+                            "description": "Turn verbose logging on (true) or off (false)"
+                        }
+                    }
+                })
+            ),
+            tool_def("zsh_sequence",
+                "Run commands in order, waiting for each to finish. Stops at the first failing step unless that step sets continue_on_error; later steps are reported as skipped. Returns per-step status, exit code and output.",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "steps": {
+                            "type": "array",
+                            "description": "Commands to run. Each item is a command string or {command, continue_on_error}",
+                            "items": {
+                                "anyOf": [
+                                    {"type": "string"},
+                                    {
+                                        "type": "object",
+                                        "properties": {
+                                            "command": {"type": "string"},
+                                            "continue_on_error": {
+                                                "type": "boolean",
+                                                "description": "Keep going if this step fails (default: false)"
+                                            }
+                                        },
+                                        "required": ["command"]
+                                    }
+                                ]
+                            }
+                        },
+                        "timeout": {
+                            "type": "integer",
+                            "description": "Per-step timeout in seconds (same limits as zsh)"
+                        }
+                    },
+                    "required": ["steps"]
+                })
+            ),
+            tool_def("zsh_benchmark",
+                "Run a command several times and report timing: min/max/mean/p50/p90 plus per-run durations and exit codes. Each run is a normal zsh run (recorded by A.L.A.N., subject to timeout and circuit breaker). At most 50 runs and 300s per call; each run's timeout is clamped to what is left of the 300s.",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "command": {
+                            "type": "string",
+                            "description": "Command to benchmark"
+                        },
+                        "runs": {
+                            "type": "integer",
+                            "description": "How many times to run it (default: 5, max: 50)"
+                        },
+                        "timeout": {
+                            "type": "integer",
+                            "description": "Per-run timeout in seconds (same limits as zsh)"
+                        }
+                    },
+                    "required": ["command"]
+                })
+            ),
+            tool_def("zsh_check",
+                "Check a command against the deny_commands/allow_commands policy without running it. Returns whether zsh would accept it, the pattern that decided, and ALAN pre-insights.",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "command": {
+                            "type": "string",
+                            "description": "Command to check"
+                        }
+                    },
+                    "required": ["command"]
+                })
+            ),
+            tool_def("zsh_retry_verbose",
+                "Re-run a command with more verbose output to debug a failure: the flag or environment setting for its base command is added (curl -v, ssh -vvv, GIT_TRACE=1 for git, ...; configurable via verbose_flags). Without command, re-runs this session's most recent failed command.",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "command": {
+                            "type": "string",
+                            "description": "Command to re-run (default: the last failed one)"
+                        },
+                        "timeout": {
+                            "type": "integer",
+                            "description": "Timeout in seconds (same limits as zsh)"
+                        },
+                        "yield_after": {
+                            "type": "number",
+                            "description": "Return after this many seconds if still running (same as zsh)"
+                        }
+                    }
+                })
+            ),
+            tool_def("zsh_cwd",
+                "Set the working directory for all later zsh calls in this session (a cd inside one zsh call does not carry over). Relative paths resolve against the current directory. Without path, returns the current one.",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "path": {
+                            "type": "string",
+                            "description": "Directory to switch to; must exist"
+                        }
+                    }
+                })
+            ),
+            tool_def("zsh_env",
+                "Set or unset environment variables for all later zsh calls in this session (an export inside one zsh call does not carry over). Per-call env overrides these. Returns the full set of session variables.",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "set": {
+                            "type": "object",
+                            "additionalProperties": { "type": "string" },
+                            "description": "Variables to set, e.g. {\"RUST_LOG\": \"debug\"}. An empty string unsets the variable in commands."
+                        },
+                        "unset": {
+                            "type": "array",
+                            "items": { "type": "string" },
+                            "description": "Names to drop from the session set (unknown names are ignored). Applied after set."
+                        }
+                    }
+                })
+            ),
+            tool_def("zsh_env_snapshot",
+                "Capture the shell's current environment variables as the baseline for zsh_env_diff",
+                json!({"type": "object", "properties": {}})
+            ),
+            tool_def("zsh_env_diff",
+                "Compare the shell's current environment against the zsh_env_snapshot baseline. Returns added, removed and changed variables.",
+                json!({"type": "object", "properties": {}})
+            ),
+            tool_def("zsh_config",
+                "Show the effective server config and where each value came from: default, yaml (~/.config/zsh-tool/config.yaml) or env. Use it to find out why a setting is ignored.",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "key": {
+                            "type": "string",
+                            "description": "Show only this field, e.g. yield_after_default (default: all)"
+                        }
+                    }
+                })
+            ),
             tool_def("zsh_neverhang_status",
-                "Get NEVERHANG circuit breaker status",
+                "Get NEVERHANG circuit breaker status and its configured thresholds",
+                json!({"type": "object", "properties": {}})
+            ),
+            tool_def("zsh_circuit_trip",
+                "Force the NEVERHANG circuit open (as if it had just tripped) to test a client's handling of blocked commands. Recovers after the usual recovery timeout, or immediately with zsh_neverhang_reset. Returns the resulting status.",
                 json!({"type": "object", "properties": {}})
             ),
             tool_def("zsh_neverhang_reset",
@@ -119,3 +487,25 @@ pub fn list_tools(timeout_default: u64, timeout_max: u64, yield_after: f64) -> V
         ]
     })
 }
+
+/// Drop tools that aren't in the `enabled_tools` allowlist.
+pub fn retain_enabled(tools: &mut Value, config: &Config) {
+    if let Some(list) = tools.get_mut("tools").and_then(|t| t.as_array_mut()) {
+        list.retain(|tool| config.tool_enabled(tool.get("name").and_then(|n| n.as_str()).unwrap_or("")));
+    }
+}
+
+/// Replace tool descriptions with operator-configured overrides, by tool name.
+pub fn apply_description_overrides(tools: &mut Value, overrides: &HashMap<String, String>) {
+    if overrides.is_empty() {
+        return;
+    }
+    if let Some(list) = tools.get_mut("tools").and_then(|t| t.as_array_mut()) {
+        for tool in list {
+            let name = tool.get("name").and_then(|n| n.as_str()).unwrap_or("");
+            if let Some(desc) = overrides.get(name) {
+                tool["description"] = json!(desc);
+            }
+        }
+    }
+}
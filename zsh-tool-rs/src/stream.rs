@@ -0,0 +1,108 @@
+//! Live output tee to a Unix domain socket.
+//!
+//! With `stream_socket`, the exec process binds a listener at the given path
+//! and copies every output chunk to whoever is connected, so dashboards can
+//! follow a task without polling. Clients see output from the moment they
+//! connect; the socket file is removed when the command finishes.
+
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// A stalled reader is dropped rather than allowed to block the command.
+const CLIENT_WRITE_TIMEOUT: Duration = Duration::from_millis(250);
+
+pub struct SocketTee {
+    path: PathBuf,
+    listener: UnixListener,
+    clients: Vec<UnixStream>,
+}
+
+impl SocketTee {
+    /// Bind a listener at `path`. A stale socket there is replaced; any other
+    /// kind of file is left alone and reported as an error.
+    pub fn bind(path: &str) -> Result<Self, String> {
+        let path = PathBuf::from(path);
+        if let Ok(meta) = std::fs::symlink_metadata(&path) {
+            if !meta.file_type().is_socket() {
+                return Err(format!("stream socket: {} exists and is not a socket", path.display()));
+            }
+            std::fs::remove_file(&path)
+                .map_err(|e| format!("stream socket: remove stale {}: {}", path.display(), e))?;
+        }
+        let listener = UnixListener::bind(&path)
+            .map_err(|e| format!("stream socket: bind {}: {}", path.display(), e))?;
+        listener
+            .set_nonblocking(true)
+            .map_err(|e| format!("stream socket: {}", e))?;
+        Ok(Self {
+            path,
+            listener,
+            clients: Vec::new(),
+        })
+    }
+
+    /// Accept any waiting clients without blocking.
+    fn accept_pending(&mut self) {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    let _ = stream.set_nonblocking(false);
+                    let _ = stream.set_write_timeout(Some(CLIENT_WRITE_TIMEOUT));
+                    self.clients.push(stream);
+                }
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => break,
+            }
+        }
+    }
+
+    /// Send a chunk of output to every connected client, dropping any that fail.
+    pub fn write(&mut self, data: &[u8]) {
+        self.accept_pending();
+        self.clients.retain_mut(|c| c.write_all(data).is_ok());
+    }
+}
+
+impl Drop for SocketTee {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_tee_reaches_connected_client() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.sock");
+        let path = path.to_str().unwrap();
+
+        let mut tee = SocketTee::bind(path).unwrap();
+        let mut client = UnixStream::connect(path).unwrap();
+        tee.write(b"line 1\n");
+        tee.write(b"line 2\n");
+        drop(tee);
+
+        let mut received = String::new();
+        client.read_to_string(&mut received).unwrap();
+        assert_eq!(received, "line 1\nline 2\n");
+        assert!(!std::path::Path::new(path).exists());
+    }
+
+    #[test]
+    fn test_bind_refuses_regular_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notes.txt");
+        std::fs::write(&path, "keep me").unwrap();
+
+        let err = SocketTee::bind(path.to_str().unwrap()).err().unwrap();
+        assert!(err.contains("not a socket"), "got: {}", err);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "keep me");
+    }
+}
@@ -0,0 +1,75 @@
+//! Re-running a command with more verbose output, for `zsh_retry_verbose`.
+//!
+//! The first command in the string gets the setting mapped to its base
+//! command. A flag (`-v`) goes right after the command name so it can't end up
+//! as an argument of something else (`ssh host cmd`); a `NAME=value` setting
+//! (`GIT_TRACE=1`) is prepended as an environment assignment.
+
+use std::collections::HashMap;
+
+/// Built-in verbosity settings per base command; `verbose_flags` adds to and
+/// overrides these.
+pub const DEFAULT_VERBOSE_FLAGS: &[(&str, &str)] = &[
+    ("curl", "-v"),
+    ("git", "GIT_TRACE=1"),
+    ("rsync", "-v"),
+    ("scp", "-v"),
+    ("ssh", "-vvv"),
+    ("wget", "-v"),
+];
+
+/// `command` with the verbosity setting for its base command applied, or None
+/// if no setting is known for it.
+pub fn augment(command: &str, flags: &HashMap<String, String>) -> Option<String> {
+    let trimmed = command.trim_start();
+    let indent = &command[..command.len() - trimmed.len()];
+    let name_len = trimmed.find(char::is_whitespace).unwrap_or(trimmed.len());
+    let (name, rest) = trimmed.split_at(name_len);
+    let base = name.rsplit('/').next().unwrap_or(name);
+    let setting = flags.get(base)?;
+    if is_env_assignment(setting) {
+        Some(format!("{}{} {}", indent, setting, trimmed))
+    } else {
+        Some(format!("{}{} {}{}", indent, name, setting, rest))
+    }
+}
+
+fn is_env_assignment(setting: &str) -> bool {
+    setting.split_once('=').is_some_and(|(name, _)| {
+        name.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn defaults() -> HashMap<String, String> {
+        DEFAULT_VERBOSE_FLAGS
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_flag_goes_after_command_name() {
+        let flags = defaults();
+        assert_eq!(augment("curl https://example.com", &flags).unwrap(), "curl -v https://example.com");
+        assert_eq!(augment("ssh host uptime", &flags).unwrap(), "ssh -vvv host uptime");
+        assert_eq!(augment("/usr/bin/curl -s x | jq .", &flags).unwrap(), "/usr/bin/curl -v -s x | jq .");
+        assert_eq!(augment("curl", &flags).unwrap(), "curl -v");
+    }
+
+    #[test]
+    fn test_env_setting_is_prepended() {
+        let flags = defaults();
+        assert_eq!(augment("git fetch origin", &flags).unwrap(), "GIT_TRACE=1 git fetch origin");
+    }
+
+    #[test]
+    fn test_unknown_command_has_no_setting() {
+        assert!(augment("ls -la", &defaults()).is_none());
+        assert!(augment("", &defaults()).is_none());
+    }
+}
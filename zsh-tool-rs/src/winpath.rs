@@ -0,0 +1,122 @@
+//! Opt-in rewriting of Windows-style paths in commands to POSIX form.
+//!
+//! Only unquoted words that are unmistakably paths are touched:
+//! `C:\Users\me` (or `C:/Users/me`) becomes `/mnt/c/Users/me`, and a word of
+//! plain path segments joined by backslashes with a `.` somewhere in it
+//! (`src\main.rs`, `..\build`) gets forward slashes. Anything with quotes, a
+//! leading or trailing backslash, a segment starting with `.` other than
+//! `.`/`..`, or no `.` at all is left alone, so `foo\nbar`, `a\tb`, `a\.b`
+//! and escaped spaces survive.
+
+/// Rewrite Windows-style path words in `command`. Returns the new command and
+/// each `(original, converted)` word, empty when nothing changed.
+pub fn normalize(command: &str) -> (String, Vec<(String, String)>) {
+    let mut out = String::with_capacity(command.len());
+    let mut changes = Vec::new();
+    let mut word = String::new();
+    for c in command.chars() {
+        if c.is_whitespace() {
+            flush_word(&mut word, &mut out, &mut changes);
+            out.push(c);
+        } else {
+            word.push(c);
+        }
+    }
+    flush_word(&mut word, &mut out, &mut changes);
+    (out, changes)
+}
+
+fn flush_word(word: &mut String, out: &mut String, changes: &mut Vec<(String, String)>) {
+    match convert_word(word) {
+        Some(converted) => {
+            out.push_str(&converted);
+            changes.push((std::mem::take(word), converted));
+        }
+        None => out.push_str(word),
+    }
+    word.clear();
+}
+
+/// POSIX form of one word, or None if it isn't an obvious Windows path.
+fn convert_word(word: &str) -> Option<String> {
+    let bytes = word.as_bytes();
+    // Drive letter: C:\... or C:/...
+    if bytes.len() >= 3
+        && bytes[0].is_ascii_alphabetic()
+        && bytes[1] == b':'
+        && (bytes[2] == b'\\' || bytes[2] == b'/')
+    {
+        let rest = &word[3..];
+        if !rest.split(['\\', '/']).all(plain_segment_or_empty) {
+            return None;
+        }
+        return Some(format!(
+            "/mnt/{}/{}",
+            (bytes[0] as char).to_ascii_lowercase(),
+            rest.replace('\\', "/")
+        ));
+    }
+    // Relative: dir\sub\file.ext. A trailing backslash escapes whitespace,
+    // and without a `.` the backslashes are as likely escapes (`foo\nbar`).
+    if !word.contains('\\') || word.starts_with('\\') || word.ends_with('\\') || !word.contains('.') {
+        return None;
+    }
+    if !word.split('\\').all(plain_segment) {
+        return None;
+    }
+    Some(word.replace('\\', "/"))
+}
+
+fn plain_segment(segment: &str) -> bool {
+    !segment.is_empty()
+        && (segment == "." || segment == ".." || !segment.starts_with('.'))
+        && segment.chars().all(|c| c.is_ascii_alphanumeric() || "._-~+@".contains(c))
+}
+
+fn plain_segment_or_empty(segment: &str) -> bool {
+    segment.is_empty() || plain_segment(segment)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_converts_drive_and_backslash_paths() {
+        let (cmd, changes) = normalize(r"cat C:\Users\me\notes.txt src\main.rs");
+        assert_eq!(cmd, "cat /mnt/c/Users/me/notes.txt src/main.rs");
+        assert_eq!(
+            changes,
+            vec![
+                (r"C:\Users\me\notes.txt".to_string(), "/mnt/c/Users/me/notes.txt".to_string()),
+                (r"src\main.rs".to_string(), "src/main.rs".to_string()),
+            ]
+        );
+        assert_eq!(normalize(r"ls D:/data").0, "ls /mnt/d/data");
+        assert_eq!(normalize(r"cd ..\build").0, "cd ../build");
+    }
+
+    #[test]
+    fn test_leaves_shell_escapes_and_quotes_alone() {
+        for cmd in [
+            r"printf 'a\nb'",
+            r"echo foo\nbar",
+            r"echo a\tb",
+            r"echo foo\ bar",
+            r"grep a\.b file",
+            r#"echo "C:\Users""#,
+            r"echo \$HOME",
+            "ls -la /tmp",
+            "echo a:b",
+        ] {
+            let (out, changes) = normalize(cmd);
+            assert_eq!(out, cmd);
+            assert!(changes.is_empty(), "{} changed: {:?}", cmd, changes);
+        }
+    }
+
+    #[test]
+    fn test_preserves_whitespace() {
+        assert_eq!(normalize("cat\tsrc\\a.rs\n").0, "cat\tsrc/a.rs\n");
+    }
+}
@@ -0,0 +1,144 @@
+use std::process::Command;
+
+use zsh_tool_exec::alan;
+
+fn exec_path() -> String {
+    env!("CARGO_BIN_EXE_zsh-tool-exec").to_string()
+}
+
+fn populate(db: &str) {
+    let conn = alan::open_db(db).unwrap();
+    for cmd in ["echo one", "git status", "ls -la /tmp", "false | true"] {
+        alan::record(&conn, "backuptest", cmd, 0, 10, false, "", &[0, 0]).unwrap();
+    }
+}
+
+fn count(db: &str) -> i64 {
+    let conn = rusqlite::Connection::open(db).unwrap();
+    conn.query_row("SELECT COUNT(*) FROM observations", [], |r| r.get(0))
+        .unwrap()
+}
+
+#[test]
+fn test_backup_and_restore_roundtrip() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = dir.path().join("alan.db");
+    let snapshot = dir.path().join("snap/alan-backup.db");
+    let fresh = dir.path().join("fresh/alan.db");
+    let (db, snapshot, fresh) = (
+        db.to_str().unwrap(),
+        snapshot.to_str().unwrap(),
+        fresh.to_str().unwrap(),
+    );
+
+    populate(db);
+    let original = count(db);
+    assert!(original > 0);
+
+    alan::backup::backup(db, snapshot).unwrap();
+    assert_eq!(count(snapshot), original);
+
+    alan::backup::restore(fresh, snapshot).unwrap();
+    assert_eq!(count(fresh), original);
+}
+
+#[test]
+fn test_restore_missing_snapshot_errors() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = dir.path().join("alan.db");
+    let err = alan::backup::restore(db.to_str().unwrap(), "/nonexistent/snapshot.db").unwrap_err();
+    assert!(err.contains("no such file"));
+}
+
+#[test]
+fn test_cli_backup_restore() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = dir.path().join("alan.db");
+    let snapshot = dir.path().join("snapshot.db");
+    let fresh = dir.path().join("fresh.db");
+    populate(db.to_str().unwrap());
+    let original = count(db.to_str().unwrap());
+
+    let output = Command::new(exec_path())
+        .args(["alan-backup", snapshot.to_str().unwrap()])
+        .env("ALAN_DB_PATH", &db)
+        .output()
+        .expect("run backup");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let output = Command::new(exec_path())
+        .args(["alan-restore", snapshot.to_str().unwrap()])
+        .env("ALAN_DB_PATH", &fresh)
+        .output()
+        .expect("run restore");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stdout).contains(&format!("restored {}", original)));
+    assert_eq!(count(fresh.to_str().unwrap()), original);
+}
+
+#[test]
+fn test_import_merges_export_into_another_db() {
+    let dir = tempfile::tempdir().unwrap();
+    let (src, dst) = (dir.path().join("src.db"), dir.path().join("dst.db"));
+    let (src, dst) = (src.to_str().unwrap(), dst.to_str().unwrap());
+    populate(src);
+    {
+        let conn = alan::open_db(dst).unwrap();
+        alan::record(&conn, "other", "git status", 0, 10, false, "", &[0]).unwrap();
+        alan::record(&conn, "other", "ls -la /tmp", 2, 10, false, "", &[2]).unwrap();
+    }
+    let weights = |conn: &rusqlite::Connection| -> (f64, i64) {
+        conn.query_row(
+            "SELECT SUM(weight), SUM(repeat_count) FROM observations WHERE command_preview = 'git status'",
+            [],
+            |r| Ok((r.get(0)?, r.get(1)?)),
+        )
+        .unwrap()
+    };
+
+    let export = alan::stats::export(&alan::open_db(src).unwrap(), None).unwrap();
+    let mut conn = alan::open_db(dst).unwrap();
+    let summary = alan::backup::merge_export(&mut conn, &export).unwrap();
+    // git status succeeded on both machines, so it merges; ls failed here and
+    // succeeded there, so both outcomes are kept
+    assert_eq!(summary.observations_merged, 1);
+    assert_eq!(summary.observations_added, export.observations.len() - 1);
+    assert!(summary.streaks_merged >= 1, "git status has a streak on both sides: {:?}", summary);
+    assert_eq!(count(dst), count(src) + 1);
+    assert_eq!(weights(&conn), (2.0, 2));
+
+    // Importing the same file again changes nothing
+    let again = alan::backup::merge_export(&mut conn, &export).unwrap();
+    assert_eq!((again.observations_added, again.observations_merged), (0, 0));
+    assert_eq!(again.observations_skipped, export.observations.len());
+    assert_eq!(count(dst), count(src) + 1);
+    assert_eq!(weights(&conn), (2.0, 2));
+}
+
+#[test]
+fn test_import_rejects_unknown_keys_and_rolls_back_bad_rows() {
+    let unknown = serde_json::json!({"observations": [], "command_graph": []});
+    let err = serde_json::from_value::<alan::stats::AlanExport>(unknown).unwrap_err();
+    assert!(err.to_string().contains("command_graph"), "{}", err);
+
+    let dir = tempfile::tempdir().unwrap();
+    let (src, dst) = (dir.path().join("src.db"), dir.path().join("dst.db"));
+    let (src, dst) = (src.to_str().unwrap(), dst.to_str().unwrap());
+    populate(src);
+    {
+        let conn = alan::open_db(src).unwrap();
+        alan::record(&conn, "backuptest", "ssh badhost uptime", 0, 10, false, "", &[0]).unwrap();
+    }
+    let export = alan::stats::export(&alan::open_db(src).unwrap(), None).unwrap();
+    assert_eq!(export.ssh_observations.len(), 1);
+
+    // The SSH rows are written last; failing one must undo the observations too
+    let mut conn = alan::open_db(dst).unwrap();
+    conn.execute_batch(
+        "CREATE TRIGGER reject_badhost BEFORE INSERT ON ssh_observations
+         WHEN NEW.host = 'badhost' BEGIN SELECT RAISE(ABORT, 'bad host'); END;",
+    )
+    .unwrap();
+    assert!(alan::backup::merge_export(&mut conn, &export).is_err());
+    assert_eq!(count(dst), 0);
+}
@@ -42,6 +42,34 @@ fn test_retry_detection_insight() {
     let _ = std::fs::remove_file(path);
 }
 
+#[test]
+fn test_retry_info_matches_history() {
+    let (conn, path) = fresh_db();
+
+    record(&conn, "make deploy", "s1", 1);
+    record(&conn, "make deploy", "s1", 2);
+    record(&conn, "make deploy", "s1", 0);
+    record(&conn, "make other", "s1", 1);
+
+    let (insights, info) =
+        alan::insights::get_pre_insights_with_retry(&conn, "make deploy", "s1", 3, 10);
+    assert_eq!(
+        info,
+        alan::insights::RetryInfo {
+            retry_count: 3,
+            recent_successes: 1,
+            recent_failures: 2,
+            window_minutes: 10,
+        }
+    );
+    assert!(insights.iter().any(|(_, msg)| msg.starts_with("Retry #4")), "got: {:?}", insights);
+
+    let (_, fresh) = alan::insights::get_pre_insights_with_retry(&conn, "make new", "s1", 3, 10);
+    assert_eq!(fresh.retry_count, 0);
+
+    let _ = std::fs::remove_file(path);
+}
+
 #[test]
 fn test_streak_insight() {
     let (conn, path) = fresh_db();
@@ -169,3 +197,56 @@ fn test_extract_base_command() {
     );
     assert_eq!(alan::insights::extract_base_command(""), "");
 }
+
+#[test]
+fn test_post_insights_skip_expected_silent_commands() {
+    let no_output = |insights: &[(String, String)]| {
+        insights.iter().any(|(_, msg)| msg.contains("No output produced"))
+    };
+    assert!(!no_output(&alan::insights::get_post_insights("touch file", &[0], "")));
+    assert!(!no_output(&alan::insights::get_post_insights("mkdir -p out && cd out", &[0], "")));
+    assert!(no_output(&alan::insights::get_post_insights("ls -la", &[0], "")));
+    assert!(no_output(&alan::insights::get_post_insights("touch file && ls", &[0], "")));
+
+    // A configured set replaces the defaults
+    let custom = vec!["ls".to_string()];
+    assert!(!no_output(&alan::insights::get_post_insights_with_silent("ls", &[0], "", &custom)));
+    assert!(no_output(&alan::insights::get_post_insights_with_silent("touch file", &[0], "", &custom)));
+}
+
+#[test]
+fn test_runtime_insights_slow_timeout_and_error_output() {
+    let keys = |insights: &[(String, (String, String))]| {
+        insights.iter().map(|(k, _)| k.clone()).collect::<Vec<_>>()
+    };
+
+    // Within normal bounds: nothing to say
+    assert!(alan::insights::get_runtime_insights(5.0, Some(4.0), 60, "building...\n").is_empty());
+
+    let insights = alan::insights::get_runtime_insights(13.0, Some(4.0), 60, "");
+    assert_eq!(keys(&insights), vec!["slow"]);
+    assert!(insights[0].1 .1.contains("3.2x its usual 4.0s"), "{:?}", insights);
+
+    let insights = alan::insights::get_runtime_insights(50.0, None, 60, "");
+    assert_eq!(keys(&insights), vec!["timeout"]);
+    assert!(insights[0].1 .1.contains("50s of 60s"), "{:?}", insights);
+
+    let output = "step 1\nthread 'main' panicked at src/main.rs:3:5\n";
+    let insights = alan::insights::get_runtime_insights(1.0, None, 60, output);
+    assert_eq!(keys(&insights), vec!["output:panicked at"]);
+    assert!(insights[0].1 .1.contains("a Rust panic"), "{:?}", insights);
+}
+
+#[test]
+fn test_classify_failure_for_retries() {
+    use alan::insights::{classify_failure, FailureClass};
+    let transient = [255, 127];
+    assert_eq!(classify_failure(255, false, &transient, true), FailureClass::Transient);
+    assert_eq!(classify_failure(127, false, &transient, true), FailureClass::Permanent, "listed, still permanent");
+    assert_eq!(classify_failure(126, false, &[], true), FailureClass::Permanent);
+    assert_eq!(classify_failure(1, false, &transient, true), FailureClass::Other);
+    assert_eq!(classify_failure(-1, true, &[], true), FailureClass::Transient);
+    assert_eq!(classify_failure(-1, true, &[], false), FailureClass::Other);
+    assert_eq!(alan::insights::describe_exit(127), "command not found (exit 127)");
+    assert_eq!(alan::insights::describe_exit(3), "exit 3");
+}
@@ -0,0 +1,81 @@
+use std::process::Command;
+
+use zsh_tool_exec::alan;
+
+#[test]
+fn test_check_reports_healthy_db() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = dir.path().join("alan.db");
+    let conn = alan::open_db(db.to_str().unwrap()).unwrap();
+    alan::record(&conn, "checktest", "ssh myhost uptime", 0, 50, false, "", &[0]).unwrap();
+    alan::record(&conn, "checktest", "git status", 0, 10, false, "", &[0]).unwrap();
+
+    let report = alan::maintenance::check(&conn).unwrap();
+    assert!(report.ok);
+    assert_eq!(report.integrity, vec!["ok"]);
+    assert_eq!(report.orphaned_ssh_observations, 0);
+    let rows = |name: &str| report.tables.iter().find(|t| t.table == name).map(|t| t.rows);
+    assert_eq!(rows("observations"), Some(2));
+    assert_eq!(rows("ssh_observations"), Some(1));
+    assert!(rows("streaks").is_some());
+}
+
+#[test]
+fn test_check_counts_orphaned_ssh_observations() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = dir.path().join("alan.db");
+    let conn = alan::open_db(db.to_str().unwrap()).unwrap();
+    alan::record(&conn, "checktest", "ssh myhost uptime", 0, 50, false, "", &[0]).unwrap();
+    conn.execute("DELETE FROM observations", []).unwrap();
+
+    let report = alan::maintenance::check(&conn).unwrap();
+    assert!(report.ok, "orphans do not make the database corrupt");
+    assert_eq!(report.orphaned_ssh_observations, 1);
+}
+
+#[test]
+fn test_check_path_reads_without_creating_or_writing() {
+    let dir = tempfile::tempdir().unwrap();
+    let missing = dir.path().join("nope").join("alan.db");
+    let err = alan::maintenance::check_path(missing.to_str().unwrap()).unwrap_err();
+    assert!(err.contains("no ALAN database"), "{}", err);
+    assert!(!missing.parent().unwrap().exists());
+
+    let garbage = dir.path().join("garbage.db");
+    let bytes = b"this is not a sqlite database, just some text padding it out".repeat(100);
+    std::fs::write(&garbage, &bytes).unwrap();
+    let err = alan::maintenance::check_path(garbage.to_str().unwrap()).unwrap_err();
+    assert!(err.contains("not a database"), "{}", err);
+    assert_eq!(std::fs::read(&garbage).unwrap(), bytes);
+}
+
+#[test]
+fn test_cli_alan_check() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = dir.path().join("alan.db");
+    alan::open_db(db.to_str().unwrap()).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_zsh-tool-exec"))
+        .arg("alan-check")
+        .env("ALAN_DB_PATH", &db)
+        .output()
+        .expect("run alan-check");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}{}", stdout, String::from_utf8_lossy(&output.stderr));
+    assert!(stdout.contains("integrity: ok\n"), "{}", stdout);
+    assert!(stdout.contains("observations: 0 rows\n"), "{}", stdout);
+    assert!(stdout.contains("orphaned ssh_observations: 0\n"), "{}", stdout);
+}
+
+#[test]
+fn test_cli_alan_check_fails_on_missing_db() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = dir.path().join("alan.db");
+    let output = Command::new(env!("CARGO_BIN_EXE_zsh-tool-exec"))
+        .arg("alan-check")
+        .env("ALAN_DB_PATH", &db)
+        .output()
+        .expect("run alan-check");
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("no ALAN database"));
+    assert!(!db.exists());
+}
@@ -66,3 +66,93 @@ fn test_without_db_flag_still_works() {
 
     let _ = fs::remove_file(meta);
 }
+
+#[test]
+fn test_failed_command_stderr_lands_in_error_snippet() {
+    let path = format!("/tmp/zsh-test-error-snippet-{}.db", uuid::Uuid::new_v4());
+    let conn = zsh_tool_exec::alan::open_db(&path).unwrap();
+
+    zsh_tool_exec::alan::record_with_stderr(
+        &conn, "s1", "cat /nope", 1, 10, false, "", "cat: /nope: No such file or directory\n", &[1],
+    )
+    .unwrap();
+    // Successful runs don't keep stderr (warnings aren't errors)
+    zsh_tool_exec::alan::record_with_stderr(
+        &conn, "s1", "cat /etc/hostname", 0, 10, false, "", "some warning\n", &[0],
+    )
+    .unwrap();
+
+    let snippets: Vec<Option<String>> = conn
+        .prepare("SELECT error_snippet FROM observations ORDER BY exit_code DESC")
+        .unwrap()
+        .query_map([], |row| row.get(0))
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    assert_eq!(
+        snippets,
+        vec![Some("cat: /nope: No such file or directory\n".to_string()), None]
+    );
+
+    let _ = fs::remove_file(path);
+}
+
+#[test]
+fn test_exec_records_separate_stderr_on_failure() {
+    let db_path = "/tmp/zsh-test-alan-stderr.db";
+    let meta = "/tmp/zsh-test-alan-stderr-meta.json";
+    let _ = fs::remove_file(db_path);
+    let _ = fs::remove_file(meta);
+
+    let _output = Command::new(exec_path())
+        .args([
+            "--meta", meta,
+            "--db", db_path,
+            "--session-id", "test1234",
+            "--separate-stderr",
+            "--", "echo boom >&2; exit 3",
+        ])
+        .output()
+        .expect("failed to run");
+
+    let conn = rusqlite::Connection::open(db_path).unwrap();
+    let snippet: Option<String> = conn
+        .query_row("SELECT error_snippet FROM observations", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(snippet.as_deref(), Some("boom\n"));
+
+    let _ = fs::remove_file(db_path);
+    let _ = fs::remove_file(meta);
+}
+
+#[test]
+fn test_exec_records_bounded_output_snippet() {
+    let db_path = "/tmp/zsh-test-alan-stdout.db";
+    let meta = "/tmp/zsh-test-alan-stdout-meta.json";
+    let _ = fs::remove_file(db_path);
+    let _ = fs::remove_file(meta);
+
+    let _output = Command::new(exec_path())
+        .args([
+            "--meta", meta,
+            "--db", db_path,
+            "--session-id", "test1234",
+            "--", "echo first; printf 'x%.0s' $(seq 1000)",
+        ])
+        .output()
+        .expect("failed to run");
+
+    let conn = rusqlite::Connection::open(db_path).unwrap();
+    let snippet: Option<String> = conn
+        .query_row("SELECT output_snippet FROM observations", [], |row| row.get(0))
+        .unwrap();
+    let snippet = snippet.expect("stdout should be recorded");
+    assert!(snippet.starts_with("first\nxxx"), "got: {}", snippet);
+    assert_eq!(snippet.len(), 500);
+
+    let meta_json: serde_json::Value = serde_json::from_str(&fs::read_to_string(meta).unwrap()).unwrap();
+    assert_eq!(meta_json["output_snippet"].as_str(), Some(snippet.as_str()));
+
+    let _ = fs::remove_file(db_path);
+    let _ = fs::remove_file(meta);
+}
@@ -101,3 +101,30 @@ fn test_ssh_exit_classification() {
     assert_eq!(alan::ssh::classify_ssh_exit(1), "command_failed");
     assert_eq!(alan::ssh::classify_ssh_exit(255), "connection_failed");
 }
+
+#[test]
+fn test_ssh_multibyte_remote_command_at_preview_boundary() {
+    let (conn, path) = fresh_db();
+
+    // 'é' is two bytes; 199 ASCII bytes put one straddling the 200-byte cut
+    let remote = format!("echo {}é{}", "x".repeat(194), "ü".repeat(20));
+    let command = format!("ssh myhost {}", remote);
+    alan::record(&conn, "s1", &command, 0, 500, false, "", &[0]).unwrap();
+
+    let stored: String = conn
+        .query_row(
+            "SELECT remote_command FROM ssh_observations WHERE host = 'myhost'",
+            [],
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert_eq!(stored, format!("echo {}", "x".repeat(194)));
+
+    let preview: String = conn
+        .query_row("SELECT command_preview FROM observations", [], |row| row.get(0))
+        .unwrap();
+    assert!(preview.len() <= 200);
+    assert!(command.starts_with(&preview));
+
+    let _ = std::fs::remove_file(path);
+}
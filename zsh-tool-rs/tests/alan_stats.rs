@@ -0,0 +1,205 @@
+use zsh_tool_exec::alan;
+
+fn fresh_db() -> (rusqlite::Connection, String) {
+    let path = format!("/tmp/zsh-test-stats-{}.db", uuid::Uuid::new_v4());
+    let conn = rusqlite::Connection::open(&path).unwrap();
+    alan::init_schema(&conn).unwrap();
+    (conn, path)
+}
+
+fn record(conn: &rusqlite::Connection, cmd: &str, exit_code: i32) {
+    alan::record(conn, "s1", cmd, exit_code, 100, false, "", &[exit_code]).unwrap();
+}
+
+#[test]
+fn test_list_templates_ordered_by_frequency() {
+    let (conn, path) = fresh_db();
+
+    for i in 0..3 {
+        record(&conn, &format!("git commit -m msg{}", i), 0);
+    }
+    record(&conn, "git commit -m broken", 1);
+    record(&conn, "ls -la /tmp", 0);
+    record(&conn, "ls -la /var", 0);
+    record(&conn, "cargo build", 0);
+
+    let list = alan::stats::list_templates(&conn, 50, 0);
+    assert_eq!(list.total, 3);
+    assert_eq!(list.templates.len(), 3);
+
+    let first = &list.templates[0];
+    assert_eq!(first.template, "git commit -m *");
+    assert_eq!(first.observations, 4);
+    assert!((first.success_rate - 0.75).abs() < 1e-9);
+
+    assert_eq!(list.templates[1].template, "ls -la *");
+    assert_eq!(list.templates[1].observations, 2);
+    assert_eq!(list.templates[2].observations, 1);
+
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn test_counts_weight_coalesced_observations_by_repeats() {
+    let (conn, path) = fresh_db();
+    let opts = alan::RecordOptions { dedup_window_secs: 60, ..Default::default() };
+    for exit_code in [0, 0, 0, 1] {
+        alan::record_with_options(&conn, "s1", "kubectl get pods", exit_code, 100, false, "", "", &[exit_code], &opts).unwrap();
+    }
+
+    let list = alan::stats::list_templates(&conn, 50, 0);
+    assert_eq!(list.templates[0].observations, 4);
+    assert!((list.templates[0].success_rate - 0.75).abs() < 1e-9);
+    assert_eq!(alan::stats::get_stats(&conn, "s1").total_observations, 4);
+    assert_eq!(alan::stats::query_pattern(&conn, "kubectl get pods").observations, Some(4));
+
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn test_list_templates_paging() {
+    let (conn, path) = fresh_db();
+
+    record(&conn, "make build", 0);
+    record(&conn, "make build", 0);
+    record(&conn, "make test", 0);
+
+    let page = alan::stats::list_templates(&conn, 1, 1);
+    assert_eq!(page.total, 2);
+    assert_eq!(page.templates.len(), 1);
+    assert_eq!(page.templates[0].template, "make test");
+
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn test_estimate_duration_from_history() {
+    let (conn, path) = fresh_db();
+
+    for ms in [1000, 1200, 1100, 900, 1000, 1050, 950, 1000, 1100, 5000] {
+        alan::record(&conn, "s1", "cargo build --release", 0, ms, false, "", &[0]).unwrap();
+    }
+
+    let est = alan::stats::estimate_duration(&conn, "cargo build --release");
+    assert!(est.known);
+    assert_eq!(est.confidence, "high");
+    assert_eq!(est.observations, Some(10));
+    let avg = est.avg_duration_ms.unwrap();
+    assert!((avg - 1430.0).abs() < 1e-6, "avg={}", avg);
+    assert_eq!(est.p90_duration_ms, Some(1200));
+
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn test_estimate_duration_unknown_pattern() {
+    let (conn, path) = fresh_db();
+    record(&conn, "ls", 0);
+
+    let est = alan::stats::estimate_duration(&conn, "terraform apply");
+    assert!(!est.known);
+    assert_eq!(est.confidence, "unknown");
+    assert!(est.avg_duration_ms.is_none());
+
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn test_query_pattern_returns_last_error() {
+    let (conn, path) = fresh_db();
+
+    alan::record_with_stderr(&conn, "s1", "make deploy", 2, 100, false, "", "error: old failure\n", &[2]).unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(5));
+    alan::record_with_stderr(&conn, "s1", "make deploy", 2, 100, false, "", "error: missing AWS_PROFILE\n", &[2]).unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(5));
+    record(&conn, "make deploy", 0);
+
+    let result = alan::stats::query_pattern(&conn, "make deploy");
+    assert_eq!(result.last_error.as_deref(), Some("error: missing AWS_PROFILE\n"));
+
+    let clean = alan::stats::query_pattern(&conn, "ls");
+    assert!(clean.last_error.is_none());
+
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn test_timeout_ranking_puts_hanging_patterns_first() {
+    let (conn, path) = fresh_db();
+    let run = |cmd: &str, duration: u64, timed_out: bool| {
+        let exit = if timed_out { 124 } else { 0 };
+        alan::record(&conn, "s1", cmd, exit, duration, timed_out, "", &[exit]).unwrap();
+    };
+
+    // npm install: 2 of 2 runs time out
+    run("npm install left-pad", 120_000, true);
+    run("npm install right-pad", 120_000, true);
+    // cargo build: 1 of 3
+    run("cargo build --release", 120_000, true);
+    run("cargo build --release", 40_000, false);
+    run("cargo build --release", 20_000, false);
+    // never times out
+    run("ls -la /tmp", 5, false);
+
+    let ranking = alan::stats::timeout_ranking(&conn, 10);
+    assert_eq!(ranking.len(), 2, "patterns without timeouts are omitted");
+    assert_eq!(ranking[0].template, "npm install *");
+    assert_eq!(ranking[0].timeouts, 2);
+    assert!((ranking[0].timeout_rate - 1.0).abs() < 1e-9);
+    assert_eq!(ranking[1].template, "cargo build --release");
+    assert_eq!(ranking[1].observations, 3);
+    assert!((ranking[1].timeout_rate - 1.0 / 3.0).abs() < 1e-9);
+    assert_eq!(ranking[1].avg_duration_ms, Some(60_000.0));
+    assert_eq!(ranking[1].max_duration_ms, Some(120_000));
+
+    assert_eq!(alan::stats::timeout_ranking(&conn, 1).len(), 1);
+
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn test_timeout_ranking_counts_coalesced_timeouts() {
+    let (conn, path) = fresh_db();
+    let opts = alan::RecordOptions { dedup_window_secs: 60, ..Default::default() };
+    for timed_out in [true, true, true, false] {
+        let exit_code = if timed_out { -1 } else { 0 };
+        alan::record_with_options(&conn, "s1", "ssh deadhost", exit_code, 100, timed_out, "", "", &[exit_code], &opts)
+            .unwrap();
+    }
+
+    let ranking = alan::stats::timeout_ranking(&conn, 10);
+    assert_eq!(ranking[0].observations, 4);
+    assert_eq!(ranking[0].timeouts, 3);
+    assert!((ranking[0].timeout_rate - 0.75).abs() < 1e-9);
+
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn test_export_dumps_rows_and_filters_by_since() {
+    let (conn, path) = fresh_db();
+
+    record(&conn, "cargo build", 0);
+    record(&conn, "ssh web1 uptime", 255);
+
+    let export = alan::stats::export(&conn, None).unwrap();
+    assert_eq!(export.observations.len(), 2);
+    assert!(export.observations.iter().any(|o| o.command_preview.as_deref() == Some("cargo build")));
+    assert!(!export.streaks.is_empty());
+    assert_eq!(export.ssh_observations.len(), 1);
+    assert_eq!(export.ssh_observations[0].host, "web1");
+
+    let json = serde_json::to_value(&export).unwrap();
+    assert!(json["observations"][0]["created_at"].is_string());
+
+    let past = alan::stats::export(&conn, Some("2000-01-01T00:00:00Z")).unwrap();
+    assert_eq!(past.observations.len(), 2);
+    let future = alan::stats::export(&conn, Some("2999-01-01T00:00:00+02:00")).unwrap();
+    assert!(future.observations.is_empty());
+    assert!(future.streaks.is_empty());
+    assert!(future.ssh_observations.is_empty());
+
+    assert!(alan::stats::export(&conn, Some("yesterday")).is_err());
+
+    let _ = std::fs::remove_file(path);
+}
@@ -15,6 +15,7 @@ fn test_default_config_values() {
     assert_eq!(cfg.alan_max_entries, 10000);
     assert_eq!(cfg.alan_recent_window_minutes, 10);
     assert_eq!(cfg.alan_streak_threshold, 3);
+    assert_eq!(cfg.alan_dedup_window_secs, 0);
     assert!(cfg.alan_manopt_enabled);
     assert_eq!(cfg.alan_manopt_timeout, 2.0);
     assert_eq!(cfg.alan_manopt_fail_trigger, 2);
@@ -34,6 +35,148 @@ fn test_config_from_yaml_file() {
     assert_eq!(cfg.alan_decay_half_life_hours, 24);
 }
 
+#[test]
+fn test_config_empty_output_text_from_yaml() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("config.yaml");
+    fs::write(&path, "empty_output_text: \"\"\n").unwrap();
+
+    let cfg = zsh_tool_exec::config::Config::load_from(&path);
+    assert_eq!(cfg.empty_output_text, "");
+    assert_eq!(zsh_tool_exec::config::Config::default().empty_output_text, "(no output)");
+}
+
+#[test]
+fn test_config_tool_descriptions_from_yaml() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("config.yaml");
+    fs::write(
+        &path,
+        "tool_descriptions:\n  zsh: \"Org shell: run builds here\"\n  zsh_poll: Keep polling\nyield_after: 3.0\n",
+    )
+    .unwrap();
+
+    let cfg = zsh_tool_exec::config::Config::load_from(&path);
+    assert_eq!(cfg.tool_descriptions.len(), 2);
+    assert_eq!(cfg.tool_descriptions["zsh"], "Org shell: run builds here");
+    assert_eq!(cfg.tool_descriptions["zsh_poll"], "Keep polling");
+    assert_eq!(cfg.yield_after_default, 3.0);
+}
+
+#[test]
+fn test_config_separate_stderr_from_yaml() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("config.yaml");
+    fs::write(&path, "separate_stderr:\n  cargo: true\n  grep: false\n").unwrap();
+
+    let cfg = zsh_tool_exec::config::Config::load_from(&path);
+    assert_eq!(cfg.separate_stderr.get("cargo"), Some(&true));
+    assert_eq!(cfg.separate_stderr.get("grep"), Some(&false));
+    assert!(!cfg.separate_stderr.contains_key("npm"));
+}
+
+#[test]
+fn test_config_circuit_trip_exit_codes() {
+    let defaults = zsh_tool_exec::config::Config::default();
+    assert_eq!(defaults.circuit_trip_exit_codes, vec![124, 137, 143]);
+
+    let dir = tempfile::tempdir().unwrap();
+    let inline = dir.path().join("inline.yaml");
+    fs::write(&inline, "circuit_trip_exit_codes: [137, nope, 255]\n").unwrap();
+    let cfg = zsh_tool_exec::config::Config::load_from(&inline);
+    assert_eq!(cfg.circuit_trip_exit_codes, vec![137, 255]);
+
+    let block = dir.path().join("block.yaml");
+    fs::write(&block, "circuit_trip_exit_codes:\n  - 124\n  - 9x\n").unwrap();
+    let cfg = zsh_tool_exec::config::Config::load_from(&block);
+    assert_eq!(cfg.circuit_trip_exit_codes, vec![124]);
+}
+
+#[test]
+fn test_config_retry_policy() {
+    let defaults = zsh_tool_exec::config::Config::default();
+    assert_eq!(defaults.retry_exit_codes, vec![255]);
+    assert!(defaults.retry_timeouts);
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("config.yaml");
+    fs::write(&path, "retry_exit_codes:\n  - 255\n  - 7\nretry_timeouts: false\n").unwrap();
+    let cfg = zsh_tool_exec::config::Config::load_from(&path);
+    assert_eq!(cfg.retry_exit_codes, vec![255, 7]);
+    assert!(!cfg.retry_timeouts);
+}
+
+#[test]
+fn test_config_half_open_required_successes() {
+    assert_eq!(zsh_tool_exec::config::Config::default().half_open_required_successes, 1);
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("config.yaml");
+    fs::write(&path, "half_open_required_successes: 3\n").unwrap();
+    let cfg = zsh_tool_exec::config::Config::load_from(&path);
+    assert_eq!(cfg.half_open_required_successes, 3);
+}
+
+#[test]
+fn test_config_output_markers_from_yaml() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("config.yaml");
+    fs::write(&path, "output_marker_start: \"<<<OUTPUT\"\noutput_marker_end: OUTPUT>>>\n").unwrap();
+    let cfg = zsh_tool_exec::config::Config::load_from(&path);
+    assert_eq!(cfg.output_marker_start, "<<<OUTPUT");
+    assert_eq!(cfg.output_marker_end, "OUTPUT>>>");
+}
+
+#[test]
+fn test_config_ssh_timeouts_from_yaml() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("config.yaml");
+    fs::write(&path, "ssh_timeouts:\n  far.example.com: 900\n  \"gpu-box\": 300\n  bad: soon\n").unwrap();
+
+    let cfg = zsh_tool_exec::config::Config::load_from(&path);
+    assert_eq!(cfg.ssh_timeouts.get("far.example.com"), Some(&900));
+    assert_eq!(cfg.ssh_timeouts.get("gpu-box"), Some(&300));
+    assert!(!cfg.ssh_timeouts.contains_key("bad"));
+}
+
+#[test]
+fn test_config_max_output_lines_from_yaml() {
+    use zsh_tool_exec::config::{Config, LineKeep};
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("config.yaml");
+    fs::write(&path, "max_output_lines: 400\noutput_lines_keep: tail\n").unwrap();
+
+    let cfg = Config::load_from(&path);
+    assert_eq!(cfg.max_output_lines, 400);
+    assert_eq!(cfg.output_lines_keep, LineKeep::Tail);
+    assert_eq!(Config::default().max_output_lines, 0);
+}
+
+#[test]
+fn test_config_alan_dedup_window_from_yaml() {
+    use zsh_tool_exec::config::{Config, ConfigSource};
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("config.yaml");
+    fs::write(&path, "alan_dedup_window_secs: 30\n").unwrap();
+    let cfg = Config::load_from(&path);
+    assert_eq!(cfg.alan_dedup_window_secs, 30);
+    assert_eq!(cfg.source("alan_dedup_window_secs"), ConfigSource::Yaml);
+
+    // The old key still works
+    fs::write(&path, "alan_dedup_window: 45\n").unwrap();
+    assert_eq!(Config::load_from(&path).alan_dedup_window_secs, 45);
+}
+
+#[test]
+fn test_config_session_idle_timeout_from_yaml() {
+    use zsh_tool_exec::config::Config;
+    assert_eq!(Config::default().session_idle_timeout_secs, 0);
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("config.yaml");
+    fs::write(&path, "session_idle_timeout: 900\n").unwrap();
+
+    assert_eq!(Config::load_from(&path).session_idle_timeout_secs, 900);
+}
+
 #[test]
 fn test_config_env_overrides() {
     // TODO(post-phase3): env var tests race with parallel tests that call load_from/from_env.
@@ -42,9 +185,26 @@ fn test_config_env_overrides() {
     std::env::set_var("NEVERHANG_TIMEOUT_DEFAULT", "60");
     let cfg = zsh_tool_exec::config::Config::from_env();
     assert_eq!(cfg.neverhang_timeout_default, 60);
+    assert_eq!(cfg.source("neverhang_timeout_default").as_str(), "env");
     std::env::remove_var("NEVERHANG_TIMEOUT_DEFAULT");
 }
 
+#[test]
+fn test_config_source_tracks_yaml_and_defaults() {
+    use zsh_tool_exec::config::{Config, ConfigSource};
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("config.yaml");
+    fs::write(&path, "yield_after: 5.0\ntab_width: wide\ndeny_commands:\n  - sudo *\n").unwrap();
+
+    let cfg = Config::load_from(&path);
+    assert_eq!(cfg.source("yield_after_default"), ConfigSource::Yaml);
+    assert_eq!(cfg.source("deny_commands"), ConfigSource::Yaml);
+    // An unparseable value leaves the default in place
+    assert_eq!(cfg.source("tab_width"), ConfigSource::Default);
+    assert_eq!(cfg.source("alan_decay_half_life_hours"), ConfigSource::Default);
+    assert!(cfg.entries().iter().any(|(field, _)| *field == "yield_after_default"));
+}
+
 #[test]
 fn test_config_missing_yaml_uses_defaults() {
     let path = std::path::Path::new("/tmp/nonexistent-zsh-tool-config.yaml");
@@ -54,6 +214,165 @@ fn test_config_missing_yaml_uses_defaults() {
 
 #[test]
 fn test_pipestatus_marker_matches_python() {
-    let cfg = zsh_tool_exec::config::Config::default();
-    assert_eq!(cfg.pipestatus_marker, "___ZSH_PIPESTATUS_MARKER_f9a8b7c6___");
+    assert_eq!(zsh_tool_exec::executor::PIPESTATUS_MARKER, "___ZSH_PIPESTATUS_MARKER_f9a8b7c6___");
+}
+
+#[test]
+fn test_config_command_lists_from_yaml() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("config.yaml");
+    fs::write(
+        &path,
+        "deny_commands:\n  - \"sudo *\"\n  - '*rm -rf /*'\nallow_commands: [git *, make*]\n",
+    )
+    .unwrap();
+
+    let cfg = zsh_tool_exec::config::Config::load_from(&path);
+    assert_eq!(cfg.deny_commands, vec!["sudo *", "*rm -rf /*"]);
+    assert_eq!(
+        cfg.allow_commands,
+        Some(vec!["git *".to_string(), "make*".to_string()])
+    );
+}
+
+#[test]
+fn test_config_timeout_exempt_patterns_from_yaml() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("config.yaml");
+    // Block lists keep regexes with commas in one piece
+    fs::write(&path, "timeout_exempt_patterns:\n  - '^cargo build'\n  - '^make -j[0-9]{1,2}'\ntimeout_exempt_max: 7200\n").unwrap();
+
+    let cfg = zsh_tool_exec::config::Config::load_from(&path);
+    assert_eq!(cfg.timeout_exempt_patterns, vec!["^cargo build", "^make -j[0-9]{1,2}"]);
+    assert_eq!(cfg.timeout_exempt_max, 7200);
+}
+
+#[test]
+fn test_config_truncate_mode_from_yaml() {
+    use zsh_tool_exec::config::{Config, TruncateMode};
+    assert_eq!(Config::default().truncate_mode, TruncateMode::Head);
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("config.yaml");
+    fs::write(&path, "truncate_mode: middle\n").unwrap();
+
+    assert_eq!(Config::load_from(&path).truncate_mode, TruncateMode::Middle);
+}
+
+#[test]
+fn test_config_push_notifications_from_yaml() {
+    use zsh_tool_exec::config::Config;
+    assert!(!Config::default().push_notifications);
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("config.yaml");
+    fs::write(&path, "push_notifications: true\n").unwrap();
+
+    assert!(Config::load_from(&path).push_notifications);
+}
+
+#[test]
+fn test_config_home_root_guard_from_yaml() {
+    use zsh_tool_exec::config::Config;
+    assert!(!Config::default().home_root_guard);
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("config.yaml");
+    fs::write(&path, "home_root_guard: true\n").unwrap();
+
+    assert!(Config::load_from(&path).home_root_guard);
+}
+
+#[test]
+fn test_config_pty_fallback_from_yaml() {
+    use zsh_tool_exec::config::Config;
+    assert!(!Config::default().pty_fallback);
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("config.yaml");
+    fs::write(&path, "pty_fallback: true\n").unwrap();
+
+    assert!(Config::load_from(&path).pty_fallback);
+}
+
+#[test]
+fn test_config_pty_drain_ms_from_yaml() {
+    use zsh_tool_exec::config::Config;
+    assert_eq!(Config::default().pty_drain_ms, zsh_tool_exec::executor::PTY_DRAIN_GRACE_MS);
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("config.yaml");
+    fs::write(&path, "pty_drain_ms: 750\n").unwrap();
+
+    assert_eq!(Config::load_from(&path).pty_drain_ms, 750);
+}
+
+#[test]
+fn test_config_render_terminal_from_yaml() {
+    use zsh_tool_exec::config::Config;
+    assert!(!Config::default().render_terminal);
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("config.yaml");
+    fs::write(&path, "render_terminal: true\n").unwrap();
+
+    assert!(Config::load_from(&path).render_terminal);
+}
+
+#[test]
+fn test_config_shell_from_yaml() {
+    use zsh_tool_exec::config::Config;
+    use zsh_tool_exec::executor::ShellKind;
+    assert_eq!(Config::default().shell, ShellKind::Zsh);
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("config.yaml");
+    fs::write(&path, "shell: bash\n").unwrap();
+    assert_eq!(Config::load_from(&path).shell, ShellKind::Bash);
+
+    // Unknown shells keep the default
+    fs::write(&path, "shell: fish\n").unwrap();
+    assert_eq!(Config::load_from(&path).shell, ShellKind::Zsh);
+
+    fs::write(&path, "shell_path: /usr/local/bin/bash\n").unwrap();
+    assert_eq!(Config::load_from(&path).shell_path.as_deref(), Some("/usr/local/bin/bash"));
+    // The old key still works
+    fs::write(&path, "zsh_path: /usr/local/bin/zsh\n").unwrap();
+    assert_eq!(Config::load_from(&path).shell_path.as_deref(), Some("/usr/local/bin/zsh"));
+
+    // Without an explicit `shell`, the language follows the binary
+    fs::write(&path, "zsh_path: /usr/bin/bash\n").unwrap();
+    assert_eq!(Config::load_from(&path).shell_kind(), ShellKind::Bash);
+    fs::write(&path, "shell: sh\nshell_path: /usr/bin/bash\n").unwrap();
+    assert_eq!(Config::load_from(&path).shell_kind(), ShellKind::Sh);
+    assert_eq!(Config::default().shell_kind(), ShellKind::Zsh);
+}
+
+#[test]
+fn test_config_silent_commands_from_yaml() {
+    use zsh_tool_exec::config::Config;
+    assert!(Config::default().silent_commands.iter().any(|c| c == "touch"));
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("config.yaml");
+    fs::write(&path, "silent_commands:\n  - make\n  - terraform\n").unwrap();
+
+    assert_eq!(Config::load_from(&path).silent_commands, vec!["make", "terraform"]);
+}
+
+#[test]
+fn test_config_meta_dir_from_yaml() {
+    use zsh_tool_exec::config::Config;
+    assert_eq!(Config::default().meta_dir, std::env::temp_dir().to_string_lossy());
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("config.yaml");
+    fs::write(&path, "meta_dir: /var/tmp/zsh-tool\n").unwrap();
+
+    assert_eq!(Config::load_from(&path).meta_dir, "/var/tmp/zsh-tool");
+}
+
+#[test]
+fn test_config_verbose_flags_from_yaml() {
+    use zsh_tool_exec::config::Config;
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("config.yaml");
+    fs::write(&path, "verbose_flags:\n  make: \"V=1\"\n  curl: --trace-ascii -\n").unwrap();
+
+    let cfg = Config::load_from(&path);
+    assert_eq!(cfg.verbose_flags["make"], "V=1");
+    assert_eq!(cfg.verbose_flags["curl"], "--trace-ascii -");
+    // Entries not mentioned keep their defaults
+    assert_eq!(cfg.verbose_flags["ssh"], "-vvv");
 }
@@ -0,0 +1,36 @@
+use std::process::Command;
+
+fn exec_path() -> String {
+    env!("CARGO_BIN_EXE_zsh-tool-exec").to_string()
+}
+
+#[test]
+fn test_health_ok() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = dir.path().join("alan.db");
+
+    let output = Command::new(exec_path())
+        .arg("health")
+        .env("ALAN_DB_PATH", &db)
+        .output()
+        .expect("failed to run");
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(0), "stdout: {}", stdout);
+    assert!(stdout.starts_with("healthy"));
+    assert_eq!(stdout.lines().count(), 1);
+}
+
+#[test]
+fn test_health_unwritable_db_fails() {
+    let output = Command::new(exec_path())
+        .arg("health")
+        .env("ALAN_DB_PATH", "/proc/zsh-tool-nonexistent/alan.db")
+        .output()
+        .expect("failed to run");
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(1), "stdout: {}", stdout);
+    assert!(stdout.starts_with("unhealthy"));
+    assert!(stdout.contains("alan db"));
+}
@@ -67,12 +67,22 @@ fn spawn_server() -> (
     std::process::ChildStdin,
     BufReader<std::process::ChildStdout>,
     std::process::Child,
+) {
+    spawn_server_with_env(&[])
+}
+
+/// Spawn the server subprocess with extra environment variables.
+fn spawn_server_with_env(envs: &[(&str, &str)]) -> (
+    std::process::ChildStdin,
+    BufReader<std::process::ChildStdout>,
+    std::process::Child,
 ) {
     // Build in case it hasn't been compiled
     let binary = env!("CARGO_BIN_EXE_zsh-tool-exec");
 
     let mut child = Command::new(binary)
         .arg("serve")
+        .envs(envs.iter().copied())
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
         .stderr(Stdio::null())
@@ -85,6 +95,77 @@ fn spawn_server() -> (
     (stdin, reader, child)
 }
 
+#[test]
+fn test_exec_path_override_is_used() {
+    let dir = tempfile::tempdir().unwrap();
+    let marker = dir.path().join("used");
+    let wrapper = dir.path().join("exec-wrapper");
+    std::fs::write(
+        &wrapper,
+        format!(
+            "#!/bin/sh\ntouch '{}'\nexec '{}' \"$@\"\n",
+            marker.display(),
+            env!("CARGO_BIN_EXE_zsh-tool-exec")
+        ),
+    )
+    .unwrap();
+    std::fs::set_permissions(&wrapper, std::os::unix::fs::PermissionsExt::from_mode(0o755)).unwrap();
+
+    let (mut stdin, mut reader, mut child) =
+        spawn_server_with_env(&[("ZSH_TOOL_EXEC_PATH", wrapper.to_str().unwrap())]);
+    send_request(&mut stdin, "initialize", 1, None);
+    let _ = read_response(&mut reader);
+    send_notification(&mut stdin, "notifications/initialized");
+    send_request(
+        &mut stdin,
+        "tools/call",
+        2,
+        Some(serde_json::json!({"name": "zsh", "arguments": {"command": "echo hi", "timeout": 10}})),
+    );
+    let _ = read_response(&mut reader);
+    assert!(marker.exists(), "commands should run through ZSH_TOOL_EXEC_PATH");
+
+    drop(stdin);
+    let _ = child.wait();
+}
+
+#[test]
+fn test_bad_exec_path_fails_fast() {
+    let output = Command::new(env!("CARGO_BIN_EXE_zsh-tool-exec"))
+        .arg("serve")
+        .env("ZSH_TOOL_EXEC_PATH", "/nonexistent/zsh-tool-exec")
+        .stdin(Stdio::null())
+        .output()
+        .expect("Failed to spawn server");
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Executor /nonexistent/zsh-tool-exec (ZSH_TOOL_EXEC_PATH) is unusable"),
+        "stderr: {}",
+        stderr
+    );
+}
+
+#[test]
+fn test_missing_shell_path_fails_fast() {
+    // ZSH_TOOL_ZSH_PATH is the old name of ZSH_TOOL_SHELL_PATH
+    for var in ["ZSH_TOOL_SHELL_PATH", "ZSH_TOOL_ZSH_PATH"] {
+        let output = Command::new(env!("CARGO_BIN_EXE_zsh-tool-exec"))
+            .arg("serve")
+            .env(var, "/nonexistent/zsh")
+            .stdin(Stdio::null())
+            .output()
+            .expect("Failed to spawn server");
+        assert_eq!(output.status.code(), Some(1), "{}", var);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(
+            stderr.contains("Shell /nonexistent/zsh (ZSH_TOOL_SHELL_PATH) is unusable"),
+            "stderr: {}",
+            stderr
+        );
+    }
+}
+
 #[test]
 fn test_initialize() {
     let (mut stdin, mut reader, mut child) = spawn_server();
@@ -108,6 +189,9 @@ fn test_initialize() {
     assert_eq!(result["protocolVersion"], "2024-11-05");
     assert_eq!(result["serverInfo"]["name"], "zsh-tool");
     assert!(result["capabilities"]["tools"].is_object());
+    assert!(result["capabilities"]["resources"].is_object());
+    assert!(result["capabilities"]["prompts"].is_object());
+    assert!(result["capabilities"]["logging"].is_object());
 
     drop(stdin);
     let _ = child.wait();
@@ -129,19 +213,41 @@ fn test_tools_list() {
     let resp = read_response(&mut reader);
 
     let tools = resp["result"]["tools"].as_array().expect("tools array");
-    assert_eq!(tools.len(), 10, "Expected 10 tools");
+    assert_eq!(tools.len(), 32, "Expected 32 tools");
 
     let names: Vec<&str> = tools.iter().map(|t| t["name"].as_str().unwrap()).collect();
     assert!(names.contains(&"zsh"));
     assert!(names.contains(&"zsh_poll"));
     assert!(names.contains(&"zsh_send"));
     assert!(names.contains(&"zsh_kill"));
+    assert!(names.contains(&"zsh_output"));
+    assert!(names.contains(&"zsh_estimate"));
+    assert!(names.contains(&"zsh_debug"));
+    assert!(names.contains(&"zsh_sequence"));
+    assert!(names.contains(&"zsh_check"));
+    assert!(names.contains(&"zsh_env_snapshot"));
+    assert!(names.contains(&"zsh_env_diff"));
     assert!(names.contains(&"zsh_tasks"));
     assert!(names.contains(&"zsh_health"));
     assert!(names.contains(&"zsh_alan_stats"));
     assert!(names.contains(&"zsh_alan_query"));
+    assert!(names.contains(&"zsh_alan_templates"));
+    assert!(names.contains(&"zsh_alan_next"));
+    assert!(names.contains(&"zsh_alan_timeouts"));
+    assert!(names.contains(&"zsh_alan_check"));
+    assert!(names.contains(&"zsh_alan_export"));
+    assert!(names.contains(&"zsh_alan_import"));
+    assert!(names.contains(&"zsh_alan_pin"));
+    assert!(names.contains(&"zsh_session_summary"));
+    assert!(names.contains(&"zsh_manopt_warm"));
+    assert!(names.contains(&"zsh_cwd"));
+    assert!(names.contains(&"zsh_env"));
+    assert!(names.contains(&"zsh_retry_verbose"));
+    assert!(names.contains(&"zsh_config"));
+    assert!(names.contains(&"zsh_benchmark"));
     assert!(names.contains(&"zsh_neverhang_status"));
     assert!(names.contains(&"zsh_neverhang_reset"));
+    assert!(names.contains(&"zsh_circuit_trip"));
 
     drop(stdin);
     let _ = child.wait();
@@ -213,6 +319,555 @@ fn test_zsh_exit_code() {
     let _ = child.wait();
 }
 
+#[test]
+fn test_zsh_pipeline_segments() {
+    let (mut stdin, mut reader, mut child) = spawn_server();
+
+    send_request(&mut stdin, "initialize", 1, None);
+    let _ = read_response(&mut reader);
+    send_notification(&mut stdin, "notifications/initialized");
+
+    send_request(
+        &mut stdin,
+        "tools/call",
+        2,
+        Some(serde_json::json!({
+            "name": "zsh",
+            "arguments": {
+                "command": "false | true",
+                "timeout": 10,
+                "yield_after": 5.0
+            }
+        })),
+    );
+
+    let resp = read_response(&mut reader);
+    let text = resp["result"]["content"][0]["text"].as_str().unwrap();
+    assert!(text.contains("pipestatus="), "Should show pipestatus, got: {}", text);
+    assert!(text.contains("false → \x1b[31m1"), "false segment should map to 1, got: {}", text);
+    assert!(text.contains("true → \x1b[32m0"), "true segment should map to 0, got: {}", text);
+
+    drop(stdin);
+    let _ = child.wait();
+}
+
+#[test]
+fn test_test_clock_skips_yield_sleep() {
+    let (mut stdin, mut reader, mut child) = spawn_server_with_env(&[("ZSH_TOOL_TEST_CLOCK", "0")]);
+
+    send_request(&mut stdin, "initialize", 1, None);
+    let _ = read_response(&mut reader);
+    send_notification(&mut stdin, "notifications/initialized");
+
+    let start = std::time::Instant::now();
+    send_request(
+        &mut stdin,
+        "tools/call",
+        2,
+        Some(serde_json::json!({
+            "name": "zsh",
+            "arguments": {
+                "command": "sleep 3",
+                "timeout": 10,
+                "yield_after": 30.0
+            }
+        })),
+    );
+
+    let resp = read_response(&mut reader);
+    let elapsed = start.elapsed();
+    assert!(
+        elapsed < Duration::from_secs(3),
+        "yield should be skipped under ZSH_TOOL_TEST_CLOCK=0, took {:?}",
+        elapsed
+    );
+    assert!(resp["result"]["content"][0]["text"].is_string());
+
+    drop(stdin);
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn test_spawn_burst_is_rate_limited() {
+    let (mut stdin, mut reader, mut child) = spawn_server_with_env(&[
+        ("ZSH_TOOL_TEST_CLOCK", "0"),
+        ("ZSH_TOOL_MAX_SPAWNS_PER_SEC", "2"),
+    ]);
+
+    send_request(&mut stdin, "initialize", 1, None);
+    let _ = read_response(&mut reader);
+    send_notification(&mut stdin, "notifications/initialized");
+
+    let mut limited = 0;
+    for id in 2..10 {
+        send_request(
+            &mut stdin,
+            "tools/call",
+            id,
+            Some(serde_json::json!({
+                "name": "zsh",
+                "arguments": {"command": "true", "timeout": 5}
+            })),
+        );
+        let resp = read_response(&mut reader);
+        let text = resp["result"]["content"][0]["text"].as_str().unwrap();
+        if text.contains("reason=rate_limited") {
+            assert!(text.contains("Spawn rate limit exceeded"), "got: {}", text);
+            limited += 1;
+        }
+    }
+    assert!(limited >= 4, "expected most of the burst to be limited, got {}", limited);
+
+    drop(stdin);
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn test_kill_preserves_output_for_zsh_output() {
+    let (mut stdin, mut reader, mut child) = spawn_server();
+
+    send_request(&mut stdin, "initialize", 1, None);
+    let _ = read_response(&mut reader);
+    send_notification(&mut stdin, "notifications/initialized");
+
+    send_request(
+        &mut stdin,
+        "tools/call",
+        2,
+        Some(serde_json::json!({
+            "name": "zsh",
+            "arguments": {
+                "command": "for i in 1 2 3; do echo follow-$i; done; tail -f /dev/null",
+                "timeout": 30,
+                "yield_after": 1.0
+            }
+        })),
+    );
+    let resp = read_response(&mut reader);
+    let text = resp["result"]["content"][0]["text"].as_str().unwrap();
+    let task_id = text
+        .split("task=")
+        .nth(1)
+        .and_then(|s| s.split_whitespace().next())
+        .expect("task id in running result")
+        .to_string();
+
+    send_request(
+        &mut stdin,
+        "tools/call",
+        3,
+        Some(serde_json::json!({"name": "zsh_kill", "arguments": {"task_id": task_id}})),
+    );
+    let _ = read_response(&mut reader);
+
+    send_request(
+        &mut stdin,
+        "tools/call",
+        4,
+        Some(serde_json::json!({"name": "zsh_output", "arguments": {"task_id": task_id}})),
+    );
+    let resp = read_response(&mut reader);
+    let text = resp["result"]["content"][0]["text"].as_str().unwrap();
+    for i in 1..=3 {
+        assert!(text.contains(&format!("{}: follow-{}", i, i)), "missing line {}, got: {}", i, text);
+    }
+    assert!(text.contains("KILLED"), "got: {}", text);
+
+    drop(stdin);
+    let _ = child.wait();
+}
+
+#[test]
+fn test_tool_description_override_from_config() {
+    let home = tempfile::tempdir().unwrap();
+    let config_dir = home.path().join(".config/zsh-tool");
+    std::fs::create_dir_all(&config_dir).unwrap();
+    std::fs::write(
+        config_dir.join("config.yaml"),
+        "tool_descriptions:\n  zsh: \"Run commands on the build host. Never deploy from here.\"\n",
+    )
+    .unwrap();
+    let (mut stdin, mut reader, mut child) =
+        spawn_server_with_env(&[("HOME", home.path().to_str().unwrap())]);
+
+    send_request(&mut stdin, "initialize", 1, None);
+    let _ = read_response(&mut reader);
+    send_notification(&mut stdin, "notifications/initialized");
+
+    send_request(&mut stdin, "tools/list", 2, None);
+    let resp = read_response(&mut reader);
+    let tools = resp["result"]["tools"].as_array().unwrap();
+    let desc = |name: &str| {
+        tools
+            .iter()
+            .find(|t| t["name"] == name)
+            .and_then(|t| t["description"].as_str())
+            .unwrap()
+            .to_string()
+    };
+    assert_eq!(desc("zsh"), "Run commands on the build host. Never deploy from here.");
+    assert!(desc("zsh_poll").contains("output"), "unoverridden tools keep defaults");
+
+    drop(stdin);
+    let _ = child.wait();
+}
+
+#[test]
+fn test_enabled_tools_hides_and_rejects_zsh() {
+    let (mut stdin, mut reader, mut child) = spawn_server_with_env(&[(
+        "ZSH_TOOL_ENABLED_TOOLS",
+        "zsh_alan_stats,zsh_alan_query,zsh_estimate",
+    )]);
+
+    send_request(&mut stdin, "initialize", 1, None);
+    let _ = read_response(&mut reader);
+    send_notification(&mut stdin, "notifications/initialized");
+
+    send_request(&mut stdin, "tools/list", 2, None);
+    let resp = read_response(&mut reader);
+    let names: Vec<&str> = resp["result"]["tools"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|t| t["name"].as_str().unwrap())
+        .collect();
+    assert_eq!(names, vec!["zsh_alan_stats", "zsh_alan_query", "zsh_estimate"]);
+
+    send_request(
+        &mut stdin,
+        "tools/call",
+        3,
+        Some(serde_json::json!({"name": "zsh", "arguments": {"command": "echo hi"}})),
+    );
+    let resp = read_response(&mut reader);
+    assert_eq!(resp["result"]["isError"], true);
+    let text = resp["result"]["content"][0]["text"].as_str().unwrap();
+    assert!(text.contains("disabled"), "got: {}", text);
+
+    drop(stdin);
+    let _ = child.wait();
+}
+
+#[test]
+fn test_configured_command_captures_stderr_separately() {
+    let home = tempfile::tempdir().unwrap();
+    let config_dir = home.path().join(".config/zsh-tool");
+    std::fs::create_dir_all(&config_dir).unwrap();
+    std::fs::write(config_dir.join("config.yaml"), "separate_stderr:\n  sh: true\n").unwrap();
+    let (mut stdin, mut reader, mut child) =
+        spawn_server_with_env(&[("HOME", home.path().to_str().unwrap())]);
+
+    send_request(&mut stdin, "initialize", 1, None);
+    let _ = read_response(&mut reader);
+    send_notification(&mut stdin, "notifications/initialized");
+
+    send_request(
+        &mut stdin,
+        "tools/call",
+        2,
+        Some(serde_json::json!({
+            "name": "zsh",
+            "arguments": {"command": "sh -c 'echo to-out; echo to-err >&2'", "timeout": 10}
+        })),
+    );
+    let resp = read_response(&mut reader);
+    let text = resp["result"]["content"][0]["text"].as_str().unwrap();
+    let err_at = text.find("stderr:").unwrap_or_else(|| panic!("no stderr section: {}", text));
+    assert!(text[..err_at].contains("to-out"), "got: {}", text);
+    assert!(!text[..err_at].contains("\nto-err"), "stderr leaked into stdout: {}", text);
+    assert!(text[err_at..].contains("to-err"), "got: {}", text);
+
+    drop(stdin);
+    let _ = child.wait();
+}
+
+#[test]
+fn test_extract_json_returns_structured_content() {
+    let (mut stdin, mut reader, mut child) = spawn_server();
+
+    send_request(&mut stdin, "initialize", 1, None);
+    let _ = read_response(&mut reader);
+    send_notification(&mut stdin, "notifications/initialized");
+
+    send_request(
+        &mut stdin,
+        "tools/call",
+        2,
+        Some(serde_json::json!({
+            "name": "zsh",
+            "arguments": {
+                "command": "printf '{\"items\": [1, 2], \"ok\": true}\\n'",
+                "extract_json": true,
+                "timeout": 10
+            }
+        })),
+    );
+    let resp = read_response(&mut reader);
+    let result = &resp["result"];
+    assert_eq!(result["structuredContent"]["items"], serde_json::json!([1, 2]), "got: {}", resp);
+    assert_eq!(result["structuredContent"]["ok"], true);
+
+    send_request(
+        &mut stdin,
+        "tools/call",
+        3,
+        Some(serde_json::json!({
+            "name": "zsh",
+            "arguments": {"command": "echo not-json", "extract_json": true, "timeout": 10}
+        })),
+    );
+    let resp = read_response(&mut reader);
+    assert!(resp["result"].get("structuredContent").is_none(), "got: {}", resp);
+    let text = resp["result"]["content"][0]["text"].as_str().unwrap();
+    assert!(text.contains("not-json"), "got: {}", text);
+    assert!(text.contains("not valid JSON"), "got: {}", text);
+
+    drop(stdin);
+    let _ = child.wait();
+}
+
+#[test]
+fn test_pty_fallback_reruns_tty_error_in_pty() {
+    let (mut stdin, mut reader, mut child) = spawn_server_with_env(&[("ZSH_TOOL_PTY_FALLBACK", "1")]);
+
+    send_request(&mut stdin, "initialize", 1, None);
+    let _ = read_response(&mut reader);
+    send_notification(&mut stdin, "notifications/initialized");
+
+    send_request(
+        &mut stdin,
+        "tools/call",
+        2,
+        Some(serde_json::json!({
+            "name": "zsh",
+            "arguments": {
+                "command": "if [ -t 0 ]; then echo got-terminal; else echo 'stdin: not a tty' >&2; exit 1; fi",
+                "timeout": 10
+            }
+        })),
+    );
+    let resp = read_response(&mut reader);
+    let text = resp["result"]["content"][0]["text"].as_str().unwrap();
+    assert!(text.contains("got-terminal"), "PTY retry should succeed: {}", text);
+    assert!(text.contains("re-ran in PTY mode"), "got: {}", text);
+
+    drop(stdin);
+    let _ = child.wait();
+}
+
+#[test]
+fn test_render_terminal_returns_screen_for_cursor_movement() {
+    let (mut stdin, mut reader, mut child) = spawn_server();
+
+    send_request(&mut stdin, "initialize", 1, None);
+    let _ = read_response(&mut reader);
+    send_notification(&mut stdin, "notifications/initialized");
+
+    // Draw "world" on row 2, jump back to row 1 and overwrite "hello" with "J"
+    let command = r"printf '\033[2J\033[H\033[2;1Hworld\033[1;1Hhello\033[1;1HJ'";
+    for (id, render) in [(2, false), (3, true)] {
+        send_request(
+            &mut stdin,
+            "tools/call",
+            id,
+            Some(serde_json::json!({
+                "name": "zsh",
+                "arguments": {"command": command, "pty": true, "render_terminal": render, "timeout": 10}
+            })),
+        );
+        let resp = read_response(&mut reader);
+        let text = resp["result"]["content"][0]["text"].as_str().unwrap();
+        if render {
+            assert!(text.contains("Jello\nworld"), "expected the rendered screen: {:?}", text);
+            assert!(!text.contains("\x1b[2;1H"), "escapes should be interpreted: {:?}", text);
+        } else {
+            assert!(text.contains("\x1b[2;1Hworld"), "raw stream by default: {:?}", text);
+        }
+    }
+
+    drop(stdin);
+    let _ = child.wait();
+}
+
+#[test]
+fn test_session_summary_after_commands() {
+    let db = tempfile::tempdir().unwrap();
+    let db_path = db.path().join("alan.db");
+    let (mut stdin, mut reader, mut child) =
+        spawn_server_with_env(&[("ALAN_DB_PATH", db_path.to_str().unwrap())]);
+
+    send_request(&mut stdin, "initialize", 1, None);
+    let _ = read_response(&mut reader);
+    send_notification(&mut stdin, "notifications/initialized");
+
+    for (id, command) in [(2, "echo one"), (3, "echo two"), (4, "exit 7")] {
+        send_request(
+            &mut stdin,
+            "tools/call",
+            id,
+            Some(serde_json::json!({"name": "zsh", "arguments": {"command": command, "timeout": 10}})),
+        );
+        let _ = read_response(&mut reader);
+    }
+
+    send_request(
+        &mut stdin,
+        "tools/call",
+        5,
+        Some(serde_json::json!({"name": "zsh_session_summary", "arguments": {}})),
+    );
+    let resp = read_response(&mut reader);
+    let text = resp["result"]["content"][0]["text"].as_str().unwrap();
+    let v: Value = serde_json::from_str(text).unwrap();
+    assert_eq!(v["session"]["total_commands"], 3, "{}", text);
+    assert_eq!(v["session"]["failures"], 1, "{}", text);
+    assert_eq!(v["notable_failures"][0]["command"], "exit 7");
+    assert_eq!(v["notable_failures"][0]["last_exit_code"], 7);
+    assert!(v["running_tasks"].as_array().unwrap().is_empty());
+
+    drop(stdin);
+    let _ = child.wait();
+}
+
+#[test]
+fn test_alan_stats_resource_tracks_commands() {
+    let db = tempfile::tempdir().unwrap();
+    let db_path = db.path().join("alan.db");
+    let (mut stdin, mut reader, mut child) =
+        spawn_server_with_env(&[("ALAN_DB_PATH", db_path.to_str().unwrap())]);
+
+    send_request(&mut stdin, "initialize", 1, None);
+    let _ = read_response(&mut reader);
+    send_notification(&mut stdin, "notifications/initialized");
+
+    send_request(
+        &mut stdin,
+        "tools/call",
+        2,
+        Some(serde_json::json!({"name": "zsh", "arguments": {"command": "echo one", "timeout": 10}})),
+    );
+    let _ = read_response(&mut reader);
+
+    send_request(&mut stdin, "resources/read", 3, Some(serde_json::json!({"uri": "zsh://alan/stats"})));
+    let resp = read_response(&mut reader);
+    let text = resp["result"]["contents"][0]["text"].as_str().unwrap();
+    let stats: Value = serde_json::from_str(text).unwrap();
+    assert_eq!(stats["total_observations"], 1, "{}", text);
+
+    drop(stdin);
+    let _ = child.wait();
+}
+
+#[test]
+fn test_retries_rerun_transient_failures_only() {
+    let db = tempfile::tempdir().unwrap();
+    let db_path = db.path().join("alan.db");
+    let (mut stdin, mut reader, mut child) = spawn_server_with_env(&[
+        ("ALAN_DB_PATH", db_path.to_str().unwrap()),
+        ("ZSH_TOOL_SHELL", "bash"),
+        ("ZSH_TOOL_SHELL_PATH", "/bin/bash"),
+        ("ZSH_TOOL_CIRCUIT_TRIP_EXIT_CODES", "255"),
+    ]);
+
+    send_request(&mut stdin, "initialize", 1, None);
+    let _ = read_response(&mut reader);
+    send_notification(&mut stdin, "notifications/initialized");
+
+    // Each run appends a line, so the file counts the runs
+    let runs = db.path().join("runs");
+    let run = |stdin: &mut std::process::ChildStdin, reader: &mut BufReader<std::process::ChildStdout>, id: u64, code: i32| {
+        let command = format!("echo run >> {}; (exit {})", runs.display(), code);
+        send_request(
+            stdin,
+            "tools/call",
+            id,
+            Some(serde_json::json!({"name": "zsh", "arguments": {"command": command, "retries": 2, "yield_after": 2}})),
+        );
+        read_response(reader)["result"]["content"][0]["text"].as_str().unwrap().to_string()
+    };
+    let count_runs = || std::fs::read_to_string(&runs).map(|s| s.lines().count()).unwrap_or(0);
+
+    let text = run(&mut stdin, &mut reader, 2, 127);
+    assert_eq!(count_runs(), 1, "127 is not retried: {}", text);
+    assert!(text.contains("Not retried: command not found (exit 127) is a permanent failure"), "{}", text);
+
+    std::fs::remove_file(&runs).unwrap();
+    let text = run(&mut stdin, &mut reader, 3, 255);
+    assert_eq!(count_runs(), 3, "255 is retried twice: {}", text);
+    assert!(text.contains("Retried 2 time(s) after transient failures"), "{}", text);
+    assert!(text.contains("Still failing after 2 retries"), "{}", text);
+
+    // Three attempts, one call: the breaker sees a single failure
+    send_request(&mut stdin, "tools/call", 4, Some(serde_json::json!({"name": "zsh_neverhang_status", "arguments": {}})));
+    let resp = read_response(&mut reader);
+    let status: Value = serde_json::from_str(resp["result"]["content"][0]["text"].as_str().unwrap()).unwrap();
+    assert_eq!(status["recent_failures"], 1, "{}", status);
+    assert_eq!(status["state"], "closed");
+
+    drop(stdin);
+    let _ = child.wait();
+}
+
+#[test]
+fn test_request_id_follows_command_to_task_and_alan() {
+    let db = tempfile::tempdir().unwrap();
+    let db_path = db.path().join("alan.db");
+    let (mut stdin, mut reader, mut child) =
+        spawn_server_with_env(&[("ALAN_DB_PATH", db_path.to_str().unwrap())]);
+
+    send_request(&mut stdin, "initialize", 1, None);
+    let _ = read_response(&mut reader);
+    send_notification(&mut stdin, "notifications/initialized");
+
+    send_request(
+        &mut stdin,
+        "tools/call",
+        42,
+        Some(serde_json::json!({"name": "zsh", "arguments": {"command": "echo traced", "timeout": 10}})),
+    );
+    let resp = read_response(&mut reader);
+    let task_id = extract_task_id(resp["result"]["content"][0]["text"].as_str().unwrap());
+
+    send_request(&mut stdin, "tools/call", 43, Some(serde_json::json!({"name": "zsh_tasks", "arguments": {}})));
+    let resp = read_response(&mut reader);
+    let listed: Value = serde_json::from_str(resp["result"]["content"][0]["text"].as_str().unwrap()).unwrap();
+    let task = listed["tasks"].as_array().unwrap().iter().find(|t| t["task_id"] == task_id.as_str()).unwrap();
+    assert_eq!(task["request_id"], 42);
+
+    let conn = rusqlite::Connection::open(&db_path).unwrap();
+    let recorded: Option<String> = conn
+        .query_row("SELECT request_id FROM recent_commands WHERE command_preview = 'echo traced'", [], |r| r.get(0))
+        .unwrap();
+    assert_eq!(recorded.as_deref(), Some("42"));
+
+    drop(stdin);
+    let _ = child.wait();
+}
+
+#[test]
+fn test_server_exits_cleanly_when_client_stops_reading() {
+    let (mut stdin, reader, mut child) = spawn_server();
+
+    // Client closes its read end; the next response has nowhere to go
+    drop(reader);
+    send_request(&mut stdin, "ping", 1, None);
+
+    // stdin stays open, so only the write failure can end the loop
+    let deadline = std::time::Instant::now() + Duration::from_secs(10);
+    let status = loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            break status;
+        }
+        assert!(std::time::Instant::now() < deadline, "server did not exit after client disconnect");
+        std::thread::sleep(Duration::from_millis(50));
+    };
+    assert!(status.success(), "expected clean exit, got {:?}", status);
+
+    drop(stdin);
+}
+
 #[test]
 fn test_ping() {
     let (mut stdin, mut reader, mut child) = spawn_server();
@@ -430,16 +1085,163 @@ fn extract_task_id(text: &str) -> String {
 }
 
 #[test]
-fn test_background_completion_notifies_on_next_tool_call() {
-    // When a background task completes while the caller isn't watching,
-    // the NEXT tool call (any tool) should include a [notify] line.
-    let (mut stdin, mut reader, mut child) = spawn_server();
+fn test_yield_after_zero_returns_running_immediately() {
+    let (mut stdin, mut reader, mut child) = spawn_server();
+
+    send_request(&mut stdin, "initialize", 1, None);
+    let _ = read_response(&mut reader);
+    send_notification(&mut stdin, "notifications/initialized");
+
+    let started = std::time::Instant::now();
+    send_request(
+        &mut stdin,
+        "tools/call",
+        2,
+        Some(serde_json::json!({
+            "name": "zsh",
+            "arguments": {"command": "sleep 1 && echo x", "timeout": 30, "yield_after": 0}
+        })),
+    );
+    let resp = read_response(&mut reader);
+    let waited = started.elapsed();
+    let text = resp["result"]["content"][0]["text"].as_str().unwrap();
+    assert!(text.contains("RUNNING"), "should yield as RUNNING, got: {}", text);
+    assert!(!extract_task_id(text).is_empty());
+    assert!(waited < Duration::from_millis(500), "returned after {:?}", waited);
+
+    drop(stdin);
+    let _ = child.wait();
+}
+
+#[test]
+fn test_yield_on_output_returns_early_with_output() {
+    let (mut stdin, mut reader, mut child) = spawn_server();
+
+    send_request(&mut stdin, "initialize", 1, None);
+    let _ = read_response(&mut reader);
+    send_notification(&mut stdin, "notifications/initialized");
+
+    let started = std::time::Instant::now();
+    send_request(
+        &mut stdin,
+        "tools/call",
+        2,
+        Some(serde_json::json!({
+            "name": "zsh",
+            "arguments": {
+                "command": "echo result-ready; sleep 10",
+                "timeout": 30,
+                "yield_after": 8,
+                "yield_on_output": 1
+            }
+        })),
+    );
+    let resp = read_response(&mut reader);
+    let waited = started.elapsed();
+    let text = resp["result"]["content"][0]["text"].as_str().unwrap();
+    assert!(text.contains("RUNNING"), "should yield as RUNNING, got: {}", text);
+    assert!(text.contains("result-ready"), "should carry the output, got: {}", text);
+    assert!(waited < Duration::from_secs(4), "returned after {:?}", waited);
+
+    send_request(
+        &mut stdin,
+        "tools/call",
+        3,
+        Some(serde_json::json!({"name": "zsh_kill", "arguments": {"task_id": extract_task_id(text)}})),
+    );
+    let _ = read_response(&mut reader);
+    drop(stdin);
+    let _ = child.wait();
+}
+
+#[test]
+fn test_background_completion_notifies_on_next_tool_call() {
+    // When a background task completes while the caller isn't watching,
+    // the NEXT tool call (any tool) should include a [notify] line.
+    let (mut stdin, mut reader, mut child) = spawn_server();
+
+    send_request(&mut stdin, "initialize", 1, None);
+    let _ = read_response(&mut reader);
+    send_notification(&mut stdin, "notifications/initialized");
+
+    // Command runs ~0.4s; yield_after=0.1s → yields RUNNING, then finishes in background.
+    send_request(
+        &mut stdin,
+        "tools/call",
+        2,
+        Some(serde_json::json!({
+            "name": "zsh",
+            "arguments": {
+                "command": "sleep 0.4 && echo notify-test",
+                "timeout": 10,
+                "yield_after": 0.1
+            }
+        })),
+    );
+
+    let resp = read_response(&mut reader);
+    let text = resp["result"]["content"][0]["text"].as_str().unwrap();
+    assert!(text.contains("RUNNING"), "should yield as RUNNING, got: {}", text);
+
+    // Wait well past command completion.
+    std::thread::sleep(Duration::from_millis(800));
+
+    // Call a completely unrelated tool. The response should contain a [notify] line
+    // reporting that the background task finished.
+    send_request(
+        &mut stdin,
+        "tools/call",
+        3,
+        Some(serde_json::json!({
+            "name": "zsh_health",
+            "arguments": {}
+        })),
+    );
+
+    let resp = read_response(&mut reader);
+    let text = resp["result"]["content"][0]["text"].as_str().unwrap();
+    assert!(
+        text.contains("notify"),
+        "Expected notify in next tool call response, got:\n{}", text
+    );
+    assert!(
+        text.contains("completed") || text.contains("failed"),
+        "Expected completed/failed status in notify, got:\n{}", text
+    );
+
+    // The notification should be fire-once: a second unrelated tool call should NOT
+    // include the same notification again.
+    send_request(
+        &mut stdin,
+        "tools/call",
+        4,
+        Some(serde_json::json!({
+            "name": "zsh_health",
+            "arguments": {}
+        })),
+    );
+
+    let resp = read_response(&mut reader);
+    let text = resp["result"]["content"][0]["text"].as_str().unwrap();
+    assert!(
+        !text.contains("notify"),
+        "Notification should fire only once, but appeared again:\n{}", text
+    );
+
+    drop(stdin);
+    let _ = child.wait();
+}
+
+#[test]
+fn test_push_notifications_announce_completion_unprompted() {
+    let (mut stdin, mut reader, mut child) =
+        spawn_server_with_env(&[("ZSH_TOOL_PUSH_NOTIFICATIONS", "1")]);
 
     send_request(&mut stdin, "initialize", 1, None);
-    let _ = read_response(&mut reader);
+    let resp = read_response(&mut reader);
+    assert!(resp["result"]["capabilities"]["notifications"].is_object());
     send_notification(&mut stdin, "notifications/initialized");
 
-    // Command runs ~0.4s; yield_after=0.1s → yields RUNNING, then finishes in background.
     send_request(
         &mut stdin,
         "tools/call",
@@ -447,61 +1249,78 @@ fn test_background_completion_notifies_on_next_tool_call() {
         Some(serde_json::json!({
             "name": "zsh",
             "arguments": {
-                "command": "sleep 0.4 && echo notify-test",
+                "command": "sleep 0.4 && echo push-test",
                 "timeout": 10,
                 "yield_after": 0.1
             }
         })),
     );
-
     let resp = read_response(&mut reader);
     let text = resp["result"]["content"][0]["text"].as_str().unwrap();
     assert!(text.contains("RUNNING"), "should yield as RUNNING, got: {}", text);
+    let task_id = extract_task_id(text);
 
-    // Wait well past command completion.
-    std::thread::sleep(Duration::from_millis(800));
+    // No further request: the completion has to arrive on its own
+    let note = read_response(&mut reader);
+    assert_eq!(note["method"], "notifications/message");
+    assert!(note.get("id").is_none());
+    assert_eq!(note["params"]["data"]["event"], "task_completed");
+    assert_eq!(note["params"]["data"]["task_id"], task_id.as_str());
 
-    // Call a completely unrelated tool. The response should contain a [notify] line
-    // reporting that the background task finished.
+    // Already delivered, so the next tool result carries no piggybacked copy
     send_request(
         &mut stdin,
         "tools/call",
         3,
-        Some(serde_json::json!({
-            "name": "zsh_health",
-            "arguments": {}
-        })),
+        Some(serde_json::json!({"name": "zsh_health", "arguments": {}})),
     );
-
     let resp = read_response(&mut reader);
+    assert_eq!(resp["id"], 3);
     let text = resp["result"]["content"][0]["text"].as_str().unwrap();
-    assert!(
-        text.contains("notify"),
-        "Expected notify in next tool call response, got:\n{}", text
-    );
-    assert!(
-        text.contains("completed") || text.contains("failed"),
-        "Expected completed/failed status in notify, got:\n{}", text
-    );
+    assert!(!text.contains("notify"), "unexpected piggyback:\n{}", text);
+
+    drop(stdin);
+    let _ = child.wait();
+}
+
+#[test]
+fn test_cancelled_request_kills_yielded_task() {
+    let (mut stdin, mut reader, mut child) = spawn_server();
+
+    send_request(&mut stdin, "initialize", 1, None);
+    let _ = read_response(&mut reader);
 
-    // The notification should be fire-once: a second unrelated tool call should NOT
-    // include the same notification again.
     send_request(
         &mut stdin,
         "tools/call",
-        4,
+        2,
         Some(serde_json::json!({
-            "name": "zsh_health",
-            "arguments": {}
+            "name": "zsh",
+            "arguments": {"command": "sleep 30", "timeout": 60, "yield_after": 0.1}
         })),
     );
-
     let resp = read_response(&mut reader);
     let text = resp["result"]["content"][0]["text"].as_str().unwrap();
-    assert!(
-        !text.contains("notify"),
-        "Notification should fire only once, but appeared again:\n{}", text
+    assert!(text.contains("RUNNING"), "should yield as RUNNING, got: {}", text);
+    let task_id = extract_task_id(text);
+
+    let cancel = serde_json::json!({
+        "jsonrpc": "2.0",
+        "method": "notifications/cancelled",
+        "params": {"requestId": 2, "reason": "user aborted"},
+    });
+    stdin.write_all(frame_message(&cancel.to_string()).as_bytes()).unwrap();
+    stdin.flush().unwrap();
+
+    send_request(
+        &mut stdin,
+        "tools/call",
+        3,
+        Some(serde_json::json!({"name": "zsh_poll", "arguments": {"task_id": task_id}})),
     );
+    let resp = read_response(&mut reader);
+    let text = resp["result"]["content"][0]["text"].as_str().unwrap();
+    assert!(text.contains("KILLED"), "task should be killed, got: {}", text);
 
     drop(stdin);
     let _ = child.wait();
@@ -845,3 +1664,459 @@ fn test_poll_completion_returns_delta_not_full() {
     drop(stdin);
     let _ = child.wait();
 }
+
+#[test]
+fn test_logging_set_level_streams_log_notifications() {
+    let (mut stdin, mut reader, mut child) = spawn_server();
+
+    send_request(&mut stdin, "initialize", 1, None);
+    let _ = read_response(&mut reader);
+    send_notification(&mut stdin, "notifications/initialized");
+
+    send_request(&mut stdin, "logging/setLevel", 2, Some(serde_json::json!({"level": "bogus"})));
+    let resp = read_response(&mut reader);
+    assert_eq!(resp["id"], 2);
+    assert_eq!(resp["error"]["code"], -32602);
+
+    send_request(&mut stdin, "logging/setLevel", 3, Some(serde_json::json!({"level": "debug"})));
+    let resp = read_response(&mut reader);
+    assert_eq!(resp["id"], 3);
+    assert!(resp["result"].is_object());
+
+    // Entries logged while handling a request follow its response
+    let mut notes = Vec::new();
+    loop {
+        let msg = read_response(&mut reader);
+        assert_eq!(msg["method"], "notifications/message", "got: {}", msg);
+        assert!(msg.get("id").is_none());
+        assert_eq!(msg["params"]["logger"], "zsh-tool");
+        let data = msg["params"]["data"].as_str().unwrap().to_string();
+        let done = data.contains("Response sent for: logging/setLevel");
+        notes.push((msg["params"]["level"].as_str().unwrap().to_string(), data));
+        if done {
+            break;
+        }
+    }
+    assert!(notes.iter().any(|(level, data)| level == "info" && data.contains("Client log level set to debug")), "{:?}", notes);
+
+    send_request(&mut stdin, "ping", 4, None);
+    let resp = read_response(&mut reader);
+    assert_eq!(resp["id"], 4);
+    // Debug entries queued while handling the ping, and the one saying its
+    // response went out, follow that response and nothing older does
+    let mut pings = Vec::new();
+    loop {
+        let msg = read_response(&mut reader);
+        assert_eq!(msg["params"]["level"], "debug", "got: {}", msg);
+        let data = msg["params"]["data"].as_str().unwrap_or("").to_string();
+        assert!(data.contains("ping"), "stale log entry after the ping response: {}", data);
+        let done = data.contains("Response sent for: ping");
+        pings.push(data);
+        if done {
+            break;
+        }
+    }
+    assert!(pings[0].contains("Request: ping"), "{:?}", pings);
+
+    drop(stdin);
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn test_benchmark_reports_stats_over_runs() {
+    let (mut stdin, mut reader, mut child) = spawn_server_with_env(&[("ZSH_TOOL_TEST_CLOCK", "0")]);
+
+    send_request(&mut stdin, "initialize", 1, None);
+    let _ = read_response(&mut reader);
+    send_notification(&mut stdin, "notifications/initialized");
+
+    send_request(
+        &mut stdin,
+        "tools/call",
+        2,
+        Some(serde_json::json!({
+            "name": "zsh_benchmark",
+            "arguments": {"command": "echo bench", "runs": 4}
+        })),
+    );
+    let resp = read_response(&mut reader);
+    let text = resp["result"]["content"][0]["text"].as_str().unwrap();
+    let v: Value = serde_json::from_str(text).unwrap();
+
+    assert_eq!(v["runs_completed"], 4, "{}", text);
+    assert_eq!(v["successes"], 4, "{}", text);
+    assert_eq!(v["exit_codes"], serde_json::json!([0, 0, 0, 0]));
+    assert_eq!(v["durations_seconds"].as_array().unwrap().len(), 4);
+    let stats = &v["stats"];
+    let (min, max) = (stats["min"].as_f64().unwrap(), stats["max"].as_f64().unwrap());
+    for key in ["mean", "p50", "p90"] {
+        let x = stats[key].as_f64().unwrap();
+        assert!(min <= x && x <= max, "{} out of range: {}", key, text);
+    }
+    assert!(v["stopped"].is_null());
+
+    drop(stdin);
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn test_sequence_aborts_at_failing_step() {
+    let (mut stdin, mut reader, mut child) = spawn_server_with_env(&[("ZSH_TOOL_TEST_CLOCK", "0")]);
+
+    send_request(&mut stdin, "initialize", 1, None);
+    let _ = read_response(&mut reader);
+    send_notification(&mut stdin, "notifications/initialized");
+
+    send_request(
+        &mut stdin,
+        "tools/call",
+        2,
+        Some(serde_json::json!({
+            "name": "zsh_sequence",
+            "arguments": {"steps": [
+                "echo one",
+                {"command": "false", "continue_on_error": true},
+                "echo two && exit 3",
+                "echo never"
+            ]}
+        })),
+    );
+    let resp = read_response(&mut reader);
+    let text = resp["result"]["content"][0]["text"].as_str().unwrap();
+    let v: Value = serde_json::from_str(text).unwrap();
+
+    assert_eq!(v["success"], false);
+    assert_eq!(v["aborted_at"], 2);
+    let steps = v["steps"].as_array().unwrap();
+    assert_eq!(steps.len(), 4);
+    assert_eq!(steps[0]["success"], true);
+    assert!(steps[0]["output"].as_str().unwrap().contains("one"));
+    assert_eq!(steps[1]["exit_code"], 1);
+    assert_eq!(steps[2]["exit_code"], 3);
+    assert_eq!(steps[3]["status"], "skipped");
+    assert!(!text.contains("never\n"), "skipped step ran: {}", text);
+
+    drop(stdin);
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn test_cwd_persists_across_zsh_calls() {
+    let (mut stdin, mut reader, mut child) = spawn_server();
+
+    send_request(&mut stdin, "initialize", 1, None);
+    let _ = read_response(&mut reader);
+    send_notification(&mut stdin, "notifications/initialized");
+
+    send_request(
+        &mut stdin,
+        "tools/call",
+        2,
+        Some(serde_json::json!({"name": "zsh_cwd", "arguments": {"path": "/tmp"}})),
+    );
+    let resp = read_response(&mut reader);
+    assert!(resp["result"].get("isError").is_none(), "got: {}", resp);
+
+    send_request(
+        &mut stdin,
+        "tools/call",
+        3,
+        Some(serde_json::json!({"name": "zsh", "arguments": {"command": "pwd", "yield_after": 5}})),
+    );
+    let resp = read_response(&mut reader);
+    let text = resp["result"]["content"][0]["text"].as_str().unwrap();
+    assert!(text.contains("/tmp"), "got: {}", text);
+
+    drop(stdin);
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn test_server_exits_after_idle_timeout() {
+    let (mut stdin, mut reader, mut child) =
+        spawn_server_with_env(&[("ZSH_TOOL_SESSION_IDLE_TIMEOUT", "1")]);
+    send_request(&mut stdin, "initialize", 1, None);
+    let resp = read_response(&mut reader);
+    assert!(resp.get("result").is_some());
+
+    // stdin stays open: only the idle timeout can end the server
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+    let status = loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            break status;
+        }
+        assert!(std::time::Instant::now() < deadline, "server still running after idle timeout");
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    };
+    assert!(status.success());
+    drop(stdin);
+}
+
+#[test]
+fn test_session_env_reaches_later_zsh_calls() {
+    let (mut stdin, mut reader, mut child) = spawn_server();
+
+    send_request(&mut stdin, "initialize", 1, None);
+    let _ = read_response(&mut reader);
+    send_notification(&mut stdin, "notifications/initialized");
+
+    send_request(
+        &mut stdin,
+        "tools/call",
+        2,
+        Some(serde_json::json!({"name": "zsh_env", "arguments": {"set": {"ZT_SESSION": "k=v", "ZT_OVERRIDE": "session"}}})),
+    );
+    let resp = read_response(&mut reader);
+    assert!(resp["result"].get("isError").is_none(), "got: {}", resp);
+
+    send_request(
+        &mut stdin,
+        "tools/call",
+        3,
+        Some(serde_json::json!({"name": "zsh", "arguments": {
+            "command": "echo \"[$ZT_SESSION][$ZT_OVERRIDE]\"; tr '\\0' ' ' < /proc/$PPID/cmdline",
+            "env": {"ZT_OVERRIDE": "call"},
+            "yield_after": 5
+        }})),
+    );
+    let resp = read_response(&mut reader);
+    let text = resp["result"]["content"][0]["text"].as_str().unwrap();
+    assert!(text.contains("[k=v][call]"), "got: {}", text);
+    // Values stay out of the executor's argv, which every local user can read
+    assert!(!text.contains("ZT_SESSION=k=v") && !text.contains("ZT_OVERRIDE=call"), "got: {}", text);
+
+    drop(stdin);
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[test]
+fn test_large_output_fetched_through_resource_link() {
+    let (mut stdin, mut reader, mut child) = spawn_server();
+
+    send_request(&mut stdin, "initialize", 1, None);
+    let resp = read_response(&mut reader);
+    assert!(resp["result"]["capabilities"]["resources"].is_object());
+    send_notification(&mut stdin, "notifications/initialized");
+
+    send_request(
+        &mut stdin,
+        "tools/call",
+        2,
+        Some(serde_json::json!({"name": "zsh", "arguments": {"command": "seq 1 20000", "yield_after": 5}})),
+    );
+    let resp = read_response(&mut reader);
+    let content = resp["result"]["content"].as_array().unwrap();
+    let link = content
+        .iter()
+        .find(|c| c["type"] == "resource_link")
+        .unwrap_or_else(|| panic!("no resource_link in: {}", resp));
+    let uri = link["uri"].as_str().unwrap();
+    assert!(uri.starts_with("zsh-tool://output/"), "uri: {}", uri);
+
+    let expected: String = (1..=20000).map(|n| format!("{}\n", n)).collect();
+    assert_eq!(link["size"], expected.len());
+
+    send_request(&mut stdin, "resources/read", 3, Some(serde_json::json!({"uri": uri})));
+    let resp = read_response(&mut reader);
+    assert_eq!(resp["result"]["contents"][0]["text"].as_str().unwrap(), expected);
+
+    send_request(&mut stdin, "resources/list", 4, None);
+    let resp = read_response(&mut reader);
+    assert_eq!(resp["result"]["resources"][1]["uri"], uri);
+
+    send_request(&mut stdin, "resources/read", 5, Some(serde_json::json!({"uri": "zsh-tool://output/nope"})));
+    let resp = read_response(&mut reader);
+    assert_eq!(resp["error"]["code"], -32002);
+
+    drop(stdin);
+    let _ = child.wait();
+}
+
+/// Start `serve --tcp` on an ephemeral port; returns the server and its address.
+fn spawn_tcp_server() -> (std::process::Child, String) {
+    spawn_tcp_server_with_env(&[])
+}
+
+fn spawn_tcp_server_with_env(env: &[(&str, &str)]) -> (std::process::Child, String) {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_zsh-tool-exec"))
+        .args(["serve", "--tcp", "127.0.0.1:0"])
+        .envs(env.iter().copied())
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to spawn server");
+    let mut stderr = BufReader::new(child.stderr.take().unwrap());
+    let addr = loop {
+        let mut line = String::new();
+        assert!(stderr.read_line(&mut line).unwrap() > 0, "server exited before listening");
+        if let Some(addr) = line.trim().split("listening on ").nth(1) {
+            break addr.to_string();
+        }
+    };
+    // Keep draining so server logging never blocks on a full pipe
+    std::thread::spawn(move || std::io::copy(&mut stderr, &mut std::io::sink()));
+    (child, addr)
+}
+
+fn connect(addr: &str) -> (std::net::TcpStream, BufReader<std::net::TcpStream>) {
+    let stream = std::net::TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(30))).unwrap();
+    let reader = BufReader::new(stream.try_clone().unwrap());
+    (stream, reader)
+}
+
+#[test]
+fn test_tcp_serves_two_clients_at_once() {
+    let (mut server, addr) = spawn_tcp_server();
+    let (mut a, mut a_reader) = connect(&addr);
+    let (mut b, mut b_reader) = connect(&addr);
+
+    // Interleave: b is answered while a's connection stays open
+    send_request(&mut a, "initialize", 1, None);
+    send_request(&mut b, "initialize", 1, None);
+    assert_eq!(read_response(&mut b_reader)["result"]["serverInfo"]["name"], "zsh-tool");
+    assert_eq!(read_response(&mut a_reader)["result"]["serverInfo"]["name"], "zsh-tool");
+
+    send_request(&mut b, "tools/list", 2, None);
+    assert!(read_response(&mut b_reader)["result"]["tools"].is_array());
+    drop((b, b_reader));
+
+    // One client leaving does not stop the server
+    send_request(&mut a, "ping", 2, None);
+    assert_eq!(read_response(&mut a_reader)["id"], 2);
+
+    let _ = server.kill();
+    let _ = server.wait();
+}
+
+#[test]
+fn test_tcp_clients_run_independent_commands() {
+    let (mut server, addr) = spawn_tcp_server();
+    let clients: Vec<_> = ["alpha", "beta"]
+        .into_iter()
+        .map(|name| {
+            let addr = addr.clone();
+            std::thread::spawn(move || {
+                let (mut stream, mut reader) = connect(&addr);
+                send_request(&mut stream, "initialize", 1, None);
+                let _ = read_response(&mut reader);
+                send_request(
+                    &mut stream,
+                    "tools/call",
+                    2,
+                    Some(serde_json::json!({"name": "zsh", "arguments": {
+                        // printf so the expected text only appears in the output
+                        "command": format!("sleep 0.2; printf 'client-%s\\n' {}", name),
+                        "yield_after": 5
+                    }})),
+                );
+                let resp = read_response(&mut reader);
+                resp["result"]["content"][0]["text"].as_str().unwrap().to_string()
+            })
+        })
+        .collect();
+    let outputs: Vec<String> = clients.into_iter().map(|h| h.join().unwrap()).collect();
+    assert!(outputs[0].contains("client-alpha") && !outputs[0].contains("client-beta"), "got: {}", outputs[0]);
+    assert!(outputs[1].contains("client-beta") && !outputs[1].contains("client-alpha"), "got: {}", outputs[1]);
+
+    let _ = server.kill();
+    let _ = server.wait();
+}
+
+#[test]
+fn test_tcp_push_notifications_reach_only_the_starting_client() {
+    let (mut server, addr) = spawn_tcp_server_with_env(&[
+        ("ZSH_TOOL_PUSH_NOTIFICATIONS", "1"),
+        ("ZSH_TOOL_SHELL", "bash"),
+        ("ZSH_TOOL_SHELL_PATH", "/bin/bash"),
+    ]);
+    let (mut a, mut a_reader) = connect(&addr);
+    let (mut b, mut b_reader) = connect(&addr);
+    for (stream, reader) in [(&mut a, &mut a_reader), (&mut b, &mut b_reader)] {
+        send_request(stream, "initialize", 1, None);
+        assert!(read_response(reader)["result"]["capabilities"]["notifications"].is_object());
+    }
+
+    send_request(
+        &mut a,
+        "tools/call",
+        2,
+        Some(serde_json::json!({"name": "zsh", "arguments": {
+            "command": "sleep 0.4 && echo push-test",
+            "yield_after": 0.1
+        }})),
+    );
+    let text = read_response(&mut a_reader)["result"]["content"][0]["text"].as_str().unwrap().to_string();
+    let task_id = extract_task_id(&text);
+
+    let note = read_response(&mut a_reader);
+    assert_eq!(note["method"], "notifications/message");
+    assert_eq!(note["params"]["data"]["task_id"], task_id.as_str());
+
+    // b's next message is the answer to its own request, not a's event
+    send_request(&mut b, "ping", 2, None);
+    assert_eq!(read_response(&mut b_reader)["id"], 2);
+
+    let _ = server.kill();
+    let _ = server.wait();
+}
+
+#[test]
+fn test_tcp_framing_and_log_level_are_per_connection() {
+    let (mut server, addr) = spawn_tcp_server_with_env(&[
+        ("ZSH_TOOL_SHELL", "bash"),
+        ("ZSH_TOOL_SHELL_PATH", "/bin/bash"),
+    ]);
+    let (mut a, mut a_reader) = connect(&addr);
+    let (mut b, mut b_reader) = connect(&addr);
+
+    // a speaks bare JSON lines and asks for debug logs
+    let mut send_bare = |id: u64, method: &str, params: Value| {
+        let line = serde_json::json!({"jsonrpc": "2.0", "id": id, "method": method, "params": params});
+        writeln!(a, "{}", line).unwrap();
+        a.flush().unwrap();
+    };
+    let mut read_bare = || {
+        let mut line = String::new();
+        a_reader.read_line(&mut line).unwrap();
+        serde_json::from_str::<Value>(&line).unwrap_or_else(|e| panic!("not bare JSON ({}): {:?}", e, line))
+    };
+    send_bare(1, "initialize", serde_json::json!({}));
+    assert_eq!(read_bare()["id"], 1);
+    send_bare(2, "logging/setLevel", serde_json::json!({"level": "debug"}));
+    while read_bare()["id"] != 2 {}
+
+    // b stays Content-Length framed and hears none of a's logs
+    send_request(&mut b, "initialize", 1, None);
+    assert_eq!(read_response(&mut b_reader)["id"], 1);
+    send_request(&mut b, "tools/list", 2, None);
+    assert_eq!(read_response(&mut b_reader)["id"], 2);
+    send_request(&mut b, "ping", 3, None);
+    assert_eq!(read_response(&mut b_reader)["id"], 3);
+
+    // a's logs after its ping are its own, not b's requests
+    send_bare(3, "ping", serde_json::json!({}));
+    let mut logs = Vec::new();
+    loop {
+        let msg = read_bare();
+        if msg.get("id").is_some() {
+            continue;
+        }
+        let data = msg["params"]["data"].as_str().unwrap_or("").to_string();
+        let done = data.contains("Response sent for: ping");
+        logs.push(data);
+        if done {
+            break;
+        }
+    }
+    assert!(logs.iter().all(|l| !l.contains("tools/list") && !l.contains("initialize")), "{:?}", logs);
+
+    let _ = server.kill();
+    let _ = server.wait();
+}
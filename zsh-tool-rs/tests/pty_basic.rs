@@ -99,3 +99,66 @@ fn test_pty_timeout() {
 
     let _ = fs::remove_file(meta);
 }
+
+#[test]
+fn test_pty_env_injected() {
+    let meta = "/tmp/zsh-test-pty-env.json";
+    let _ = fs::remove_file(meta);
+
+    let output = Command::new(exec_path())
+        .args(["--meta", meta, "--pty", "--env", "ZSH_TOOL_TEST_FOO=bar", "--", "echo \"[$ZSH_TOOL_TEST_FOO]\""])
+        .output()
+        .expect("failed to run");
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("[bar]"), "stdout: {:?}", stdout);
+    assert!(fs::read_to_string(meta).is_ok(), "meta file missing");
+
+    let _ = fs::remove_file(meta);
+}
+
+#[test]
+fn test_pty_keeps_output_written_just_before_exit() {
+    // The last write races the child's exit; repeat so a lost tail shows up
+    let dir = tempfile::tempdir().unwrap();
+    let meta = dir.path().join("meta.json");
+    for run in 0..20 {
+        let output = Command::new(exec_path())
+            .args(["--meta", meta.to_str().unwrap(), "--pty", "--shell-kind", "bash", "--"])
+            .arg("head -c 50000 /dev/zero | tr '\\0' x; printf 'LAST_BYTES_%s' 42")
+            .output()
+            .expect("failed to run");
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(stdout.ends_with("LAST_BYTES_42"), "run {}: {} bytes", run, stdout.len());
+    }
+}
+
+#[test]
+fn test_pty_drain_stops_when_background_process_holds_terminal() {
+    // The background sleep keeps the slave open, so EOF never comes on its own
+    let dir = tempfile::tempdir().unwrap();
+    let meta = dir.path().join("meta.json");
+    let started = std::time::Instant::now();
+    let output = Command::new(exec_path())
+        .args(["--meta", meta.to_str().unwrap(), "--pty", "--pty-drain-ms", "100", "--shell-kind", "bash", "--", "sleep 5 3>&- & echo done"])
+        .output()
+        .expect("failed to run");
+    assert!(String::from_utf8_lossy(&output.stdout).contains("done"));
+    assert!(started.elapsed() < std::time::Duration::from_secs(3), "took {:?}", started.elapsed());
+}
+
+#[test]
+fn test_pty_drain_deadline_stops_chatty_background_process() {
+    // The background loop ignores SIGHUP and never goes quiet, so only the
+    // drain deadline ends the read
+    let dir = tempfile::tempdir().unwrap();
+    let meta = dir.path().join("meta.json");
+    let started = std::time::Instant::now();
+    let output = Command::new(exec_path())
+        .args(["--meta", meta.to_str().unwrap(), "--pty", "--timeout", "3", "--pty-drain-ms", "100", "--shell-kind", "bash", "--"])
+        .arg("(trap '' HUP; for i in $(seq 100); do echo x; sleep 0.05; done) 3>&- & echo fg")
+        .output()
+        .expect("failed to run");
+    assert!(String::from_utf8_lossy(&output.stdout).contains("fg"));
+    assert!(started.elapsed() < std::time::Duration::from_secs(3), "took {:?}", started.elapsed());
+}
@@ -46,3 +46,77 @@ fn test_fast_command_no_timeout() {
 
     let _ = fs::remove_file(meta);
 }
+
+#[test]
+fn test_timed_out_pipeline_reports_no_fake_pipestatus() {
+    let meta = "/tmp/zsh-test-timeout-pipeline.json";
+    let _ = fs::remove_file(meta);
+
+    let _output = Command::new(exec_path())
+        .args(["--meta", meta, "--timeout", "1", "--", "true | sleep 60 | cat"])
+        .output()
+        .expect("failed to run");
+
+    let meta_content = fs::read_to_string(meta).expect("meta file missing");
+    let v: serde_json::Value = serde_json::from_str(&meta_content).expect("invalid json");
+    assert_eq!(v["timed_out"], true);
+    assert_eq!(v["pipestatus"], serde_json::json!([]), "meta: {}", meta_content);
+
+    let _ = fs::remove_file(meta);
+}
+
+#[test]
+fn test_timeout_reaps_daemonized_descendant() {
+    let meta = "/tmp/zsh-test-daemon.json";
+    let dir = tempfile::tempdir().unwrap();
+    let pidfile = dir.path().join("daemon.pid");
+    let _ = fs::remove_file(meta);
+
+    // The background job calls setsid, leaving the shell's process group
+    let cmd = format!(
+        "setsid sh -c 'echo $$ > {}; exec sleep 60' >/dev/null 2>&1 < /dev/null & sleep 60",
+        pidfile.display()
+    );
+    let _ = Command::new(exec_path())
+        .args(["--meta", meta, "--timeout", "2", "--", &cmd])
+        .output()
+        .expect("failed to run");
+
+    let pid: i32 = fs::read_to_string(&pidfile)
+        .expect("daemon never started")
+        .trim()
+        .parse()
+        .unwrap();
+    // Reparented to init, so it may linger as a zombie until reaped there
+    let alive = fs::read_to_string(format!("/proc/{}/stat", pid))
+        .map(|stat| !stat.contains(") Z "))
+        .unwrap_or(false);
+    assert!(!alive, "daemonized process {} survived the timeout", pid);
+
+    let _ = fs::remove_file(meta);
+}
+
+#[test]
+fn test_timeout_signal_delivered_before_kill() {
+    let meta = "/tmp/zsh-test-timeout-signal.json";
+    let _ = fs::remove_file(meta);
+
+    let start = Instant::now();
+    let output = Command::new(exec_path())
+        .args([
+            "--meta", meta, "--timeout", "1", "--timeout-signal", "TERM", "--",
+            "trap 'echo got-term; exit 0' TERM; while true; do sleep 0.1; done",
+        ])
+        .output()
+        .expect("failed to run");
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("got-term"), "trap never ran, stdout: {}", stdout);
+    // Exited on the signal, well inside the SIGKILL grace period
+    assert!(start.elapsed().as_secs() < 4, "took {:?}", start.elapsed());
+
+    let v: serde_json::Value = serde_json::from_str(&fs::read_to_string(meta).unwrap()).unwrap();
+    assert_eq!(v["timed_out"], true);
+
+    let _ = fs::remove_file(meta);
+}